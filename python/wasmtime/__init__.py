@@ -1,4 +1,10 @@
-from .lib_wasmtime import imported_modules, instantiate
+from .lib_wasmtime import imported_modules, instantiate, run_command, Memory, Module, Store, ResourceLimiter
+from .lib_wasmtime import Trap, Interrupted, Timeout, ResourceLimitExceeded, Frame
+from .lib_wasmtime import WasiConfig, WasiInstance, WasiExit, VirtualDir
+from .lib_wasmtime import enable_cache, disable_cache, clear_cache, compile_to_object
+from .lib_wasmtime import configure_compiler, compiler_threads
+from .lib_wasmtime import enable_perf_map, disable_perf_map, Profile
+import contextlib
 import sys
 import os.path
 
@@ -6,6 +12,41 @@ from importlib import import_module
 from importlib.abc import Loader, MetaPathFinder
 from importlib.util import spec_from_file_location
 
+WASI_MODULES = ("wasi_snapshot_preview1", "wasi_unstable")
+
+_import_hook_lazy = False
+
+def configure_import_hook(lazy=False):
+    """Configures the instantiation of `.wasm` modules by the import hook:
+    with `lazy`, functions are only compiled when first called, see
+    `instantiate`."""
+    global _import_hook_lazy
+    _import_hook_lazy = lazy
+
+def _format_trap(trap):
+    """Renders a `Trap` with its wasm frames, innermost first."""
+    message = Exception.__str__(trap)
+    frames = getattr(trap, "frames", None)
+    if not frames:
+        return message
+    lines = [message, "wasm backtrace:"]
+    for i, frame in enumerate(frames):
+        lines.append("  %2d: %s" % (i, frame))
+    return "\n".join(lines)
+
+Trap.__str__ = _format_trap
+
+@contextlib.contextmanager
+def profile(instance, interval=0.001):
+    """Samples the wasm stack of the calls into `instance` every `interval`
+    seconds of CPU time while the block runs, see `Profile`."""
+    p = Profile(instance, interval)
+    p.start()
+    try:
+        yield p
+    finally:
+        p.stop()
+
 # Mostly copied from
 # https://stackoverflow.com/questions/43571737/how-to-implement-an-import-hook-that-can-modify-the-source-code-on-the-fly-using
 class MyMetaFinder(MetaPathFinder):
@@ -37,13 +78,20 @@ class MyLoader(Loader):
             data = f.read()
 
         imports = {}
+        wasi = None
         for module_name, fields in imported_modules(data).items():
+            if module_name in WASI_MODULES:
+                # WASI modules run with the stdio of the process.
+                if wasi is None:
+                    wasi = WasiInstance(WasiConfig(argv=[self.filename]))
+                imports[module_name] = wasi
+                continue
             imports[module_name] = {}
             imported_module = import_module(module_name)
             for field_name in fields:
                 imports[module_name][field_name] = imported_module.__dict__[field_name]
 
-        res = instantiate(data, imports)
+        res = instantiate(data, imports, lazy=_import_hook_lazy)
         module.__dict__.update(res.instance.exports)
 
 sys.meta_path.insert(0, MyMetaFinder())
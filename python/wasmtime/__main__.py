@@ -0,0 +1,90 @@
+"""Command line runner for WASI commands.
+
+    python -m wasmtime run [--dir DIR] [--env NAME=VALUE] [--invoke FUNC]
+                           module.wasm [-- ARGS...]
+
+Runs `_start`, or the export given with `--invoke` and prints its result.
+The exit code is the one passed to `proc_exit`, 0 if the guest returns and
+1 if it traps.
+"""
+
+import argparse
+import sys
+
+from . import run_command, Trap, WasiConfig, WasiExit
+
+
+def parse_dir(value):
+    # GUEST::HOST maps the host directory HOST to GUEST, a plain HOST keeps
+    # its name.
+    guest, sep, host = value.partition("::")
+    return (guest, host) if sep else (value, value)
+
+
+def parse_env(value):
+    name, sep, env_value = value.partition("=")
+    if not sep:
+        raise argparse.ArgumentTypeError("expected NAME=VALUE: %r" % value)
+    return (name, env_value)
+
+
+def parse_arg(value):
+    for ty in (int, float):
+        try:
+            return ty(value)
+        except ValueError:
+            pass
+    raise argparse.ArgumentTypeError("not a number: %r" % value)
+
+
+def main(argv=None):
+    parser = argparse.ArgumentParser(prog="python -m wasmtime")
+    commands = parser.add_subparsers(dest="command")
+    commands.required = True
+    run = commands.add_parser("run", help="run a WASI command")
+    run.add_argument("--dir", action="append", default=[], type=parse_dir,
+                     metavar="[GUEST::]HOST",
+                     help="give the guest access to a host directory")
+    run.add_argument("--env", action="append", default=[], type=parse_env,
+                     metavar="NAME=VALUE",
+                     help="set an environment variable of the guest")
+    run.add_argument("--invoke", metavar="FUNC",
+                     help="call FUNC with ARGS instead of _start")
+    run.add_argument("module", help="the .wasm file to run")
+    run.add_argument("args", nargs=argparse.REMAINDER,
+                     help="arguments of the command, or of FUNC")
+    options = parser.parse_args(argv)
+
+    args = options.args
+    if args[:1] == ["--"]:
+        args = args[1:]
+    with open(options.module, "rb") as f:
+        data = f.read()
+
+    if options.invoke is not None:
+        try:
+            func_args = [parse_arg(arg) for arg in args]
+        except argparse.ArgumentTypeError as e:
+            parser.error(str(e))
+        argv = [options.module]
+    else:
+        func_args = None
+        argv = [options.module] + args
+    config = WasiConfig(argv=argv, env=dict(options.env),
+                        preopens=dict(options.dir))
+
+    try:
+        result = run_command(data, config, invoke=options.invoke,
+                             args=func_args, handle_sigint=True)
+    except WasiExit as e:
+        return e.args[0]
+    except Trap as e:
+        print("error: %s" % e, file=sys.stderr)
+        return 1
+    if options.invoke is not None and result is not None:
+        print(result)
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
@@ -0,0 +1,225 @@
+//! Atomic instructions of the threads proposal.
+//!
+//! The code generator doesn't support them, so `instrument.rs` lowers them
+//! into calls of the support instance, which performs the accesses on the
+//! memory of the calling instance with the helpers below.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// First opcode (after the `0xfe` prefix) of each group of atomic accesses.
+pub const LOAD: u32 = 0x10;
+pub const STORE: u32 = 0x17;
+pub const RMW: u32 = 0x1e;
+pub const CMPXCHG: u32 = 0x48;
+pub const END: u32 = 0x4f;
+
+/// Access width in bytes within a group: `i32`, `i64`, `i32` 8 and 16 bits,
+/// `i64` 8, 16 and 32 bits.
+const WIDTHS: [usize; 7] = [4, 8, 1, 2, 1, 2, 4];
+
+/// Whether the operands of the opcode `code` (of any group) are `i64`s.
+pub fn is_i64(code: u32) -> bool {
+    match (code - LOAD) % 7 {
+        1 | 4 | 5 | 6 => true,
+        _ => false,
+    }
+}
+
+/// Access width in bytes of the opcode `code` (of any group).
+pub fn width(code: u32) -> usize {
+    WIDTHS[((code - LOAD) % 7) as usize]
+}
+
+#[derive(Clone, Copy)]
+pub enum RmwOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Xchg,
+}
+
+impl RmwOp {
+    pub fn from_code(code: u32) -> Self {
+        match (code - RMW) / 7 {
+            0 => RmwOp::Add,
+            1 => RmwOp::Sub,
+            2 => RmwOp::And,
+            3 => RmwOp::Or,
+            4 => RmwOp::Xor,
+            _ => RmwOp::Xchg,
+        }
+    }
+}
+
+macro_rules! with_atomic {
+    ($ptr:expr, $size:expr, |$a:ident, $int:ident| $body:expr) => {
+        match $size {
+            1 => {
+                #[allow(dead_code)]
+                type $int = u8;
+                let $a = &*($ptr as *const AtomicU8);
+                $body
+            }
+            2 => {
+                #[allow(dead_code)]
+                type $int = u16;
+                let $a = &*($ptr as *const AtomicU16);
+                $body
+            }
+            4 => {
+                #[allow(dead_code)]
+                type $int = u32;
+                let $a = &*($ptr as *const AtomicU32);
+                $body
+            }
+            _ => {
+                #[allow(dead_code)]
+                type $int = u64;
+                let $a = &*($ptr as *const AtomicU64);
+                $body
+            }
+        }
+    };
+}
+
+/// Loads `size` bytes from the (aligned) `ptr`, zero-extended.
+pub unsafe fn load(ptr: *mut u8, size: usize) -> u64 {
+    with_atomic!(ptr, size, |a, Int| a.load(Ordering::SeqCst) as u64)
+}
+
+/// Stores the low `size` bytes of `value` to the (aligned) `ptr`.
+pub unsafe fn store(ptr: *mut u8, size: usize, value: u64) {
+    with_atomic!(ptr, size, |a, Int| a.store(value as Int, Ordering::SeqCst))
+}
+
+/// Performs a read-modify-write and returns the old value, zero-extended.
+pub unsafe fn rmw(ptr: *mut u8, size: usize, op: RmwOp, value: u64) -> u64 {
+    with_atomic!(ptr, size, |a, Int| {
+        let value = value as Int;
+        let old = match op {
+            RmwOp::Add => a.fetch_add(value, Ordering::SeqCst),
+            RmwOp::Sub => a.fetch_sub(value, Ordering::SeqCst),
+            RmwOp::And => a.fetch_and(value, Ordering::SeqCst),
+            RmwOp::Or => a.fetch_or(value, Ordering::SeqCst),
+            RmwOp::Xor => a.fetch_xor(value, Ordering::SeqCst),
+            RmwOp::Xchg => a.swap(value, Ordering::SeqCst),
+        };
+        old as u64
+    })
+}
+
+/// Replaces the value at `ptr` if it equals `expected` (wrapped to `size`
+/// bytes) and returns the old value, zero-extended.
+pub unsafe fn cmpxchg(ptr: *mut u8, size: usize, expected: u64, replacement: u64) -> u64 {
+    with_atomic!(ptr, size, |a, Int| {
+        match a.compare_exchange(
+            expected as Int,
+            replacement as Int,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(old) | Err(old) => old as u64,
+        }
+    })
+}
+
+/// Results of `memory.atomic.wait`.
+pub const WAIT_OK: u32 = 0;
+pub const WAIT_NOT_EQUAL: u32 = 1;
+pub const WAIT_TIMED_OUT: u32 = 2;
+
+/// How often a waiting thread checks whether it was interrupted.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+struct Waiter {
+    woken: Mutex<bool>,
+    cond: Condvar,
+}
+
+/// Threads blocked in `memory.atomic.wait` on a shared memory, by address.
+#[derive(Default)]
+pub struct ParkingLot {
+    waiters: Mutex<HashMap<u64, Vec<Arc<Waiter>>>>,
+}
+
+impl ParkingLot {
+    /// Blocks until `notify` is called for `address`, unless `matches`
+    /// (evaluated atomically with respect to `notify`) returns false.
+    /// A wait is cut short, as if it timed out, when `interrupted` returns
+    /// true, so the guest reaches its next interruption check.
+    pub fn wait(
+        &self,
+        address: u64,
+        matches: impl FnOnce() -> bool,
+        timeout: Option<Duration>,
+        interrupted: impl Fn() -> bool,
+    ) -> u32 {
+        let waiter = Arc::new(Waiter::default());
+        {
+            let mut waiters = self.waiters.lock().unwrap();
+            if !matches() {
+                return WAIT_NOT_EQUAL;
+            }
+            waiters
+                .entry(address)
+                .or_insert_with(Vec::new)
+                .push(waiter.clone());
+        }
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut woken = waiter.woken.lock().unwrap();
+        while !*woken {
+            let now = Instant::now();
+            if deadline.map_or(false, |deadline| now >= deadline) || interrupted() {
+                break;
+            }
+            let slice = match deadline {
+                Some(deadline) => (deadline - now).min(WAIT_POLL_INTERVAL),
+                None => WAIT_POLL_INTERVAL,
+            };
+            woken = waiter.cond.wait_timeout(woken, slice).unwrap().0;
+        }
+        if *woken {
+            return WAIT_OK;
+        }
+        drop(woken);
+
+        // Timed out, unless a `notify` came in between.
+        let mut waiters = self.waiters.lock().unwrap();
+        if *waiter.woken.lock().unwrap() {
+            return WAIT_OK;
+        }
+        if let Some(list) = waiters.get_mut(&address) {
+            list.retain(|w| !Arc::ptr_eq(w, &waiter));
+            if list.is_empty() {
+                waiters.remove(&address);
+            }
+        }
+        WAIT_TIMED_OUT
+    }
+
+    /// Wakes up to `count` threads waiting on `address`, in arrival order,
+    /// and returns how many were woken.
+    pub fn notify(&self, address: u64, count: u32) -> u32 {
+        let mut waiters = self.waiters.lock().unwrap();
+        let list = match waiters.get_mut(&address) {
+            Some(list) => list,
+            None => return 0,
+        };
+        let woken = (count as usize).min(list.len());
+        for waiter in list.drain(..woken) {
+            *waiter.woken.lock().unwrap() = true;
+            waiter.cond.notify_one();
+        }
+        if list.is_empty() {
+            waiters.remove(&address);
+        }
+        woken as u32
+    }
+}
@@ -0,0 +1,808 @@
+//! Backtraces of wasm code which traps.
+//!
+//! The machine code of the instances is registered here with the wasm
+//! offsets it was compiled from. A handler of the signals raised by traps,
+//! installed in front of the one of wasmtime-runtime, walks the frame
+//! pointers from the trapping instruction and records the addresses which
+//! are wasm code; the export call then resolves them to `Frame`s of the
+//! module as given by the user and attaches them to the `Trap` it raises.
+//! Frames are only captured on x86-64 Linux.
+//!
+//! Frames are given a source location when the module has DWARF line
+//! tables, see `dwarf.rs`.
+
+use pyo3::class::PyObjectProtocol;
+use pyo3::ffi;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+
+use crate::compiler::LocalSlot;
+use crate::dwarf::{LineTable, Location};
+use crate::instrument::{Instrumentation, OffsetMap};
+use crate::module::{function_names, module_name};
+use crate::perf;
+use crate::trap::{update_exception, Trap};
+use cranelift_entity::EntityRef;
+use cranelift_wasm::DefinedFuncIndex;
+use lazy_static::lazy_static;
+use wasmparser::{BinaryReaderError, ImportSectionEntryType, ModuleReader, SectionCode};
+use wasmtime_environ::FunctionAddressMap;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::os::raw::c_int;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Frames recorded at most for a trap.
+const MAX_FRAMES: usize = 64;
+
+/// Values of locals recorded at most for a trap.
+const MAX_LOCALS: usize = 256;
+
+/// The module an instance was created from, as given by the user.
+pub struct ModuleInfo {
+    source: Vec<u8>,
+    instrumentation: Instrumentation,
+    /// Parsed the first time they are needed.
+    names: Mutex<Option<Arc<Names>>>,
+    /// Parsed the first time a trap goes through the module.
+    details: Mutex<Option<Arc<Details>>>,
+}
+
+/// Names of the "name" section of a module; an unreadable section is
+/// ignored.
+#[derive(Default)]
+struct Names {
+    module: Option<String>,
+    functions: BTreeMap<u32, String>,
+    imported_functions: u32,
+}
+
+struct Details {
+    offsets: OffsetMap,
+    /// Unreadable debug info is ignored.
+    lines: Option<LineTable>,
+}
+
+fn imported_functions(wasm: &[u8]) -> Result<u32, BinaryReaderError> {
+    let mut count = 0;
+    let mut parser = ModuleReader::new(wasm)?;
+    while !parser.eof() {
+        let section = parser.read()?;
+        if let SectionCode::Import = section.code {
+            for import in section.get_import_section_reader()? {
+                if let ImportSectionEntryType::Function(_) = import?.ty {
+                    count += 1;
+                }
+            }
+        }
+    }
+    Ok(count)
+}
+
+impl ModuleInfo {
+    pub fn new(source: &[u8], instrumentation: &Instrumentation) -> Arc<Self> {
+        Arc::new(Self {
+            source: source.to_vec(),
+            instrumentation: instrumentation.clone(),
+            names: Mutex::new(None),
+            details: Mutex::new(None),
+        })
+    }
+
+    fn names(&self) -> Arc<Names> {
+        let mut names = self.names.lock().unwrap();
+        if names.is_none() {
+            let source = &self.source;
+            let parse = || -> Result<Names, BinaryReaderError> {
+                Ok(Names {
+                    module: module_name(source)?,
+                    functions: function_names(source)?,
+                    imported_functions: imported_functions(source)?,
+                })
+            };
+            *names = Some(Arc::new(parse().unwrap_or_default()));
+        }
+        names.as_ref().unwrap().clone()
+    }
+
+    /// Name of the module, from its "name" section.
+    pub fn name(&self) -> Option<String> {
+        self.names().module.clone()
+    }
+
+    /// Label of the defined function `index`, see `function_label`.
+    pub fn function_label(&self, index: DefinedFuncIndex) -> String {
+        let names = self.names();
+        let func_index = names.imported_functions + index.index() as u32;
+        function_label(
+            names.module.as_ref().map(String::as_str),
+            func_index,
+            names.functions.get(&func_index).map(String::as_str),
+        )
+    }
+
+    /// The module binary as given by the user.
+    pub fn source(&self) -> &[u8] {
+        &self.source
+    }
+
+    fn details(&self) -> PyResult<Arc<Details>> {
+        let mut details = self.details.lock().unwrap();
+        if details.is_none() {
+            let source = &self.source;
+            *details = Some(Arc::new(Details {
+                offsets: self.instrumentation.source_offsets(source)?,
+                lines: LineTable::parse(source).unwrap_or(None),
+            }));
+        }
+        Ok(details.as_ref().unwrap().clone())
+    }
+}
+
+/// Code of a defined function of an instance.
+struct FunctionCode {
+    end: usize,
+    /// See `Registration::owner`.
+    owner: usize,
+    module: Arc<ModuleInfo>,
+    index: DefinedFuncIndex,
+    address_map: FunctionAddressMap,
+    local_slots: Vec<LocalSlot>,
+}
+
+lazy_static! {
+    /// The registered functions, by start address.
+    static ref CODE: RwLock<BTreeMap<usize, FunctionCode>> = RwLock::new(BTreeMap::new());
+    /// The ends of the trampolines through which wasm code calls host
+    /// functions, by start address.
+    static ref HOST_TRAMPOLINES: RwLock<BTreeMap<usize, usize>> = RwLock::new(BTreeMap::new());
+}
+
+fn lookup(code: &BTreeMap<usize, FunctionCode>, address: usize) -> Option<(usize, &FunctionCode)> {
+    let (start, function) = code.range(..=address).next_back()?;
+    if address < function.end {
+        Some((*start, function))
+    } else {
+        None
+    }
+}
+
+/// Functions registered for an instance of `module`, unregistered when
+/// dropped.
+pub struct Registration {
+    module: Arc<ModuleInfo>,
+    owner: usize,
+    starts: Vec<usize>,
+}
+
+/// The next `Registration::owner`.
+static NEXT_OWNER: AtomicUsize = AtomicUsize::new(1);
+
+impl Registration {
+    pub fn new(module: &Arc<ModuleInfo>) -> Self {
+        Self {
+            module: module.clone(),
+            owner: NEXT_OWNER.fetch_add(1, Ordering::SeqCst),
+            starts: Vec::new(),
+        }
+    }
+
+    pub fn module(&self) -> &Arc<ModuleInfo> {
+        &self.module
+    }
+
+    /// Identifies the code of the instance among the registered functions.
+    pub fn owner(&self) -> usize {
+        self.owner
+    }
+
+    /// Registers the code of the defined function `index`, the `len` bytes
+    /// at `start`.
+    pub fn register(
+        &mut self,
+        index: DefinedFuncIndex,
+        start: usize,
+        len: usize,
+        address_map: FunctionAddressMap,
+        local_slots: Vec<LocalSlot>,
+    ) {
+        perf::record(start, len, || self.module.function_label(index));
+        let function = FunctionCode {
+            end: start + len,
+            owner: self.owner,
+            module: self.module.clone(),
+            index,
+            address_map,
+            local_slots,
+        };
+        CODE.write().unwrap().insert(start, function);
+        self.starts.push(start);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut code = CODE.write().unwrap();
+        for start in &self.starts {
+            code.remove(start);
+        }
+    }
+}
+
+/// Registers the trampoline to a host function of `len` bytes at `start`,
+/// see `trampoline::check_pending_error`, for the lifetime of the process.
+pub fn register_host_trampoline(start: usize, len: usize) {
+    HOST_TRAMPOLINES.write().unwrap().insert(start, start + len);
+}
+
+/// Trampolines to host functions registered for an import object,
+/// unregistered when dropped.
+#[derive(Default)]
+pub struct HostTrampolines {
+    starts: Vec<usize>,
+}
+
+impl HostTrampolines {
+    /// Registers the trampoline of `len` bytes at `start`.
+    pub fn register(&mut self, start: usize, len: usize) {
+        register_host_trampoline(start, len);
+        self.starts.push(start);
+    }
+}
+
+impl Drop for HostTrampolines {
+    fn drop(&mut self) {
+        let mut trampolines = HOST_TRAMPOLINES.write().unwrap();
+        for start in &self.starts {
+            trampolines.remove(start);
+        }
+    }
+}
+
+/// Label of the registered function whose code starts at `address`.
+pub fn function_label_at(address: usize) -> Option<String> {
+    let (module, index) = {
+        let code = CODE.read().unwrap();
+        let function = code.get(&address)?;
+        (function.module.clone(), function.index)
+    };
+    Some(module.function_label(index))
+}
+
+/// Labels of the functions of the addresses recorded for a trap or a
+/// sample, innermost first; addresses no longer registered are skipped.
+pub fn labels(addresses: &[usize]) -> Vec<String> {
+    let functions = {
+        let code = CODE.read().unwrap();
+        addresses
+            .iter()
+            .enumerate()
+            .filter_map(|(i, address)| {
+                // Return addresses are after the call instruction.
+                let address = if i == 0 { *address } else { *address - 1 };
+                let (_, function) = lookup(&code, address)?;
+                Some((function.module.clone(), function.index))
+            })
+            .collect::<Vec<_>>()
+    };
+    functions
+        .iter()
+        .map(|(module, index)| module.function_label(*index))
+        .collect()
+}
+
+/// Addresses recorded by the signal handler: the trapping instruction, or
+/// the call of the host function whose trampoline trapped, then the return
+/// addresses; with the frame pointer of the function of each.
+pub struct Captured {
+    pub addresses: [usize; MAX_FRAMES],
+    frame_pointers: [usize; MAX_FRAMES],
+    pub len: usize,
+}
+
+impl Captured {
+    pub fn new() -> Self {
+        Self {
+            addresses: [0; MAX_FRAMES],
+            frame_pointers: [0; MAX_FRAMES],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, address: usize, frame_pointer: usize) {
+        self.addresses[self.len] = address;
+        self.frame_pointers[self.len] = frame_pointer;
+        self.len += 1;
+    }
+}
+
+/// The value of the local `local` of the frame `frame` of a trap, as found
+/// in its stack slot.
+#[derive(Clone, Copy, Default)]
+pub struct LocalValue {
+    pub frame: usize,
+    pub local: u32,
+    /// The bytes of the slot, little-endian: a 32-bit value is in the low
+    /// ones.
+    pub bits: u64,
+}
+
+/// Values of locals recorded by the signal handler, read from the frames of
+/// `Captured` before they are overwritten.
+struct Locals {
+    values: [LocalValue; MAX_LOCALS],
+    len: usize,
+}
+
+thread_local! {
+    static CAPTURED: RefCell<Captured> = RefCell::new(Captured::new());
+    static LOCALS: RefCell<Locals> = RefCell::new(Locals {
+        values: [LocalValue::default(); MAX_LOCALS],
+        len: 0,
+    });
+}
+
+/// Called before running wasm code on the current thread: forgets the
+/// frames of a previous trap. This also sets up the storage of the thread
+/// before the signal handler needs it.
+pub fn reset() {
+    CAPTURED.with(|captured| captured.borrow_mut().len = 0);
+    LOCALS.with(|locals| locals.borrow_mut().len = 0);
+}
+
+/// What the signal handler recorded for the last trap of a thread.
+pub struct Trace {
+    pub addresses: Vec<usize>,
+    pub locals: Vec<LocalValue>,
+}
+
+/// Takes the addresses and values of locals recorded by the last trap of
+/// the current thread.
+pub fn take() -> Trace {
+    let addresses = CAPTURED.with(|captured| {
+        let mut captured = captured.borrow_mut();
+        let addresses = captured.addresses[..captured.len].to_vec();
+        captured.len = 0;
+        addresses
+    });
+    let locals = LOCALS.with(|locals| {
+        let mut locals = locals.borrow_mut();
+        let values = locals.values[..locals.len].to_vec();
+        locals.len = 0;
+        values
+    });
+    Trace { addresses, locals }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod unwind {
+    use super::{
+        lookup, Captured, FunctionCode, LocalValue, Locals, CAPTURED, CODE, HOST_TRAMPOLINES,
+        LOCALS, MAX_FRAMES, MAX_LOCALS,
+    };
+    use std::collections::BTreeMap;
+    use std::mem;
+    use std::os::raw::{c_int, c_void};
+    use std::ptr;
+    use std::sync::Once;
+
+    const TRAP_SIGNALS: [c_int; 4] = [libc::SIGSEGV, libc::SIGBUS, libc::SIGILL, libc::SIGFPE];
+
+    static HOOK_TRAP_SIGNALS: Once = Once::new();
+    static mut PREVIOUS_HANDLERS: [Option<libc::sigaction>; 4] = [None; 4];
+
+    /// Records the return addresses of the wasm frames from the frame
+    /// pointer `fp`. Cranelift keeps frame pointers: the return address of a
+    /// frame is above the saved frame pointer of its caller.
+    unsafe fn walk(code: &BTreeMap<usize, FunctionCode>, captured: &mut Captured, mut fp: usize) {
+        while fp != 0 && captured.len < MAX_FRAMES {
+            let return_address = *((fp + 8) as *const usize);
+            if lookup(code, return_address.wrapping_sub(1)).is_none() {
+                break;
+            }
+            fp = *(fp as *const usize);
+            captured.push(return_address, fp);
+        }
+    }
+
+    fn in_host_trampoline(trampolines: &BTreeMap<usize, usize>, address: usize) -> bool {
+        trampolines
+            .range(..=address)
+            .next_back()
+            .map_or(false, |(_, end)| address < *end)
+    }
+
+    /// Records the wasm frames interrupted by a signal into `captured`, if
+    /// the signal comes from wasm code or from a trampoline to a host
+    /// function called by wasm code.
+    unsafe fn capture(
+        code: &BTreeMap<usize, FunctionCode>,
+        trampolines: &BTreeMap<usize, usize>,
+        context: *mut c_void,
+        captured: &mut Captured,
+    ) -> bool {
+        let registers = &(*(context as *const libc::ucontext_t)).uc_mcontext.gregs;
+        let pc = registers[libc::REG_RIP as usize] as usize;
+        let fp = registers[libc::REG_RBP as usize] as usize;
+        if lookup(code, pc).is_some() {
+            captured.len = 0;
+            captured.push(pc, fp);
+            walk(code, captured, fp);
+            return true;
+        }
+        if !in_host_trampoline(trampolines, pc) {
+            return false;
+        }
+        // The trampoline traps in its own frame when the host function
+        // raised an error, see `trampoline::check_pending_error`, so the
+        // frame pointer is the one of the trampoline, called by wasm code.
+        let return_address = *((fp + 8) as *const usize);
+        if lookup(code, return_address.wrapping_sub(1)).is_none() {
+            return false;
+        }
+        captured.len = 0;
+        // The first address is the one of the instruction.
+        let fp = *(fp as *const usize);
+        captured.push(return_address - 1, fp);
+        walk(code, captured, fp);
+        true
+    }
+
+    /// Records the values of the locals of the frames of `captured` which
+    /// are in stack slots.
+    unsafe fn read_locals(
+        code: &BTreeMap<usize, FunctionCode>,
+        captured: &Captured,
+        locals: &mut Locals,
+    ) {
+        locals.len = 0;
+        for frame in 0..captured.len {
+            let address = captured.addresses[frame];
+            let address = if frame == 0 { address } else { address - 1 };
+            let (start, function) = match lookup(code, address) {
+                Some(found) => found,
+                None => continue,
+            };
+            let offset = (address - start) as u32;
+            let fp = captured.frame_pointers[frame] as isize;
+            for slot in &function.local_slots {
+                if slot.start <= offset && offset < slot.end && locals.len < MAX_LOCALS {
+                    locals.values[locals.len] = LocalValue {
+                        frame,
+                        local: slot.local,
+                        bits: *((fp + slot.offset as isize) as *const u64),
+                    };
+                    locals.len += 1;
+                }
+            }
+        }
+    }
+
+    /// Records the wasm frames of the trap, if the signal comes from wasm
+    /// code.
+    unsafe fn record_frames(context: *mut c_void) {
+        // The code is being registered by another thread.
+        let (code, trampolines) = match (CODE.try_read(), HOST_TRAMPOLINES.try_read()) {
+            (Ok(code), Ok(trampolines)) => (code, trampolines),
+            _ => return,
+        };
+        let _ = CAPTURED.try_with(|captured| {
+            if let Ok(mut captured) = captured.try_borrow_mut() {
+                if capture(&code, &trampolines, context, &mut captured) {
+                    let _ = LOCALS.try_with(|locals| {
+                        if let Ok(mut locals) = locals.try_borrow_mut() {
+                            read_locals(&code, &captured, &mut locals);
+                        }
+                    });
+                }
+            }
+        });
+    }
+
+    /// Records the wasm frames interrupted by a signal into `captured`, if
+    /// some of them are code of `owner`.
+    pub unsafe fn sample(context: *mut c_void, owner: usize, captured: &mut Captured) -> bool {
+        let (code, trampolines) = match (CODE.try_read(), HOST_TRAMPOLINES.try_read()) {
+            (Ok(code), Ok(trampolines)) => (code, trampolines),
+            _ => return false,
+        };
+        if !capture(&code, &trampolines, context, captured) {
+            return false;
+        }
+        captured.addresses[..captured.len]
+            .iter()
+            .enumerate()
+            .any(|(i, address)| {
+                let address = if i == 0 { *address } else { *address - 1 };
+                lookup(&code, address).map_or(false, |(_, f)| f.owner == owner)
+            })
+    }
+
+    unsafe extern "C" fn on_trap_signal(
+        signum: c_int,
+        info: *mut libc::siginfo_t,
+        context: *mut c_void,
+    ) {
+        record_frames(context);
+        let position = TRAP_SIGNALS.iter().position(|s| *s == signum);
+        let previous = match position.and_then(|position| PREVIOUS_HANDLERS[position]) {
+            Some(previous) => previous,
+            None => return,
+        };
+        if previous.sa_flags & libc::SA_SIGINFO != 0 {
+            let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) =
+                mem::transmute(previous.sa_sigaction);
+            handler(signum, info, context);
+        } else if previous.sa_sigaction == libc::SIG_DFL || previous.sa_sigaction == libc::SIG_IGN {
+            // The faulting instruction runs again, with the previous action.
+            libc::sigaction(signum, &previous, ptr::null_mut());
+        } else {
+            let handler: extern "C" fn(c_int) = mem::transmute(previous.sa_sigaction);
+            handler(signum);
+        }
+    }
+
+    pub fn hook_trap_signals() {
+        HOOK_TRAP_SIGNALS.call_once(|| unsafe {
+            for (signal, previous) in TRAP_SIGNALS.iter().zip(PREVIOUS_HANDLERS.iter_mut()) {
+                let mut handler: libc::sigaction = mem::zeroed();
+                handler.sa_sigaction = on_trap_signal as usize;
+                handler.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER | libc::SA_ONSTACK;
+                libc::sigemptyset(&mut handler.sa_mask);
+                let mut old: libc::sigaction = mem::zeroed();
+                if libc::sigaction(*signal, &handler, &mut old) == 0 {
+                    *previous = Some(old);
+                }
+            }
+        });
+    }
+}
+
+/// Installs the handler recording the frames of traps. It must run before
+/// the one of wasmtime-runtime, which is installed by the first
+/// instantiation, so this is called after it.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub fn hook_trap_signals() {
+    unwind::hook_trap_signals();
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+pub fn hook_trap_signals() {}
+
+/// Records the wasm frames interrupted by a signal, whose handler got
+/// `context`, if some of them are code of `owner`; see `profile.rs`.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub unsafe fn sample(
+    context: *mut std::os::raw::c_void,
+    owner: usize,
+    captured: &mut Captured,
+) -> bool {
+    unwind::sample(context, owner, captured)
+}
+
+/// Name of a wasm function in backtraces and profiles, `module!function`,
+/// with placeholders for the names missing from the "name" section.
+pub fn function_label(
+    module_name: Option<&str>,
+    func_index: u32,
+    func_name: Option<&str>,
+) -> String {
+    let module = module_name.unwrap_or("<module>");
+    match func_name {
+        Some(name) => format!("{}!{}", module, name),
+        None => format!("{}!<wasm function {}>", module, func_index),
+    }
+}
+
+/// A frame of the wasm stack when a `Trap` was raised.
+#[pyclass]
+pub struct Frame {
+    pub module: Arc<ModuleInfo>,
+    pub module_name: Option<String>,
+    pub func_index: u32,
+    pub func_name: Option<String>,
+    pub module_offset: Option<usize>,
+    pub location: Option<Location>,
+    /// The locals whose values were found, with the bits of their value.
+    pub locals: Vec<(u32, u64)>,
+}
+
+#[pymethods]
+impl Frame {
+    /// Name of the module, from its "name" section.
+    #[getter(module_name)]
+    fn get_module_name(&self) -> Option<String> {
+        self.module_name.clone()
+    }
+
+    /// Index of the function, imported functions included.
+    #[getter(func_index)]
+    fn get_func_index(&self) -> u32 {
+        self.func_index
+    }
+
+    /// Name of the function, from the "name" section of the module.
+    #[getter(func_name)]
+    fn get_func_name(&self) -> Option<String> {
+        self.func_name.clone()
+    }
+
+    /// Offset of the instruction in the module binary.
+    #[getter(module_offset)]
+    fn get_module_offset(&self) -> Option<usize> {
+        self.module_offset
+    }
+
+    /// Source file of the instruction, from the DWARF line tables of the
+    /// module.
+    #[getter(file)]
+    fn get_file(&self) -> Option<String> {
+        self.location.as_ref().and_then(|l| l.file.clone())
+    }
+
+    /// Source line of the instruction.
+    #[getter(line)]
+    fn get_line(&self) -> Option<u32> {
+        self.location.as_ref().map(|l| l.line)
+    }
+
+    /// Source column of the instruction; 0 when unknown.
+    #[getter(column)]
+    fn get_column(&self) -> Option<u32> {
+        self.location.as_ref().map(|l| l.column)
+    }
+}
+
+#[pyproto]
+impl<'p> PyObjectProtocol<'p> for Frame {
+    fn __str__(&self) -> PyResult<String> {
+        let offset = match self.module_offset {
+            Some(offset) => format!("{:#x}", offset),
+            None => "?".to_string(),
+        };
+        let label = function_label(
+            self.module_name.as_ref().map(String::as_str),
+            self.func_index,
+            self.func_name.as_ref().map(String::as_str),
+        );
+        let mut text = format!("{} - {}", offset, label);
+        if let Some(ref location) = self.location {
+            let file = location.file.as_ref().map_or("<unknown>", String::as_str);
+            text += &format!(" at {}:{}:{}", file, location.line, location.column);
+        }
+        Ok(text)
+    }
+
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("<Frame {}>", self.__str__()?))
+    }
+}
+
+impl Frame {
+    /// File, function name and line of the frame in a Python traceback. The
+    /// file of frames without a source location tells the offset instead.
+    fn traceback_entry(&self) -> (String, String, u32) {
+        let function = match self.func_name {
+            Some(ref name) => name.clone(),
+            None => format!("<wasm function {}>", self.func_index),
+        };
+        if let Some(Location {
+            file: Some(ref file),
+            line,
+            ..
+        }) = self.location
+        {
+            return (file.clone(), function, line);
+        }
+        let module = self.module_name.as_ref().map_or("module", String::as_str);
+        let file = match self.module_offset {
+            Some(offset) => format!("<wasm {} @ {:#x}>", module, offset),
+            None => format!("<wasm {}>", module),
+        };
+        (file, function, 0)
+    }
+}
+
+/// Resolves the addresses of a `Trace`, with the values of `locals`.
+pub fn resolve(addresses: &[usize], locals: &[LocalValue]) -> PyResult<Vec<Frame>> {
+    let code = CODE.read().unwrap();
+    let mut frames = Vec::new();
+    for (i, address) in addresses.iter().enumerate() {
+        // Return addresses are after the call instruction.
+        let address = if i == 0 { *address } else { *address - 1 };
+        let (start, function) = match lookup(&code, address) {
+            Some(found) => found,
+            None => continue,
+        };
+        let names = function.module.names();
+        let details = function.module.details()?;
+        let offset = address - start;
+        let map = &function.address_map;
+        let srcloc = map
+            .instructions
+            .iter()
+            .find(|i| i.code_offset <= offset && offset < i.code_offset + i.code_len)
+            .map_or(map.start_srcloc, |i| i.srcloc);
+        let func_index = names.imported_functions + function.index.index() as u32;
+        let module_offset = if srcloc.is_default() {
+            None
+        } else {
+            details.offsets.original(srcloc.bits() as usize)
+        };
+        let location = match (&details.lines, module_offset) {
+            (Some(lines), Some(offset)) => lines.location(offset),
+            _ => None,
+        };
+        frames.push(Frame {
+            module: function.module.clone(),
+            module_name: names.module.clone(),
+            func_index,
+            func_name: names.functions.get(&func_index).cloned(),
+            module_offset,
+            location,
+            locals: locals
+                .iter()
+                .filter(|value| value.frame == i)
+                .map(|value| (value.local, value.bits))
+                .collect(),
+        });
+    }
+    Ok(frames)
+}
+
+/// Prepends an entry for each of `frames`, innermost first, to the
+/// traceback of the current exception, like Cython does for its C
+/// functions.
+unsafe fn add_traceback(py: Python, frames: &[Frame]) {
+    let globals = PyDict::new(py);
+    for frame in frames {
+        let (file, function, line) = frame.traceback_entry();
+        let file = CString::new(file.replace('\0', "")).unwrap();
+        let function = CString::new(function.replace('\0', "")).unwrap();
+        let code = ffi::PyCode_NewEmpty(file.as_ptr(), function.as_ptr(), line as c_int);
+        if code.is_null() {
+            return;
+        }
+        let frame = ffi::PyFrame_New(
+            ffi::PyThreadState_Get(),
+            code,
+            globals.as_ptr(),
+            ptr::null_mut(),
+        );
+        ffi::Py_DECREF(code as *mut ffi::PyObject);
+        if frame.is_null() {
+            return;
+        }
+        ffi::PyTraceBack_Here(frame);
+        ffi::Py_DECREF(frame as *mut ffi::PyObject);
+    }
+}
+
+/// Adds the wasm frames at `addresses` to the traceback of `err`, raised by
+/// a call into wasm code. A `Trap` also gets them as `frames`, unless it
+/// has some already (a trap of a nested call keeps its own).
+pub fn attach(py: Python, err: PyErr, addresses: &[usize]) -> PyErr {
+    let frames = resolve(addresses, &[]).unwrap_or_default();
+    let is_trap = err.is_instance::<Trap>(py);
+    err.restore(py);
+    unsafe { add_traceback(py, &frames) };
+    let err = PyErr::fetch(py);
+    if !is_trap {
+        return err;
+    }
+    update_exception(py, err, |value| {
+        if !value.hasattr("frames").unwrap_or(true) {
+            let mut objects = Vec::new();
+            for frame in frames {
+                if let Ok(frame) = Py::new(py, frame) {
+                    objects.push(frame);
+                }
+            }
+            let _ = value.setattr("frames", PyList::new(py, &objects));
+        }
+    })
+}
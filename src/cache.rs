@@ -0,0 +1,215 @@
+//! On-disk cache of compiled modules.
+//!
+//! Entries are serialized artifacts named after the SHA-256 of the wasm
+//! (instrumented) and of the fingerprint of the code generator, so
+//! another build of the extension, of Cranelift or other target flags
+//! use other entries. Entries which cannot be loaded are removed, and the
+//! least recently used ones are evicted when the cache grows beyond its
+//! maximum size.
+
+use pyo3::exceptions::OSError;
+use pyo3::prelude::*;
+
+use crate::compiler::{compile, deserialize, fingerprint, serialize, Artifact};
+use cranelift_codegen::isa::TargetIsa;
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Default maximum size of the cache, in bytes.
+const DEFAULT_MAX_SIZE: u64 = 512 * 1024 * 1024;
+
+const ENTRY_EXTENSION: &str = "bin";
+
+#[derive(Clone)]
+struct Cache {
+    directory: PathBuf,
+    max_size: u64,
+}
+
+lazy_static! {
+    static ref CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+}
+
+/// The default directory of the cache, if the user has a cache or home
+/// directory; a shared one like `/tmp` would let other users plant entries.
+fn default_directory() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .map(|dir| dir.join("wasmtime-py"))
+}
+
+/// Creates `directory`, only accessible by the user, and checks that it
+/// belongs to the user, since its entries are loaded as machine code.
+fn create_directory(directory: &Path) -> io::Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(directory)?;
+    let metadata = fs::metadata(directory)?;
+    if metadata.uid() != unsafe { libc::geteuid() } || metadata.mode() & 0o022 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "cache directory {} is not owned by the user or writable by others",
+                directory.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Updates the modification time of `path`, the time of its last use.
+fn touch(path: &Path) {
+    if let Ok(path) = CString::new(path.as_os_str().as_bytes()) {
+        unsafe {
+            libc::utime(path.as_ptr(), ptr::null());
+        }
+    }
+}
+
+impl Cache {
+    fn entry(&self, isa: &dyn TargetIsa, wasm: &[u8]) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.input(&fingerprint(isa));
+        hasher.input(wasm);
+        let key: String = hasher
+            .result()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        self.directory.join(key).with_extension(ENTRY_EXTENSION)
+    }
+
+    fn entries(&self) -> io::Result<Vec<(PathBuf, fs::Metadata)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != ENTRY_EXTENSION) {
+                continue;
+            }
+            // Entries may be removed by another process meanwhile.
+            if let Ok(metadata) = fs::metadata(&path) {
+                entries.push((path, metadata));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn load(&self, path: &Path, isa: &dyn TargetIsa, wasm: &[u8]) -> Option<Artifact> {
+        let bytes = fs::read(path).ok()?;
+        match deserialize::<Artifact>(&bytes, isa) {
+            Ok(ref artifact) if artifact.wasm != wasm => {}
+            Ok(artifact) => {
+                touch(path);
+                return Some(artifact);
+            }
+            Err(_) => {}
+        }
+        let _ = fs::remove_file(path);
+        None
+    }
+
+    fn store(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        create_directory(&self.directory)?;
+        // Concurrent readers only see complete entries.
+        let temp = path.with_extension(format!("{}.tmp", process::id()));
+        fs::write(&temp, bytes)?;
+        if let Err(e) = fs::rename(&temp, path) {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        self.evict()
+    }
+
+    /// Removes the least recently used entries until the cache fits in
+    /// `max_size`.
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = self.entries()?;
+        let mut size: u64 = entries.iter().map(|(_, metadata)| metadata.len()).sum();
+        if size <= self.max_size {
+            return Ok(());
+        }
+        entries.sort_by_key(|(_, metadata)| metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+        for (path, metadata) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                size -= metadata.len();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compiles `wasm` for `isa`, going through the cache when it is enabled.
+pub fn compile_cached(isa: &dyn TargetIsa, wasm: &[u8]) -> Result<Artifact, String> {
+    let cache = match CACHE.lock().unwrap().clone() {
+        Some(cache) => cache,
+        None => return compile(isa, wasm),
+    };
+    let path = cache.entry(isa, wasm);
+    if let Some(artifact) = cache.load(&path, isa, wasm) {
+        return Ok(artifact);
+    }
+    let artifact = compile(isa, wasm)?;
+    // The cache is an optimization, failing to write to it is not an error.
+    let _ = cache.store(&path, &serialize(isa, &artifact));
+    Ok(artifact)
+}
+
+/// Enables the on-disk cache of compiled modules in `directory`, by default
+/// `$XDG_CACHE_HOME/wasmtime-py` or `~/.cache/wasmtime-py`. The least
+/// recently used modules are evicted when the cache grows beyond
+/// `max_size` bytes, 512 MiB by default.
+///
+/// The directory is created only accessible by the user; one belonging to
+/// another user or writable by others is refused.
+#[pyfunction(directory = "None", max_size = "None")]
+pub fn enable_cache(directory: Option<String>, max_size: Option<u64>) -> PyResult<()> {
+    let directory = match directory {
+        Some(directory) => PathBuf::from(directory),
+        None => default_directory().ok_or_else(|| {
+            OSError::py_err("no cache directory: neither XDG_CACHE_HOME nor HOME is set")
+        })?,
+    };
+    let cache = Cache {
+        directory,
+        max_size: max_size.unwrap_or(DEFAULT_MAX_SIZE),
+    };
+    create_directory(&cache.directory)?;
+    cache.evict()?;
+    *CACHE.lock().unwrap() = Some(cache);
+    Ok(())
+}
+
+/// Disables the on-disk cache; its content is kept.
+#[pyfunction]
+pub fn disable_cache() {
+    *CACHE.lock().unwrap() = None;
+}
+
+/// Removes all the modules of the enabled cache.
+#[pyfunction]
+pub fn clear_cache() -> PyResult<()> {
+    let cache = CACHE.lock().unwrap().clone();
+    if let Some(cache) = cache {
+        for (path, _) in cache.entries()? {
+            let _ = fs::remove_file(path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,484 @@
+//! Compilation of wasm modules into artifacts which can be stored.
+//!
+//! Instead of going through `wasmtime_jit::Context`, modules are translated
+//! and compiled here, and the resulting machine code and relocations are
+//! kept in an `Artifact`. `link.rs` turns an artifact into an instance, so
+//! an artifact loaded from the compilation cache doesn't need Cranelift.
+
+use pyo3::exceptions::ValueError;
+use pyo3::prelude::*;
+
+use crate::func_environ::{
+    get_func_name, get_imported_memory32_size_name, get_imported_memory_grow_name,
+    get_memory32_size_name, get_memory_grow_name, FuncEnvironment,
+};
+use crate::store::native_isa;
+use cranelift_codegen::ir::{self, JumpTableOffsets};
+use cranelift_codegen::isa::{self, TargetIsa};
+use cranelift_codegen::{binemit, settings, Context, ValueLabelsRanges};
+use cranelift_entity::{EntityRef, PrimaryMap};
+use cranelift_wasm::{DefinedFuncIndex, FuncIndex, FuncTranslator};
+use lazy_static::lazy_static;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use wasmtime_environ::{
+    Compilation, Compiler, Cranelift, FunctionAddressMap, InstructionAddressMap, Module,
+    ModuleAddressMap, ModuleEnvironment, ModuleTranslation, Relocation, RelocationTarget,
+    Relocations, Tunables,
+};
+
+use std::ffi::{CStr, OsStr};
+use std::fmt;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::mem;
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use target_lexicon::{Architecture, Triple};
+
+/// Magic number starting serialized artifacts.
+const MAGIC: &[u8; 8] = b"\0wasmpyc";
+
+/// Version of the layout of serialized artifacts.
+const FORMAT_VERSION: u32 = 4;
+
+lazy_static! {
+    /// Identifies this build of the extension, since the code generated by
+    /// two builds of the same versions may differ (e.g. other dependencies
+    /// or local changes).
+    static ref BUILD_ID: String = build_id();
+
+    /// Threads compiling the function bodies.
+    static ref POOL: Mutex<Arc<ThreadPool>> = Mutex::new(Arc::new(new_pool(None).expect("compiler threads")));
+}
+
+fn new_pool(threads: Option<usize>) -> Result<ThreadPool, String> {
+    // Zero threads means one per CPU for rayon.
+    ThreadPoolBuilder::new()
+        .num_threads(threads.unwrap_or(0))
+        .thread_name(|index| format!("wasmtime-compiler-{}", index))
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// The SHA-256 of the shared library of the extension.
+fn build_id() -> String {
+    let mut info: libc::Dl_info = unsafe { mem::zeroed() };
+    let found = unsafe { libc::dladdr(build_id as *const libc::c_void, &mut info) } != 0
+        && !info.dli_fname.is_null();
+    let library = if found {
+        let name = unsafe { CStr::from_ptr(info.dli_fname) };
+        fs::read(OsStr::from_bytes(name.to_bytes())).ok()
+    } else {
+        None
+    };
+    match library {
+        Some(bytes) => Sha256::digest(&bytes)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+        // Artifacts are then only usable by this process.
+        None => format!("unknown-{}", process::id()),
+    }
+}
+
+/// Describes the code generator of an artifact; artifacts are only usable
+/// by the same one.
+#[derive(Serialize, Deserialize, PartialEq)]
+struct Header {
+    format_version: u32,
+    version: String,
+    compiler: String,
+    triple: String,
+    flags: String,
+    build: String,
+}
+
+impl Header {
+    fn new(isa: &dyn TargetIsa) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            compiler: format!("cranelift {}", cranelift_codegen::VERSION),
+            triple: isa.triple().to_string(),
+            flags: isa.flags().to_string(),
+            build: BUILD_ID.clone(),
+        }
+    }
+}
+
+/// Identifies the code generator of `isa`: artifacts produced by another
+/// one have another fingerprint.
+pub fn fingerprint(isa: &dyn TargetIsa) -> Vec<u8> {
+    bincode::serialize(&Header::new(isa)).expect("header")
+}
+
+/// A compiled module.
+#[derive(Serialize, Deserialize)]
+pub struct Artifact {
+    /// The (instrumented) wasm module, translated again when instantiating.
+    pub wasm: Vec<u8>,
+    pub compilation: Compilation,
+    pub relocations: Relocations,
+    /// Wasm offsets of the machine code, for backtraces.
+    pub address_map: ModuleAddressMap,
+    /// Stack slots of the locals, for core dumps.
+    pub local_slots: PrimaryMap<DefinedFuncIndex, Vec<LocalSlot>>,
+}
+
+/// A stack slot holding the value of a local while the code of its
+/// function runs from `start` to `end`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LocalSlot {
+    /// Index of the local, params included.
+    pub local: u32,
+    /// Offsets in the code of the function.
+    pub start: u32,
+    pub end: u32,
+    /// Offset of the slot from the frame pointer of the function.
+    pub offset: i32,
+}
+
+/// The `LocalSlot`s of a function, from the value ranges and stack slots
+/// Cranelift computes along with the debug info. The value labels are the
+/// indices of the locals, see `cranelift_wasm`.
+fn local_slots(ranges: &ValueLabelsRanges, stack_slots: &ir::StackSlots) -> Vec<LocalSlot> {
+    let mut slots = Vec::new();
+    for (label, ranges) in ranges {
+        for range in ranges {
+            let offset = match range.loc {
+                ir::ValueLoc::Stack(slot) => stack_slots[slot].offset,
+                _ => None,
+            };
+            if let Some(offset) = offset {
+                slots.push(LocalSlot {
+                    local: label.index() as u32,
+                    start: range.start,
+                    end: range.end,
+                    // Slot offsets are from the stack pointer before the
+                    // call, above the return address and the frame pointer
+                    // saved by the prologue.
+                    offset: offset + 16,
+                });
+            }
+        }
+    }
+    slots.sort_by_key(|slot| (slot.local, slot.start));
+    slots
+}
+
+#[derive(Debug)]
+pub enum ArtifactError {
+    /// Not a serialized artifact, or a truncated one.
+    Corrupt,
+    /// An artifact produced by another version or for another target.
+    Incompatible(String),
+}
+
+impl fmt::Display for ArtifactError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArtifactError::Corrupt => write!(f, "not a serialized module"),
+            ArtifactError::Incompatible(reason) => {
+                write!(f, "incompatible serialized module: {}", reason)
+            }
+        }
+    }
+}
+
+/// Returns the ISA of `target`, a target-lexicon triple such as
+/// `x86_64-unknown-freebsd`, or of the host by default. Code generated
+/// for another target can be serialized but not run.
+///
+/// Only x86-64 targets are supported, the other backends of Cranelift
+/// being incomplete. Another target than the host gets the default flags
+/// of Cranelift, without the extensions of the host CPU.
+pub fn target_isa(target: Option<&str>) -> Result<Box<dyn TargetIsa>, String> {
+    let host = native_isa();
+    let target = match target {
+        Some(target) => target,
+        None => return Ok(host),
+    };
+    let triple = Triple::from_str(target).map_err(|e| format!("{}: {}", target, e))?;
+    // The host ISA also enables the features of the host CPU.
+    if &triple == host.triple() {
+        return Ok(host);
+    }
+    if triple.architecture != Architecture::X86_64 {
+        return Err(format!(
+            "{}: unsupported architecture {}, only x86_64 targets are supported",
+            target, triple.architecture
+        ));
+    }
+    let builder = isa::lookup(triple).map_err(|e| format!("{}: {}", target, e))?;
+    Ok(builder.finish(settings::Flags::new(settings::builder())))
+}
+
+/// Translates `wasm` for the target `isa`.
+pub fn translate<'data>(
+    isa: &dyn TargetIsa,
+    wasm: &'data [u8],
+) -> Result<ModuleTranslation<'data>, String> {
+    ModuleEnvironment::new(isa.frontend_config(), Tunables::default())
+        .translate(wasm)
+        .map_err(|e| e.to_string())
+}
+
+/// Compiles `wasm` for the target `isa`. The function bodies are compiled
+/// in parallel on the threads of the compiler, see `configure_compiler`.
+pub fn compile(isa: &dyn TargetIsa, wasm: &[u8]) -> Result<Artifact, String> {
+    let translation = translate(isa, wasm)?;
+    let pool = POOL.lock().unwrap().clone();
+    // `compile_module` uses rayon's parallel iterators, which run on the
+    // pool they are called from. The address maps and the value ranges are
+    // only computed along with the debug info.
+    let (compilation, relocations, address_map, value_ranges, stack_slots) = pool
+        .install(|| {
+            Cranelift::compile_module(
+                &translation.module,
+                translation.function_body_inputs,
+                isa,
+                true,
+            )
+        })
+        .map_err(|e| e.to_string())?;
+    let local_slots = value_ranges
+        .values()
+        .zip(stack_slots.values())
+        .map(|(ranges, slots)| local_slots(ranges, slots))
+        .collect();
+    Ok(Artifact {
+        wasm: wasm.to_vec(),
+        compilation,
+        relocations,
+        address_map,
+        local_slots,
+    })
+}
+
+/// Code of a single function.
+pub struct CompiledFunction {
+    pub body: Vec<u8>,
+    pub jt_offsets: JumpTableOffsets,
+    pub relocations: Vec<Relocation>,
+    pub address_map: FunctionAddressMap,
+    pub local_slots: Vec<LocalSlot>,
+}
+
+/// Collects the relocations of a function, like the one of
+/// `Cranelift::compile_module`.
+struct FunctionRelocSink {
+    func_index: FuncIndex,
+    relocations: Vec<Relocation>,
+}
+
+impl binemit::RelocSink for FunctionRelocSink {
+    fn reloc_ebb(
+        &mut self,
+        _offset: binemit::CodeOffset,
+        _reloc: binemit::Reloc,
+        _ebb_offset: binemit::CodeOffset,
+    ) {
+        panic!("ebb headers not yet implemented");
+    }
+
+    fn reloc_external(
+        &mut self,
+        offset: binemit::CodeOffset,
+        reloc: binemit::Reloc,
+        name: &ir::ExternalName,
+        addend: binemit::Addend,
+    ) {
+        let reloc_target = if *name == get_memory_grow_name() {
+            RelocationTarget::Memory32Grow
+        } else if *name == get_imported_memory_grow_name() {
+            RelocationTarget::ImportedMemory32Grow
+        } else if *name == get_memory32_size_name() {
+            RelocationTarget::Memory32Size
+        } else if *name == get_imported_memory32_size_name() {
+            RelocationTarget::ImportedMemory32Size
+        } else if let ir::ExternalName::User { index, .. } = *name {
+            RelocationTarget::UserFunc(FuncIndex::from_u32(index))
+        } else if let ir::ExternalName::LibCall(libcall) = *name {
+            RelocationTarget::LibCall(libcall)
+        } else {
+            panic!("unrecognized external name")
+        };
+        self.relocations.push(Relocation {
+            reloc,
+            reloc_target,
+            offset,
+            addend,
+        });
+    }
+
+    fn reloc_jt(&mut self, offset: binemit::CodeOffset, reloc: binemit::Reloc, jt: ir::JumpTable) {
+        self.relocations.push(Relocation {
+            reloc,
+            reloc_target: RelocationTarget::JumpTable(self.func_index, jt),
+            offset,
+            addend: 0,
+        });
+    }
+}
+
+/// The wasm offsets of the machine code of `context`, compiled from the
+/// body at `range` of the module.
+fn function_address_map(
+    context: &Context,
+    range: &Range<usize>,
+    body_len: usize,
+    isa: &dyn TargetIsa,
+) -> FunctionAddressMap {
+    let func = &context.func;
+    let mut ebbs = func.layout.ebbs().collect::<Vec<_>>();
+    // Offsets have to increase.
+    ebbs.sort_by_key(|ebb| func.offsets[*ebb]);
+    let encinfo = isa.encoding_info();
+    let mut instructions = Vec::new();
+    for ebb in ebbs {
+        for (offset, inst, size) in func.inst_offsets(ebb, &encinfo) {
+            instructions.push(InstructionAddressMap {
+                srcloc: func.srclocs[inst],
+                code_offset: offset as usize,
+                code_len: size as usize,
+            });
+        }
+    }
+    FunctionAddressMap {
+        instructions,
+        start_srcloc: ir::SourceLoc::new(range.start as u32),
+        end_srcloc: ir::SourceLoc::new(range.end as u32),
+        body_offset: 0,
+        body_len,
+    }
+}
+
+/// Translates `body`, at `offset` in the module, the defined function
+/// `index` of `module`, to Cranelift IR for the target `isa`.
+pub fn translate_function(
+    isa: &dyn TargetIsa,
+    module: &Module,
+    body: &[u8],
+    offset: usize,
+    index: DefinedFuncIndex,
+) -> Result<Context, String> {
+    let func_index = module.func_index(index);
+    let mut context = Context::new();
+    context.func.name = get_func_name(func_index);
+    context.func.signature = module.signatures[module.functions[func_index]].clone();
+    // For the source locations of the address map and the value labels of
+    // the locals.
+    context.func.collect_debug_info();
+    FuncTranslator::new()
+        .translate(
+            body,
+            offset,
+            &mut context.func,
+            &mut FuncEnvironment::new(isa.frontend_config(), module),
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(context)
+}
+
+/// Compiles the defined function `index` of `module`, whose bodies are the
+/// `bodies` ranges of `wasm`, for the target `isa`, like
+/// `Cranelift::compile_module` does for all of them.
+pub fn compile_function(
+    isa: &dyn TargetIsa,
+    module: &Module,
+    wasm: &[u8],
+    bodies: &[Range<usize>],
+    index: DefinedFuncIndex,
+) -> Result<CompiledFunction, String> {
+    let range = &bodies[index.index()];
+    let mut context = translate_function(isa, module, &wasm[range.clone()], range.start, index)?;
+    let mut body = Vec::new();
+    let mut reloc_sink = FunctionRelocSink {
+        func_index: module.func_index(index),
+        relocations: Vec::new(),
+    };
+    let mut trap_sink = binemit::NullTrapSink {};
+    context
+        .compile_and_emit(isa, &mut body, &mut reloc_sink, &mut trap_sink)
+        .map_err(|e| e.to_string())?;
+    let address_map = function_address_map(&context, range, body.len(), isa);
+    let ranges = context
+        .build_value_labels_ranges(isa)
+        .map_err(|e| e.to_string())?;
+    Ok(CompiledFunction {
+        body,
+        jt_offsets: context.func.jt_offsets.clone(),
+        relocations: reloc_sink.relocations,
+        address_map,
+        local_slots: local_slots(&ranges, &context.func.stack_slots),
+    })
+}
+
+/// Serializes `value`, produced by the code generator of `isa`.
+pub fn serialize<T: Serialize>(isa: &dyn TargetIsa, value: &T) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bincode::serialize_into(&mut bytes, &Header::new(isa)).expect("header");
+    bincode::serialize_into(&mut bytes, value).expect("value");
+    bytes
+}
+
+/// Deserializes a value, which has to be produced by the code generator
+/// of `isa`.
+pub fn deserialize<T: DeserializeOwned>(
+    bytes: &[u8],
+    isa: &dyn TargetIsa,
+) -> Result<T, ArtifactError> {
+    let mut reader = Cursor::new(bytes);
+    let mut magic = [0; 8];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|_| ArtifactError::Corrupt)?;
+    if &magic != MAGIC {
+        return Err(ArtifactError::Corrupt);
+    }
+    let header: Header =
+        bincode::deserialize_from(&mut reader).map_err(|_| ArtifactError::Corrupt)?;
+    let expected = Header::new(isa);
+    if header.format_version != expected.format_version {
+        return Err(ArtifactError::Incompatible(format!(
+            "format version {}, expected {}",
+            header.format_version, expected.format_version
+        )));
+    }
+    for (what, found, expected) in &[
+        ("produced by version", &header.version, &expected.version),
+        ("produced by", &header.compiler, &expected.compiler),
+        ("compiled for", &header.triple, &expected.triple),
+        ("compiled with flags", &header.flags, &expected.flags),
+        ("produced by build", &header.build, &expected.build),
+    ] {
+        if found != expected {
+            return Err(ArtifactError::Incompatible(format!(
+                "{} {}, expected {}",
+                what, found, expected
+            )));
+        }
+    }
+    bincode::deserialize_from(&mut reader).map_err(|_| ArtifactError::Corrupt)
+}
+
+/// Configures the compiler: `threads` is the number of threads compiling
+/// the function bodies of a module, one per CPU by default.
+#[pyfunction(threads = "None")]
+pub fn configure_compiler(threads: Option<usize>) -> PyResult<()> {
+    let pool = new_pool(threads).map_err(ValueError::py_err)?;
+    *POOL.lock().unwrap() = Arc::new(pool);
+    Ok(())
+}
+
+/// Returns the number of threads compiling the function bodies.
+#[pyfunction]
+pub fn compiler_threads() -> usize {
+    POOL.lock().unwrap().current_num_threads()
+}
@@ -0,0 +1,382 @@
+//! Core dumps of instances whose calls trap.
+//!
+//! A dump is a wasm module in the format of the WebAssembly tool
+//! conventions (Coredump.md): its memory, global and data sections hold the
+//! memories and globals of the instance, and its "core", "coremodules",
+//! "coreinstances" and "corestack" custom sections the wasm stack of the
+//! trap, which debuggers read along with the modules.
+//!
+//! The memories and globals dumped are the ones of the instance whose export
+//! was called; the frames of other modules get an instance without any.
+//!
+//! The values of the locals which are in stack slots where a frame stopped
+//! are read by the signal handler of the trap, see `compiler::LocalSlot`;
+//! the other locals are written as missing. Operand stacks are left empty.
+
+use pyo3::prelude::*;
+
+use crate::backtrace::{Frame, ModuleInfo};
+use crate::instrument::{write_name, write_section, write_var_i32, write_var_u32};
+use crate::link::module_info;
+use cranelift_entity::EntityRef;
+use cranelift_wasm::{GlobalIndex, MemoryIndex};
+use wasmparser::{BinaryReaderError, ImportSectionEntryType, ModuleReader, SectionCode, Type};
+use wasmtime_environ::{Export, WASM_PAGE_SIZE};
+use wasmtime_jit::InstanceHandle;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Encoding of a value which could not be recovered.
+const MISSING_VALUE: u8 = 0x01;
+
+/// Memory is dumped by blocks of this size, blocks of zeros being left out
+/// of the data segments.
+const BLOCK_SIZE: usize = 4096;
+
+/// Dumps written by the process so far, numbering the files.
+static DUMPS: AtomicUsize = AtomicUsize::new(0);
+
+/// What a dump needs of a module binary as given by the user.
+#[derive(Default)]
+struct Layout {
+    imported_functions: u32,
+    imported_globals: u32,
+    /// Type and mutability of the globals, imported ones included.
+    globals: Vec<(Type, bool)>,
+    /// Offset of the body of each defined function in the module, and the
+    /// types of its params and locals.
+    bodies: Vec<(usize, Vec<Type>)>,
+}
+
+fn read_layout(wasm: &[u8]) -> Result<Layout, BinaryReaderError> {
+    let mut layout = Layout::default();
+    let mut params = Vec::new();
+    let mut function_types = Vec::new();
+    let mut parser = ModuleReader::new(wasm)?;
+    while !parser.eof() {
+        let section = parser.read()?;
+        match section.code {
+            SectionCode::Type => {
+                for ty in section.get_type_section_reader()? {
+                    params.push(ty?.params.to_vec());
+                }
+            }
+            SectionCode::Import => {
+                for import in section.get_import_section_reader()? {
+                    match import?.ty {
+                        ImportSectionEntryType::Function(_) => layout.imported_functions += 1,
+                        ImportSectionEntryType::Global(ty) => {
+                            layout.imported_globals += 1;
+                            layout.globals.push((ty.content_type, ty.mutable));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            SectionCode::Function => {
+                for ty in section.get_function_section_reader()? {
+                    function_types.push(ty?);
+                }
+            }
+            SectionCode::Global => {
+                for global in section.get_global_section_reader()? {
+                    let ty = global?.ty;
+                    layout.globals.push((ty.content_type, ty.mutable));
+                }
+            }
+            SectionCode::Code => {
+                let bodies = section.get_code_section_reader()?;
+                for (body, ty) in bodies.into_iter().zip(&function_types) {
+                    let body = body?;
+                    let mut locals = params.get(*ty as usize).cloned().unwrap_or_default();
+                    let mut reader = body.get_locals_reader()?;
+                    for _ in 0..reader.get_count() {
+                        let (count, ty) = reader.read()?;
+                        locals.extend((0..count).map(|_| ty));
+                    }
+                    let start = body.get_binary_reader().original_position();
+                    layout.bodies.push((start, locals));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(layout)
+}
+
+fn write_var_i64(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_vec(out: &mut Vec<u8>, count: usize, items: &[u8]) {
+    write_var_u32(out, count as u32);
+    out.extend_from_slice(items);
+}
+
+fn write_custom_section(out: &mut Vec<u8>, name: &str, contents: &[u8]) {
+    let mut payload = Vec::new();
+    write_name(&mut payload, name);
+    payload.extend_from_slice(contents);
+    write_section(out, 0, &payload);
+}
+
+/// Appends the global type and the constant initializer of a global of type
+/// `ty` whose value is at `definition`.
+unsafe fn write_global(
+    out: &mut Vec<u8>,
+    ty: Type,
+    mutable: bool,
+    definition: *const u8,
+) -> Result<(), String> {
+    let (value_type, init) = match ty {
+        Type::I32 => (0x7f, 0x41),
+        Type::I64 => (0x7e, 0x42),
+        Type::F32 => (0x7d, 0x43),
+        Type::F64 => (0x7c, 0x44),
+        _ => return Err(format!("cannot dump a global of type {:?}", ty)),
+    };
+    out.push(value_type);
+    out.push(mutable as u8);
+    out.push(init);
+    match ty {
+        Type::I32 => write_var_i32(out, *(definition as *const i32)),
+        Type::I64 => write_var_i64(out, *(definition as *const i64)),
+        Type::F32 => out.extend_from_slice(&(*(definition as *const u32)).to_le_bytes()),
+        _ => out.extend_from_slice(&(*(definition as *const u64)).to_le_bytes()),
+    }
+    out.push(0x0b);
+    Ok(())
+}
+
+/// Appends the value of a local of type `ty` whose stack slot holds `bits`.
+fn write_value(out: &mut Vec<u8>, ty: Type, bits: u64) {
+    match ty {
+        Type::I32 => {
+            out.push(0x7f);
+            write_var_i32(out, bits as u32 as i32);
+        }
+        Type::I64 => {
+            out.push(0x7e);
+            write_var_i64(out, bits as i64);
+        }
+        Type::F32 => {
+            out.push(0x7d);
+            out.extend_from_slice(&(bits as u32).to_le_bytes());
+        }
+        Type::F64 => {
+            out.push(0x7c);
+            out.extend_from_slice(&bits.to_le_bytes());
+        }
+        _ => out.push(MISSING_VALUE),
+    }
+}
+
+/// Appends an active data segment of the memory `memory` per run of
+/// non-zero blocks of `data`; returns their number.
+fn write_data_segments(out: &mut Vec<u8>, memory: u32, data: &[u8]) -> usize {
+    let mut count = 0;
+    let mut segment = |out: &mut Vec<u8>, start: usize, end: usize| {
+        if memory == 0 {
+            out.push(0x00);
+        } else {
+            out.push(0x02);
+            write_var_u32(out, memory);
+        }
+        out.push(0x41);
+        write_var_i32(out, start as u32 as i32);
+        out.push(0x0b);
+        write_vec(out, end - start, &data[start..end]);
+        count += 1;
+    };
+    let mut start = None;
+    for (i, block) in data.chunks(BLOCK_SIZE).enumerate() {
+        let offset = i * BLOCK_SIZE;
+        let zeros = block.iter().all(|byte| *byte == 0);
+        match start {
+            None if !zeros => start = Some(offset),
+            Some(run) if zeros => {
+                segment(out, run, offset);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(run) = start {
+        segment(out, run, data.len());
+    }
+    count
+}
+
+/// Name of the current Python thread.
+fn thread_name(py: Python) -> PyResult<String> {
+    py.import("threading")?
+        .call0("current_thread")?
+        .getattr("name")?
+        .extract()
+}
+
+/// Writes a core dump of `instance`, whose export call trapped with the wasm
+/// stack `frames`, to a new file of `dir`; returns its path.
+pub fn write(
+    py: Python,
+    dir: &Path,
+    instance: &mut InstanceHandle,
+    frames: &[Frame],
+) -> Result<PathBuf, String> {
+    let info = module_info(instance).ok_or("not an instance of a compiled module")?;
+    // The modules of the stack, the one of the instance first.
+    let mut modules: Vec<Arc<ModuleInfo>> = vec![info];
+    for frame in frames {
+        if !modules.iter().any(|m| Arc::ptr_eq(m, &frame.module)) {
+            modules.push(frame.module.clone());
+        }
+    }
+    let layouts = modules
+        .iter()
+        .map(|m| read_layout(m.source()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.message.to_string())?;
+    let names = modules
+        .iter()
+        .map(|m| m.name().unwrap_or_else(|| "module".to_string()))
+        .collect::<Vec<_>>();
+
+    let mut dump = b"\0asm\x01\0\0\0".to_vec();
+
+    let mut core = vec![0x00];
+    write_name(&mut core, &names[0]);
+    write_custom_section(&mut dump, "core", &core);
+
+    let mut coremodules = Vec::new();
+    for name in &names {
+        coremodules.push(0x00);
+        write_name(&mut coremodules, name);
+    }
+    let mut listed = Vec::new();
+    write_vec(&mut listed, names.len(), &coremodules);
+    write_custom_section(&mut dump, "coremodules", &listed);
+
+    // The memories and globals of the instance, in the index spaces of the
+    // module as given: the globals added by the instrumentation are left
+    // out.
+    let layout = &layouts[0];
+    let memory_count = instance.module_ref().memory_plans.len();
+    let added_globals = instance.module_ref().globals.len() - layout.globals.len();
+    let mut memories = Vec::new();
+    let mut segments = Vec::new();
+    let mut segment_count = 0;
+    for index in 0..memory_count {
+        let export = instance.lookup_by_declaration(&Export::Memory(MemoryIndex::new(index)));
+        let data = match export {
+            wasmtime_runtime::Export::Memory { definition, .. } => unsafe {
+                slice::from_raw_parts((*definition).base, (*definition).current_length)
+            },
+            _ => panic!("memory is expected"),
+        };
+        memories.push(0x00);
+        write_var_u32(&mut memories, (data.len() / WASM_PAGE_SIZE as usize) as u32);
+        segment_count += write_data_segments(&mut segments, index as u32, data);
+    }
+    let mut globals = Vec::new();
+    for (index, (ty, mutable)) in layout.globals.iter().enumerate() {
+        let index = if index < layout.imported_globals as usize {
+            index
+        } else {
+            index + added_globals
+        };
+        let export = instance.lookup_by_declaration(&Export::Global(GlobalIndex::new(index)));
+        let definition = match export {
+            wasmtime_runtime::Export::Global { definition, .. } => definition as *const u8,
+            _ => panic!("global is expected"),
+        };
+        unsafe { write_global(&mut globals, *ty, *mutable, definition)? };
+    }
+
+    let mut coreinstances = Vec::new();
+    write_var_u32(&mut coreinstances, modules.len() as u32);
+    for index in 0..modules.len() {
+        let (memories, globals) = if index == 0 {
+            (memory_count, layout.globals.len())
+        } else {
+            (0, 0)
+        };
+        coreinstances.push(0x00);
+        write_var_u32(&mut coreinstances, index as u32);
+        write_var_u32(&mut coreinstances, memories as u32);
+        for memory in 0..memories {
+            write_var_u32(&mut coreinstances, memory as u32);
+        }
+        write_var_u32(&mut coreinstances, globals as u32);
+        for global in 0..globals {
+            write_var_u32(&mut coreinstances, global as u32);
+        }
+    }
+    write_custom_section(&mut dump, "coreinstances", &coreinstances);
+
+    // Frames are listed innermost first, as in the backtrace.
+    let mut corestack = vec![0x00];
+    write_name(
+        &mut corestack,
+        &thread_name(py).unwrap_or_else(|_| "main".to_string()),
+    );
+    write_var_u32(&mut corestack, frames.len() as u32);
+    for frame in frames {
+        let instance = modules
+            .iter()
+            .position(|m| Arc::ptr_eq(m, &frame.module))
+            .unwrap();
+        let layout = &layouts[instance];
+        let defined = frame.func_index.checked_sub(layout.imported_functions);
+        let (start, locals) = defined
+            .and_then(|index| layout.bodies.get(index as usize))
+            .map_or((0, &[][..]), |(start, locals)| (*start, &locals[..]));
+        let code_offset = frame
+            .module_offset
+            .and_then(|offset| offset.checked_sub(start))
+            .unwrap_or(0);
+        corestack.push(0x00);
+        write_var_u32(&mut corestack, instance as u32);
+        write_var_u32(&mut corestack, frame.func_index);
+        write_var_u32(&mut corestack, code_offset as u32);
+        write_var_u32(&mut corestack, locals.len() as u32);
+        for (local, ty) in locals.iter().enumerate() {
+            match frame.locals.iter().find(|(l, _)| *l as usize == local) {
+                Some((_, bits)) => write_value(&mut corestack, *ty, *bits),
+                None => corestack.push(MISSING_VALUE),
+            }
+        }
+        // The operand stack.
+        write_var_u32(&mut corestack, 0);
+    }
+    write_custom_section(&mut dump, "corestack", &corestack);
+
+    let mut section = Vec::new();
+    write_vec(&mut section, memory_count, &memories);
+    write_section(&mut dump, 5, &section);
+    let mut section = Vec::new();
+    write_vec(&mut section, layout.globals.len(), &globals);
+    write_section(&mut dump, 6, &section);
+    let mut section = Vec::new();
+    write_vec(&mut section, segment_count, &segments);
+    write_section(&mut dump, 11, &section);
+
+    let number = DUMPS.fetch_add(1, Ordering::SeqCst);
+    let path = dir.join(format!("wasm-{}-{}.coredump", process::id(), number));
+    fs::create_dir_all(dir)
+        .and_then(|()| fs::write(&path, &dump))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(path)
+}
@@ -0,0 +1,156 @@
+//! Source locations of wasm code, from the DWARF sections of a module.
+//!
+//! Compilers put the DWARF sections of a module in custom sections named
+//! after them (".debug_line", ".debug_info"...). Addresses are offsets in
+//! the contents of the code section.
+
+use crate::module::custom_sections;
+use gimli::{ColumnType, Dwarf, EndianSlice, LittleEndian, SectionId};
+use wasmparser::{BinaryReaderError, ModuleReader, SectionCode};
+
+use std::path::Path;
+
+type Reader<'a> = EndianSlice<'a, LittleEndian>;
+
+/// A file, line and column of the source of a module.
+pub struct Location {
+    pub file: Option<String>,
+    pub line: u32,
+    pub column: u32,
+}
+
+struct Row {
+    address: u64,
+    /// Index in `LineTable::files`.
+    file: Option<usize>,
+    line: u32,
+    column: u32,
+    /// First address after a sequence of rows.
+    end: bool,
+}
+
+/// The line tables of the compilation units of a module.
+pub struct LineTable {
+    /// Offset of the contents of the code section in the module.
+    code_offset: usize,
+    files: Vec<String>,
+    /// Sorted by address.
+    rows: Vec<Row>,
+}
+
+fn code_offset(wasm: &[u8]) -> Result<Option<usize>, BinaryReaderError> {
+    let mut parser = ModuleReader::new(wasm)?;
+    while !parser.eof() {
+        let section = parser.read()?;
+        if let SectionCode::Code = section.code {
+            return Ok(Some(section.get_binary_reader().original_position()));
+        }
+    }
+    Ok(None)
+}
+
+fn attr_path<'a>(
+    dwarf: &Dwarf<Reader<'a>>,
+    unit: &gimli::Unit<Reader<'a>>,
+    attr: gimli::AttributeValue<Reader<'a>>,
+) -> gimli::Result<String> {
+    let path = dwarf.attr_string(unit, attr)?;
+    Ok(String::from_utf8_lossy(path.slice()).into_owned())
+}
+
+impl LineTable {
+    /// Reads the line tables of `wasm`; `None` if it has no debug info.
+    pub fn parse(wasm: &[u8]) -> Result<Option<Self>, String> {
+        let code_offset = match code_offset(wasm).map_err(|e| e.message.to_string())? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let load = |id: SectionId| -> Result<Reader, String> {
+            let sections = custom_sections(wasm, id.name()).map_err(|e| e.message.to_string())?;
+            let data = sections.first().cloned().unwrap_or(&[]);
+            Ok(EndianSlice::new(data, LittleEndian))
+        };
+        let no_sup =
+            |_: SectionId| -> Result<Reader, String> { Ok(EndianSlice::new(&[], LittleEndian)) };
+        let dwarf = Dwarf::load(load, no_sup)?;
+        let mut table = Self {
+            code_offset,
+            files: Vec::new(),
+            rows: Vec::new(),
+        };
+        table.read_units(&dwarf).map_err(|e| e.to_string())?;
+        if table.rows.is_empty() {
+            return Ok(None);
+        }
+        table.rows.sort_by_key(|row| (row.address, !row.end));
+        Ok(Some(table))
+    }
+
+    fn read_units(&mut self, dwarf: &Dwarf<Reader>) -> gimli::Result<()> {
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let program = match unit.line_program {
+                Some(ref program) => program.clone(),
+                None => continue,
+            };
+            // Indices of the files of the unit in `files`, by file index.
+            let mut files = Vec::new();
+            let mut rows = program.rows();
+            while let Some((header, row)) = rows.next_row()? {
+                let index = row.file_index() as usize;
+                if index >= files.len() {
+                    files.resize(index + 1, None);
+                }
+                if files[index].is_none() {
+                    if let Some(file) = row.file(header) {
+                        let mut path = attr_path(dwarf, &unit, file.path_name())?;
+                        if let Some(directory) = file.directory(header) {
+                            let directory = attr_path(dwarf, &unit, directory)?;
+                            path = Path::new(&directory).join(path).display().to_string();
+                        }
+                        self.files.push(path);
+                        files[index] = Some(self.files.len() - 1);
+                    }
+                }
+                self.rows.push(Row {
+                    address: row.address(),
+                    file: files[index],
+                    line: row.line().unwrap_or(0) as u32,
+                    column: match row.column() {
+                        ColumnType::LeftEdge => 0,
+                        ColumnType::Column(column) => column as u32,
+                    },
+                    end: row.end_sequence(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Location of the instruction at `offset` in the module.
+    pub fn location(&self, offset: usize) -> Option<Location> {
+        let address = offset.checked_sub(self.code_offset)? as u64;
+        let position = match self.rows.binary_search_by_key(&address, |row| row.address) {
+            // The last row at the address, after the end of a previous
+            // sequence.
+            Ok(mut position) => {
+                while position + 1 < self.rows.len() && self.rows[position + 1].address == address {
+                    position += 1;
+                }
+                position
+            }
+            Err(0) => return None,
+            Err(position) => position - 1,
+        };
+        let row = &self.rows[position];
+        if row.end || row.line == 0 {
+            return None;
+        }
+        Some(Location {
+            file: row.file.map(|file| self.files[file].clone()),
+            line: row.line,
+            column: row.column,
+        })
+    }
+}
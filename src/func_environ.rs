@@ -0,0 +1,445 @@
+//! The environment function bodies are translated in, which accesses the
+//! `VMContext` laid out by wasmtime-runtime.
+// Mostly a copy of wasmtime's wasmtime-environ/src/func_environ.rs, whose
+// `FuncEnvironment` is not public, so that single functions can be
+// translated.
+
+use cranelift_codegen::cursor::FuncCursor;
+use cranelift_codegen::ir;
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::immediates::{Offset32, Uimm64};
+use cranelift_codegen::ir::types::I32;
+use cranelift_codegen::ir::{
+    AbiParam, ArgumentPurpose, ExtFuncData, ExternalName, FuncRef, Function, InstBuilder, Signature,
+};
+use cranelift_codegen::isa::TargetFrontendConfig;
+use cranelift_entity::EntityRef;
+use cranelift_wasm::{
+    self, FuncIndex, GlobalIndex, GlobalVariable, MemoryIndex, SignatureIndex, TableIndex,
+    WasmResult,
+};
+use wasmtime_environ::{MemoryPlan, MemoryStyle, Module, TableStyle, VMOffsets, WASM_PAGE_SIZE};
+
+use std::convert::TryFrom;
+
+/// Name of the wasm function `func_index`.
+pub fn get_func_name(func_index: FuncIndex) -> ExternalName {
+    ExternalName::user(0, func_index.as_u32())
+}
+
+/// Name of the runtime function growing a memory defined by the module.
+pub fn get_memory_grow_name() -> ExternalName {
+    ExternalName::user(1, 0)
+}
+
+/// Name of the runtime function growing an imported memory.
+pub fn get_imported_memory_grow_name() -> ExternalName {
+    ExternalName::user(1, 1)
+}
+
+/// Name of the runtime function returning the size of a memory defined by
+/// the module.
+pub fn get_memory32_size_name() -> ExternalName {
+    ExternalName::user(1, 2)
+}
+
+/// Name of the runtime function returning the size of an imported memory.
+pub fn get_imported_memory32_size_name() -> ExternalName {
+    ExternalName::user(1, 3)
+}
+
+/// The environment of the functions of `module`.
+pub struct FuncEnvironment<'module> {
+    target_config: TargetFrontendConfig,
+    module: &'module Module,
+    /// The global value holding the address of the `VMContext`.
+    vmctx: Option<ir::GlobalValue>,
+    /// The runtime functions implementing `memory.size` and `memory.grow`,
+    /// once declared.
+    memory32_size_extfunc: Option<FuncRef>,
+    imported_memory32_size_extfunc: Option<FuncRef>,
+    memory_grow_extfunc: Option<FuncRef>,
+    imported_memory_grow_extfunc: Option<FuncRef>,
+    offsets: VMOffsets,
+}
+
+impl<'module> FuncEnvironment<'module> {
+    pub fn new(target_config: TargetFrontendConfig, module: &'module Module) -> Self {
+        Self {
+            target_config,
+            module,
+            vmctx: None,
+            memory32_size_extfunc: None,
+            imported_memory32_size_extfunc: None,
+            memory_grow_extfunc: None,
+            imported_memory_grow_extfunc: None,
+            offsets: VMOffsets::new(target_config.pointer_bytes(), module),
+        }
+    }
+
+    fn pointer_type(&self) -> ir::Type {
+        self.target_config.pointer_type()
+    }
+
+    fn vmctx(&mut self, func: &mut Function) -> ir::GlobalValue {
+        self.vmctx.unwrap_or_else(|| {
+            let vmctx = func.create_global_value(ir::GlobalValueData::VMContext);
+            self.vmctx = Some(vmctx);
+            vmctx
+        })
+    }
+
+    /// Loads a pointer at `offset` from `base`, which doesn't change.
+    fn load_pointer(
+        &self,
+        func: &mut Function,
+        base: ir::GlobalValue,
+        offset: u32,
+    ) -> ir::GlobalValue {
+        func.create_global_value(ir::GlobalValueData::Load {
+            base,
+            offset: Offset32::new(i32::try_from(offset).unwrap()),
+            global_type: self.pointer_type(),
+            readonly: true,
+        })
+    }
+
+    fn import_runtime_function(
+        &self,
+        func: &mut Function,
+        name: ExternalName,
+        params: &[ir::Type],
+    ) -> FuncRef {
+        let mut signature = Signature::new(self.target_config.default_call_conv);
+        signature.params.push(AbiParam::special(
+            self.pointer_type(),
+            ArgumentPurpose::VMContext,
+        ));
+        signature
+            .params
+            .extend(params.iter().map(|ty| AbiParam::new(*ty)));
+        signature.returns.push(AbiParam::new(I32));
+        let signature = func.import_signature(signature);
+        // Code is not allocated along with the runtime, so nothing is
+        // colocated.
+        func.import_function(ExtFuncData {
+            name,
+            signature,
+            colocated: false,
+        })
+    }
+
+    /// Returns the `memory.grow` function of the memory `index`, and the
+    /// index to pass to it.
+    fn get_memory_grow_func(
+        &mut self,
+        func: &mut Function,
+        index: MemoryIndex,
+    ) -> (FuncRef, usize) {
+        if self.module.is_imported_memory(index) {
+            let extfunc = match self.imported_memory_grow_extfunc {
+                Some(extfunc) => extfunc,
+                None => {
+                    self.import_runtime_function(func, get_imported_memory_grow_name(), &[I32, I32])
+                }
+            };
+            self.imported_memory_grow_extfunc = Some(extfunc);
+            (extfunc, index.index())
+        } else {
+            let extfunc = match self.memory_grow_extfunc {
+                Some(extfunc) => extfunc,
+                None => self.import_runtime_function(func, get_memory_grow_name(), &[I32, I32]),
+            };
+            self.memory_grow_extfunc = Some(extfunc);
+            let defined = self.module.defined_memory_index(index).unwrap();
+            (extfunc, defined.index())
+        }
+    }
+
+    /// Returns the `memory.size` function of the memory `index`, and the
+    /// index to pass to it.
+    fn get_memory32_size_func(
+        &mut self,
+        func: &mut Function,
+        index: MemoryIndex,
+    ) -> (FuncRef, usize) {
+        if self.module.is_imported_memory(index) {
+            let extfunc = match self.imported_memory32_size_extfunc {
+                Some(extfunc) => extfunc,
+                None => {
+                    self.import_runtime_function(func, get_imported_memory32_size_name(), &[I32])
+                }
+            };
+            self.imported_memory32_size_extfunc = Some(extfunc);
+            (extfunc, index.index())
+        } else {
+            let extfunc = match self.memory32_size_extfunc {
+                Some(extfunc) => extfunc,
+                None => self.import_runtime_function(func, get_memory32_size_name(), &[I32]),
+            };
+            self.memory32_size_extfunc = Some(extfunc);
+            let defined = self.module.defined_memory_index(index).unwrap();
+            (extfunc, defined.index())
+        }
+    }
+}
+
+impl<'module> cranelift_wasm::FuncEnvironment for FuncEnvironment<'module> {
+    fn target_config(&self) -> TargetFrontendConfig {
+        self.target_config
+    }
+
+    fn make_table(&mut self, func: &mut Function, index: TableIndex) -> ir::Table {
+        let vmctx = self.vmctx(func);
+        let (ptr, base_offset, current_elements_offset) =
+            match self.module.defined_table_index(index) {
+                Some(def_index) => (
+                    vmctx,
+                    self.offsets.vmctx_vmtable_definition_base(def_index),
+                    self.offsets
+                        .vmctx_vmtable_definition_current_elements(def_index),
+                ),
+                None => (
+                    self.load_pointer(func, vmctx, self.offsets.vmctx_vmtable_import_from(index)),
+                    u32::from(self.offsets.vmtable_definition_base()),
+                    u32::from(self.offsets.vmtable_definition_current_elements()),
+                ),
+            };
+        let base_gv = func.create_global_value(ir::GlobalValueData::Load {
+            base: ptr,
+            offset: Offset32::new(i32::try_from(base_offset).unwrap()),
+            global_type: self.pointer_type(),
+            readonly: false,
+        });
+        let bound_gv = func.create_global_value(ir::GlobalValueData::Load {
+            base: ptr,
+            offset: Offset32::new(i32::try_from(current_elements_offset).unwrap()),
+            global_type: self.offsets.type_of_vmtable_definition_current_elements(),
+            readonly: false,
+        });
+        let element_size = match self.module.table_plans[index].style {
+            TableStyle::CallerChecksSignature => {
+                u64::from(self.offsets.size_of_vmcaller_checked_anyfunc())
+            }
+        };
+        func.create_table(ir::TableData {
+            base_gv,
+            min_size: Uimm64::new(0),
+            bound_gv,
+            element_size: Uimm64::new(element_size),
+            index_type: I32,
+        })
+    }
+
+    fn make_heap(&mut self, func: &mut Function, index: MemoryIndex) -> ir::Heap {
+        let vmctx = self.vmctx(func);
+        let (ptr, base_offset, current_length_offset) =
+            match self.module.defined_memory_index(index) {
+                Some(def_index) => (
+                    vmctx,
+                    self.offsets.vmctx_vmmemory_definition_base(def_index),
+                    self.offsets
+                        .vmctx_vmmemory_definition_current_length(def_index),
+                ),
+                None => (
+                    self.load_pointer(func, vmctx, self.offsets.vmctx_vmmemory_import_from(index)),
+                    u32::from(self.offsets.vmmemory_definition_base()),
+                    u32::from(self.offsets.vmmemory_definition_current_length()),
+                ),
+            };
+        // A memory with a declared maximum is allocated up front and never
+        // moves: its base can be loaded once.
+        let (offset_guard_size, style, readonly_base) = match self.module.memory_plans[index] {
+            MemoryPlan {
+                style: MemoryStyle::Dynamic,
+                offset_guard_size,
+                ..
+            } => {
+                let bound_gv = func.create_global_value(ir::GlobalValueData::Load {
+                    base: ptr,
+                    offset: Offset32::new(i32::try_from(current_length_offset).unwrap()),
+                    global_type: self.offsets.type_of_vmmemory_definition_current_length(),
+                    readonly: false,
+                });
+                (
+                    offset_guard_size,
+                    ir::HeapStyle::Dynamic { bound_gv },
+                    false,
+                )
+            }
+            MemoryPlan {
+                style: MemoryStyle::Static { bound },
+                offset_guard_size,
+                ..
+            } => (
+                offset_guard_size,
+                ir::HeapStyle::Static {
+                    bound: Uimm64::new(u64::from(bound) * u64::from(WASM_PAGE_SIZE)),
+                },
+                true,
+            ),
+        };
+        let base = func.create_global_value(ir::GlobalValueData::Load {
+            base: ptr,
+            offset: Offset32::new(i32::try_from(base_offset).unwrap()),
+            global_type: self.pointer_type(),
+            readonly: readonly_base,
+        });
+        func.create_heap(ir::HeapData {
+            base,
+            min_size: Uimm64::new(0),
+            offset_guard_size: Uimm64::new(offset_guard_size),
+            style,
+            index_type: I32,
+        })
+    }
+
+    fn make_global(&mut self, func: &mut Function, index: GlobalIndex) -> GlobalVariable {
+        let vmctx = self.vmctx(func);
+        let (gv, offset) = match self.module.defined_global_index(index) {
+            Some(def_index) => (vmctx, self.offsets.vmctx_vmglobal_definition(def_index)),
+            None => (
+                self.load_pointer(func, vmctx, self.offsets.vmctx_vmglobal_import_from(index)),
+                0,
+            ),
+        };
+        GlobalVariable::Memory {
+            gv,
+            offset: Offset32::new(i32::try_from(offset).unwrap()),
+            ty: self.module.globals[index].ty,
+        }
+    }
+
+    fn make_indirect_sig(&mut self, func: &mut Function, index: SignatureIndex) -> ir::SigRef {
+        func.import_signature(self.module.signatures[index].clone())
+    }
+
+    fn make_direct_func(&mut self, func: &mut Function, index: FuncIndex) -> FuncRef {
+        let signature = self.module.signatures[self.module.functions[index]].clone();
+        let signature = func.import_signature(signature);
+        func.import_function(ExtFuncData {
+            name: get_func_name(index),
+            signature,
+            colocated: false,
+        })
+    }
+
+    fn translate_call_indirect(
+        &mut self,
+        mut pos: FuncCursor,
+        table_index: TableIndex,
+        table: ir::Table,
+        sig_index: SignatureIndex,
+        sig_ref: ir::SigRef,
+        callee: ir::Value,
+        call_args: &[ir::Value],
+    ) -> WasmResult<ir::Inst> {
+        let pointer_type = self.pointer_type();
+        let mem_flags = ir::MemFlags::trusted();
+        let table_entry_addr = pos.ins().table_addr(pointer_type, table, callee, 0);
+        let func_addr = pos.ins().load(
+            pointer_type,
+            mem_flags,
+            table_entry_addr,
+            i32::from(self.offsets.vmcaller_checked_anyfunc_func_ptr()),
+        );
+        pos.ins().trapz(func_addr, ir::TrapCode::IndirectCallToNull);
+
+        match self.module.table_plans[table_index].style {
+            TableStyle::CallerChecksSignature => {
+                let sig_id_type =
+                    ir::Type::int(u16::from(self.offsets.size_of_vmshared_signature_index()) * 8)
+                        .unwrap();
+                let vmctx = self.vmctx(pos.func);
+                let base = pos.ins().global_value(pointer_type, vmctx);
+                let offset =
+                    i32::try_from(self.offsets.vmctx_vmshared_signature_id(sig_index)).unwrap();
+                let mut caller_flags = ir::MemFlags::trusted();
+                caller_flags.set_readonly();
+                let caller_sig_id = pos.ins().load(sig_id_type, caller_flags, base, offset);
+                let callee_sig_id = pos.ins().load(
+                    sig_id_type,
+                    mem_flags,
+                    table_entry_addr,
+                    i32::from(self.offsets.vmcaller_checked_anyfunc_type_index()),
+                );
+                let cmp = pos.ins().icmp(IntCC::Equal, callee_sig_id, caller_sig_id);
+                pos.ins().trapz(cmp, ir::TrapCode::BadSignature);
+            }
+        }
+
+        // The callee's vmctx comes first.
+        let callee_vmctx = pos.ins().load(
+            pointer_type,
+            mem_flags,
+            table_entry_addr,
+            i32::from(self.offsets.vmcaller_checked_anyfunc_vmctx()),
+        );
+        let mut real_call_args = Vec::with_capacity(call_args.len() + 1);
+        real_call_args.push(callee_vmctx);
+        real_call_args.extend_from_slice(call_args);
+        Ok(pos.ins().call_indirect(sig_ref, func_addr, &real_call_args))
+    }
+
+    fn translate_call(
+        &mut self,
+        mut pos: FuncCursor,
+        callee_index: FuncIndex,
+        callee: FuncRef,
+        call_args: &[ir::Value],
+    ) -> WasmResult<ir::Inst> {
+        let mut real_call_args = Vec::with_capacity(call_args.len() + 1);
+
+        if !self.module.is_imported_function(callee_index) {
+            real_call_args.push(pos.func.special_param(ArgumentPurpose::VMContext).unwrap());
+            real_call_args.extend_from_slice(call_args);
+            return Ok(pos.ins().call(callee, &real_call_args));
+        }
+
+        // Imported functions are called indirectly through the `VMContext`,
+        // so that the code doesn't need to be patched.
+        let pointer_type = self.pointer_type();
+        let sig_ref = pos.func.dfg.ext_funcs[callee].signature;
+        let vmctx = self.vmctx(pos.func);
+        let base = pos.ins().global_value(pointer_type, vmctx);
+        let mem_flags = ir::MemFlags::trusted();
+        let body_offset =
+            i32::try_from(self.offsets.vmctx_vmfunction_import_body(callee_index)).unwrap();
+        let func_addr = pos.ins().load(pointer_type, mem_flags, base, body_offset);
+        let vmctx_offset =
+            i32::try_from(self.offsets.vmctx_vmfunction_import_vmctx(callee_index)).unwrap();
+        let callee_vmctx = pos.ins().load(pointer_type, mem_flags, base, vmctx_offset);
+        real_call_args.push(callee_vmctx);
+        real_call_args.extend_from_slice(call_args);
+        Ok(pos.ins().call_indirect(sig_ref, func_addr, &real_call_args))
+    }
+
+    fn translate_memory_grow(
+        &mut self,
+        mut pos: FuncCursor,
+        index: MemoryIndex,
+        _heap: ir::Heap,
+        val: ir::Value,
+    ) -> WasmResult<ir::Value> {
+        let (memory_grow_func, index_arg) = self.get_memory_grow_func(pos.func, index);
+        let memory_index = pos.ins().iconst(I32, index_arg as i64);
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        let call_inst = pos
+            .ins()
+            .call(memory_grow_func, &[vmctx, val, memory_index]);
+        Ok(pos.func.dfg.first_result(call_inst))
+    }
+
+    fn translate_memory_size(
+        &mut self,
+        mut pos: FuncCursor,
+        index: MemoryIndex,
+        _heap: ir::Heap,
+    ) -> WasmResult<ir::Value> {
+        let (memory_size_func, index_arg) = self.get_memory32_size_func(pos.func, index);
+        let memory_index = pos.ins().iconst(I32, index_arg as i64);
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+        let call_inst = pos.ins().call(memory_size_func, &[vmctx, memory_index]);
+        Ok(pos.func.dfg.first_result(call_inst))
+    }
+}
@@ -1,23 +1,33 @@
 //! Support for a calling of a bounds (exported) function.
 
+use pyo3::exceptions::Exception;
 use pyo3::prelude::*;
 use pyo3::types::PyTuple;
 
-use crate::value::{default_value_for, outcome_into_pyobj, pyobj_to_runtime_value};
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::backtrace::{self, Trace};
+use crate::coredump;
+use crate::interrupt::{duration_from_secs, Deadline, InterruptState};
+use crate::store::StoreState;
+use crate::trace::{Callee, Tracers};
+use crate::trampoline::Call;
+use crate::trap::{take_pending_error, update_exception, Interrupted, Trap};
+use crate::value::{read_value_from, write_value_to};
+use std::cmp;
+use std::sync::Arc;
 
 use cranelift_codegen::ir;
-use wasmtime_jit::{Context, InstanceHandle};
+use wasmtime_jit::InstanceHandle;
 use wasmtime_runtime::Export;
 
 // TODO support non-export functions
 #[pyclass]
 pub struct Function {
-    pub context: Rc<RefCell<Context>>,
+    pub store: Arc<StoreState>,
     pub instance: InstanceHandle,
     pub export_name: String,
     pub args_types: Vec<ir::Type>,
+    pub interrupt: Arc<InterruptState>,
+    pub tracers: Tracers,
 }
 
 impl Function {
@@ -29,31 +39,123 @@ impl Function {
             panic!()
         }
     }
+
+    /// Writes a core dump of the instance for a `Trap` of the wasm code,
+    /// not an `Interrupted` or `Timeout` one, when the store has a
+    /// `coredump_dir`, and sets the `coredump` of the trap to its path (or
+    /// `None` when it could not be written). A trap of a nested call keeps
+    /// its own dump.
+    fn dump_core(&self, py: Python, err: PyErr, trace: &Trace) -> PyErr {
+        let dir = match self.store.coredump_dir() {
+            Some(ref dir) if err.is_instance::<Trap>(py) && !err.is_instance::<Interrupted>(py) => {
+                dir.clone()
+            }
+            _ => return err,
+        };
+        update_exception(py, err, |value| {
+            if value.hasattr("coredump").unwrap_or(true) {
+                return;
+            }
+            let frames = backtrace::resolve(&trace.addresses, &trace.locals).unwrap_or_default();
+            let mut instance = self.instance.clone();
+            let path = coredump::write(py, &dir, &mut instance, &frames).ok();
+            let _ = value.setattr("coredump", path.map(|p| p.display().to_string()));
+        })
+    }
+
+    /// Makes the call of `__call__`, without its tracing.
+    fn invoke(&self, py: Python, args: &PyTuple, timeout: Option<f64>) -> PyResult<PyObject> {
+        let _execution = self.store.execution.lock(py);
+        let mut instance = self.instance.clone();
+        let (address, vmctx, signature) = match instance.lookup(&self.export_name) {
+            Some(Export::Function {
+                address,
+                vmctx,
+                signature,
+            }) => (address, vmctx, signature),
+            _ => panic!("function is expected"),
+        };
+
+        // Missing arguments are zeros.
+        let mut values_vec = vec![0i64; cmp::max(self.args_types.len(), signature.returns.len())];
+        for (i, ty) in self.args_types.iter().enumerate().take(args.len()) {
+            unsafe {
+                write_value_to(
+                    py,
+                    values_vec.as_mut_ptr().add(i),
+                    *ty,
+                    args.get_item(i).to_object(py),
+                )?;
+            }
+        }
+
+        let trampoline = self
+            .store
+            .trampolines
+            .lock()
+            .unwrap()
+            .get(address, &signature, || {
+                let callee = backtrace::function_label_at(address as usize)
+                    .unwrap_or_else(|| format!("export {}", self.export_name));
+                format!("trampoline to {}", callee)
+            });
+        let call = Call {
+            vmctx,
+            trampoline,
+            values_vec: values_vec.as_mut_ptr(),
+        };
+
+        let timeout = match timeout {
+            Some(secs) => Some(duration_from_secs(secs)?),
+            None => self.store.timeout(),
+        };
+        self.interrupt.reset_sigint();
+        let deadline = timeout.map(|t| Deadline::start(self.interrupt.clone(), t));
+        backtrace::reset();
+        let result = py.allow_threads(move || call.invoke());
+        if let Some(deadline) = deadline {
+            deadline.finish();
+        }
+        if let Err(message) = result {
+            let err = if let Some(err) = take_pending_error() {
+                err
+            } else if let Some(err) = self.interrupt.take_error(py, timeout) {
+                err
+            } else {
+                Trap::py_err(message)
+            };
+            let trace = backtrace::take();
+            let err = backtrace::attach(py, err, &trace.addresses);
+            return Err(self.dump_core(py, err, &trace));
+        }
+
+        Ok(match signature.returns.len() {
+            0 => PyTuple::empty(py).into_object(py),
+            1 => unsafe {
+                read_value_from(py, values_vec.as_mut_ptr(), signature.returns[0].value_type)
+            },
+            _ => return Err(PyErr::new::<Exception, _>("multivalue return unsupported")),
+        })
+    }
 }
 
 #[pymethods]
 impl Function {
+    /// Calls the export; with a `timeout` (in seconds, defaults to the
+    /// store's one) the call raises `Timeout` when it runs longer.
+    ///
+    /// The GIL is released while the wasm code runs, and only reacquired
+    /// when it calls an imported Python function. Calls into the same store
+    /// from other threads wait for this one to finish.
     #[__call__]
-    #[args(args = "*")]
-    fn call(&self, py: Python, args: &PyTuple) -> PyResult<PyObject> {
-        let mut runtime_args = Vec::new();
-        for i in 0..self.args_types.len() {
-            if i >= args.len() {
-                runtime_args.push(default_value_for(py, self.args_types[i])?);
-                continue;
-            }
-            runtime_args.push(pyobj_to_runtime_value(
-                py,
-                args.get_item(i),
-                self.args_types[i],
-            )?);
+    #[args(args = "*", timeout = "None")]
+    fn call(&self, py: Python, args: &PyTuple, timeout: Option<f64>) -> PyResult<PyObject> {
+        if !self.tracers.enabled() {
+            return self.invoke(py, args, timeout);
         }
-        let mut instance = self.instance.clone();
-        let outcome = self
-            .context
-            .borrow_mut()
-            .invoke(&mut instance, self.export_name.as_str(), &runtime_args)
-            .expect("good run");
-        outcome_into_pyobj(py, outcome)
+        self.tracers
+            .trace(py, Callee::Wasm, &self.export_name, args, || {
+                self.invoke(py, args, timeout)
+            })
     }
 }
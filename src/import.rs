@@ -3,9 +3,14 @@
 use pyo3::prelude::*;
 use pyo3::types::{PyAny, PyDict, PyTuple};
 
+use crate::backtrace::HostTrampolines;
 use crate::code_memory::CodeMemory;
 use crate::function::Function;
 use crate::memory::Memory;
+use crate::perf;
+use crate::trace::{Callee, Tracers};
+use crate::trampoline::check_pending_error;
+use crate::trap::raise;
 use crate::value::{read_value_from, write_value_to};
 use cranelift_codegen::ir::types;
 use cranelift_codegen::ir::{InstBuilder, StackSlotData, StackSlotKind};
@@ -30,23 +35,49 @@ struct BoundPyFunction {
 
 struct ImportObjState {
     calls: Vec<BoundPyFunction>,
+    /// Name of the import object in the imports of the instance, and the
+    /// tracers of the instance, for the events of its host calls.
+    module_name: String,
+    tracers: Tracers,
     #[allow(dead_code)]
     code_memory: CodeMemory,
+    /// For the traceback of the errors of the calls, see `backtrace::attach`.
+    #[allow(dead_code)]
+    host_trampolines: HostTrampolines,
 }
 
 unsafe extern "C" fn stub_fn(vmctx: *mut VMContext, call_id: u32, values_vec: *mut i64) {
-    let gil = Python::acquire_gil();
-    let py = gil.python();
+    let result = {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        call_py_function(py, vmctx, call_id, values_vec)
+    };
+    if let Err(err) = result {
+        raise(err);
+    }
+}
 
+unsafe fn call_py_function(
+    py: Python,
+    vmctx: *mut VMContext,
+    call_id: u32,
+    values_vec: *mut i64,
+) -> PyResult<()> {
     let mut instance = InstanceHandle::from_vmctx(vmctx);
-    let (_name, obj) = {
+    let (obj, traced) = {
         let state = instance
             .host_state()
             .downcast_mut::<ImportObjState>()
             .expect("state");
-        let name = state.calls[call_id as usize].name.to_owned();
-        let obj = state.calls[call_id as usize].obj.clone_ref(py);
-        (name, obj)
+        let call = &state.calls[call_id as usize];
+        // Nothing more is done without a trace callback.
+        let traced = if state.tracers.enabled() {
+            let name = format!("{}.{}", state.module_name, call.name);
+            Some((state.tracers.clone(), name))
+        } else {
+            None
+        };
+        (call.obj.clone_ref(py), traced)
     };
     let module = instance.module_ref();
     let signature = &module.signatures[module.functions[FuncIndex::new(call_id as usize)]];
@@ -59,7 +90,13 @@ unsafe extern "C" fn stub_fn(vmctx: *mut VMContext, call_id: u32, values_vec: *m
             signature.params[i].value_type,
         ))
     }
-    let result = obj.call(py, PyTuple::new(py, args), None).expect("result");
+    let args = PyTuple::new(py, args);
+    let result = match traced {
+        None => obj.call(py, args, None)?,
+        Some((tracers, name)) => {
+            tracers.trace(py, Callee::Host, &name, args, || obj.call(py, args, None))?
+        }
+    };
     for i in 0..signature.returns.len() {
         let val = if result.is_none() {
             0.into_object(py) // FIXME default ???
@@ -74,17 +111,20 @@ unsafe extern "C" fn stub_fn(vmctx: *mut VMContext, call_id: u32, values_vec: *m
             values_vec.offset(i as isize),
             signature.returns[i].value_type,
             val,
-        );
+        )?;
     }
+    Ok(())
 }
 
 /// Create a trampoline for invoking a python function.
 fn make_trampoline(
     isa: &dyn isa::TargetIsa,
     code_memory: &mut CodeMemory,
+    host_trampolines: &mut HostTrampolines,
     fn_builder_ctx: &mut FunctionBuilderContext,
     call_id: u32,
     signature: &ir::Signature,
+    name: &str,
 ) -> *const VMFunctionBody {
     // Mostly reverse copy of the similar method from wasmtime's
     // wasmtime-jit/src/compiler.rs.
@@ -152,6 +192,7 @@ fn make_trampoline(
         builder
             .ins()
             .call_indirect(new_sig, callee_value, &callee_args);
+        check_pending_error(&mut builder, isa);
 
         let mflags = ir::MemFlags::trusted();
         let mut results = Vec::new();
@@ -175,10 +216,15 @@ fn make_trampoline(
         .compile_and_emit(isa, &mut code_buf, &mut reloc_sink, &mut trap_sink)
         .expect("compile_and_emit");
 
-    code_memory
+    let trampoline = code_memory
         .allocate_copy_of_byte_slice(&code_buf)
         .expect("allocate_copy_of_byte_slice")
-        .as_ptr()
+        .as_ptr();
+    perf::record(trampoline as usize, code_buf.len(), || {
+        format!("trampoline to host function {}", name)
+    });
+    host_trampolines.register(trampoline as usize, code_buf.len());
+    trampoline
 }
 
 fn parse_annotation_type(s: &str) -> ir::Type {
@@ -222,7 +268,9 @@ fn get_signature_from_py_annotation(
 pub fn into_instance_from_obj(
     py: Python,
     global_exports: Rc<RefCell<HashMap<String, Option<wasmtime_runtime::Export>>>>,
+    module_name: &str,
     obj: &PyAny,
+    tracers: Tracers,
 ) -> PyResult<InstanceHandle> {
     let isa = {
         let isa_builder =
@@ -236,6 +284,7 @@ pub fn into_instance_from_obj(
     let mut finished_functions: PrimaryMap<DefinedFuncIndex, *const VMFunctionBody> =
         PrimaryMap::new();
     let mut code_memory = CodeMemory::new();
+    let mut host_trampolines = HostTrampolines::default();
 
     let pointer_type = types::Type::triple_pointer_type(&HOST);
     let call_conv = isa::CallConv::triple_default(&HOST);
@@ -267,9 +316,11 @@ pub fn into_instance_from_obj(
             let trampoline = make_trampoline(
                 isa.as_ref(),
                 &mut code_memory,
+                &mut host_trampolines,
                 &mut fn_builder_ctx,
                 func_id.index() as u32,
                 &sig,
+                &name.to_string(),
             );
             finished_functions.push(trampoline);
 
@@ -308,7 +359,10 @@ pub fn into_instance_from_obj(
 
     let import_obj_state = ImportObjState {
         calls: bound_functions,
+        module_name: module_name.to_string(),
+        tracers,
         code_memory,
+        host_trampolines,
     };
 
     Ok(InstanceHandle::new(
@@ -326,7 +380,7 @@ pub fn into_instance_from_obj(
 
 /// We don't expect trampoline compilation to produce any relocations, so
 /// this `RelocSink` just asserts that it doesn't recieve any.
-struct RelocSink {}
+pub(crate) struct RelocSink {}
 
 impl binemit::RelocSink for RelocSink {
     fn reloc_ebb(
@@ -0,0 +1,96 @@
+//! Inspection of the code generated for the functions of a module.
+
+use crate::compiler::{translate, translate_function};
+use crate::module::CompiledModule;
+#[cfg(feature = "disassemble")]
+use capstone::prelude::*;
+use cranelift_codegen::isa::TargetIsa;
+use cranelift_wasm::DefinedFuncIndex;
+#[cfg(feature = "disassemble")]
+use target_lexicon::Architecture;
+use wasmtime_environ::{Export, Module};
+
+#[cfg(feature = "disassemble")]
+use std::fmt::Write;
+
+/// Index of the defined function exported as `name`.
+fn exported_function(module: &Module, name: &str) -> Result<DefinedFuncIndex, String> {
+    match module.exports.get(name) {
+        Some(Export::Function(index)) => module
+            .defined_func_index(*index)
+            .ok_or_else(|| format!("{} is an imported function", name)),
+        _ => Err(format!("the module has no {} function export", name)),
+    }
+}
+
+#[cfg(feature = "disassemble")]
+fn capstone(arch: Architecture) -> Result<Capstone, String> {
+    match arch {
+        Architecture::X86_64 => Capstone::new()
+            .x86()
+            .mode(arch::x86::ArchMode::Mode64)
+            .build(),
+        _ => return Err(format!("cannot disassemble {} code", arch)),
+    }
+    .map_err(|e| e.to_string())
+}
+
+/// Returns the machine code of the function exported as `name`, compiled
+/// for `isa`, as text. Addresses are offsets in the function.
+#[cfg(feature = "disassemble")]
+pub fn disassemble(
+    isa: &dyn TargetIsa,
+    compiled: &CompiledModule,
+    name: &str,
+) -> Result<String, String> {
+    let artifact = &compiled.artifact;
+    let index = exported_function(&translate(isa, &artifact.wasm)?.module, name)?;
+    let code = artifact.compilation.get(index);
+    let cs = capstone(isa.triple().architecture)?;
+    let insns = cs.disasm_all(code, 0).map_err(|e| e.to_string())?;
+    let mut text = String::new();
+    for insn in insns.iter() {
+        writeln!(
+            text,
+            "{:6x}:  {} {}",
+            insn.address(),
+            insn.mnemonic().unwrap_or("?"),
+            insn.op_str().unwrap_or("")
+        )
+        .unwrap();
+    }
+    Ok(text)
+}
+
+#[cfg(not(feature = "disassemble"))]
+pub fn disassemble(
+    _isa: &dyn TargetIsa,
+    _compiled: &CompiledModule,
+    _name: &str,
+) -> Result<String, String> {
+    Err("wasmtime-py was built without the disassemble feature".to_string())
+}
+
+/// Returns the Cranelift IR of the function exported as `name`, as
+/// translated from wasm and as compiled, in the environment of the
+/// compiled code.
+pub fn cranelift_ir(
+    isa: &dyn TargetIsa,
+    compiled: &CompiledModule,
+    name: &str,
+) -> Result<(String, String), String> {
+    let translation = translate(isa, &compiled.artifact.wasm)?;
+    let index = exported_function(&translation.module, name)?;
+    let body = &translation.function_body_inputs[index];
+    let mut context = translate_function(
+        isa,
+        &translation.module,
+        body.data,
+        body.module_offset,
+        index,
+    )?;
+    let before = context.func.display(isa).to_string();
+    context.compile(isa).map_err(|e| e.to_string())?;
+    let after = context.func.display(isa).to_string();
+    Ok((before, after))
+}
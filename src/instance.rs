@@ -4,20 +4,25 @@ use pyo3::prelude::*;
 use pyo3::types::PyDict;
 
 use crate::function::Function;
+use crate::interrupt::{InterruptHandle, InterruptState};
 use crate::memory::Memory;
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::store::StoreState;
+use crate::trace::{Tracer, Tracers};
+use std::sync::Arc;
 
 use cranelift_codegen::ir;
 use cranelift_codegen::ir::types;
 use wasmtime_environ::Export;
-use wasmtime_jit::{Context, InstanceHandle};
+use wasmtime_jit::InstanceHandle;
 use wasmtime_runtime::Export as RuntimeExport;
 
 #[pyclass]
 pub struct Instance {
-    pub context: Rc<RefCell<Context>>,
+    pub store: Arc<StoreState>,
     pub instance: InstanceHandle,
+    pub interrupt: Arc<InterruptState>,
+    /// Callback of the calls of this instance, besides the store's one.
+    pub tracer: Arc<Tracer>,
 }
 
 fn get_type_annot(ty: ir::Type) -> &'static str {
@@ -32,6 +37,30 @@ fn get_type_annot(ty: ir::Type) -> &'static str {
 
 #[pymethods]
 impl Instance {
+    fn interrupt_handle(&self, py: Python) -> PyResult<Py<InterruptHandle>> {
+        Py::new(
+            py,
+            InterruptHandle {
+                state: self.interrupt.clone(),
+            },
+        )
+    }
+
+    /// Calls `callback(event, name, values)` around the calls crossing the
+    /// boundary of the instance; `None` stops tracing:
+    ///
+    /// - `"call_wasm"` and `"return_wasm"` for the calls of its exports,
+    ///   `name` being the export name,
+    /// - `"call_host"` and `"return_host"` for the calls of the Python
+    ///   functions it imports, `name` being `module.field`.
+    ///
+    /// `values` is the tuple of the arguments or the results of the call, or
+    /// the exception it raised. An exception raised by the callback is
+    /// raised by the call.
+    fn set_trace(&self, callback: Option<PyObject>) {
+        self.tracer.set(callback);
+    }
+
     #[getter(exports)]
     fn get_exports(&mut self) -> PyResult<PyObject> {
         let gil = Python::acquire_gil();
@@ -54,7 +83,7 @@ impl Instance {
                 let f = Py::new(
                     py,
                     Memory {
-                        context: self.context.clone(),
+                        store: self.store.clone(),
                         instance: self.instance.clone(),
                         export_name: name.clone(),
                     },
@@ -84,10 +113,15 @@ impl Instance {
                 let f = Py::new(
                     py,
                     Function {
-                        context: self.context.clone(),
+                        store: self.store.clone(),
                         instance: self.instance.clone(),
                         export_name: name.clone(),
                         args_types,
+                        interrupt: self.interrupt.clone(),
+                        tracers: Tracers {
+                            store: self.store.tracer.clone(),
+                            instance: self.tracer.clone(),
+                        },
                     },
                 )?;
                 // FIXME set the f object the `__annotations__` attribute somehow?
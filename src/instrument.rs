@@ -0,0 +1,981 @@
+//! Rewriting of wasm binaries before they are handed to the compiler.
+//!
+//! The code generator lives in the `wasmtime` submodule and knows nothing
+//! about the Python side, so features that need cooperation from the guest
+//! code are implemented by patching the module instead: extra imports from
+//! the `__wasmtime` namespace (provided by `support.rs`) and a few
+//! instructions injected into the function bodies.
+
+use pyo3::exceptions::Exception;
+use pyo3::prelude::*;
+
+use crate::atomics;
+use crate::limits::ResourceLimitExceeded;
+use serde::{Deserialize, Serialize};
+use wasmparser::{BinaryReader, BinaryReaderError, Operator};
+
+/// Namespace of the imports added to an instrumented module.
+pub const SUPPORT_MODULE: &str = "__wasmtime";
+
+const SECTION_CUSTOM: u8 = 0;
+const SECTION_TYPE: u8 = 1;
+const SECTION_IMPORT: u8 = 2;
+const SECTION_TABLE: u8 = 4;
+const SECTION_MEMORY: u8 = 5;
+const SECTION_EXPORT: u8 = 7;
+const SECTION_START: u8 = 8;
+const SECTION_ELEMENT: u8 = 9;
+const SECTION_CODE: u8 = 10;
+
+const EXTERNAL_FUNCTION: u8 = 0;
+const EXTERNAL_TABLE: u8 = 1;
+const EXTERNAL_MEMORY: u8 = 2;
+const EXTERNAL_GLOBAL: u8 = 3;
+
+const NAME_FUNCTIONS: u8 = 1;
+const NAME_LOCALS: u8 = 2;
+
+const TYPE_I32: u8 = 0x7f;
+const TYPE_I64: u8 = 0x7e;
+const TYPE_FUNC: u8 = 0x60;
+const BLOCK_TYPE_EMPTY: u8 = 0x40;
+
+const OP_UNREACHABLE: u8 = 0x00;
+const OP_IF: u8 = 0x04;
+const OP_END: u8 = 0x0b;
+const OP_CALL: u8 = 0x10;
+const OP_GET_GLOBAL: u8 = 0x23;
+const OP_SET_GLOBAL: u8 = 0x24;
+const OP_MEMORY_SIZE: u8 = 0x3f;
+const OP_I32_CONST: u8 = 0x41;
+const OP_I32_OR: u8 = 0x72;
+const OP_ATOMIC_PREFIX: u8 = 0xfe;
+
+const ATOMIC_NOTIFY: u32 = 0x00;
+const ATOMIC_WAIT_I32: u32 = 0x01;
+const ATOMIC_WAIT_I64: u32 = 0x02;
+const ATOMIC_FENCE: u32 = 0x03;
+
+const LIMITS_HAS_MAXIMUM: u32 = 1;
+const LIMITS_SHARED: u32 = 2;
+
+/// A host function imported from `SUPPORT_MODULE`.
+#[derive(Clone, Copy)]
+struct SupportFunction {
+    name: &'static str,
+    params: &'static [u8],
+    results: &'static [u8],
+}
+
+/// `interrupted()`, called by the interrupt checks before they trap, see
+/// `support::interrupted`.
+const INTERRUPTED: SupportFunction = SupportFunction {
+    name: "interrupted",
+    params: &[],
+    results: &[],
+};
+
+/// `memory_grow(delta, current_pages) -> delta`, see `support::memory_grow`.
+const MEMORY_GROW: SupportFunction = SupportFunction {
+    name: "memory_grow",
+    params: &[TYPE_I32, TYPE_I32],
+    results: &[TYPE_I32],
+};
+
+/// `memory_grow_shared(delta) -> previous_pages`, replaces `memory.grow`
+/// on shared memories, see `support::memory_grow_shared`.
+const MEMORY_GROW_SHARED: SupportFunction = SupportFunction {
+    name: "memory_grow_shared",
+    params: &[TYPE_I32],
+    results: &[TYPE_I32],
+};
+
+/// Replacements of the atomic instructions, see `support.rs`. They all
+/// take the `offset` immediate as an extra operand and, except for notify
+/// and wait, the opcode.
+const ATOMIC_FUNCTIONS: [SupportFunction; 11] = [
+    SupportFunction {
+        name: "atomic_load_i32",
+        params: &[TYPE_I32, TYPE_I32, TYPE_I32],
+        results: &[TYPE_I32],
+    },
+    SupportFunction {
+        name: "atomic_load_i64",
+        params: &[TYPE_I32, TYPE_I32, TYPE_I32],
+        results: &[TYPE_I64],
+    },
+    SupportFunction {
+        name: "atomic_store_i32",
+        params: &[TYPE_I32, TYPE_I32, TYPE_I32, TYPE_I32],
+        results: &[],
+    },
+    SupportFunction {
+        name: "atomic_store_i64",
+        params: &[TYPE_I32, TYPE_I64, TYPE_I32, TYPE_I32],
+        results: &[],
+    },
+    SupportFunction {
+        name: "atomic_rmw_i32",
+        params: &[TYPE_I32, TYPE_I32, TYPE_I32, TYPE_I32],
+        results: &[TYPE_I32],
+    },
+    SupportFunction {
+        name: "atomic_rmw_i64",
+        params: &[TYPE_I32, TYPE_I64, TYPE_I32, TYPE_I32],
+        results: &[TYPE_I64],
+    },
+    SupportFunction {
+        name: "atomic_cmpxchg_i32",
+        params: &[TYPE_I32, TYPE_I32, TYPE_I32, TYPE_I32, TYPE_I32],
+        results: &[TYPE_I32],
+    },
+    SupportFunction {
+        name: "atomic_cmpxchg_i64",
+        params: &[TYPE_I32, TYPE_I64, TYPE_I64, TYPE_I32, TYPE_I32],
+        results: &[TYPE_I64],
+    },
+    SupportFunction {
+        name: "atomic_notify",
+        params: &[TYPE_I32, TYPE_I32, TYPE_I32],
+        results: &[TYPE_I32],
+    },
+    SupportFunction {
+        name: "atomic_wait_i32",
+        params: &[TYPE_I32, TYPE_I32, TYPE_I64, TYPE_I32],
+        results: &[TYPE_I32],
+    },
+    SupportFunction {
+        name: "atomic_wait_i64",
+        params: &[TYPE_I32, TYPE_I64, TYPE_I64, TYPE_I32],
+        results: &[TYPE_I32],
+    },
+];
+
+/// Describes what has to be injected into a module.
+#[derive(Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Instrumentation {
+    /// Also poll the process-wide `sigint` flag, see `interrupt::hook_sigint`.
+    pub handle_sigint: bool,
+    /// Upper bound for the maximum of defined memories and the minimum of
+    /// imported ones, in wasm pages.
+    pub memory_pages: Option<u32>,
+    /// Upper bound for the maximum of defined tables and the minimum of
+    /// imported ones.
+    pub table_elements: Option<u32>,
+    /// Route `memory.grow` through the `memory_grow` support function.
+    pub hook_memory_grow: bool,
+}
+
+struct Section<'a> {
+    id: u8,
+    payload: &'a [u8],
+}
+
+/// Index spaces of the original module and the entries we append to them.
+struct Layout {
+    types: u32,
+    imported_functions: u32,
+    imported_globals: u32,
+    added_functions: Vec<SupportFunction>,
+    added_globals: Vec<&'static str>,
+    /// The memory of the module is shared (threads proposal).
+    shared_memory: bool,
+    /// Route unshared `memory.grow` through the `memory_grow` support
+    /// function.
+    hook_memory_grow: bool,
+}
+
+impl Layout {
+    fn function_index(&self, index: u32) -> u32 {
+        if index < self.imported_functions {
+            index
+        } else {
+            index + self.added_functions.len() as u32
+        }
+    }
+
+    fn global_index(&self, index: u32) -> u32 {
+        if index < self.imported_globals {
+            index
+        } else {
+            index + self.added_globals.len() as u32
+        }
+    }
+
+    fn added_function_index(&self, name: &str) -> u32 {
+        let position = self
+            .added_functions
+            .iter()
+            .position(|f| f.name == name)
+            .expect("added function");
+        self.imported_functions + position as u32
+    }
+
+    fn added_global_index(&self, name: &str) -> u32 {
+        let position = self
+            .added_globals
+            .iter()
+            .position(|n| *n == name)
+            .expect("added global");
+        self.imported_globals + position as u32
+    }
+
+    /// Emits `if (interrupt | sigint) { interrupted(); unreachable }`. The
+    /// call records the flags, so that the caller of the export recognizes
+    /// this trap and tells it from the other ones.
+    fn emit_interrupt_check(&self, out: &mut Vec<u8>) {
+        let mut first = true;
+        for name in &["interrupt", "sigint"] {
+            if !self.added_globals.contains(name) {
+                continue;
+            }
+            out.push(OP_GET_GLOBAL);
+            write_var_u32(out, self.added_global_index(name));
+            if !first {
+                out.push(OP_I32_OR);
+            }
+            first = false;
+        }
+        out.extend_from_slice(&[OP_IF, BLOCK_TYPE_EMPTY]);
+        self.emit_call(out, INTERRUPTED.name);
+        out.extend_from_slice(&[OP_UNREACHABLE, OP_END]);
+    }
+
+    fn emit_call(&self, out: &mut Vec<u8>, name: &str) {
+        out.push(OP_CALL);
+        write_var_u32(out, self.added_function_index(name));
+    }
+
+    /// Emits the support call replacing an atomic instruction; the operands
+    /// are already on the stack.
+    fn emit_atomic(
+        &self,
+        out: &mut Vec<u8>,
+        op: &AtomicOp,
+        offset: usize,
+    ) -> Result<(), BinaryReaderError> {
+        let typed = |i32_name, i64_name| {
+            if atomics::is_i64(op.code) {
+                i64_name
+            } else {
+                i32_name
+            }
+        };
+        let name = match op.code {
+            ATOMIC_FENCE => return Ok(()),
+            ATOMIC_NOTIFY => "atomic_notify",
+            ATOMIC_WAIT_I32 => "atomic_wait_i32",
+            ATOMIC_WAIT_I64 => "atomic_wait_i64",
+            code if code >= atomics::LOAD && code < atomics::STORE => {
+                typed("atomic_load_i32", "atomic_load_i64")
+            }
+            code if code >= atomics::STORE && code < atomics::RMW => {
+                typed("atomic_store_i32", "atomic_store_i64")
+            }
+            code if code >= atomics::RMW && code < atomics::CMPXCHG => {
+                typed("atomic_rmw_i32", "atomic_rmw_i64")
+            }
+            code if code >= atomics::CMPXCHG && code < atomics::END => {
+                typed("atomic_cmpxchg_i32", "atomic_cmpxchg_i64")
+            }
+            _ => {
+                return Err(BinaryReaderError {
+                    message: "unknown atomic instruction",
+                    offset,
+                })
+            }
+        };
+        out.push(OP_I32_CONST);
+        write_var_i32(out, op.offset as i32);
+        if op.code >= atomics::LOAD {
+            out.push(OP_I32_CONST);
+            write_var_i32(out, op.code as i32);
+        }
+        self.emit_call(out, name);
+        Ok(())
+    }
+}
+
+/// An instruction of the threads proposal (`0xfe` prefix).
+struct AtomicOp {
+    code: u32,
+    offset: u32,
+}
+
+/// Reads an atomic instruction if one starts at the reader position. They
+/// are decoded by hand since not all of them are known to wasmparser.
+fn read_atomic_op(
+    reader: &mut BinaryReader,
+    body: &[u8],
+) -> Result<Option<AtomicOp>, BinaryReaderError> {
+    if body[reader.current_position()] != OP_ATOMIC_PREFIX {
+        return Ok(None);
+    }
+    reader.read_u8()?;
+    let code = reader.read_var_u32()?;
+    if code == ATOMIC_FENCE {
+        reader.read_u8()?;
+        return Ok(Some(AtomicOp { code, offset: 0 }));
+    }
+    let _alignment = reader.read_var_u32()?;
+    let offset = reader.read_var_u32()?;
+    Ok(Some(AtomicOp { code, offset }))
+}
+
+/// Skips the locals declarations of a function body.
+fn skip_locals(reader: &mut BinaryReader) -> Result<(), BinaryReaderError> {
+    for _ in 0..reader.read_var_u32()? {
+        reader.read_var_u32()?;
+        reader.read_u8()?;
+    }
+    Ok(())
+}
+
+/// Whether any function body of the code section uses atomic instructions.
+fn uses_atomics(payload: &[u8]) -> Result<bool, BinaryReaderError> {
+    let mut reader = BinaryReader::new(payload);
+    for _ in 0..reader.read_var_u32()? {
+        let size = reader.read_var_u32()? as usize;
+        let body = reader.read_bytes(size)?;
+        let mut reader = BinaryReader::new(body);
+        skip_locals(&mut reader)?;
+        while !reader.eof() {
+            if read_atomic_op(&mut reader, body)?.is_some() {
+                return Ok(true);
+            }
+            reader.read_operator()?;
+        }
+    }
+    Ok(false)
+}
+
+/// Whether the (single) memory of the module, imported or defined, is shared.
+fn has_shared_memory(sections: &[Section]) -> Result<bool, BinaryReaderError> {
+    for section in sections {
+        let mut reader = BinaryReader::new(section.payload);
+        match section.id {
+            SECTION_IMPORT => {
+                for _ in 0..reader.read_var_u32()? {
+                    reader.read_string()?;
+                    reader.read_string()?;
+                    match reader.read_u8()? as u8 {
+                        EXTERNAL_MEMORY => {
+                            return Ok(reader.read_var_u32()? & LIMITS_SHARED != 0);
+                        }
+                        EXTERNAL_TABLE => {
+                            reader.read_u8()?;
+                            skip_limits(&mut reader)?;
+                        }
+                        EXTERNAL_GLOBAL => {
+                            reader.read_u8()?;
+                            reader.read_u8()?;
+                        }
+                        _ => {
+                            reader.read_var_u32()?;
+                        }
+                    }
+                }
+            }
+            SECTION_MEMORY => {
+                if reader.read_var_u32()? > 0 {
+                    return Ok(reader.read_var_u32()? & LIMITS_SHARED != 0);
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(false)
+}
+
+fn reader_error(e: BinaryReaderError) -> PyErr {
+    PyErr::new::<Exception, _>(format!("{} (at offset {})", e.message, e.offset))
+}
+
+pub fn write_var_u32(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub fn write_var_i32(out: &mut Vec<u8>, mut value: i32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub fn write_name(out: &mut Vec<u8>, name: &str) {
+    write_var_u32(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+}
+
+pub fn write_section(out: &mut Vec<u8>, id: u8, payload: &[u8]) {
+    out.push(id);
+    write_var_u32(out, payload.len() as u32);
+    out.extend_from_slice(payload);
+}
+
+fn read_sections(data: &[u8]) -> Result<Vec<Section>, BinaryReaderError> {
+    let mut reader = BinaryReader::new(data);
+    if reader.read_bytes(8)? != b"\0asm\x01\0\0\0" {
+        return Err(BinaryReaderError {
+            message: "not a wasm module",
+            offset: 0,
+        });
+    }
+    let mut sections = Vec::new();
+    while !reader.eof() {
+        let id = reader.read_u8()? as u8;
+        let size = reader.read_var_u32()? as usize;
+        let payload = reader.read_bytes(size)?;
+        sections.push(Section { id, payload });
+    }
+    Ok(sections)
+}
+
+fn read_count(payload: &[u8]) -> Result<u32, BinaryReaderError> {
+    BinaryReader::new(payload).read_var_u32()
+}
+
+/// Skips the limits of a table or memory type.
+fn skip_limits(reader: &mut BinaryReader) -> Result<(), BinaryReaderError> {
+    let flags = reader.read_var_u32()?;
+    reader.read_var_u32()?;
+    if flags & LIMITS_HAS_MAXIMUM != 0 {
+        reader.read_var_u32()?;
+    }
+    Ok(())
+}
+
+/// Copies the limits of a table or memory type, capping the maximum. The
+/// shared flag is dropped: shared memories are handled by the support
+/// instance and the compiler only supports unshared ones.
+fn rewrite_limits(
+    reader: &mut BinaryReader,
+    out: &mut Vec<u8>,
+    cap: Option<u32>,
+    what: &str,
+) -> PyResult<()> {
+    let flags = reader.read_var_u32().map_err(reader_error)?;
+    let minimum = reader.read_var_u32().map_err(reader_error)?;
+    let maximum = if flags & LIMITS_HAS_MAXIMUM != 0 {
+        Some(reader.read_var_u32().map_err(reader_error)?)
+    } else {
+        None
+    };
+    let maximum = match (maximum, cap) {
+        (_, Some(cap)) if minimum > cap => {
+            return Err(ResourceLimitExceeded::py_err(format!(
+                "{} minimum of {} exceeds the limit of {}",
+                what, minimum, cap
+            )));
+        }
+        (Some(maximum), Some(cap)) => Some(maximum.min(cap)),
+        (None, Some(cap)) => Some(cap),
+        (maximum, None) => maximum,
+    };
+    write_var_u32(
+        out,
+        flags & !LIMITS_SHARED
+            | if maximum.is_some() {
+                LIMITS_HAS_MAXIMUM
+            } else {
+                0
+            },
+    );
+    write_var_u32(out, minimum);
+    if let Some(maximum) = maximum {
+        write_var_u32(out, maximum);
+    }
+    Ok(())
+}
+
+/// Fails if the minimum of the imported table or memory type at the start
+/// of `limits` exceeds `cap`. Its maximum is kept: it has to match the
+/// provided table or memory, which is not ours to change.
+fn check_minimum(limits: &[u8], cap: Option<u32>, what: &str) -> PyResult<()> {
+    let mut reader = BinaryReader::new(limits);
+    reader.read_var_u32().map_err(reader_error)?;
+    let minimum = reader.read_var_u32().map_err(reader_error)?;
+    match cap {
+        Some(cap) if minimum > cap => Err(ResourceLimitExceeded::py_err(format!(
+            "{} minimum of {} exceeds the limit of {}",
+            what, minimum, cap
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Counts the imported functions, globals and memories.
+fn count_imports(payload: &[u8]) -> Result<(u32, u32, u32), BinaryReaderError> {
+    let mut reader = BinaryReader::new(payload);
+    let mut functions = 0;
+    let mut globals = 0;
+    let mut memories = 0;
+    for _ in 0..reader.read_var_u32()? {
+        reader.read_string()?;
+        reader.read_string()?;
+        match reader.read_u8()? as u8 {
+            EXTERNAL_FUNCTION => {
+                reader.read_var_u32()?;
+                functions += 1;
+            }
+            EXTERNAL_TABLE => {
+                reader.read_u8()?;
+                skip_limits(&mut reader)?;
+            }
+            EXTERNAL_MEMORY => {
+                skip_limits(&mut reader)?;
+                memories += 1;
+            }
+            EXTERNAL_GLOBAL => {
+                reader.read_u8()?;
+                reader.read_u8()?;
+                globals += 1;
+            }
+            _ => {
+                return Err(BinaryReaderError {
+                    message: "invalid external kind",
+                    offset: reader.current_position(),
+                })
+            }
+        }
+    }
+    Ok((functions, globals, memories))
+}
+
+/// Copies an initializer expression including its `end`.
+fn copy_init_expr(
+    reader: &mut BinaryReader,
+    data: &[u8],
+    out: &mut Vec<u8>,
+) -> Result<(), BinaryReaderError> {
+    let start = reader.current_position();
+    loop {
+        if let Operator::End = reader.read_operator()? {
+            break;
+        }
+    }
+    out.extend_from_slice(&data[start..reader.current_position()]);
+    Ok(())
+}
+
+/// Offsets of the instructions of an instrumented module in the original
+/// one, see `Instrumentation::source_offsets`.
+pub struct OffsetMap {
+    /// (instrumented, original) offsets of each instruction and function
+    /// body, in increasing order.
+    entries: Vec<(usize, usize)>,
+}
+
+impl OffsetMap {
+    /// Offset in the original module of the instruction at `offset` in the
+    /// instrumented one; injected code maps to the instruction it was
+    /// injected for, or to the start of the function body.
+    pub fn original(&self, offset: usize) -> Option<usize> {
+        let position = match self.entries.binary_search_by_key(&offset, |e| e.0) {
+            Ok(position) => position,
+            Err(0) => return None,
+            Err(position) => position - 1,
+        };
+        Some(self.entries[position].1)
+    }
+}
+
+impl Instrumentation {
+    /// Returns a copy of the `data` module with the instrumentation applied.
+    pub fn apply(&self, data: &[u8]) -> PyResult<Vec<u8>> {
+        self.rewrite(data, None)
+    }
+
+    /// Maps the code offsets of `data` instrumented to the ones of `data`.
+    pub fn source_offsets(&self, data: &[u8]) -> PyResult<OffsetMap> {
+        let mut entries = Vec::new();
+        self.rewrite(data, Some(&mut entries))?;
+        Ok(OffsetMap { entries })
+    }
+
+    fn rewrite(&self, data: &[u8], offsets: Option<&mut Vec<(usize, usize)>>) -> PyResult<Vec<u8>> {
+        let sections = read_sections(data).map_err(reader_error)?;
+
+        let types = match sections.iter().find(|s| s.id == SECTION_TYPE) {
+            Some(section) => read_count(section.payload).map_err(reader_error)?,
+            None => 0,
+        };
+        let (imported_functions, imported_globals, imported_memories) =
+            match sections.iter().find(|s| s.id == SECTION_IMPORT) {
+                Some(section) => count_imports(section.payload).map_err(reader_error)?,
+                None => (0, 0, 0),
+            };
+        let shared_memory = has_shared_memory(&sections).map_err(reader_error)?;
+        let atomic_ops = match sections.iter().find(|s| s.id == SECTION_CODE) {
+            Some(section) => uses_atomics(section.payload).map_err(reader_error)?,
+            None => false,
+        };
+        // The maximum of an imported memory is the one of the provided
+        // memory, so the page limit is enforced when it grows.
+        let hook_memory_grow = !shared_memory
+            && (self.hook_memory_grow || self.memory_pages.is_some() && imported_memories > 0);
+        let mut added_functions = vec![INTERRUPTED];
+        if hook_memory_grow {
+            added_functions.push(MEMORY_GROW);
+        }
+        if shared_memory {
+            added_functions.push(MEMORY_GROW_SHARED);
+        }
+        if atomic_ops {
+            added_functions.extend_from_slice(&ATOMIC_FUNCTIONS);
+        }
+        let mut added_globals = vec!["interrupt"];
+        if self.handle_sigint {
+            added_globals.push("sigint");
+        }
+        let layout = Layout {
+            types,
+            imported_functions,
+            imported_globals,
+            added_functions,
+            added_globals,
+            shared_memory,
+            hook_memory_grow,
+        };
+
+        // Sections that may be missing in the original module but which
+        // receive new entries.
+        let mut missing = vec![SECTION_IMPORT];
+        if !layout.added_functions.is_empty() {
+            missing.insert(0, SECTION_TYPE);
+        }
+
+        let mut out = data[..8].to_vec();
+        for section in &sections {
+            while !missing.is_empty() && section.id != SECTION_CUSTOM && section.id >= missing[0] {
+                let id = missing.remove(0);
+                if id != section.id {
+                    let payload = self.rewrite_section(id, &[0], &layout)?;
+                    write_section(&mut out, id, &payload);
+                }
+            }
+            if section.id != SECTION_CODE || offsets.is_none() {
+                let payload = self.rewrite_section(section.id, section.payload, &layout)?;
+                write_section(&mut out, section.id, &payload);
+                continue;
+            }
+            let mut entries = Vec::new();
+            let payload = self.rewrite_code(section.payload, &layout, Some(&mut entries))?;
+            write_section(&mut out, section.id, &payload);
+            // Both are relative to the payloads so far.
+            let base = out.len() - payload.len();
+            let original_base = section.payload.as_ptr() as usize - data.as_ptr() as usize;
+            if let Some(ref mut offsets) = offsets {
+                offsets.extend(
+                    entries
+                        .into_iter()
+                        .map(|(at, original)| (base + at, original_base + original)),
+                );
+            }
+        }
+        for id in missing {
+            let payload = self.rewrite_section(id, &[0], &layout)?;
+            write_section(&mut out, id, &payload);
+        }
+        Ok(out)
+    }
+
+    fn rewrite_section(&self, id: u8, payload: &[u8], layout: &Layout) -> PyResult<Vec<u8>> {
+        match id {
+            SECTION_CUSTOM => self.rewrite_custom(payload, layout),
+            SECTION_TYPE => self.rewrite_types(payload, layout),
+            SECTION_IMPORT => self.rewrite_imports(payload, layout),
+            SECTION_TABLE => self.rewrite_tables(payload),
+            SECTION_MEMORY => self.rewrite_memories(payload),
+            SECTION_EXPORT => self.rewrite_exports(payload, layout),
+            SECTION_START => {
+                let index = read_count(payload).map_err(reader_error)?;
+                let mut out = Vec::new();
+                write_var_u32(&mut out, layout.function_index(index));
+                Ok(out)
+            }
+            SECTION_ELEMENT => self.rewrite_elements(payload, layout),
+            SECTION_CODE => self.rewrite_code(payload, layout, None),
+            _ => Ok(payload.to_vec()),
+        }
+    }
+
+    fn rewrite_types(&self, payload: &[u8], layout: &Layout) -> PyResult<Vec<u8>> {
+        let mut reader = BinaryReader::new(payload);
+        let count = reader.read_var_u32().map_err(reader_error)?;
+        let mut out = Vec::new();
+        write_var_u32(&mut out, count + layout.added_functions.len() as u32);
+        out.extend_from_slice(&payload[reader.current_position()..]);
+        for function in &layout.added_functions {
+            out.push(TYPE_FUNC);
+            write_var_u32(&mut out, function.params.len() as u32);
+            out.extend_from_slice(function.params);
+            write_var_u32(&mut out, function.results.len() as u32);
+            out.extend_from_slice(function.results);
+        }
+        Ok(out)
+    }
+
+    fn rewrite_imports(&self, payload: &[u8], layout: &Layout) -> PyResult<Vec<u8>> {
+        let mut reader = BinaryReader::new(payload);
+        let count = reader.read_var_u32().map_err(reader_error)?;
+        let added = layout.added_functions.len() + layout.added_globals.len();
+        let mut out = Vec::new();
+        write_var_u32(&mut out, count + added as u32);
+        for _ in 0..count {
+            let start = reader.current_position();
+            reader.read_string().map_err(reader_error)?;
+            reader.read_string().map_err(reader_error)?;
+            let kind = reader.read_u8().map_err(reader_error)? as u8;
+            if kind == EXTERNAL_MEMORY {
+                out.extend_from_slice(&payload[start..reader.current_position()]);
+                let limits = &payload[reader.current_position()..];
+                check_minimum(limits, self.memory_pages, "memory pages")?;
+                rewrite_limits(&mut reader, &mut out, None, "memory pages")?;
+                continue;
+            }
+            match kind {
+                EXTERNAL_TABLE => {
+                    reader.read_u8().map_err(reader_error)?;
+                    let limits = &payload[reader.current_position()..];
+                    check_minimum(limits, self.table_elements, "table elements")?;
+                    skip_limits(&mut reader).map_err(reader_error)?;
+                }
+                EXTERNAL_GLOBAL => {
+                    reader.read_u8().map_err(reader_error)?;
+                    reader.read_u8().map_err(reader_error)?;
+                }
+                _ => {
+                    reader.read_var_u32().map_err(reader_error)?;
+                }
+            }
+            out.extend_from_slice(&payload[start..reader.current_position()]);
+        }
+        for (i, function) in layout.added_functions.iter().enumerate() {
+            write_name(&mut out, SUPPORT_MODULE);
+            write_name(&mut out, function.name);
+            out.push(EXTERNAL_FUNCTION);
+            write_var_u32(&mut out, layout.types + i as u32);
+        }
+        for name in &layout.added_globals {
+            write_name(&mut out, SUPPORT_MODULE);
+            write_name(&mut out, name);
+            out.extend_from_slice(&[EXTERNAL_GLOBAL, TYPE_I32, 1]);
+        }
+        Ok(out)
+    }
+
+    fn rewrite_tables(&self, payload: &[u8]) -> PyResult<Vec<u8>> {
+        let mut reader = BinaryReader::new(payload);
+        let count = reader.read_var_u32().map_err(reader_error)?;
+        let mut out = Vec::new();
+        write_var_u32(&mut out, count);
+        for _ in 0..count {
+            out.push(reader.read_u8().map_err(reader_error)? as u8);
+            rewrite_limits(&mut reader, &mut out, self.table_elements, "table")?;
+        }
+        Ok(out)
+    }
+
+    fn rewrite_memories(&self, payload: &[u8]) -> PyResult<Vec<u8>> {
+        let mut reader = BinaryReader::new(payload);
+        let count = reader.read_var_u32().map_err(reader_error)?;
+        let mut out = Vec::new();
+        write_var_u32(&mut out, count);
+        for _ in 0..count {
+            rewrite_limits(&mut reader, &mut out, self.memory_pages, "memory pages")?;
+        }
+        Ok(out)
+    }
+
+    fn rewrite_exports(&self, payload: &[u8], layout: &Layout) -> PyResult<Vec<u8>> {
+        let mut reader = BinaryReader::new(payload);
+        let count = reader.read_var_u32().map_err(reader_error)?;
+        let mut out = Vec::new();
+        write_var_u32(&mut out, count);
+        for _ in 0..count {
+            let name = reader.read_string().map_err(reader_error)?;
+            let kind = reader.read_u8().map_err(reader_error)? as u8;
+            let mut index = reader.read_var_u32().map_err(reader_error)?;
+            match kind {
+                EXTERNAL_FUNCTION => index = layout.function_index(index),
+                EXTERNAL_GLOBAL => index = layout.global_index(index),
+                _ => (),
+            }
+            write_name(&mut out, name);
+            out.push(kind);
+            write_var_u32(&mut out, index);
+        }
+        Ok(out)
+    }
+
+    fn rewrite_elements(&self, payload: &[u8], layout: &Layout) -> PyResult<Vec<u8>> {
+        let mut reader = BinaryReader::new(payload);
+        let count = reader.read_var_u32().map_err(reader_error)?;
+        let mut out = Vec::new();
+        write_var_u32(&mut out, count);
+        for _ in 0..count {
+            let table_index = reader.read_var_u32().map_err(reader_error)?;
+            if table_index != 0 {
+                return Err(PyErr::new::<Exception, _>("unsupported element segment"));
+            }
+            write_var_u32(&mut out, table_index);
+            copy_init_expr(&mut reader, payload, &mut out).map_err(reader_error)?;
+            let items = reader.read_var_u32().map_err(reader_error)?;
+            write_var_u32(&mut out, items);
+            for _ in 0..items {
+                let index = reader.read_var_u32().map_err(reader_error)?;
+                write_var_u32(&mut out, layout.function_index(index));
+            }
+        }
+        Ok(out)
+    }
+
+    /// Renumbers the functions in the "name" section.
+    fn rewrite_custom(&self, payload: &[u8], layout: &Layout) -> PyResult<Vec<u8>> {
+        let mut reader = BinaryReader::new(payload);
+        if reader.read_string().map_err(reader_error)? != "name" {
+            return Ok(payload.to_vec());
+        }
+        let mut out = payload[..reader.current_position()].to_vec();
+        while !reader.eof() {
+            let id = reader.read_u8().map_err(reader_error)? as u8;
+            let size = reader.read_var_u32().map_err(reader_error)? as usize;
+            let data = reader.read_bytes(size).map_err(reader_error)?;
+            let data = match id {
+                NAME_FUNCTIONS | NAME_LOCALS => {
+                    rewrite_name_map(data, layout, id == NAME_LOCALS).map_err(reader_error)?
+                }
+                _ => data.to_vec(),
+            };
+            out.push(id);
+            write_var_u32(&mut out, data.len() as u32);
+            out.extend_from_slice(&data);
+        }
+        Ok(out)
+    }
+
+    /// Rewrites the code section; with `offsets`, records the offsets of
+    /// the instructions in the rewritten payload and in `payload`.
+    fn rewrite_code(
+        &self,
+        payload: &[u8],
+        layout: &Layout,
+        mut offsets: Option<&mut Vec<(usize, usize)>>,
+    ) -> PyResult<Vec<u8>> {
+        let mut reader = BinaryReader::new(payload);
+        let count = reader.read_var_u32().map_err(reader_error)?;
+        let mut out = Vec::new();
+        write_var_u32(&mut out, count);
+        for _ in 0..count {
+            let size = reader.read_var_u32().map_err(reader_error)? as usize;
+            let original = reader.current_position();
+            let body = reader.read_bytes(size).map_err(reader_error)?;
+            let mut entries = Vec::new();
+            let body = self
+                .rewrite_body(body, layout, &mut entries)
+                .map_err(reader_error)?;
+            write_var_u32(&mut out, body.len() as u32);
+            if let Some(ref mut offsets) = offsets {
+                let at = out.len();
+                offsets.push((at, original));
+                offsets.extend(entries.into_iter().map(|(a, o)| (at + a, original + o)));
+            }
+            out.extend_from_slice(&body);
+        }
+        Ok(out)
+    }
+
+    /// Rewrites a function body, recording the offsets of its instructions
+    /// in the rewritten body and in `body` into `offsets`.
+    fn rewrite_body(
+        &self,
+        body: &[u8],
+        layout: &Layout,
+        offsets: &mut Vec<(usize, usize)>,
+    ) -> Result<Vec<u8>, BinaryReaderError> {
+        let mut reader = BinaryReader::new(body);
+        skip_locals(&mut reader)?;
+        let mut out = body[..reader.current_position()].to_vec();
+        layout.emit_interrupt_check(&mut out);
+        while !reader.eof() {
+            let start = reader.current_position();
+            offsets.push((out.len(), start));
+            if let Some(op) = read_atomic_op(&mut reader, body)? {
+                layout.emit_atomic(&mut out, &op, start)?;
+                continue;
+            }
+            let op = reader.read_operator()?;
+            let end = reader.current_position();
+            match op {
+                Operator::Call { function_index } => {
+                    out.push(OP_CALL);
+                    write_var_u32(&mut out, layout.function_index(function_index));
+                }
+                Operator::GetGlobal { global_index } => {
+                    out.push(OP_GET_GLOBAL);
+                    write_var_u32(&mut out, layout.global_index(global_index));
+                }
+                Operator::SetGlobal { global_index } => {
+                    out.push(OP_SET_GLOBAL);
+                    write_var_u32(&mut out, layout.global_index(global_index));
+                }
+                Operator::Loop { .. } => {
+                    out.extend_from_slice(&body[start..end]);
+                    layout.emit_interrupt_check(&mut out);
+                }
+                Operator::MemoryGrow { .. } if layout.shared_memory => {
+                    layout.emit_call(&mut out, MEMORY_GROW_SHARED.name);
+                }
+                Operator::MemoryGrow { .. } if layout.hook_memory_grow => {
+                    // delta -> delta, current -> delta' -> result
+                    out.extend_from_slice(&[OP_MEMORY_SIZE, 0]);
+                    layout.emit_call(&mut out, MEMORY_GROW.name);
+                    out.extend_from_slice(&body[start..end]);
+                }
+                _ => out.extend_from_slice(&body[start..end]),
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Renumbers a function name map, or an indirect (function locals) one.
+fn rewrite_name_map(
+    data: &[u8],
+    layout: &Layout,
+    indirect: bool,
+) -> Result<Vec<u8>, BinaryReaderError> {
+    let mut reader = BinaryReader::new(data);
+    let count = reader.read_var_u32()?;
+    let mut out = Vec::new();
+    write_var_u32(&mut out, count);
+    for _ in 0..count {
+        let index = reader.read_var_u32()?;
+        write_var_u32(&mut out, layout.function_index(index));
+        let start = reader.current_position();
+        if indirect {
+            for _ in 0..reader.read_var_u32()? {
+                reader.read_var_u32()?;
+                reader.read_string()?;
+            }
+        } else {
+            reader.read_string()?;
+        }
+        out.extend_from_slice(&data[start..reader.current_position()]);
+    }
+    Ok(out)
+}
@@ -0,0 +1,285 @@
+//! Interruption of running wasm code.
+//!
+//! Instrumented modules (see `instrument.rs`) poll an imported `i32` global
+//! at every function entry and loop header and, when it is set, call
+//! `support::interrupted` and execute `unreachable`. The storage behind that
+//! global lives here, so it can be flipped from another thread, a signal
+//! handler or a timeout watchdog while the guest is running.
+
+use pyo3::exceptions::{KeyboardInterrupt, ValueError};
+use pyo3::ffi;
+use pyo3::prelude::*;
+
+use crate::trap::{Interrupted, Timeout};
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicI32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use wasmtime_runtime::VMGlobalDefinition;
+
+/// Bit of an `InterruptFlag` set by `InterruptHandle.interrupt` and Ctrl-C.
+const REQUESTED: i32 = 1;
+/// Bit of an `InterruptFlag` set when the deadline of a call passed.
+const TIMED_OUT: i32 = 2;
+/// Bit of `InterruptState::stopped` for the process-wide `sigint` flag.
+const SIGINT: i32 = 4;
+
+/// Storage of an `i32` wasm global, laid out like `VMGlobalDefinition`.
+/// The guest stops when any bit is set; the bits record who asked.
+#[repr(C, align(16))]
+pub struct InterruptFlag {
+    value: AtomicI32,
+}
+
+impl InterruptFlag {
+    const fn new() -> Self {
+        Self {
+            value: AtomicI32::new(0),
+        }
+    }
+
+    fn set(&self, bit: i32) {
+        self.value.fetch_or(bit, Ordering::SeqCst);
+    }
+
+    fn is_set(&self) -> bool {
+        self.value.load(Ordering::SeqCst) != 0
+    }
+
+    /// Clears `bit` and returns whether it was set.
+    fn take(&self, bit: i32) -> bool {
+        self.value.fetch_and(!bit, Ordering::SeqCst) & bit != 0
+    }
+
+    /// Pointer suitable for a `VMGlobalImport`.
+    pub fn as_global(&self) -> *mut VMGlobalDefinition {
+        self as *const Self as *mut VMGlobalDefinition
+    }
+}
+
+static SIGINT_FLAG: InterruptFlag = InterruptFlag::new();
+static PREVIOUS_SIGINT_HANDLER: AtomicUsize = AtomicUsize::new(0);
+static HOOK_SIGINT: Once = Once::new();
+
+pub fn sigint_flag() -> &'static InterruptFlag {
+    &SIGINT_FLAG
+}
+
+extern "C" fn on_sigint(signum: c_int) {
+    SIGINT_FLAG.set(REQUESTED);
+    // Chain to the handler that was installed before us (normally the
+    // Python one), so `KeyboardInterrupt` is still raised.
+    let previous = PREVIOUS_SIGINT_HANDLER.load(Ordering::SeqCst);
+    if previous != libc::SIG_DFL && previous != libc::SIG_IGN && previous != libc::SIG_ERR {
+        let previous: extern "C" fn(c_int) = unsafe { std::mem::transmute(previous) };
+        previous(signum);
+    }
+}
+
+/// Installs a SIGINT handler that sets the `sigint` flag polled by modules
+/// instantiated with `handle_sigint=True`.
+pub fn hook_sigint() {
+    HOOK_SIGINT.call_once(|| {
+        let previous = unsafe { libc::signal(libc::SIGINT, on_sigint as libc::sighandler_t) };
+        PREVIOUS_SIGINT_HANDLER.store(previous, Ordering::SeqCst);
+    });
+}
+
+/// Interruption state shared by an instance and its handles.
+pub struct InterruptState {
+    flag: InterruptFlag,
+    handle_sigint: bool,
+    /// The requests seen by the interrupt check that stopped the guest, so
+    /// that other traps are not blamed on them.
+    stopped: AtomicI32,
+}
+
+impl InterruptState {
+    pub fn new(handle_sigint: bool) -> Self {
+        if handle_sigint {
+            hook_sigint();
+        }
+        Self {
+            flag: InterruptFlag::new(),
+            handle_sigint,
+            stopped: AtomicI32::new(0),
+        }
+    }
+
+    pub fn flag(&self) -> &InterruptFlag {
+        &self.flag
+    }
+
+    pub fn handle_sigint(&self) -> bool {
+        self.handle_sigint
+    }
+
+    pub fn interrupt(&self) {
+        self.flag.set(REQUESTED);
+    }
+
+    fn time_out(&self) {
+        self.flag.set(TIMED_OUT);
+    }
+
+    /// Called by the interrupt check about to stop the guest.
+    pub fn stop(&self) {
+        let mut stopped = self.flag.value.load(Ordering::SeqCst);
+        if self.handle_sigint && SIGINT_FLAG.is_set() {
+            stopped |= SIGINT;
+        }
+        self.stopped.store(stopped, Ordering::SeqCst);
+    }
+
+    /// Whether the running guest was asked to stop, without clearing the
+    /// request.
+    pub fn is_requested(&self) -> bool {
+        self.flag.is_set() || (self.handle_sigint && SIGINT_FLAG.is_set())
+    }
+
+    /// Called before entering the guest: a Ctrl-C that arrived while no
+    /// guest was running was already delivered to Python.
+    pub fn reset_sigint(&self) {
+        if self.handle_sigint {
+            SIGINT_FLAG.take(REQUESTED);
+        }
+    }
+
+    /// Converts a trap into the matching Python exception if it was caused
+    /// by an interruption request, and clears the request. The deadline of
+    /// a call with `timeout` gives a `Timeout`, unless an explicit request
+    /// stopped the guest too.
+    pub fn take_error(&self, py: Python, timeout: Option<Duration>) -> Option<PyErr> {
+        let stopped = self.stopped.swap(0, Ordering::SeqCst);
+        if stopped & SIGINT != 0 {
+            SIGINT_FLAG.take(REQUESTED);
+            self.flag.take(REQUESTED);
+            if unsafe { ffi::PyErr_CheckSignals() } != 0 {
+                return Some(PyErr::fetch(py));
+            }
+            return Some(PyErr::new::<KeyboardInterrupt, _>(()));
+        }
+        if stopped & REQUESTED != 0 {
+            self.flag.take(REQUESTED);
+            return Some(Interrupted::py_err("wasm execution interrupted"));
+        }
+        match timeout {
+            Some(timeout) if stopped & TIMED_OUT != 0 => Some(Timeout::py_err(format!(
+                "wasm execution exceeded the timeout of {:?}",
+                timeout
+            ))),
+            _ => None,
+        }
+    }
+}
+
+/// Converts a timeout given in seconds from Python.
+pub fn duration_from_secs(secs: f64) -> PyResult<Duration> {
+    if !secs.is_finite() || secs <= 0.0 {
+        return Err(ValueError::py_err("timeout must be a positive number"));
+    }
+    Ok(Duration::new(
+        secs.trunc() as u64,
+        (secs.fract() * 1e9) as u32,
+    ))
+}
+
+/// Deadlines of the timed calls in progress, by expiry, watched by a single
+/// thread for all stores.
+struct Timer {
+    deadlines: Mutex<BTreeMap<(Instant, u64), Arc<InterruptState>>>,
+    changed: Condvar,
+}
+
+lazy_static! {
+    static ref TIMER: Timer = Timer {
+        deadlines: Mutex::new(BTreeMap::new()),
+        changed: Condvar::new(),
+    };
+}
+
+static START_TIMER: Once = Once::new();
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+impl Timer {
+    /// Interrupts the calls whose deadline passed, which are removed, and
+    /// waits for the next one.
+    fn run(&self) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            while let Some(key) = deadlines.keys().next().cloned() {
+                if key.0 > now {
+                    break;
+                }
+                deadlines.remove(&key).unwrap().time_out();
+            }
+            deadlines = match deadlines.keys().next() {
+                Some(&(expiry, _)) => {
+                    self.changed
+                        .wait_timeout(deadlines, expiry - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.changed.wait(deadlines).unwrap(),
+            };
+        }
+    }
+}
+
+/// Watchdog interrupting the guest unless it is finished before the timeout.
+pub struct Deadline {
+    state: Arc<InterruptState>,
+    key: (Instant, u64),
+}
+
+impl Deadline {
+    pub fn start(state: Arc<InterruptState>, timeout: Duration) -> Self {
+        START_TIMER.call_once(|| {
+            thread::Builder::new()
+                .name("wasmtime-timeout".to_string())
+                .spawn(|| TIMER.run())
+                .expect("timeout thread");
+        });
+        let key = (
+            Instant::now() + timeout,
+            NEXT_DEADLINE.fetch_add(1, Ordering::SeqCst),
+        );
+        let mut deadlines = TIMER.deadlines.lock().unwrap();
+        deadlines.insert(key, state.clone());
+        // The timer may be waiting for a later deadline.
+        if deadlines.keys().next() == Some(&key) {
+            TIMER.changed.notify_one();
+        }
+        Self { state, key }
+    }
+
+    /// Stops the watchdog. If it fired, its request is withdrawn, so it
+    /// cannot leak into the next call when the guest returned before
+    /// reaching a check; an `interrupt()` made in the meantime stays
+    /// pending.
+    pub fn finish(self) {
+        if TIMER.deadlines.lock().unwrap().remove(&self.key).is_none() {
+            self.state.flag.take(TIMED_OUT);
+        }
+    }
+}
+
+/// Handle that allows to stop wasm code running in an instance.
+#[pyclass]
+pub struct InterruptHandle {
+    pub state: Arc<InterruptState>,
+}
+
+#[pymethods]
+impl InterruptHandle {
+    /// Requests the running (or the next) export call to trap with
+    /// `Interrupted` at the next function entry or loop header.
+    fn interrupt(&self) {
+        self.state.interrupt();
+    }
+}
@@ -0,0 +1,254 @@
+//! Lazy compilation of function bodies.
+//!
+//! A module instantiated lazily starts without compiled code: each defined
+//! function is a stub jumping through a slot, which first points to an
+//! entry compiling the function and patching the slot with the compiled
+//! code. Calls between functions go through the stubs as well. Only x86-64
+//! is supported.
+
+use pyo3::prelude::*;
+
+use crate::backtrace::{ModuleInfo, Registration};
+use crate::code_memory::CodeMemory;
+use crate::compiler::compile_function;
+use crate::link::relocate_function;
+use crate::perf;
+use crate::store::native_isa;
+use crate::trap::{raise, Trap};
+use cranelift_codegen::isa::TargetIsa;
+use cranelift_entity::{EntityRef, PrimaryMap};
+use cranelift_wasm::DefinedFuncIndex;
+use wasmtime_environ::Module;
+use wasmtime_runtime::VMFunctionBody;
+
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Whether lazy compilation is supported on the host.
+pub fn supported() -> bool {
+    cfg!(target_arch = "x86_64")
+}
+
+/// `movabs r11, slot; jmp [r11]`
+const STUB_SIZE: usize = 13;
+/// `mov r11d, index; jmp common`
+const ENTRY_SIZE: usize = 11;
+
+/// Code of the functions of a lazily compiled instance.
+pub struct LazyCode {
+    isa: Box<dyn TargetIsa>,
+    /// The (instrumented) wasm module and the ranges of its function bodies.
+    wasm: Vec<u8>,
+    bodies: Vec<Range<usize>>,
+    module: Rc<Module>,
+    /// Address the stub of each function jumps to.
+    slots: Box<[AtomicUsize]>,
+    stubs: Vec<usize>,
+    entries: usize,
+    /// `ud2`, jumped to instead of the code of a function which failed to
+    /// compile.
+    trap: usize,
+    trampolines: CodeMemory,
+    /// Code of the functions compiled so far; held while compiling.
+    compiled: Mutex<CodeMemory>,
+    registration: Mutex<Registration>,
+}
+
+// `module` is only cloned or dropped with the GIL held, by the instance;
+// the wasm code calling `lazy_compile` only reads it.
+unsafe impl Send for LazyCode {}
+unsafe impl Sync for LazyCode {}
+
+impl LazyCode {
+    /// Prepares the stubs of the functions of `module`, whose bodies are the
+    /// `bodies` ranges of `wasm`, instrumented from `info`.
+    pub fn new(
+        wasm: Vec<u8>,
+        bodies: Vec<Range<usize>>,
+        module: Rc<Module>,
+        info: Arc<ModuleInfo>,
+    ) -> Result<Box<Self>, String> {
+        let isa = native_isa();
+        if !supported() {
+            return Err(format!(
+                "lazy compilation is not supported on {}",
+                isa.triple()
+            ));
+        }
+        let slots = (0..bodies.len()).map(|_| AtomicUsize::new(0)).collect();
+        let mut code = Box::new(Self {
+            isa,
+            wasm,
+            bodies,
+            module,
+            slots,
+            stubs: Vec::new(),
+            entries: 0,
+            trap: 0,
+            trampolines: CodeMemory::new(),
+            compiled: Mutex::new(CodeMemory::new()),
+            registration: Mutex::new(Registration::new(&info)),
+        });
+        code.emit_trampolines()?;
+        Ok(code)
+    }
+
+    /// The module the instance was created from.
+    pub fn module_info(&self) -> Arc<ModuleInfo> {
+        self.registration.lock().unwrap().module().clone()
+    }
+
+    /// See `Registration::owner`.
+    pub fn owner(&self) -> usize {
+        self.registration.lock().unwrap().owner()
+    }
+
+    /// Addresses of the functions, to be used as the finished functions of
+    /// the instance.
+    pub fn functions(&self) -> PrimaryMap<DefinedFuncIndex, *const VMFunctionBody> {
+        self.stubs
+            .iter()
+            .map(|stub| *stub as *const VMFunctionBody)
+            .collect()
+    }
+
+    fn entry(&self, index: usize) -> usize {
+        self.entries + index * ENTRY_SIZE
+    }
+
+    fn emit_trampolines(&mut self) -> Result<(), String> {
+        let count = self.bodies.len();
+        let entries = count * STUB_SIZE;
+        let common = entries + count * ENTRY_SIZE;
+        let mut code = Vec::with_capacity(common + 128);
+        for slot in self.slots.iter() {
+            code.extend_from_slice(&[0x49, 0xbb]);
+            code.extend_from_slice(&(slot as *const AtomicUsize as u64).to_le_bytes());
+            code.extend_from_slice(&[0x41, 0xff, 0x23]);
+        }
+        for index in 0..count {
+            code.extend_from_slice(&[0x41, 0xbb]);
+            code.extend_from_slice(&(index as u32).to_le_bytes());
+            code.push(0xe9);
+            let next = code.len() + 4;
+            code.extend_from_slice(&((common - next) as i32).to_le_bytes());
+        }
+        // Saves the argument registers, calls `lazy_compile(self, r11d)` and
+        // jumps to the compiled code with the arguments restored. The stack
+        // is 16-byte aligned at the call.
+        code.extend_from_slice(&[
+            0x55, // push rbp
+            0x48, 0x89, 0xe5, // mov rbp, rsp
+            0x57, 0x56, 0x52, 0x51, // push rdi, rsi, rdx, rcx
+            0x41, 0x50, 0x41, 0x51, // push r8, r9
+            0x48, 0x81, 0xec, 0x80, 0x00, 0x00, 0x00, // sub rsp, 128
+        ]);
+        for xmm in 0..8u8 {
+            // movdqu [rsp + 16 * xmm], xmm
+            code.extend_from_slice(&[0xf3, 0x0f, 0x7f, 0x44 | xmm << 3, 0x24, xmm * 16]);
+        }
+        code.extend_from_slice(&[0x48, 0xbf]); // movabs rdi, self
+        code.extend_from_slice(&(self as *const Self as u64).to_le_bytes());
+        code.extend_from_slice(&[0x44, 0x89, 0xde]); // mov esi, r11d
+        code.extend_from_slice(&[0x48, 0xb8]); // movabs rax, lazy_compile
+        code.extend_from_slice(&(lazy_compile as usize as u64).to_le_bytes());
+        code.extend_from_slice(&[
+            0xff, 0xd0, // call rax
+            0x49, 0x89, 0xc3, // mov r11, rax
+        ]);
+        for xmm in 0..8u8 {
+            // movdqu xmm, [rsp + 16 * xmm]
+            code.extend_from_slice(&[0xf3, 0x0f, 0x6f, 0x44 | xmm << 3, 0x24, xmm * 16]);
+        }
+        code.extend_from_slice(&[
+            0x48, 0x81, 0xc4, 0x80, 0x00, 0x00, 0x00, // add rsp, 128
+            0x41, 0x59, 0x41, 0x58, // pop r9, r8
+            0x59, 0x5a, 0x5e, 0x5f, // pop rcx, rdx, rsi, rdi
+            0x5d, // pop rbp
+            0x41, 0xff, 0xe3, // jmp r11
+        ]);
+        let trap = code.len();
+        code.extend_from_slice(&[0x0f, 0x0b]); // ud2
+
+        let base = self
+            .trampolines
+            .allocate_copy_of_byte_slice(&code)?
+            .as_ptr() as usize;
+        self.trampolines.publish();
+        self.stubs = (0..count).map(|index| base + index * STUB_SIZE).collect();
+        self.entries = base + entries;
+        self.trap = base + trap;
+        let info = self.module_info();
+        for index in 0..count {
+            let label = || info.function_label(DefinedFuncIndex::new(index));
+            perf::record(self.stubs[index], STUB_SIZE, || {
+                format!("{} (lazy stub)", label())
+            });
+            perf::record(self.entry(index), ENTRY_SIZE, || {
+                format!("{} (lazy entry)", label())
+            });
+        }
+        perf::record(base + common, code.len() - common, || {
+            "lazy compilation trampoline".to_string()
+        });
+        for (index, slot) in self.slots.iter().enumerate() {
+            slot.store(self.entries + index * ENTRY_SIZE, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Compiles the function `index`, unless another thread did meanwhile,
+    /// and returns the address of its code.
+    fn compile(&self, index: usize) -> Result<usize, String> {
+        let mut compiled = self.compiled.lock().unwrap();
+        let current = self.slots[index].load(Ordering::SeqCst);
+        if current != self.entry(index) {
+            return Ok(current);
+        }
+        let function = compile_function(
+            self.isa.as_ref(),
+            &self.module,
+            &self.wasm,
+            &self.bodies,
+            DefinedFuncIndex::new(index),
+        )?;
+        let body: *mut [VMFunctionBody] = compiled.allocate_copy_of_byte_slice(&function.body)?;
+        let body = body as *const VMFunctionBody;
+        relocate_function(
+            body,
+            &function.relocations,
+            &function.jt_offsets,
+            &self.module,
+            &|f| self.stubs[f.index()],
+        );
+        compiled.publish();
+        self.registration.lock().unwrap().register(
+            DefinedFuncIndex::new(index),
+            body as usize,
+            function.body.len(),
+            function.address_map,
+            function.local_slots,
+        );
+        self.slots[index].store(body as usize, Ordering::SeqCst);
+        Ok(body as usize)
+    }
+}
+
+/// Called by the entry of a function on its first call; compile errors
+/// make the call trap, with the error raised by the export call.
+unsafe extern "C" fn lazy_compile(code: *const LazyCode, index: u32) -> usize {
+    let result = (*code).compile(index as usize);
+    match result {
+        Ok(address) => address,
+        Err(message) => {
+            let err = {
+                let _gil = Python::acquire_gil();
+                Trap::py_err(format!("compiling function {}: {}", index, message))
+            };
+            raise(err);
+            (*code).trap
+        }
+    }
+}
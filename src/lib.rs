@@ -1,26 +1,71 @@
+use pyo3::exceptions::ValueError;
 use pyo3::prelude::*;
-use pyo3::types::{PyBytes, PyDict, PySet};
+use pyo3::types::{PyAny, PyBytes, PyDict, PySet, PyTuple};
 use pyo3::wrap_pyfunction;
 
+use crate::backtrace::{Frame, ModuleInfo};
+use crate::cache::{clear_cache, disable_cache, enable_cache};
+use crate::compiler::{compiler_threads, configure_compiler};
 use crate::import::into_instance_from_obj;
 use crate::instance::Instance;
+use crate::instrument::{Instrumentation, SUPPORT_MODULE};
+use crate::interrupt::{InterruptHandle, InterruptState};
+use crate::limits::{Limits, ResourceLimitExceeded, ResourceLimiter};
 use crate::memory::Memory;
-use crate::module::Module;
+use crate::module::{compiled_module, CompiledModule, Module};
+use crate::object::compile_to_object;
+use crate::perf::{disable_perf_map, enable_perf_map};
+use crate::profile::Profile;
+use crate::store::{native_isa, Store, StoreState};
+use crate::support::{attach_memory, instantiate_support};
+use crate::trace::{Tracer, Tracers};
+use crate::trap::{Interrupted, Timeout, Trap};
+use crate::vfs::VirtualDir;
+use crate::wasi::{WasiConfig, WasiExit, WasiInstance, WASI_MODULES};
 use std::cell::RefCell;
-use std::rc::Rc;
+use std::collections::HashMap;
+use std::sync::Arc;
 
+mod atomics;
+mod backtrace;
+mod cache;
 mod code_memory;
+mod compiler;
+mod coredump;
+mod dwarf;
+mod func_environ;
 mod function;
 mod import;
+mod inspect;
 mod instance;
+mod instrument;
+mod interrupt;
+mod lazy;
+mod limits;
+mod link;
 mod memory;
 mod module;
+mod object;
+mod perf;
+mod profile;
+mod store;
+mod support;
+mod trace;
+mod trampoline;
+mod trap;
 mod value;
+mod vfs;
+mod wasi;
+mod wasi_fs;
+mod wasi_stdio;
 
 #[pyclass]
 pub struct InstantiateResultObject {
     instance: Py<Instance>,
-    module: Py<Module>,
+    module: RefCell<Option<Py<Module>>>,
+    /// Source and instrumentation of a lazily compiled module, compiled
+    /// when `module` is first requested.
+    lazy_source: Option<(Vec<u8>, Instrumentation)>,
 }
 
 #[pymethods]
@@ -36,78 +81,214 @@ impl InstantiateResultObject {
     fn get_module(&self) -> PyResult<Py<Module>> {
         let gil = Python::acquire_gil();
         let py = gil.python();
-        Ok(self.module.clone_ref(py))
+        let mut module = self.module.borrow_mut();
+        if module.is_none() {
+            let (source, instrumentation) = self.lazy_source.as_ref().expect("lazy source");
+            let compiled =
+                CompiledModule::new(py, source, instrumentation.clone(), native_isa().as_ref())?;
+            *module = Some(Py::new(
+                py,
+                Module {
+                    compiled: Arc::new(compiled),
+                },
+            )?);
+        }
+        Ok(module.as_ref().unwrap().clone_ref(py))
     }
 }
 
-/// WebAssembly instantiate API method.
-#[pyfunction]
+/// WebAssembly instantiate API method, `buffer_source` being the bytes of
+/// a module or a `Module`.
+///
+/// With `handle_sigint` set, Ctrl-C raises `KeyboardInterrupt` out of
+/// running wasm code of the instance. Without a `store`, the instance gets
+/// a new one.
+///
+/// With `lazy` set, the functions of a module given as bytes are only
+/// compiled on their first call (on x86-64; elsewhere the flag is
+/// ignored). Errors in a function body are then reported by its first
+/// call, as a `Trap`.
+#[pyfunction(handle_sigint = "false", store = "None", lazy = "false")]
 pub fn instantiate(
     py: Python,
-    buffer_source: &PyBytes,
+    buffer_source: &PyAny,
     import_obj: &PyDict,
+    handle_sigint: bool,
+    store: Option<&Store>,
+    lazy: bool,
 ) -> PyResult<Py<InstantiateResultObject>> {
-    let wasm_data = buffer_source.as_bytes();
-
-    let generate_debug_info = false;
+    let store = match store {
+        Some(store) => store.state.clone(),
+        None => Arc::new(StoreState::new(Limits::default())),
+    };
+    let slot = store.reserve_instance()?;
 
-    let isa = {
-        let isa_builder =
-            cranelift_native::builder().expect("host machine is not a supported target");
-        let flag_builder = cranelift_codegen::settings::builder();
-        isa_builder.finish(cranelift_codegen::settings::Flags::new(flag_builder))
+    let instrumentation = store.limits.instrumentation(handle_sigint);
+    // A `Module` is compiled already.
+    let (compiled, lazy_source) =
+        if lazy && lazy::supported() && !buffer_source.get_type().is_subclass::<Module>()? {
+            let source = buffer_source.downcast_ref::<PyBytes>()?.as_bytes();
+            (None, Some((source.to_vec(), instrumentation)))
+        } else {
+            (Some(compiled_module(buffer_source, instrumentation)?), None)
+        };
+    let (lazy_wasm, info) = match (&compiled, &lazy_source) {
+        (Some(compiled), _) => (
+            None,
+            ModuleInfo::new(&compiled.source, &compiled.instrumentation),
+        ),
+        (None, Some((source, instrumentation))) => (
+            Some(instrumentation.apply(source)?),
+            ModuleInfo::new(source, instrumentation),
+        ),
+        (None, None) => unreachable!(),
     };
 
-    let mut context = wasmtime_jit::Context::with_isa(isa);
-    context.set_debug_info(generate_debug_info);
-    let global_exports = context.get_global_exports();
+    // The start function runs during the instantiation.
+    let execution = store.execution.lock(py);
+    let mut linker = store.linker.lock().unwrap();
+    let global_exports = linker.get_global_exports();
 
+    let tracers = Tracers {
+        store: store.tracer.clone(),
+        instance: Arc::new(Tracer::default()),
+    };
+    let mut namespace = HashMap::new();
+    let mut wasi_instances = Vec::new();
     for (name, obj) in import_obj.iter() {
-        context.name_instance(
-            name.to_string(),
-            into_instance_from_obj(py, global_exports.clone(), obj).expect("obj instance"),
-        )
+        let name = name.to_string();
+        let handle = if WASI_MODULES.contains(&name.as_str())
+            && obj.get_type().is_subclass::<WasiInstance>()?
+        {
+            let wasi = obj.cast_as::<WasiInstance>()?;
+            let handle = wasi.instantiate(global_exports.clone(), &name);
+            wasi_instances.push(handle.clone());
+            handle
+        } else {
+            into_instance_from_obj(py, global_exports.clone(), &name, obj, tracers.clone())?
+        };
+        namespace.insert(name, handle);
     }
 
-    let instance = context
-        .instantiate_module(None, wasm_data)
-        .expect("instance");
+    let interrupt = Arc::new(InterruptState::new(handle_sigint));
+    let mut support = instantiate_support(
+        global_exports.clone(),
+        interrupt.clone(),
+        store.limits.clone(),
+    );
+    namespace.insert(SUPPORT_MODULE.to_string(), support.clone());
 
-    let module = Py::new(
-        py,
-        Module {
-            module: instance.module(),
-        },
-    )?;
+    let mut instance = match (&compiled, &lazy_wasm) {
+        (Some(compiled), _) => linker.instantiate(&compiled.artifact, &info, &namespace, slot),
+        (None, Some(wasm)) => linker.instantiate_lazy(wasm, &info, &namespace, slot),
+        (None, None) => unreachable!(),
+    }
+    .map_err(ValueError::py_err)?;
+    attach_memory(&mut support, &mut instance);
+    for mut wasi in wasi_instances {
+        wasi::attach_memory(&mut wasi, &mut instance);
+    }
+    drop(linker);
+    drop(execution);
+
+    let module = match compiled {
+        Some(compiled) => Some(Py::new(py, Module { compiled })?),
+        None => None,
+    };
 
     let instance = Py::new(
         py,
         Instance {
-            context: Rc::new(RefCell::new(context)),
+            store,
             instance,
+            interrupt,
+            tracer: tracers.instance,
         },
     )?;
 
-    Py::new(py, InstantiateResultObject { instance, module })
+    Py::new(
+        py,
+        InstantiateResultObject {
+            instance,
+            module: RefCell::new(module),
+            lazy_source,
+        },
+    )
+}
+
+/// Runs the WASI command `buffer_source`: instantiates it with a
+/// `WasiInstance` of `config` and calls its `_start` function, or the
+/// export `invoke` with `args`, returning its result.
+///
+/// A `proc_exit` of the guest raises `WasiExit` with the exit code, which
+/// is not a `Trap`.
+#[pyfunction(
+    invoke = "None",
+    args = "None",
+    handle_sigint = "false",
+    store = "None"
+)]
+pub fn run_command(
+    py: Python,
+    buffer_source: &PyAny,
+    config: &WasiConfig,
+    invoke: Option<&str>,
+    args: Option<Vec<PyObject>>,
+    handle_sigint: bool,
+    store: Option<&Store>,
+) -> PyResult<PyObject> {
+    let wasi = Py::new(py, WasiInstance::from_config(py, config)?)?;
+    let import_obj = PyDict::new(py);
+    let wasm_data = match buffer_source.cast_as::<Module>() {
+        Ok(module) => PyBytes::new(py, &module.compiled.source),
+        Err(_) => buffer_source.downcast_ref::<PyBytes>()?,
+    };
+    for module_name in imported_modules(py, wasm_data)?.keys() {
+        let module_name = module_name.to_string();
+        if !WASI_MODULES.contains(&module_name.as_str()) {
+            return Err(ValueError::py_err(format!(
+                "WASI commands cannot import from {}",
+                module_name
+            )));
+        }
+        import_obj.set_item(module_name, wasi.clone_ref(py))?;
+    }
+
+    let res =
+        instantiate(py, buffer_source, import_obj, handle_sigint, store, false)?.to_object(py);
+    let exports = res.getattr(py, "instance")?.getattr(py, "exports")?;
+    let name = invoke.unwrap_or("_start");
+    let function = match exports.cast_as::<PyDict>(py)?.get_item(name) {
+        Some(function) => function,
+        None => {
+            return Err(ValueError::py_err(format!(
+                "the module has no {} export",
+                name
+            )))
+        }
+    };
+    function
+        .call1(PyTuple::new(py, args.unwrap_or_default()))
+        .map(|result| result.to_object(py))
 }
 
 #[pyfunction]
 pub fn imported_modules<'p>(py: Python<'p>, buffer_source: &PyBytes) -> PyResult<&'p PyDict> {
     let wasm_data = buffer_source.as_bytes();
     let dict = PyDict::new(py);
-    // TODO: error handling
-    let mut parser = wasmparser::ModuleReader::new(wasm_data).unwrap();
+    let invalid = |e: wasmparser::BinaryReaderError| ValueError::py_err(e.message.to_string());
+    let mut parser = wasmparser::ModuleReader::new(wasm_data).map_err(invalid)?;
     while !parser.eof() {
-        let section = parser.read().unwrap();
+        let section = parser.read().map_err(invalid)?;
         match section.code {
             wasmparser::SectionCode::Import => {}
             _ => continue,
         };
-        let reader = section.get_import_section_reader().unwrap();
+        let reader = section.get_import_section_reader().map_err(invalid)?;
         for import in reader {
-            let import = import.unwrap();
+            let import = import.map_err(invalid)?;
             let set = match dict.get_item(import.module) {
-                Some(set) => set.downcast_ref::<PySet>().unwrap(),
+                Some(set) => set.downcast_ref::<PySet>()?,
                 None => {
                     let set = PySet::new::<PyObject>(py, &[])?;
                     dict.set_item(import.module, set)?;
@@ -121,13 +302,37 @@ pub fn imported_modules<'p>(py: Python<'p>, buffer_source: &PyBytes) -> PyResult
 }
 
 #[pymodule]
-fn lib_wasmtime(_: Python, m: &PyModule) -> PyResult<()> {
+fn lib_wasmtime(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Frame>()?;
     m.add_class::<Instance>()?;
+    m.add_class::<InterruptHandle>()?;
     m.add_class::<Memory>()?;
     m.add_class::<Module>()?;
+    m.add_class::<Profile>()?;
+    m.add_class::<ResourceLimiter>()?;
+    m.add_class::<Store>()?;
+    m.add_class::<VirtualDir>()?;
+    m.add_class::<WasiConfig>()?;
+    m.add_class::<WasiInstance>()?;
     m.add_class::<InstantiateResultObject>()?;
+    m.add("Trap", py.get_type::<Trap>())?;
+    m.add("Interrupted", py.get_type::<Interrupted>())?;
+    m.add("Timeout", py.get_type::<Timeout>())?;
+    m.add(
+        "ResourceLimitExceeded",
+        py.get_type::<ResourceLimitExceeded>(),
+    )?;
+    m.add("WasiExit", py.get_type::<WasiExit>())?;
     m.add_wrapped(wrap_pyfunction!(instantiate))?;
     m.add_wrapped(wrap_pyfunction!(imported_modules))?;
+    m.add_wrapped(wrap_pyfunction!(run_command))?;
+    m.add_wrapped(wrap_pyfunction!(enable_cache))?;
+    m.add_wrapped(wrap_pyfunction!(disable_cache))?;
+    m.add_wrapped(wrap_pyfunction!(clear_cache))?;
+    m.add_wrapped(wrap_pyfunction!(compile_to_object))?;
+    m.add_wrapped(wrap_pyfunction!(configure_compiler))?;
+    m.add_wrapped(wrap_pyfunction!(compiler_threads))?;
+    m.add_wrapped(wrap_pyfunction!(enable_perf_map))?;
+    m.add_wrapped(wrap_pyfunction!(disable_perf_map))?;
     Ok(())
 }
-
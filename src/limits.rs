@@ -0,0 +1,112 @@
+//! Resource limits applied to the instances of a store.
+
+use pyo3::create_exception;
+use pyo3::exceptions::Exception;
+use pyo3::prelude::*;
+
+use crate::instrument::Instrumentation;
+use wasmtime_environ::WASM_PAGE_SIZE;
+
+create_exception!(lib_wasmtime, ResourceLimitExceeded, Exception);
+
+/// Limits of a store, see `ResourceLimiter`.
+#[derive(Default)]
+pub struct Limits {
+    pub memory_size: Option<usize>,
+    pub table_elements: Option<u32>,
+    pub instances: Option<usize>,
+    pub on_grow: Option<PyObject>,
+}
+
+impl Limits {
+    /// The `memory_size` limit in wasm pages.
+    pub fn memory_pages(&self) -> Option<u32> {
+        self.memory_size
+            .map(|size| (size / WASM_PAGE_SIZE as usize) as u32)
+    }
+
+    /// The instrumentation enforcing the limits.
+    pub fn instrumentation(&self, handle_sigint: bool) -> Instrumentation {
+        Instrumentation {
+            handle_sigint,
+            memory_pages: self.memory_pages(),
+            table_elements: self.table_elements,
+            hook_memory_grow: self.on_grow.is_some(),
+        }
+    }
+
+    /// Decides whether a memory may grow from `current` to `desired` bytes.
+    pub fn allow_memory_grow(&self, py: Python, current: usize, desired: usize) -> PyResult<bool> {
+        if let Some(memory_size) = self.memory_size {
+            if desired > memory_size {
+                return Ok(false);
+            }
+        }
+        match self.on_grow {
+            Some(ref on_grow) => on_grow.call1(py, (current, desired))?.is_true(py),
+            None => Ok(true),
+        }
+    }
+
+    /// Checks that a store with `count` instances may get another one.
+    pub fn check_instances(&self, count: usize) -> PyResult<()> {
+        match self.instances {
+            Some(instances) if count >= instances => Err(ResourceLimitExceeded::py_err(format!(
+                "the store already has {} instances",
+                count
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Policy limiting what the instances of a store may allocate.
+///
+/// `memory_size` is the maximal size of a linear memory in bytes,
+/// `table_elements` the maximal number of elements of a table, and
+/// `instances` the maximal number of live instances of modules in the store.
+/// `on_grow(current, desired)` is called with sizes in bytes whenever a
+/// memory is about to grow and denies it by returning a false value.
+#[pyclass]
+pub struct ResourceLimiter {
+    pub memory_size: Option<usize>,
+    pub table_elements: Option<u32>,
+    pub instances: Option<usize>,
+    pub on_grow: Option<PyObject>,
+}
+
+impl ResourceLimiter {
+    pub fn to_limits(&self, py: Python) -> Limits {
+        Limits {
+            memory_size: self.memory_size,
+            table_elements: self.table_elements,
+            instances: self.instances,
+            on_grow: self.on_grow.as_ref().map(|f| f.clone_ref(py)),
+        }
+    }
+}
+
+#[pymethods]
+impl ResourceLimiter {
+    #[new]
+    #[args(
+        memory_size = "None",
+        table_elements = "None",
+        instances = "None",
+        on_grow = "None"
+    )]
+    fn new(
+        obj: &PyRawObject,
+        memory_size: Option<usize>,
+        table_elements: Option<u32>,
+        instances: Option<usize>,
+        on_grow: Option<PyObject>,
+    ) {
+        obj.init(ResourceLimiter {
+            memory_size,
+            table_elements,
+            instances,
+            on_grow,
+        });
+    }
+}
@@ -0,0 +1,463 @@
+//! Instantiation of compiled artifacts: placement of the code, import
+//! resolution and relocation.
+
+use crate::backtrace::{hook_trap_signals, ModuleInfo, Registration};
+use crate::code_memory::CodeMemory;
+use crate::compiler::{translate, Artifact};
+use crate::lazy::LazyCode;
+use crate::store::InstanceSlot;
+use cranelift_codegen::binemit::Reloc;
+use cranelift_codegen::ir::JumpTableOffsets;
+use cranelift_codegen::isa::TargetIsa;
+use cranelift_entity::{BoxedSlice, PrimaryMap};
+use cranelift_wasm::{DefinedFuncIndex, Global, GlobalInit, Memory, Table, TableElementType};
+use wasmtime_environ::{
+    DataInitializer, MemoryPlan, MemoryStyle, Module, Relocation, RelocationTarget, Relocations,
+    TablePlan,
+};
+use wasmtime_runtime::libcalls::*;
+use wasmtime_runtime::{
+    Export, Imports, InstanceHandle, SignatureRegistry, VMFunctionBody, VMFunctionImport,
+    VMGlobalImport, VMMemoryImport, VMTableImport,
+};
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ptr::write_unaligned;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Compilation and linking state of a store.
+pub struct Linker {
+    pub isa: Box<dyn TargetIsa>,
+    signatures: SignatureRegistry,
+    global_exports: Rc<RefCell<HashMap<String, Option<Export>>>>,
+}
+
+/// Host state of instances of compiled modules, owning their code and
+/// their place among the instances of the store.
+#[allow(dead_code)]
+enum CompiledState {
+    Compiled(CodeMemory, Registration, InstanceSlot),
+    Lazy(Box<LazyCode>, InstanceSlot),
+}
+
+impl Linker {
+    pub fn new(isa: Box<dyn TargetIsa>) -> Self {
+        Self {
+            isa,
+            signatures: SignatureRegistry::new(),
+            global_exports: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    pub fn get_global_exports(&self) -> Rc<RefCell<HashMap<String, Option<Export>>>> {
+        self.global_exports.clone()
+    }
+
+    /// Instantiates `artifact`, compiled from `info`, resolving its imports
+    /// from the instances of `namespace`, by module name.
+    pub fn instantiate(
+        &mut self,
+        artifact: &Artifact,
+        info: &Arc<ModuleInfo>,
+        namespace: &HashMap<String, InstanceHandle>,
+        slot: InstanceSlot,
+    ) -> Result<InstanceHandle, String> {
+        let translation = translate(self.isa.as_ref(), &artifact.wasm)?;
+        let module = translation.module;
+
+        let mut code_memory = CodeMemory::new();
+        let mut allocated_functions = PrimaryMap::with_capacity(artifact.compilation.len());
+        for function in &artifact.compilation {
+            let body: *mut [VMFunctionBody] = code_memory.allocate_copy_of_byte_slice(function)?;
+            allocated_functions.push(body);
+        }
+        relocate(
+            &allocated_functions,
+            &artifact.compilation.get_jt_offsets(),
+            &artifact.relocations,
+            &module,
+        );
+        code_memory.publish();
+        let mut registration = Registration::new(info);
+        for (index, body) in allocated_functions.iter() {
+            let body = *body;
+            registration.register(
+                index,
+                body as *const VMFunctionBody as usize,
+                unsafe { (*body).len() },
+                artifact.address_map[index].clone(),
+                artifact.local_slots[index].clone(),
+            );
+        }
+        let finished_functions = allocated_functions
+            .values()
+            .map(|body| *body as *const VMFunctionBody)
+            .collect::<PrimaryMap<DefinedFuncIndex, _>>()
+            .into_boxed_slice();
+
+        self.link(
+            Rc::new(module),
+            finished_functions,
+            &translation.data_initializers,
+            namespace,
+            CompiledState::Compiled(code_memory, registration, slot),
+        )
+    }
+
+    /// Instantiates the wasm module `wasm`, instrumented from `info`,
+    /// without compiling it, its functions being compiled on their first
+    /// call.
+    pub fn instantiate_lazy(
+        &mut self,
+        wasm: &[u8],
+        info: &Arc<ModuleInfo>,
+        namespace: &HashMap<String, InstanceHandle>,
+        slot: InstanceSlot,
+    ) -> Result<InstanceHandle, String> {
+        let translation = translate(self.isa.as_ref(), wasm)?;
+        let bodies = translation
+            .function_body_inputs
+            .values()
+            .map(|input| {
+                let start = input.data.as_ptr() as usize - wasm.as_ptr() as usize;
+                start..start + input.data.len()
+            })
+            .collect();
+        let module = Rc::new(translation.module);
+        let code = LazyCode::new(wasm.to_vec(), bodies, module.clone(), info.clone())?;
+        self.link(
+            module,
+            code.functions().into_boxed_slice(),
+            &translation.data_initializers,
+            namespace,
+            CompiledState::Lazy(code, slot),
+        )
+    }
+
+    fn link(
+        &mut self,
+        module: Rc<Module>,
+        finished_functions: BoxedSlice<DefinedFuncIndex, *const VMFunctionBody>,
+        data_initializers: &[DataInitializer],
+        namespace: &HashMap<String, InstanceHandle>,
+        state: CompiledState,
+    ) -> Result<InstanceHandle, String> {
+        let imports = resolve_imports(&module, namespace)?;
+        let signatures = module
+            .signatures
+            .values()
+            .map(|sig| self.signatures.register(sig))
+            .collect::<PrimaryMap<_, _>>()
+            .into_boxed_slice();
+
+        let instance = InstanceHandle::new(
+            module,
+            self.global_exports.clone(),
+            finished_functions,
+            imports,
+            data_initializers,
+            signatures,
+            None,
+            Box::new(state),
+        )
+        .map_err(|e| e.to_string())?;
+        // After the instantiation, which installs the handlers of
+        // wasmtime-runtime.
+        hook_trap_signals();
+        Ok(instance)
+    }
+}
+
+/// The module `instance` was created from, if it is an instance of a
+/// compiled module (not of a host module).
+pub fn module_info(instance: &mut InstanceHandle) -> Option<Arc<ModuleInfo>> {
+    match instance.host_state().downcast_ref::<CompiledState>()? {
+        CompiledState::Compiled(_, registration, _) => Some(registration.module().clone()),
+        CompiledState::Lazy(code, _) => Some(code.module_info()),
+    }
+}
+
+/// The `Registration::owner` of the code of `instance`, if it is an
+/// instance of a compiled module.
+pub fn code_owner(instance: &mut InstanceHandle) -> Option<usize> {
+    match instance.host_state().downcast_ref::<CompiledState>()? {
+        CompiledState::Compiled(_, registration, _) => Some(registration.owner()),
+        CompiledState::Lazy(code, _) => Some(code.owner()),
+    }
+}
+
+fn lookup(
+    namespace: &HashMap<String, InstanceHandle>,
+    module_name: &str,
+    field: &str,
+) -> Result<Export, String> {
+    namespace
+        .get(module_name)
+        .and_then(|instance| instance.clone().lookup(field))
+        .ok_or_else(|| format!("{}/{}: unknown import", module_name, field))
+}
+
+fn incompatible(module_name: &str, field: &str, what: &str) -> String {
+    format!(
+        "{}/{}: incompatible import type: {}",
+        module_name, field, what
+    )
+}
+
+// Mostly a copy of `link_module` from wasmtime's wasmtime-jit/src/link.rs,
+// with the relocation moved out.
+fn resolve_imports(
+    module: &Module,
+    namespace: &HashMap<String, InstanceHandle>,
+) -> Result<Imports, String> {
+    let mut dependencies = HashSet::new();
+
+    let mut function_imports = PrimaryMap::with_capacity(module.imported_funcs.len());
+    for (index, (ref module_name, ref field)) in module.imported_funcs.iter() {
+        match lookup(namespace, module_name, field)? {
+            Export::Function {
+                address,
+                signature,
+                vmctx,
+            } => {
+                let import_signature = &module.signatures[module.functions[index]];
+                if signature != *import_signature {
+                    return Err(incompatible(
+                        module_name,
+                        field,
+                        "function signature mismatch",
+                    ));
+                }
+                dependencies.insert(unsafe { InstanceHandle::from_vmctx(vmctx) });
+                function_imports.push(VMFunctionImport {
+                    body: address,
+                    vmctx,
+                });
+            }
+            _ => return Err(incompatible(module_name, field, "expected a function")),
+        }
+    }
+
+    let mut table_imports = PrimaryMap::with_capacity(module.imported_tables.len());
+    for (index, (ref module_name, ref field)) in module.imported_tables.iter() {
+        match lookup(namespace, module_name, field)? {
+            Export::Table {
+                definition,
+                vmctx,
+                table,
+            } => {
+                if !is_table_compatible(&table, &module.table_plans[index]) {
+                    return Err(incompatible(module_name, field, "table mismatch"));
+                }
+                dependencies.insert(unsafe { InstanceHandle::from_vmctx(vmctx) });
+                table_imports.push(VMTableImport {
+                    from: definition,
+                    vmctx,
+                });
+            }
+            _ => return Err(incompatible(module_name, field, "expected a table")),
+        }
+    }
+
+    let mut memory_imports = PrimaryMap::with_capacity(module.imported_memories.len());
+    for (index, (ref module_name, ref field)) in module.imported_memories.iter() {
+        match lookup(namespace, module_name, field)? {
+            Export::Memory {
+                definition,
+                vmctx,
+                memory,
+            } => {
+                if !is_memory_compatible(&memory, &module.memory_plans[index]) {
+                    return Err(incompatible(module_name, field, "memory mismatch"));
+                }
+                dependencies.insert(unsafe { InstanceHandle::from_vmctx(vmctx) });
+                memory_imports.push(VMMemoryImport {
+                    from: definition,
+                    vmctx,
+                });
+            }
+            _ => return Err(incompatible(module_name, field, "expected a memory")),
+        }
+    }
+
+    let mut global_imports = PrimaryMap::with_capacity(module.imported_globals.len());
+    for (index, (ref module_name, ref field)) in module.imported_globals.iter() {
+        match lookup(namespace, module_name, field)? {
+            Export::Global {
+                definition,
+                vmctx,
+                global,
+            } => {
+                if !is_global_compatible(&global, &module.globals[index]) {
+                    return Err(incompatible(module_name, field, "global mismatch"));
+                }
+                dependencies.insert(unsafe { InstanceHandle::from_vmctx(vmctx) });
+                global_imports.push(VMGlobalImport { from: definition });
+            }
+            _ => return Err(incompatible(module_name, field, "expected a global")),
+        }
+    }
+
+    Ok(Imports::new(
+        dependencies,
+        function_imports,
+        table_imports,
+        memory_imports,
+        global_imports,
+    ))
+}
+
+fn is_global_compatible(exported: &Global, imported: &Global) -> bool {
+    match imported.initializer {
+        GlobalInit::Import => (),
+        _ => panic!("imported Global should have an Imported initializer"),
+    }
+    exported.ty == imported.ty && exported.mutability == imported.mutability
+}
+
+fn is_table_element_type_compatible(exported: &Table, imported: &Table) -> bool {
+    match (exported.ty, imported.ty) {
+        (TableElementType::Val(exported), TableElementType::Val(imported)) => exported == imported,
+        (TableElementType::Func, TableElementType::Func) => true,
+        _ => false,
+    }
+}
+
+fn is_maximum_compatible(exported: Option<u32>, imported: Option<u32>) -> bool {
+    match (exported, imported) {
+        (_, None) => true,
+        (Some(exported), Some(imported)) => exported <= imported,
+        (None, Some(_)) => false,
+    }
+}
+
+fn is_table_compatible(exported: &TablePlan, imported: &TablePlan) -> bool {
+    is_table_element_type_compatible(&exported.table, &imported.table)
+        && exported.table.minimum >= imported.table.minimum
+        && is_maximum_compatible(exported.table.maximum, imported.table.maximum)
+}
+
+fn is_memory_compatible(exported: &MemoryPlan, imported: &MemoryPlan) -> bool {
+    let (exported_memory, imported_memory): (&Memory, &Memory) =
+        (&exported.memory, &imported.memory);
+    let style_compatible = match (&exported.style, &imported.style) {
+        (MemoryStyle::Dynamic, MemoryStyle::Dynamic) => true,
+        (
+            MemoryStyle::Static {
+                bound: exported_bound,
+            },
+            MemoryStyle::Static {
+                bound: imported_bound,
+            },
+        ) => exported_bound >= imported_bound,
+        _ => false,
+    };
+    exported_memory.minimum >= imported_memory.minimum
+        && is_maximum_compatible(exported_memory.maximum, imported_memory.maximum)
+        && exported_memory.shared == imported_memory.shared
+        && style_compatible
+        && exported.offset_guard_size >= imported.offset_guard_size
+}
+
+extern "C" {
+    pub fn __rust_probestack();
+}
+
+/// Performs the relocations inside the function bytecode, provided the
+/// necessary metadata.
+fn relocate(
+    allocated_functions: &PrimaryMap<DefinedFuncIndex, *mut [VMFunctionBody]>,
+    jt_offsets: &PrimaryMap<DefinedFuncIndex, JumpTableOffsets>,
+    relocations: &Relocations,
+    module: &Module,
+) {
+    let function_address = |f: DefinedFuncIndex| {
+        let fatptr: *const [VMFunctionBody] = allocated_functions[f];
+        fatptr as *const VMFunctionBody as usize
+    };
+    for (i, function_relocs) in relocations.iter() {
+        relocate_function(
+            function_address(i) as *const VMFunctionBody,
+            function_relocs,
+            &jt_offsets[i],
+            module,
+            &function_address,
+        );
+    }
+}
+
+/// Performs the relocations of the function at `body`, calls to the
+/// function `f` going to `function_address(f)`.
+// Mostly a copy of `relocate` from wasmtime's wasmtime-jit/src/link.rs.
+pub fn relocate_function(
+    body: *const VMFunctionBody,
+    relocations: &[Relocation],
+    jt_offsets: &JumpTableOffsets,
+    module: &Module,
+    function_address: &dyn Fn(DefinedFuncIndex) -> usize,
+) {
+    for r in relocations {
+        let target_func_address: usize = match r.reloc_target {
+            RelocationTarget::UserFunc(index) => match module.defined_func_index(index) {
+                Some(f) => function_address(f),
+                None => panic!("direct call to import"),
+            },
+            RelocationTarget::Memory32Grow => wasmtime_memory32_grow as usize,
+            RelocationTarget::Memory32Size => wasmtime_memory32_size as usize,
+            RelocationTarget::ImportedMemory32Grow => wasmtime_imported_memory32_grow as usize,
+            RelocationTarget::ImportedMemory32Size => wasmtime_imported_memory32_size as usize,
+            RelocationTarget::LibCall(libcall) => {
+                use cranelift_codegen::ir::LibCall::*;
+                match libcall {
+                    CeilF32 => wasmtime_f32_ceil as usize,
+                    FloorF32 => wasmtime_f32_floor as usize,
+                    TruncF32 => wasmtime_f32_trunc as usize,
+                    NearestF32 => wasmtime_f32_nearest as usize,
+                    CeilF64 => wasmtime_f64_ceil as usize,
+                    FloorF64 => wasmtime_f64_floor as usize,
+                    TruncF64 => wasmtime_f64_trunc as usize,
+                    NearestF64 => wasmtime_f64_nearest as usize,
+                    Probestack => __rust_probestack as usize,
+                    other => panic!("unexpected libcall: {}", other),
+                }
+            }
+            // Jump tables are those of the function itself.
+            RelocationTarget::JumpTable(_, jt) => {
+                let offset = *jt_offsets.get(jt).expect("func jump table");
+                body as usize + offset as usize
+            }
+        };
+
+        match r.reloc {
+            #[cfg(target_pointer_width = "64")]
+            Reloc::Abs8 => unsafe {
+                let reloc_address = body.add(r.offset as usize) as usize;
+                let reloc_addend = r.addend as isize;
+                let reloc_abs = (target_func_address as u64)
+                    .checked_add(reloc_addend as u64)
+                    .unwrap();
+                write_unaligned(reloc_address as *mut u64, reloc_abs);
+            },
+            #[cfg(target_pointer_width = "32")]
+            Reloc::X86PCRel4 => unsafe {
+                let reloc_address = body.add(r.offset as usize) as usize;
+                let reloc_addend = r.addend as isize;
+                let reloc_delta_u32 = (target_func_address as u32)
+                    .wrapping_sub(reloc_address as u32)
+                    .checked_add(reloc_addend as u32)
+                    .unwrap();
+                write_unaligned(reloc_address as *mut u32, reloc_delta_u32);
+            },
+            #[cfg(target_pointer_width = "32")]
+            Reloc::X86CallPCRel4 => {
+                // TODO: Implement.
+            }
+            Reloc::X86PCRelRodata4 => {
+                // ignore
+            }
+            _ => panic!("unsupported reloc kind"),
+        }
+    }
+}
@@ -1,23 +1,58 @@
 //! WebAssembly Memory API object.
 
 use pyo3::class::PyBufferProtocol;
-use pyo3::exceptions::BufferError;
+use pyo3::exceptions::{BufferError, ValueError};
 use pyo3::ffi;
 use pyo3::prelude::*;
 
+use crate::atomics::ParkingLot;
+use crate::limits::{Limits, ResourceLimitExceeded};
+use crate::store::{Store, StoreState};
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::ffi::CStr;
 use std::os::raw::{c_int, c_void};
 use std::ptr;
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
-use wasmtime_environ::MemoryPlan;
-use wasmtime_jit::{Context, InstanceHandle};
-use wasmtime_runtime::{Export, VMMemoryDefinition, VMMemoryImport};
+use cranelift_entity::PrimaryMap;
+use wasmtime_environ::{Export as ModuleExport, MemoryPlan, Module, Tunables, WASM_PAGE_SIZE};
+use wasmtime_jit::InstanceHandle;
+use wasmtime_runtime::{Export, Imports, VMContext, VMMemoryDefinition, VMMemoryImport};
+
+/// Host state of the instances created for `Memory(...)`.
+pub struct MemoryState {
+    pub shared: bool,
+    pub parking_lot: ParkingLot,
+    grow_lock: Mutex<()>,
+}
+
+/// Returns the state of the memory owned by the instance `vmctx`, if it
+/// was created from Python.
+pub unsafe fn memory_state<'a>(vmctx: *mut VMContext) -> Option<&'a MemoryState> {
+    (*vmctx).host_state().downcast_ref::<MemoryState>()
+}
+
+/// Grows the memory `definition` of the instance `vmctx` by `delta` pages,
+/// returning the previous size. The growth of shared memories, which can be
+/// imported in instances of several stores, is serialized.
+///
+/// Has to be called with the GIL held, see `store::StoreState`.
+pub unsafe fn grow_memory(
+    vmctx: *mut VMContext,
+    definition: *mut VMMemoryDefinition,
+    delta: u32,
+) -> Option<u32> {
+    let _grow = memory_state(vmctx).map(|state| state.grow_lock.lock().unwrap());
+    let mut owner = InstanceHandle::from_vmctx(vmctx);
+    let index = owner.memory_index(&*definition);
+    owner.memory_grow(index, delta)
+}
 
 #[pyclass]
 pub struct Memory {
-    pub context: Rc<RefCell<Context>>,
+    pub store: Arc<StoreState>,
     pub instance: InstanceHandle,
     pub export_name: String,
 }
@@ -59,16 +94,134 @@ impl Memory {
     }
 }
 
+/// Creates an instance defining a single memory, exported as "memory".
+fn instantiate_memory(minimum: u32, maximum: Option<u32>, shared: bool) -> InstanceHandle {
+    let mut module = Module::new();
+    // Shared memories are never moved, since they have a maximum and the
+    // default tunables make such memories static. The `shared` flag of the
+    // plan stays false: instrumented modules import them as unshared ones.
+    let plan = MemoryPlan::for_memory(
+        cranelift_wasm::Memory {
+            minimum,
+            maximum,
+            shared: false,
+        },
+        &Tunables::default(),
+    );
+    let memory_id = module.memory_plans.push(plan);
+    module
+        .exports
+        .insert("memory".to_string(), ModuleExport::Memory(memory_id));
+
+    let imports = Imports::new(
+        HashSet::new(),
+        PrimaryMap::new(),
+        PrimaryMap::new(),
+        PrimaryMap::new(),
+        PrimaryMap::new(),
+    );
+    let data_initializers = Vec::new();
+    let signatures = PrimaryMap::new();
+
+    InstanceHandle::new(
+        Rc::new(module),
+        Rc::new(RefCell::new(HashMap::new())),
+        PrimaryMap::new().into_boxed_slice(),
+        imports,
+        &data_initializers,
+        signatures.into_boxed_slice(),
+        None,
+        Box::new(MemoryState {
+            shared,
+            parking_lot: ParkingLot::default(),
+            grow_lock: Mutex::new(()),
+        }),
+    )
+    .expect("memory instance")
+}
+
 #[pymethods]
 impl Memory {
+    /// Creates a memory of `minimum` pages that can be imported by modules.
+    ///
+    /// A `shared` memory (which needs a `maximum`) can be imported in
+    /// instances of several stores running on their own threads, and
+    /// supports `memory.atomic.wait` and `memory.atomic.notify`. Since
+    /// calls into a store are serialized, give each thread its own store.
+    #[new]
+    #[args(maximum = "None", shared = "false", store = "None")]
+    fn new(
+        obj: &PyRawObject,
+        minimum: u32,
+        maximum: Option<u32>,
+        shared: bool,
+        store: Option<&Store>,
+    ) -> PyResult<()> {
+        if shared && maximum.is_none() {
+            return Err(ValueError::py_err("a shared memory needs a maximum"));
+        }
+        if maximum.map_or(false, |maximum| maximum < minimum) {
+            return Err(ValueError::py_err("maximum is less than minimum"));
+        }
+        let store = match store {
+            Some(store) => store.state.clone(),
+            None => Arc::new(StoreState::new(Limits::default())),
+        };
+        let maximum = match store.limits.memory_pages() {
+            Some(cap) if minimum > cap => {
+                return Err(ResourceLimitExceeded::py_err(format!(
+                    "memory pages minimum of {} exceeds the limit of {}",
+                    minimum, cap
+                )));
+            }
+            Some(cap) => Some(maximum.map_or(cap, |maximum| maximum.min(cap))),
+            None => maximum,
+        };
+        obj.init(Memory {
+            store,
+            instance: instantiate_memory(minimum, maximum, shared),
+            export_name: "memory".to_string(),
+        });
+        Ok(())
+    }
+
+    #[getter(shared)]
+    pub fn shared(&self) -> bool {
+        let mut instance = self.instance.clone();
+        if let Some(Export::Memory { vmctx, .. }) = instance.lookup(&self.export_name) {
+            unsafe { memory_state(vmctx) }.map_or(false, |state| state.shared)
+        } else {
+            panic!("memory is expected");
+        }
+    }
+
     #[getter(current)]
     pub fn current(&self) -> u32 {
         let current_length = unsafe { (*self.descriptor()).current_length };
         (current_length >> 16) as u32
     }
 
-    pub fn grow(&self, _number: u32) -> u32 {
-        (-1i32) as u32
+    /// Grows the memory by `number` pages if the store limits allow it.
+    /// Returns the previous size in pages, or -1 (as u32).
+    pub fn grow(&self, py: Python, number: u32) -> PyResult<u32> {
+        let page_size = WASM_PAGE_SIZE as usize;
+        let current = self.current() as usize * page_size;
+        let desired = current + number as usize * page_size;
+        if !self.store.limits.allow_memory_grow(py, current, desired)? {
+            return Ok((-1i32) as u32);
+        }
+        let _execution = self.store.execution.lock(py);
+        let mut instance = self.instance.clone();
+        if let Some(Export::Memory {
+            definition, vmctx, ..
+        }) = instance.lookup(&self.export_name)
+        {
+            // The memory can be imported, grow it in the instance defining it.
+            let previous = unsafe { grow_memory(vmctx, definition, number) };
+            Ok(previous.unwrap_or((-1i32) as u32))
+        } else {
+            panic!("memory is expected");
+        }
     }
 }
 
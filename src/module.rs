@@ -1,10 +1,289 @@
 //! WebAssembly Module API object.
 
+use pyo3::exceptions::ValueError;
 use pyo3::prelude::*;
+use pyo3::types::{PyAny, PyBytes, PyDict};
 
-use std::rc::Rc;
+use crate::cache::compile_cached;
+use crate::compiler::{deserialize, serialize, target_isa, Artifact};
+use crate::inspect;
+use crate::instrument::Instrumentation;
+use crate::limits::Limits;
+use crate::object::embedded_module;
+use crate::store::{native_isa, Store};
+use cranelift_codegen::isa::TargetIsa;
+use serde::{Deserialize, Serialize};
+use wasmparser::{BinaryReaderError, CustomSectionKind, ModuleReader, Name, SectionCode};
 
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// A module compiled with a given instrumentation.
+#[derive(Serialize, Deserialize)]
+pub struct CompiledModule {
+    /// The module as given by the user.
+    pub source: Vec<u8>,
+    pub instrumentation: Instrumentation,
+    /// Triple of the target the code is generated for.
+    pub target: String,
+    pub artifact: Artifact,
+}
+
+impl CompiledModule {
+    /// Compiles `source`; the GIL is released meanwhile.
+    pub fn new(
+        py: Python,
+        source: &[u8],
+        instrumentation: Instrumentation,
+        isa: &dyn TargetIsa,
+    ) -> PyResult<Self> {
+        let wasm_data = instrumentation.apply(source)?;
+        let artifact = py
+            .allow_threads(|| compile_cached(isa, &wasm_data))
+            .map_err(ValueError::py_err)?;
+        Ok(Self {
+            source: source.to_vec(),
+            instrumentation,
+            target: isa.triple().to_string(),
+            artifact,
+        })
+    }
+
+    /// The ISA the module is compiled for.
+    pub fn isa(&self) -> PyResult<Box<dyn TargetIsa>> {
+        target_isa(Some(&self.target)).map_err(ValueError::py_err)
+    }
+}
+
+/// Returns `source`, a `Module` or the bytes of a module, compiled for the
+/// host with `instrumentation`. A `Module` compiled with another
+/// instrumentation is compiled again, one compiled for another target
+/// cannot be used.
+pub fn compiled_module(
+    source: &PyAny,
+    instrumentation: Instrumentation,
+) -> PyResult<Arc<CompiledModule>> {
+    let isa = native_isa();
+    if source.get_type().is_subclass::<Module>()? {
+        let module = source.cast_as::<Module>()?;
+        let host = isa.triple().to_string();
+        if module.compiled.target != host {
+            return Err(ValueError::py_err(format!(
+                "the module is compiled for {}, it cannot run on {}",
+                module.compiled.target, host
+            )));
+        }
+        if module.compiled.instrumentation == instrumentation {
+            return Ok(module.compiled.clone());
+        }
+        return CompiledModule::new(
+            source.py(),
+            &module.compiled.source,
+            instrumentation,
+            isa.as_ref(),
+        )
+        .map(Arc::new);
+    }
+    let bytes = source.downcast_ref::<PyBytes>()?;
+    CompiledModule::new(source.py(), bytes.as_bytes(), instrumentation, isa.as_ref()).map(Arc::new)
+}
+
+fn reader_error(e: BinaryReaderError) -> PyErr {
+    ValueError::py_err(format!("{} (at offset {})", e.message, e.offset))
+}
+
+/// Contents of the custom sections of `wasm` called `name`.
+pub fn custom_sections<'a>(wasm: &'a [u8], name: &str) -> Result<Vec<&'a [u8]>, BinaryReaderError> {
+    let mut sections = Vec::new();
+    let mut parser = ModuleReader::new(wasm)?;
+    while !parser.eof() {
+        let section = parser.read()?;
+        match section.code {
+            SectionCode::Custom { name: n, .. } if n == name => {}
+            _ => continue,
+        }
+        let mut reader = section.get_binary_reader();
+        sections.push(reader.read_bytes(reader.bytes_remaining())?);
+    }
+    Ok(sections)
+}
+
+/// Module name of the "name" section of `wasm`.
+pub fn module_name(wasm: &[u8]) -> Result<Option<String>, BinaryReaderError> {
+    let mut parser = ModuleReader::new(wasm)?;
+    while !parser.eof() {
+        let section = parser.read()?;
+        match section.code {
+            SectionCode::Custom {
+                kind: CustomSectionKind::Name,
+                ..
+            } => {}
+            _ => continue,
+        }
+        for subsection in section.get_name_section_reader()? {
+            if let Name::Module(module_name) = subsection? {
+                return Ok(Some(module_name.get_name()?.to_string()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Function names of the "name" section of `wasm`, by function index.
+pub fn function_names(wasm: &[u8]) -> Result<BTreeMap<u32, String>, BinaryReaderError> {
+    let mut names = BTreeMap::new();
+    let mut parser = ModuleReader::new(wasm)?;
+    while !parser.eof() {
+        let section = parser.read()?;
+        match section.code {
+            SectionCode::Custom {
+                kind: CustomSectionKind::Name,
+                ..
+            } => {}
+            _ => continue,
+        }
+        for subsection in section.get_name_section_reader()? {
+            if let Name::Function(function_names) = subsection? {
+                let mut map = function_names.get_map()?;
+                for _ in 0..map.get_count() {
+                    let naming = map.read()?;
+                    names.insert(naming.index, naming.name.to_string());
+                }
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Compiled module, which can be instantiated several times and saved with
+/// `serialize`.
+///
+/// The code depends on `handle_sigint` and the limits of the store, which
+/// get injected into the module; instantiating it with other ones compiles
+/// it again. With a `target` triple, the module is compiled for another
+/// x86-64 target, with the default code generator flags rather than the
+/// features of the host CPU; it can then be serialized but not
+/// instantiated.
 #[pyclass]
 pub struct Module {
-    pub module: Rc<wasmtime_environ::Module>,
+    pub compiled: Arc<CompiledModule>,
+}
+
+impl Module {
+    fn load(serialized: &[u8], isa: &dyn TargetIsa) -> PyResult<Py<Module>> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let compiled: CompiledModule =
+            deserialize(serialized, isa).map_err(|e| ValueError::py_err(e.to_string()))?;
+        Py::new(
+            py,
+            Module {
+                compiled: Arc::new(compiled),
+            },
+        )
+    }
+}
+
+#[pymethods]
+impl Module {
+    #[new]
+    #[args(handle_sigint = "false", store = "None", target = "None")]
+    fn new(
+        obj: &PyRawObject,
+        buffer_source: &PyBytes,
+        handle_sigint: bool,
+        store: Option<&Store>,
+        target: Option<&str>,
+    ) -> PyResult<()> {
+        let isa = target_isa(target).map_err(ValueError::py_err)?;
+        let instrumentation = match store {
+            Some(store) => store.state.limits.instrumentation(handle_sigint),
+            None => Limits::default().instrumentation(handle_sigint),
+        };
+        let compiled = CompiledModule::new(
+            obj.py(),
+            buffer_source.as_bytes(),
+            instrumentation,
+            isa.as_ref(),
+        )?;
+        obj.init(Module {
+            compiled: Arc::new(compiled),
+        });
+        Ok(())
+    }
+
+    /// Returns the compiled module as bytes, for `Module.deserialize`.
+    fn serialize(&self) -> PyResult<PyObject> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let bytes = serialize(self.compiled.isa()?.as_ref(), &*self.compiled);
+        Ok(PyBytes::new(py, &bytes).into_object(py))
+    }
+
+    /// Triple of the target the module is compiled for.
+    #[getter(target)]
+    fn get_target(&self) -> String {
+        self.compiled.target.clone()
+    }
+
+    /// Returns the contents of the custom sections called `name`, as a list
+    /// of bytes.
+    fn custom_sections(&self, name: &str) -> PyResult<Vec<PyObject>> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let sections = custom_sections(&self.compiled.source, name).map_err(reader_error)?;
+        Ok(sections
+            .into_iter()
+            .map(|section| PyBytes::new(py, section).into_object(py))
+            .collect())
+    }
+
+    /// The function names of the "name" section, by function index.
+    #[getter(function_names)]
+    fn get_function_names(&self) -> PyResult<PyObject> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let dict = PyDict::new(py);
+        for (index, name) in function_names(&self.compiled.source).map_err(reader_error)? {
+            dict.set_item(index, name)?;
+        }
+        Ok(dict.into_object(py))
+    }
+
+    /// Returns the machine code of the exported function `func_name`.
+    fn disassemble(&self, func_name: &str) -> PyResult<String> {
+        let isa = self.compiled.isa()?;
+        inspect::disassemble(isa.as_ref(), &self.compiled, func_name).map_err(ValueError::py_err)
+    }
+
+    /// Returns the Cranelift IR of the exported function `func_name`, before
+    /// and after its compilation, as a tuple of strings.
+    fn cranelift_ir(&self, func_name: &str) -> PyResult<(String, String)> {
+        let isa = self.compiled.isa()?;
+        inspect::cranelift_ir(isa.as_ref(), &self.compiled, func_name).map_err(ValueError::py_err)
+    }
+
+    /// Loads a module serialized by `Module.serialize`. Raises `ValueError`
+    /// for modules produced by another build of the extension or for
+    /// another target than `target`, the host by default.
+    ///
+    /// The data holds machine code which is run as is: only deserialize
+    /// trusted data, e.g. produced by this program.
+    #[staticmethod]
+    #[args(target = "None")]
+    fn deserialize(data: &PyBytes, target: Option<&str>) -> PyResult<Py<Module>> {
+        let isa = target_isa(target).map_err(ValueError::py_err)?;
+        Module::load(data.as_bytes(), isa.as_ref())
+    }
+
+    /// Loads a module from an object produced by `compile_to_object` for
+    /// the host.
+    ///
+    /// Like `Module.deserialize`, only load trusted objects, since their
+    /// machine code is run as is.
+    #[staticmethod]
+    fn from_object(data: &PyBytes) -> PyResult<Py<Module>> {
+        let serialized = embedded_module(data.as_bytes()).map_err(ValueError::py_err)?;
+        Module::load(serialized, native_isa().as_ref())
+    }
 }
@@ -0,0 +1,219 @@
+//! Ahead-of-time compilation of modules to ELF relocatable objects.
+//!
+//! Each defined function is a `_wasm_function_N` symbol of the object, with
+//! relocations against the other functions and the runtime's libcalls, so
+//! the code can be inspected with the usual binutils. The serialized
+//! `CompiledModule` is embedded as the `wasmtime_py_module` data symbol,
+//! which is what `Module.from_object` loads back; the module is
+//! instantiated from it, the object is not linked.
+
+use pyo3::exceptions::ValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyAny, PyBytes};
+
+use crate::compiler::{serialize, target_isa, translate};
+use crate::limits::Limits;
+use crate::module::{CompiledModule, Module};
+use cranelift_codegen::binemit::Reloc;
+use cranelift_codegen::ir::LibCall;
+use cranelift_codegen::isa::TargetIsa;
+use cranelift_wasm::DefinedFuncIndex;
+use faerie::{Artifact, Decl, Link};
+use goblin::elf::section_header::{SHN_LORESERVE, SHN_UNDEF, SHT_NOBITS};
+use goblin::elf::Elf;
+use target_lexicon::{Architecture, BinaryFormat};
+use wasmtime_environ::{Module as ModuleInfo, RelocationTarget};
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Name of the symbol holding the serialized module.
+const MODULE_SYMBOL: &str = "wasmtime_py_module";
+
+const R_X86_64_64: u32 = 1;
+
+fn function_symbol(index: DefinedFuncIndex) -> String {
+    format!("_wasm_function_{}", index.index())
+}
+
+/// Name of the symbol a relocation refers to, if supported.
+fn target_symbol(module: &ModuleInfo, target: &RelocationTarget) -> Option<String> {
+    let name = match *target {
+        RelocationTarget::UserFunc(index) => {
+            return module.defined_func_index(index).map(function_symbol)
+        }
+        RelocationTarget::Memory32Grow => "wasmtime_memory32_grow",
+        RelocationTarget::Memory32Size => "wasmtime_memory32_size",
+        RelocationTarget::ImportedMemory32Grow => "wasmtime_imported_memory32_grow",
+        RelocationTarget::ImportedMemory32Size => "wasmtime_imported_memory32_size",
+        RelocationTarget::LibCall(libcall) => match libcall {
+            LibCall::CeilF32 => "wasmtime_f32_ceil",
+            LibCall::FloorF32 => "wasmtime_f32_floor",
+            LibCall::TruncF32 => "wasmtime_f32_trunc",
+            LibCall::NearestF32 => "wasmtime_f32_nearest",
+            LibCall::CeilF64 => "wasmtime_f64_ceil",
+            LibCall::FloorF64 => "wasmtime_f64_floor",
+            LibCall::TruncF64 => "wasmtime_f64_trunc",
+            LibCall::NearestF64 => "wasmtime_f64_nearest",
+            LibCall::Probestack => "__rust_probestack",
+            _ => return None,
+        },
+        RelocationTarget::JumpTable(..) => return None,
+    };
+    Some(name.to_string())
+}
+
+/// ELF type of the relocation `reloc` for the architecture `arch`.
+fn elf_reloc(arch: Architecture, reloc: Reloc) -> Option<u32> {
+    match (arch, reloc) {
+        (Architecture::X86_64, Reloc::Abs8) => Some(R_X86_64_64),
+        _ => None,
+    }
+}
+
+/// Builds the object of `compiled`, compiled for `isa`.
+pub fn emit_object(isa: &dyn TargetIsa, compiled: &CompiledModule) -> Result<Vec<u8>, String> {
+    let triple = isa.triple().clone();
+    if triple.binary_format != BinaryFormat::Elf {
+        return Err(format!("{}: only ELF targets are supported", triple));
+    }
+    let arch = triple.architecture;
+    let artifact = &compiled.artifact;
+    let module = translate(isa, &artifact.wasm)?.module;
+    let mut obj = Artifact::new(triple, "module.o".to_string());
+
+    for (index, body) in (&artifact.compilation).into_iter().enumerate() {
+        let index = DefinedFuncIndex::new(index);
+        obj.declare_with(
+            function_symbol(index),
+            Decl::function().global(),
+            body.to_vec(),
+        )
+        .map_err(|e| e.to_string())?;
+    }
+
+    let mut imports = HashSet::new();
+    for (index, relocs) in artifact.relocations.iter() {
+        for r in relocs {
+            // Jump tables are within the function, Cranelift already
+            // resolved the references to them.
+            if r.reloc == Reloc::X86PCRelRodata4 {
+                continue;
+            }
+            let to = target_symbol(&module, &r.reloc_target).ok_or_else(|| {
+                format!(
+                    "{}: unsupported relocation target {:?}",
+                    function_symbol(index),
+                    r.reloc_target
+                )
+            })?;
+            let reloc = elf_reloc(arch, r.reloc).ok_or_else(|| {
+                format!(
+                    "{}: unsupported relocation {} for {}",
+                    function_symbol(index),
+                    r.reloc,
+                    arch
+                )
+            })?;
+            let import = match r.reloc_target {
+                RelocationTarget::UserFunc(_) => false,
+                _ => imports.insert(to.clone()),
+            };
+            if import {
+                obj.declare(&to, Decl::function_import())
+                    .map_err(|e| e.to_string())?;
+            }
+            obj.link_with(
+                Link {
+                    from: &function_symbol(index),
+                    to: &to,
+                    at: u64::from(r.offset),
+                },
+                faerie::Reloc::Raw {
+                    reloc,
+                    addend: r.addend as i32,
+                },
+            )
+            .map_err(|e| e.to_string())?;
+        }
+    }
+
+    obj.declare_with(
+        MODULE_SYMBOL,
+        Decl::data().global(),
+        serialize(isa, compiled),
+    )
+    .map_err(|e| e.to_string())?;
+    obj.emit().map_err(|e| e.to_string())
+}
+
+/// Returns the serialized module embedded in the object `data`.
+pub fn embedded_module(data: &[u8]) -> Result<&[u8], String> {
+    let elf = Elf::parse(data).map_err(|e| e.to_string())?;
+    for sym in elf.syms.iter() {
+        match elf.strtab.get(sym.st_name) {
+            Some(Ok(name)) if name == MODULE_SYMBOL => {}
+            _ => continue,
+        }
+        // Reserved indices (undefined, absolute or common symbols) are not
+        // sections.
+        if sym.st_shndx == SHN_UNDEF as usize || sym.st_shndx >= SHN_LORESERVE as usize {
+            return Err("invalid section index".to_string());
+        }
+        let section = elf
+            .section_headers
+            .get(sym.st_shndx)
+            .filter(|section| section.sh_type != SHT_NOBITS)
+            .ok_or("invalid section index")?;
+        sym.st_value
+            .checked_add(sym.st_size)
+            .filter(|end| *end <= section.sh_size)
+            .ok_or("symbol out of its section")?;
+        section
+            .sh_offset
+            .checked_add(section.sh_size)
+            .filter(|end| *end <= data.len() as u64)
+            .ok_or("truncated object")?;
+        let start = (section.sh_offset + sym.st_value) as usize;
+        return Ok(&data[start..start + sym.st_size as usize]);
+    }
+    Err(format!("no {} symbol in the object", MODULE_SYMBOL))
+}
+
+/// Compiles `buffer_source`, the bytes of a module or a `Module`, to an
+/// ELF relocatable object for `target`, a target triple. Objects for the
+/// host can be loaded with `Module.from_object`.
+#[pyfunction(target = "None")]
+pub fn compile_to_object(
+    py: Python,
+    buffer_source: &PyAny,
+    target: Option<&str>,
+) -> PyResult<PyObject> {
+    let module = buffer_source.cast_as::<Module>().ok();
+    // A `Module` keeps its instrumentation, and its target by default.
+    let target = target.or_else(|| module.map(|module| module.compiled.target.as_str()));
+    let isa = target_isa(target).map_err(ValueError::py_err)?;
+    let compiled = match module {
+        Some(module) if module.compiled.target == isa.triple().to_string() => {
+            module.compiled.clone()
+        }
+        Some(module) => Arc::new(CompiledModule::new(
+            py,
+            &module.compiled.source,
+            module.compiled.instrumentation.clone(),
+            isa.as_ref(),
+        )?),
+        None => {
+            let bytes = buffer_source.downcast_ref::<PyBytes>()?;
+            let instrumentation = Limits::default().instrumentation(false);
+            Arc::new(CompiledModule::new(
+                py,
+                bytes.as_bytes(),
+                instrumentation,
+                isa.as_ref(),
+            )?)
+        }
+    };
+    let data = emit_object(isa.as_ref(), &compiled).map_err(ValueError::py_err)?;
+    Ok(PyBytes::new(py, &data).into_object(py))
+}
@@ -0,0 +1,57 @@
+//! Perf map of the generated code.
+//!
+//! `perf report` names the addresses of JIT code after the lines of
+//! `/tmp/perf-<pid>.map`, `START SIZE name` in hexadecimal. Once enabled,
+//! every wasm function and trampoline we emit is appended to it; code
+//! emitted before is not listed.
+
+use pyo3::prelude::*;
+
+use lazy_static::lazy_static;
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Checked before anything else, so that emitting code costs nothing more
+/// when the map is disabled.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref MAP: Mutex<Option<File>> = Mutex::new(None);
+}
+
+/// Lists the `len` bytes of code at `start` as `name()`.
+pub fn record(start: usize, len: usize, name: impl FnOnce() -> String) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let name = name().replace('\n', " ");
+    if let Some(ref mut map) = *MAP.lock().unwrap() {
+        // A single write per line, which perf may read at any time. Failing
+        // to write only makes the profile less readable.
+        let _ = map.write_all(format!("{:x} {:x} {}\n", start, len, name).as_bytes());
+    }
+}
+
+/// Lists the code compiled from now on in `/tmp/perf-<pid>.map`, for `perf`
+/// to name it in profiles; returns the path of the map.
+#[pyfunction]
+pub fn enable_perf_map() -> PyResult<String> {
+    let path = format!("/tmp/perf-{}.map", process::id());
+    let mut map = MAP.lock().unwrap();
+    if map.is_none() {
+        *map = Some(OpenOptions::new().create(true).append(true).open(&path)?);
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+    Ok(path)
+}
+
+/// Stops listing the compiled code; the map is kept.
+#[pyfunction]
+pub fn disable_perf_map() {
+    ENABLED.store(false, Ordering::SeqCst);
+    *MAP.lock().unwrap() = None;
+}
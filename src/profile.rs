@@ -0,0 +1,364 @@
+//! Sampling profiler of the wasm code of an instance.
+//!
+//! While a `Profile` runs, an interval timer of the CPU time of the process
+//! raises `SIGPROF`. Its handler walks the interrupted wasm frames like the
+//! one of traps (see `backtrace.rs`) and, when some of them are code of the
+//! profiled instance, appends their addresses to a buffer allocated
+//! beforehand. The samples are resolved to function labels when the profile
+//! stops. Time spent in host functions called by the wasm code is not
+//! sampled. Profiles are only supported on x86-64 Linux.
+
+use pyo3::exceptions::{RuntimeError, ValueError};
+use pyo3::prelude::*;
+
+use crate::backtrace::labels;
+use crate::instance::Instance;
+use crate::interrupt::duration_from_secs;
+use crate::link::code_owner;
+use wasmtime_jit::InstanceHandle;
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Words of the sample buffer, each sample taking its number of addresses
+/// and then them; samples which do not fit are dropped.
+const BUFFER_WORDS: usize = 1 << 20;
+
+/// Samples of a running profile.
+struct Sampler {
+    /// See `Registration::owner`.
+    owner: usize,
+    buffer: Box<[AtomicUsize]>,
+    used: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+/// The sampler of the running profile, if any.
+static SAMPLER: AtomicPtr<Sampler> = AtomicPtr::new(ptr::null_mut());
+
+/// Signal handlers which may be using `SAMPLER`.
+static HANDLERS: AtomicUsize = AtomicUsize::new(0);
+
+impl Sampler {
+    /// Returns the address lists of the samples; the sampler must no longer
+    /// be in use.
+    fn samples(&self) -> Vec<Vec<usize>> {
+        let used = self.used.load(Ordering::SeqCst).min(self.buffer.len());
+        let mut samples = Vec::new();
+        let mut position = 0;
+        while position < used {
+            let len = self.buffer[position].load(Ordering::SeqCst);
+            if position + 1 + len > used {
+                break;
+            }
+            let addresses = &self.buffer[position + 1..position + 1 + len];
+            samples.push(addresses.iter().map(|a| a.load(Ordering::SeqCst)).collect());
+            position += 1 + len;
+        }
+        samples
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod timer {
+    use super::{Sampler, HANDLERS, SAMPLER};
+    use crate::backtrace::{sample, Captured};
+    use std::io;
+    use std::mem;
+    use std::os::raw::{c_int, c_void};
+    use std::ptr;
+    use std::sync::atomic::Ordering;
+    use std::sync::Once;
+    use std::time::Duration;
+
+    extern "C" {
+        fn setitimer(which: c_int, new: *const libc::itimerval, old: *mut libc::itimerval)
+            -> c_int;
+    }
+
+    static HOOK_SIGPROF: Once = Once::new();
+    static mut PREVIOUS_HANDLER: Option<libc::sigaction> = None;
+
+    unsafe fn record(sampler: &Sampler, context: *mut c_void) {
+        let mut captured = Captured::new();
+        if !sample(context, sampler.owner, &mut captured) {
+            return;
+        }
+        let len = captured.len;
+        let start = sampler.used.fetch_add(1 + len, Ordering::SeqCst);
+        if start + 1 + len > sampler.buffer.len() {
+            sampler.dropped.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+        sampler.buffer[start].store(len, Ordering::SeqCst);
+        for (i, address) in captured.addresses[..len].iter().enumerate() {
+            sampler.buffer[start + 1 + i].store(*address, Ordering::SeqCst);
+        }
+    }
+
+    unsafe extern "C" fn on_sigprof(
+        signum: c_int,
+        info: *mut libc::siginfo_t,
+        context: *mut c_void,
+    ) {
+        let errno = *libc::__errno_location();
+        HANDLERS.fetch_add(1, Ordering::SeqCst);
+        let sampler = SAMPLER.load(Ordering::SeqCst);
+        if !sampler.is_null() {
+            record(&*sampler, context);
+        }
+        HANDLERS.fetch_sub(1, Ordering::SeqCst);
+        *libc::__errno_location() = errno;
+        // Other profilers of the process keep working between our profiles.
+        if sampler.is_null() {
+            if let Some(previous) = PREVIOUS_HANDLER {
+                if previous.sa_flags & libc::SA_SIGINFO != 0 {
+                    let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) =
+                        mem::transmute(previous.sa_sigaction);
+                    handler(signum, info, context);
+                } else if previous.sa_sigaction != libc::SIG_DFL
+                    && previous.sa_sigaction != libc::SIG_IGN
+                {
+                    let handler: extern "C" fn(c_int) = mem::transmute(previous.sa_sigaction);
+                    handler(signum);
+                }
+            }
+        }
+    }
+
+    /// Installs the handler of `SIGPROF` for good: a signal still pending
+    /// when a profile stops must not get the default action, which kills
+    /// the process.
+    fn hook_sigprof() {
+        HOOK_SIGPROF.call_once(|| unsafe {
+            let mut handler: libc::sigaction = mem::zeroed();
+            handler.sa_sigaction = on_sigprof as usize;
+            handler.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut handler.sa_mask);
+            let mut previous: libc::sigaction = mem::zeroed();
+            if libc::sigaction(libc::SIGPROF, &handler, &mut previous) == 0 {
+                PREVIOUS_HANDLER = Some(previous);
+            }
+        });
+    }
+
+    /// Starts raising `SIGPROF` every `interval` of CPU time; returns the
+    /// timer it replaces.
+    pub fn start(interval: Duration) -> io::Result<libc::itimerval> {
+        hook_sigprof();
+        let value = libc::timeval {
+            tv_sec: interval.as_secs() as libc::time_t,
+            tv_usec: interval.subsec_micros() as libc::suseconds_t,
+        };
+        let timer = libc::itimerval {
+            it_interval: value,
+            it_value: value,
+        };
+        unsafe {
+            let mut previous: libc::itimerval = mem::zeroed();
+            if setitimer(libc::ITIMER_PROF, &timer, &mut previous) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(previous)
+        }
+    }
+
+    /// Restores the `previous` timer.
+    pub fn stop(previous: &libc::itimerval) {
+        unsafe {
+            setitimer(libc::ITIMER_PROF, previous, ptr::null_mut());
+        }
+    }
+}
+
+/// A running profile.
+struct Running {
+    sampler: *mut Sampler,
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    previous_timer: libc::itimerval,
+}
+
+/// Samples of the wasm stack of the calls into an instance, see
+/// `wasmtime.profile`.
+#[pyclass]
+pub struct Profile {
+    instance: InstanceHandle,
+    interval: Duration,
+    interval_secs: f64,
+    running: Option<Running>,
+    /// Number of samples by stack of function labels, outermost first.
+    stacks: HashMap<Vec<String>, usize>,
+    dropped: usize,
+}
+
+/// Uninstalls `sampler` once no signal handler uses it.
+fn release(sampler: *mut Sampler) -> Box<Sampler> {
+    SAMPLER.store(ptr::null_mut(), Ordering::SeqCst);
+    while HANDLERS.load(Ordering::SeqCst) != 0 {
+        thread::yield_now();
+    }
+    unsafe { Box::from_raw(sampler) }
+}
+
+impl Profile {
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn start_timer(&self, sampler: *mut Sampler) -> PyResult<Running> {
+        Ok(Running {
+            sampler,
+            previous_timer: timer::start(self.interval)?,
+        })
+    }
+
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+    fn start_timer(&self, _sampler: *mut Sampler) -> PyResult<Running> {
+        Err(RuntimeError::py_err(
+            "profiles are only supported on x86-64 Linux",
+        ))
+    }
+
+    /// Stops sampling and adds the samples taken to the profile.
+    fn finish(&mut self) {
+        let running = match self.running.take() {
+            Some(running) => running,
+            None => return,
+        };
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        timer::stop(&running.previous_timer);
+        let sampler = release(running.sampler);
+        self.dropped += sampler.dropped.load(Ordering::SeqCst);
+        for addresses in sampler.samples() {
+            let mut stack = labels(&addresses);
+            stack.reverse();
+            *self.stacks.entry(stack).or_insert(0) += 1;
+        }
+    }
+
+    fn seconds(&self, samples: usize) -> f64 {
+        samples as f64 * self.interval_secs
+    }
+}
+
+impl Drop for Profile {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[pymethods]
+impl Profile {
+    /// Profiles `instance`, sampling every `interval` seconds of CPU time.
+    #[new]
+    #[args(interval = "0.001")]
+    fn new(obj: &PyRawObject, instance: &Instance, interval: f64) -> PyResult<()> {
+        // The timer counts microseconds, and a zero one would be stopped.
+        if !interval.is_finite() || interval < 1e-6 {
+            return Err(ValueError::py_err(
+                "interval must be at least one microsecond",
+            ));
+        }
+        let interval = duration_from_secs(interval)?;
+        let interval = Duration::new(interval.as_secs(), interval.subsec_micros() * 1000);
+        obj.init(Profile {
+            instance: instance.instance.clone(),
+            interval,
+            interval_secs: interval.as_secs() as f64 + f64::from(interval.subsec_micros()) * 1e-6,
+            running: None,
+            stacks: HashMap::new(),
+            dropped: 0,
+        });
+        Ok(())
+    }
+
+    /// Starts sampling; a single profile runs at a time.
+    fn start(&mut self) -> PyResult<()> {
+        if self.running.is_some() {
+            return Err(RuntimeError::py_err("the profile is already running"));
+        }
+        let owner = code_owner(&mut self.instance)
+            .ok_or_else(|| ValueError::py_err("not an instance of a wasm module"))?;
+        let sampler = Box::into_raw(Box::new(Sampler {
+            owner,
+            buffer: (0..BUFFER_WORDS).map(|_| AtomicUsize::new(0)).collect(),
+            used: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }));
+        let installed =
+            SAMPLER.compare_exchange(ptr::null_mut(), sampler, Ordering::SeqCst, Ordering::SeqCst);
+        if installed.is_err() {
+            drop(unsafe { Box::from_raw(sampler) });
+            return Err(RuntimeError::py_err("another profile is running"));
+        }
+        match self.start_timer(sampler) {
+            Ok(running) => {
+                self.running = Some(running);
+                Ok(())
+            }
+            Err(err) => {
+                release(sampler);
+                Err(err)
+            }
+        }
+    }
+
+    /// Stops sampling; the profile can be started again to add samples.
+    fn stop(&mut self) {
+        self.finish();
+    }
+
+    /// Number of samples taken.
+    #[getter(samples)]
+    fn get_samples(&self) -> usize {
+        self.stacks.values().sum()
+    }
+
+    /// Number of samples lost because the buffer of a run was full.
+    #[getter(dropped)]
+    fn get_dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// `(function, self_time, total_time)` of the sampled functions, in
+    /// seconds of CPU time, by decreasing self time. The self time of a
+    /// function is spent in its own code, the total time includes the
+    /// functions it calls.
+    fn functions(&self) -> Vec<(String, f64, f64)> {
+        let mut counts: HashMap<&str, (usize, usize)> = HashMap::new();
+        for (stack, samples) in &self.stacks {
+            if let Some(innermost) = stack.last() {
+                counts.entry(innermost).or_insert((0, 0)).0 += samples;
+            }
+            // Recursive functions are counted once per sample.
+            let functions: HashSet<&str> = stack.iter().map(String::as_str).collect();
+            for function in functions {
+                counts.entry(function).or_insert((0, 0)).1 += samples;
+            }
+        }
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by_key(|(function, (own, total))| (Reverse(*own), Reverse(*total), *function));
+        counts
+            .into_iter()
+            .map(|(function, (own, total))| {
+                (function.to_string(), self.seconds(own), self.seconds(total))
+            })
+            .collect()
+    }
+
+    /// The samples in the collapsed stack format of flame graph tools: a
+    /// line per stack, its functions outermost first separated by `;`, then
+    /// its number of samples.
+    fn collapsed(&self) -> String {
+        let stacks: BTreeMap<String, usize> = self
+            .stacks
+            .iter()
+            .map(|(stack, samples)| (stack.join(";"), *samples))
+            .collect();
+        stacks
+            .iter()
+            .map(|(stack, samples)| format!("{} {}\n", stack, samples))
+            .collect()
+    }
+}
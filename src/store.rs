@@ -0,0 +1,225 @@
+//! WebAssembly Store API object.
+
+use pyo3::prelude::*;
+
+use crate::interrupt::duration_from_secs;
+use crate::limits::{Limits, ResourceLimiter};
+use crate::link::Linker;
+use crate::trace::Tracer;
+use crate::trampoline::Trampolines;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::Duration;
+
+use cranelift_codegen::isa;
+
+/// State shared by a store and everything instantiated in it.
+pub struct StoreState {
+    pub linker: Mutex<Linker>,
+    /// Held while wasm code of the store runs.
+    pub execution: ExecutionLock,
+    pub trampolines: Mutex<Trampolines>,
+    /// Timeout of export calls made without an explicit `timeout`.
+    pub timeout: Mutex<Option<Duration>>,
+    pub limits: Arc<Limits>,
+    /// Directory core dumps of trapping calls are written to.
+    pub coredump_dir: Mutex<Option<PathBuf>>,
+    /// Callback of the calls of all the instances of the store.
+    pub tracer: Arc<Tracer>,
+    /// Number of live instances of modules, see `InstanceSlot`.
+    instances: Arc<AtomicUsize>,
+}
+
+// The linker and the instances of a store are not thread-safe on their own
+// (`Rc`s, raw pointers, the non-atomic `InstanceHandle` refcount), they are
+// only touched with the GIL or the corresponding mutex held. Running wasm
+// code, which happens without the GIL, only uses its `vmctx` and holds the
+// `execution` lock; host functions reacquire the GIL before creating
+// handles from the `vmctx` or calling into Python.
+unsafe impl Send for StoreState {}
+unsafe impl Sync for StoreState {}
+
+pub fn native_isa() -> Box<dyn isa::TargetIsa> {
+    let isa_builder = cranelift_native::builder().expect("host machine is not a supported target");
+    let flag_builder = cranelift_codegen::settings::builder();
+    isa_builder.finish(cranelift_codegen::settings::Flags::new(flag_builder))
+}
+
+impl StoreState {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            linker: Mutex::new(Linker::new(native_isa())),
+            execution: ExecutionLock::new(),
+            trampolines: Mutex::new(Trampolines::new(native_isa())),
+            timeout: Mutex::new(None),
+            limits: Arc::new(limits),
+            coredump_dir: Mutex::new(None),
+            tracer: Arc::new(Tracer::default()),
+            instances: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        *self.timeout.lock().unwrap()
+    }
+
+    pub fn coredump_dir(&self) -> Option<PathBuf> {
+        self.coredump_dir.lock().unwrap().clone()
+    }
+
+    /// Takes a place for a new instance of a module, within the `instances`
+    /// limit; instantiations running concurrently can't both take the last
+    /// one.
+    pub fn reserve_instance(&self) -> PyResult<InstanceSlot> {
+        let mut count = self.instances.load(Ordering::SeqCst);
+        loop {
+            self.limits.check_instances(count)?;
+            match self.instances.compare_exchange(
+                count,
+                count + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    return Ok(InstanceSlot {
+                        instances: self.instances.clone(),
+                    })
+                }
+                Err(current) => count = current,
+            }
+        }
+    }
+}
+
+/// A place among the instances of a store, held by the host state of an
+/// instance and given back when it is dropped, or when the instantiation
+/// fails.
+pub struct InstanceSlot {
+    instances: Arc<AtomicUsize>,
+}
+
+impl Drop for InstanceSlot {
+    fn drop(&mut self) {
+        self.instances.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Reentrant lock serializing the execution of the wasm code of a store
+/// between threads. A thread running wasm code may re-enter it, e.g. when
+/// an imported Python function calls another export of the store.
+pub struct ExecutionLock {
+    owner: Mutex<(Option<ThreadId>, usize)>,
+    released: Condvar,
+}
+
+impl ExecutionLock {
+    fn new() -> Self {
+        Self {
+            owner: Mutex::new((None, 0)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until the current thread owns the lock. The GIL is released
+    /// while waiting, since the owner may need it to make progress.
+    pub fn lock(&self, py: Python) -> ExecutionGuard {
+        py.allow_threads(|| {
+            let current = thread::current().id();
+            let mut owner = self.owner.lock().unwrap();
+            while owner.0.map_or(false, |id| id != current) {
+                owner = self.released.wait(owner).unwrap();
+            }
+            owner.0 = Some(current);
+            owner.1 += 1;
+        });
+        ExecutionGuard { lock: self }
+    }
+
+    fn unlock(&self) {
+        let mut owner = self.owner.lock().unwrap();
+        owner.1 -= 1;
+        if owner.1 == 0 {
+            owner.0 = None;
+            self.released.notify_one();
+        }
+    }
+}
+
+pub struct ExecutionGuard<'a> {
+    lock: &'a ExecutionLock,
+}
+
+impl<'a> Drop for ExecutionGuard<'a> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+
+/// Store of instances. Export calls, instantiations and memory growths in a
+/// store are serialized, so its objects can be shared between threads; use
+/// separate stores to run wasm code in parallel.
+#[pyclass]
+pub struct Store {
+    pub state: Arc<StoreState>,
+}
+
+#[pymethods]
+impl Store {
+    #[new]
+    #[args(timeout = "None", limiter = "None", coredump_dir = "None")]
+    fn new(
+        obj: &PyRawObject,
+        timeout: Option<f64>,
+        limiter: Option<&ResourceLimiter>,
+        coredump_dir: Option<String>,
+    ) -> PyResult<()> {
+        let limits = match limiter {
+            Some(limiter) => limiter.to_limits(obj.py()),
+            None => Limits::default(),
+        };
+        let state = StoreState::new(limits);
+        *state.timeout.lock().unwrap() = timeout.map(duration_from_secs).transpose()?;
+        *state.coredump_dir.lock().unwrap() = coredump_dir.map(PathBuf::from);
+        obj.init(Store {
+            state: Arc::new(state),
+        });
+        Ok(())
+    }
+
+    #[getter(timeout)]
+    fn get_timeout(&self) -> Option<f64> {
+        self.state
+            .timeout()
+            .map(|t| t.as_secs() as f64 + f64::from(t.subsec_nanos()) * 1e-9)
+    }
+
+    #[setter(timeout)]
+    fn set_timeout(&mut self, timeout: Option<f64>) -> PyResult<()> {
+        *self.state.timeout.lock().unwrap() = timeout.map(duration_from_secs).transpose()?;
+        Ok(())
+    }
+
+    /// Directory to which a core dump is written when an export call of the
+    /// store traps; `None` (the default) writes none.
+    #[getter(coredump_dir)]
+    fn get_coredump_dir(&self) -> Option<String> {
+        self.state
+            .coredump_dir()
+            .map(|dir| dir.display().to_string())
+    }
+
+    #[setter(coredump_dir)]
+    fn set_coredump_dir(&mut self, coredump_dir: Option<String>) -> PyResult<()> {
+        *self.state.coredump_dir.lock().unwrap() = coredump_dir.map(PathBuf::from);
+        Ok(())
+    }
+
+    /// Calls `callback(event, name, values)` around every export call and
+    /// every call of a Python host function of the instances of the store;
+    /// `None` stops tracing. See `Instance.set_trace`.
+    fn set_trace(&self, callback: Option<PyObject>) {
+        self.state.tracer.set(callback);
+    }
+}
@@ -0,0 +1,548 @@
+//! Host instance providing the `__wasmtime` imports of instrumented modules.
+
+use pyo3::prelude::*;
+
+use crate::atomics::{self, RmwOp};
+use crate::interrupt::{sigint_flag, InterruptState};
+use crate::limits::Limits;
+use crate::memory::{grow_memory, memory_state};
+use crate::trampoline::host_function;
+use crate::trap::{or_raise, Trap};
+use cranelift_codegen::ir::types;
+use cranelift_codegen::{ir, isa};
+use cranelift_entity::{EntityRef, PrimaryMap};
+use cranelift_wasm::{DefinedFuncIndex, Global, GlobalIndex, GlobalInit, MemoryIndex};
+use target_lexicon::HOST;
+use wasmtime_environ::{Export, Module, WASM_PAGE_SIZE};
+use wasmtime_runtime::{
+    Imports, InstanceHandle, VMContext, VMFunctionBody, VMGlobalDefinition, VMGlobalImport,
+    VMMemoryDefinition,
+};
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The memory of the instrumented instance, see `attach_memory`.
+struct MemoryRef {
+    definition: *mut VMMemoryDefinition,
+    /// The instance defining the memory.
+    owner: *mut VMContext,
+}
+
+struct SupportState {
+    interrupt: Arc<InterruptState>,
+    limits: Arc<Limits>,
+    memory: Option<MemoryRef>,
+}
+
+/// Returns the state of the support instance `vmctx`. Unlike going through
+/// `InstanceHandle::from_vmctx`, this doesn't need the GIL.
+unsafe fn support_state<'a>(vmctx: *mut VMContext) -> &'a SupportState {
+    (*vmctx)
+        .host_state()
+        .downcast_ref::<SupportState>()
+        .expect("state")
+}
+
+/// Re-exports a host-owned `i32` as a mutable wasm global.
+fn add_global(
+    module: &mut Module,
+    globals: &mut PrimaryMap<GlobalIndex, VMGlobalImport>,
+    name: &str,
+    from: *mut VMGlobalDefinition,
+) {
+    let global_id = module.globals.push(Global {
+        ty: types::I32,
+        mutability: true,
+        initializer: GlobalInit::Import,
+    });
+    let _global_id_2 = module
+        .imported_globals
+        .push((String::from(""), String::from("")));
+    assert_eq!(global_id, _global_id_2);
+    let _global_id_3 = globals.push(VMGlobalImport { from });
+    assert_eq!(global_id, _global_id_3);
+    module
+        .exports
+        .insert(name.to_string(), Export::Global(global_id));
+}
+
+/// Exports a host function; `body` has to be an `extern "C"` function
+/// taking the `vmctx` followed by the `params`. Wasm code calls it through
+/// a trampoline, so that it can make the export call in progress raise an
+/// error with `trap::raise`.
+pub fn add_function(
+    module: &mut Module,
+    finished_functions: &mut PrimaryMap<DefinedFuncIndex, *const VMFunctionBody>,
+    name: &str,
+    params: &[ir::Type],
+    returns: &[ir::Type],
+    body: *const VMFunctionBody,
+) {
+    let pointer_type = types::Type::triple_pointer_type(&HOST);
+    let call_conv = isa::CallConv::triple_default(&HOST);
+    let mut sig = ir::Signature::new(call_conv);
+    sig.params.push(ir::AbiParam::special(
+        pointer_type,
+        ir::ArgumentPurpose::VMContext,
+    ));
+    sig.params
+        .extend(params.iter().map(|ty| ir::AbiParam::new(*ty)));
+    sig.returns
+        .extend(returns.iter().map(|ty| ir::AbiParam::new(*ty)));
+
+    let trampoline = host_function(body, &sig, || {
+        format!("trampoline to host function {}", name)
+    });
+    let sig_id = module.signatures.push(sig);
+    let func_id = module.functions.push(sig_id);
+    module
+        .exports
+        .insert(name.to_string(), Export::Function(func_id));
+    finished_functions.push(trampoline);
+}
+
+/// Target of the interrupt checks of instrumented code, right before they
+/// trap: records which requests stopped the guest.
+unsafe extern "C" fn interrupted(vmctx: *mut VMContext) {
+    support_state(vmctx).interrupt.stop();
+}
+
+/// Target of instrumented `memory.grow` instructions: returns `delta` if the
+/// store limits allow the growth, or a delta that makes `memory.grow` fail.
+unsafe extern "C" fn memory_grow(vmctx: *mut VMContext, delta: u32, current: u32) -> u32 {
+    let result = {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let mut instance = InstanceHandle::from_vmctx(vmctx);
+        let state = instance
+            .host_state()
+            .downcast_mut::<SupportState>()
+            .expect("state");
+        let page_size = WASM_PAGE_SIZE as usize;
+        let current = current as usize * page_size;
+        let desired = current + delta as usize * page_size;
+        state.limits.allow_memory_grow(py, current, desired)
+    };
+    or_raise(result.map(|allowed| if allowed { delta } else { u32::max_value() }))
+}
+
+/// The `Trap` raised with `message` by the running export call, see
+/// `or_raise`.
+fn trap<T>(message: &str) -> PyResult<T> {
+    let _gil = Python::acquire_gil();
+    Err(Trap::py_err(message.to_string()))
+}
+
+unsafe fn attached_memory<'a>(vmctx: *mut VMContext) -> PyResult<&'a MemoryRef> {
+    match support_state(vmctx).memory {
+        Some(ref memory) => Ok(memory),
+        None => trap("memory is not available before the instantiation is finished"),
+    }
+}
+
+/// Target of `memory.grow` on shared memories: same as `memory_grow`
+/// followed by the growth itself, which has to be serialized with the
+/// other users of the memory.
+unsafe extern "C" fn memory_grow_shared(vmctx: *mut VMContext, delta: u32) -> u32 {
+    let memory = match attached_memory(vmctx) {
+        Ok(memory) => memory,
+        Err(err) => return or_raise(Err(err)),
+    };
+    let result = {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let page_size = WASM_PAGE_SIZE as usize;
+        let current = (*memory.definition).current_length;
+        let desired = current + delta as usize * page_size;
+        match support_state(vmctx)
+            .limits
+            .allow_memory_grow(py, current, desired)
+        {
+            Ok(true) => Ok(grow_memory(memory.owner, memory.definition, delta)),
+            Ok(false) => Ok(None),
+            Err(err) => Err(err),
+        }
+    };
+    or_raise(result.map(|previous| previous.unwrap_or(u32::max_value())))
+}
+
+/// Checks an atomic access of `size` bytes at `address + offset` and
+/// returns a pointer to it.
+unsafe fn atomic_address(
+    vmctx: *mut VMContext,
+    address: u32,
+    offset: u32,
+    size: usize,
+) -> PyResult<*mut u8> {
+    let definition = &*attached_memory(vmctx)?.definition;
+    let address = u64::from(address) + u64::from(offset);
+    if address % size as u64 != 0 {
+        return trap("unaligned atomic memory access");
+    }
+    if address + size as u64 > definition.current_length as u64 {
+        return trap("out of bounds memory access");
+    }
+    Ok(definition.base.add(address as usize))
+}
+
+unsafe extern "C" fn atomic_load_i32(
+    vmctx: *mut VMContext,
+    address: u32,
+    offset: u32,
+    code: u32,
+) -> u32 {
+    let size = atomics::width(code);
+    or_raise(
+        atomic_address(vmctx, address, offset, size).map(|ptr| atomics::load(ptr, size) as u32),
+    )
+}
+
+unsafe extern "C" fn atomic_load_i64(
+    vmctx: *mut VMContext,
+    address: u32,
+    offset: u32,
+    code: u32,
+) -> u64 {
+    let size = atomics::width(code);
+    or_raise(atomic_address(vmctx, address, offset, size).map(|ptr| atomics::load(ptr, size)))
+}
+
+unsafe extern "C" fn atomic_store_i32(
+    vmctx: *mut VMContext,
+    address: u32,
+    value: u32,
+    offset: u32,
+    code: u32,
+) {
+    let size = atomics::width(code);
+    or_raise(
+        atomic_address(vmctx, address, offset, size)
+            .map(|ptr| atomics::store(ptr, size, u64::from(value))),
+    )
+}
+
+unsafe extern "C" fn atomic_store_i64(
+    vmctx: *mut VMContext,
+    address: u32,
+    value: u64,
+    offset: u32,
+    code: u32,
+) {
+    let size = atomics::width(code);
+    or_raise(
+        atomic_address(vmctx, address, offset, size).map(|ptr| atomics::store(ptr, size, value)),
+    )
+}
+
+unsafe extern "C" fn atomic_rmw_i32(
+    vmctx: *mut VMContext,
+    address: u32,
+    value: u32,
+    offset: u32,
+    code: u32,
+) -> u32 {
+    let size = atomics::width(code);
+    or_raise(
+        atomic_address(vmctx, address, offset, size)
+            .map(|ptr| atomics::rmw(ptr, size, RmwOp::from_code(code), u64::from(value)) as u32),
+    )
+}
+
+unsafe extern "C" fn atomic_rmw_i64(
+    vmctx: *mut VMContext,
+    address: u32,
+    value: u64,
+    offset: u32,
+    code: u32,
+) -> u64 {
+    let size = atomics::width(code);
+    or_raise(
+        atomic_address(vmctx, address, offset, size)
+            .map(|ptr| atomics::rmw(ptr, size, RmwOp::from_code(code), value)),
+    )
+}
+
+unsafe extern "C" fn atomic_cmpxchg_i32(
+    vmctx: *mut VMContext,
+    address: u32,
+    expected: u32,
+    replacement: u32,
+    offset: u32,
+    code: u32,
+) -> u32 {
+    let size = atomics::width(code);
+    or_raise(
+        atomic_address(vmctx, address, offset, size).map(|ptr| {
+            atomics::cmpxchg(ptr, size, u64::from(expected), u64::from(replacement)) as u32
+        }),
+    )
+}
+
+unsafe extern "C" fn atomic_cmpxchg_i64(
+    vmctx: *mut VMContext,
+    address: u32,
+    expected: u64,
+    replacement: u64,
+    offset: u32,
+    code: u32,
+) -> u64 {
+    let size = atomics::width(code);
+    or_raise(
+        atomic_address(vmctx, address, offset, size)
+            .map(|ptr| atomics::cmpxchg(ptr, size, expected, replacement)),
+    )
+}
+
+unsafe extern "C" fn atomic_notify(
+    vmctx: *mut VMContext,
+    address: u32,
+    count: u32,
+    offset: u32,
+) -> u32 {
+    or_raise(atomic_address(vmctx, address, offset, 4).and_then(|_| {
+        Ok(match memory_state(attached_memory(vmctx)?.owner) {
+            Some(state) if state.shared => state
+                .parking_lot
+                .notify(u64::from(address) + u64::from(offset), count),
+            // Nobody can wait on an unshared memory.
+            _ => 0,
+        })
+    }))
+}
+
+/// Implements `i32.atomic.wait` and `i64.atomic.wait`; a negative
+/// `timeout` (in nanoseconds) waits forever.
+unsafe fn atomic_wait(
+    vmctx: *mut VMContext,
+    address: u32,
+    expected: u64,
+    timeout: i64,
+    offset: u32,
+    size: usize,
+) -> PyResult<u32> {
+    let ptr = atomic_address(vmctx, address, offset, size)?;
+    let state = match memory_state(attached_memory(vmctx)?.owner) {
+        Some(state) if state.shared => state,
+        _ => return trap("atomic wait on an unshared memory"),
+    };
+    let timeout = if timeout < 0 {
+        None
+    } else {
+        Some(Duration::from_nanos(timeout as u64))
+    };
+    let interrupt = &support_state(vmctx).interrupt;
+    Ok(state.parking_lot.wait(
+        u64::from(address) + u64::from(offset),
+        || atomics::load(ptr, size) == expected,
+        timeout,
+        || interrupt.is_requested(),
+    ))
+}
+
+unsafe extern "C" fn atomic_wait_i32(
+    vmctx: *mut VMContext,
+    address: u32,
+    expected: u32,
+    timeout: i64,
+    offset: u32,
+) -> u32 {
+    or_raise(atomic_wait(
+        vmctx,
+        address,
+        u64::from(expected),
+        timeout,
+        offset,
+        4,
+    ))
+}
+
+unsafe extern "C" fn atomic_wait_i64(
+    vmctx: *mut VMContext,
+    address: u32,
+    expected: u64,
+    timeout: i64,
+    offset: u32,
+) -> u32 {
+    or_raise(atomic_wait(vmctx, address, expected, timeout, offset, 8))
+}
+
+/// Gives the support instance access to the memory of the instrumented
+/// `instance`, once it is instantiated.
+pub fn attach_memory(support: &mut InstanceHandle, instance: &mut InstanceHandle) {
+    if instance.module_ref().memory_plans.is_empty() {
+        return;
+    }
+    let memory = match instance.lookup_by_declaration(&Export::Memory(MemoryIndex::new(0))) {
+        wasmtime_runtime::Export::Memory {
+            definition, vmctx, ..
+        } => MemoryRef {
+            definition,
+            owner: vmctx,
+        },
+        _ => panic!("memory is expected"),
+    };
+    let state = support
+        .host_state()
+        .downcast_mut::<SupportState>()
+        .expect("state");
+    state.memory = Some(memory);
+}
+
+/// Creates the instance to be named `instrument::SUPPORT_MODULE` in the
+/// context of an instrumented module.
+pub fn instantiate_support(
+    global_exports: Rc<RefCell<HashMap<String, Option<wasmtime_runtime::Export>>>>,
+    interrupt: Arc<InterruptState>,
+    limits: Arc<Limits>,
+) -> InstanceHandle {
+    let mut module = Module::new();
+    let mut finished_functions = PrimaryMap::new();
+    let mut globals = PrimaryMap::new();
+    add_function(
+        &mut module,
+        &mut finished_functions,
+        "interrupted",
+        &[],
+        &[],
+        interrupted as *const VMFunctionBody,
+    );
+    add_function(
+        &mut module,
+        &mut finished_functions,
+        "memory_grow",
+        &[types::I32, types::I32],
+        &[types::I32],
+        memory_grow as *const VMFunctionBody,
+    );
+    add_function(
+        &mut module,
+        &mut finished_functions,
+        "memory_grow_shared",
+        &[types::I32],
+        &[types::I32],
+        memory_grow_shared as *const VMFunctionBody,
+    );
+    let (i32, i64) = (types::I32, types::I64);
+    let atomic_functions: [(&str, &[ir::Type], &[ir::Type], *const VMFunctionBody); 11] = [
+        (
+            "atomic_load_i32",
+            &[i32, i32, i32],
+            &[i32],
+            atomic_load_i32 as _,
+        ),
+        (
+            "atomic_load_i64",
+            &[i32, i32, i32],
+            &[i64],
+            atomic_load_i64 as _,
+        ),
+        (
+            "atomic_store_i32",
+            &[i32, i32, i32, i32],
+            &[],
+            atomic_store_i32 as _,
+        ),
+        (
+            "atomic_store_i64",
+            &[i32, i64, i32, i32],
+            &[],
+            atomic_store_i64 as _,
+        ),
+        (
+            "atomic_rmw_i32",
+            &[i32, i32, i32, i32],
+            &[i32],
+            atomic_rmw_i32 as _,
+        ),
+        (
+            "atomic_rmw_i64",
+            &[i32, i64, i32, i32],
+            &[i64],
+            atomic_rmw_i64 as _,
+        ),
+        (
+            "atomic_cmpxchg_i32",
+            &[i32, i32, i32, i32, i32],
+            &[i32],
+            atomic_cmpxchg_i32 as _,
+        ),
+        (
+            "atomic_cmpxchg_i64",
+            &[i32, i64, i64, i32, i32],
+            &[i64],
+            atomic_cmpxchg_i64 as _,
+        ),
+        (
+            "atomic_notify",
+            &[i32, i32, i32],
+            &[i32],
+            atomic_notify as _,
+        ),
+        (
+            "atomic_wait_i32",
+            &[i32, i32, i64, i32],
+            &[i32],
+            atomic_wait_i32 as _,
+        ),
+        (
+            "atomic_wait_i64",
+            &[i32, i64, i64, i32],
+            &[i32],
+            atomic_wait_i64 as _,
+        ),
+    ];
+    for (name, params, returns, body) in atomic_functions.iter() {
+        add_function(
+            &mut module,
+            &mut finished_functions,
+            name,
+            params,
+            returns,
+            *body,
+        );
+    }
+    add_global(
+        &mut module,
+        &mut globals,
+        "interrupt",
+        interrupt.flag().as_global(),
+    );
+    if interrupt.handle_sigint() {
+        add_global(
+            &mut module,
+            &mut globals,
+            "sigint",
+            sigint_flag().as_global(),
+        );
+    }
+
+    let imports = Imports::new(
+        HashSet::new(),
+        PrimaryMap::new(),
+        PrimaryMap::new(),
+        PrimaryMap::new(),
+        globals,
+    );
+    let data_initializers = Vec::new();
+    let signatures = PrimaryMap::new();
+
+    InstanceHandle::new(
+        Rc::new(module),
+        global_exports,
+        finished_functions.into_boxed_slice(),
+        imports,
+        &data_initializers,
+        signatures.into_boxed_slice(),
+        None,
+        Box::new(SupportState {
+            interrupt,
+            limits,
+            memory: None,
+        }),
+    )
+    .expect("support instance")
+}
@@ -0,0 +1,122 @@
+//! Tracing of the calls crossing the boundary between the host and the
+//! wasm code: export calls and calls of Python host functions.
+
+use pyo3::prelude::*;
+use pyo3::types::{PyAny, PyTuple};
+
+use crate::trap::update_exception;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The `set_trace` callback of a store or an instance.
+#[derive(Default)]
+pub struct Tracer {
+    /// Checked before anything else, so that calls cost nothing more when
+    /// no callback is set.
+    enabled: AtomicBool,
+    callback: Mutex<Option<PyObject>>,
+}
+
+impl Tracer {
+    /// Sets the callback; `None` stops tracing.
+    pub fn set(&self, callback: Option<PyObject>) {
+        let mut current = self.callback.lock().unwrap();
+        self.enabled.store(callback.is_some(), Ordering::SeqCst);
+        *current = callback;
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn emit(&self, py: Python, event: &str, name: &str, values: &PyAny) -> PyResult<()> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let callback = match *self.callback.lock().unwrap() {
+            Some(ref callback) => callback.clone_ref(py),
+            None => return Ok(()),
+        };
+        callback.call1(py, (event, name, values))?;
+        Ok(())
+    }
+}
+
+/// The side of the boundary a traced call goes to.
+#[derive(Clone, Copy)]
+pub enum Callee {
+    /// An export of the instance.
+    Wasm,
+    /// A Python function imported by the instance.
+    Host,
+}
+
+impl Callee {
+    fn events(self) -> (&'static str, &'static str) {
+        match self {
+            Callee::Wasm => ("call_wasm", "return_wasm"),
+            Callee::Host => ("call_host", "return_host"),
+        }
+    }
+}
+
+/// The tracers of the calls of an instance: the ones of its store and of
+/// the instance itself.
+#[derive(Clone)]
+pub struct Tracers {
+    pub store: Arc<Tracer>,
+    pub instance: Arc<Tracer>,
+}
+
+impl Tracers {
+    pub fn enabled(&self) -> bool {
+        self.store.enabled() || self.instance.enabled()
+    }
+
+    /// Makes the call `name` to `callee` with `args`, calling the callbacks
+    /// with `(event, name, values)` before and after it. `values` are the
+    /// arguments or the results of the call, or the exception it raised.
+    pub fn trace(
+        &self,
+        py: Python,
+        callee: Callee,
+        name: &str,
+        args: &PyTuple,
+        call: impl FnOnce() -> PyResult<PyObject>,
+    ) -> PyResult<PyObject> {
+        let (call_event, return_event) = callee.events();
+        self.emit(py, call_event, name, args.as_ref())?;
+        match call() {
+            Ok(result) => {
+                self.emit(py, return_event, name, results(py, result.as_ref(py)))?;
+                Ok(result)
+            }
+            Err(err) => {
+                let mut traced = Ok(());
+                let err = update_exception(py, err, |value| {
+                    traced = self.emit(py, return_event, name, value);
+                });
+                traced?;
+                Err(err)
+            }
+        }
+    }
+
+    fn emit(&self, py: Python, event: &str, name: &str, values: &PyAny) -> PyResult<()> {
+        self.store.emit(py, event, name, values)?;
+        self.instance.emit(py, event, name, values)
+    }
+}
+
+/// The results of a call as a tuple, from the value it returns: empty for
+/// `None`.
+fn results<'p>(py: Python<'p>, value: &'p PyAny) -> &'p PyAny {
+    if value.is_none() {
+        PyTuple::empty(py).as_ref()
+    } else if value.downcast_ref::<PyTuple>().is_ok() {
+        value
+    } else {
+        PyTuple::new(py, &[value]).as_ref()
+    }
+}
@@ -0,0 +1,281 @@
+//! Trampolines for calling exported wasm functions from the host, and host
+//! functions from wasm code.
+
+use crate::backtrace;
+use crate::code_memory::CodeMemory;
+use crate::import::RelocSink;
+use crate::perf;
+use crate::store::native_isa;
+use crate::trap::error_pending;
+use cranelift_codegen::ir::{types, InstBuilder};
+use cranelift_codegen::Context;
+use cranelift_codegen::{binemit, ir, isa};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use lazy_static::lazy_static;
+use wasmtime_runtime::{wasmtime_call_trampoline, VMContext, VMFunctionBody};
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Mutex;
+
+/// Size of a slot of the `values_vec` passed to the trampolines.
+pub const VALUE_SIZE: usize = mem::size_of::<u64>();
+
+/// Trampolines of a store, one per called function.
+pub struct Trampolines {
+    isa: Box<dyn isa::TargetIsa>,
+    code_memory: CodeMemory,
+    fn_builder_ctx: FunctionBuilderContext,
+    /// By callee and signature: the code of an instance is freed with it,
+    /// and a later instance of the store can have a function of another
+    /// signature at the same address.
+    trampolines: HashMap<(*const VMFunctionBody, ir::Signature), *const VMFunctionBody>,
+}
+
+impl Trampolines {
+    pub fn new(isa: Box<dyn isa::TargetIsa>) -> Self {
+        Self {
+            isa,
+            code_memory: CodeMemory::new(),
+            fn_builder_ctx: FunctionBuilderContext::new(),
+            trampolines: HashMap::new(),
+        }
+    }
+
+    /// Returns the (published) trampoline for calling `callee`, named
+    /// `name()` in the perf map.
+    pub fn get(
+        &mut self,
+        callee: *const VMFunctionBody,
+        signature: &ir::Signature,
+        name: impl FnOnce() -> String,
+    ) -> *const VMFunctionBody {
+        let key = (callee, signature.clone());
+        if let Some(trampoline) = self.trampolines.get(&key) {
+            return *trampoline;
+        }
+        let trampoline = make_trampoline(
+            self.isa.as_ref(),
+            &mut self.code_memory,
+            &mut self.fn_builder_ctx,
+            callee,
+            signature,
+            name,
+        );
+        self.code_memory.publish();
+        self.trampolines.insert(key, trampoline);
+        trampoline
+    }
+}
+
+/// A prepared call of a trampoline, which can be performed without the GIL.
+pub struct Call {
+    pub vmctx: *mut VMContext,
+    pub trampoline: *const VMFunctionBody,
+    pub values_vec: *mut i64,
+}
+
+// The call only touches the instance through its `vmctx`; everything that
+// needs the GIL (e.g. Python host functions) reacquires it.
+unsafe impl Send for Call {}
+
+impl Call {
+    /// Runs the wasm code, returning the trap message if it traps.
+    pub fn invoke(self) -> Result<(), String> {
+        unsafe { wasmtime_call_trampoline(self.vmctx, self.trampoline, self.values_vec as *mut u8) }
+    }
+}
+
+/// Create a trampoline for invoking a function.
+fn make_trampoline(
+    isa: &dyn isa::TargetIsa,
+    code_memory: &mut CodeMemory,
+    fn_builder_ctx: &mut FunctionBuilderContext,
+    callee_address: *const VMFunctionBody,
+    signature: &ir::Signature,
+    name: impl FnOnce() -> String,
+) -> *const VMFunctionBody {
+    // Copy of the similar method from wasmtime's wasmtime-jit/src/compiler.rs.
+    let pointer_type = isa.pointer_type();
+    let mut wrapper_sig = ir::Signature::new(isa.frontend_config().default_call_conv);
+
+    // Add the `vmctx` parameter.
+    wrapper_sig.params.push(ir::AbiParam::special(
+        pointer_type,
+        ir::ArgumentPurpose::VMContext,
+    ));
+
+    // Add the `values_vec` parameter.
+    wrapper_sig.params.push(ir::AbiParam::new(pointer_type));
+
+    let mut context = Context::new();
+    context.func = ir::Function::with_name_signature(ir::ExternalName::user(0, 0), wrapper_sig);
+
+    {
+        let mut builder = FunctionBuilder::new(&mut context.func, fn_builder_ctx);
+        let block0 = builder.create_ebb();
+
+        builder.append_ebb_params_for_function_params(block0);
+        builder.switch_to_block(block0);
+        builder.seal_block(block0);
+
+        let (vmctx_ptr_val, values_vec_ptr_val) = {
+            let params = builder.func.dfg.ebb_params(block0);
+            (params[0], params[1])
+        };
+
+        // Load the argument values out of `values_vec`.
+        let mflags = ir::MemFlags::trusted();
+        let callee_args = signature
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, r)| {
+                match r.purpose {
+                    // i - 1 because vmctx isn't passed through `values_vec`.
+                    ir::ArgumentPurpose::Normal => builder.ins().load(
+                        r.value_type,
+                        mflags,
+                        values_vec_ptr_val,
+                        ((i - 1) * VALUE_SIZE) as i32,
+                    ),
+                    ir::ArgumentPurpose::VMContext => vmctx_ptr_val,
+                    other => panic!("unsupported argument purpose {}", other),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let new_sig = builder.import_signature(signature.clone());
+
+        let callee_value = builder.ins().iconst(pointer_type, callee_address as i64);
+        let call = builder
+            .ins()
+            .call_indirect(new_sig, callee_value, &callee_args);
+
+        let results = builder.func.dfg.inst_results(call).to_vec();
+
+        // Store the return values into `values_vec`.
+        let mflags = ir::MemFlags::trusted();
+        for (i, r) in results.iter().enumerate() {
+            builder
+                .ins()
+                .store(mflags, *r, values_vec_ptr_val, (i * VALUE_SIZE) as i32);
+        }
+
+        builder.ins().return_(&[]);
+        builder.finalize()
+    }
+
+    emit(isa, code_memory, &mut context, name).0
+}
+
+/// Compiles the trampoline of `context` into `code_memory`, and returns it
+/// with the length of its code.
+fn emit(
+    isa: &dyn isa::TargetIsa,
+    code_memory: &mut CodeMemory,
+    context: &mut Context,
+    name: impl FnOnce() -> String,
+) -> (*const VMFunctionBody, usize) {
+    let mut code_buf: Vec<u8> = Vec::new();
+    let mut reloc_sink = RelocSink {};
+    let mut trap_sink = binemit::NullTrapSink {};
+    context
+        .compile_and_emit(isa, &mut code_buf, &mut reloc_sink, &mut trap_sink)
+        .expect("compile_and_emit");
+
+    let trampoline = code_memory
+        .allocate_copy_of_byte_slice(&code_buf)
+        .expect("allocate_copy_of_byte_slice")
+        .as_ptr();
+    perf::record(trampoline as usize, code_buf.len(), name);
+    (trampoline, code_buf.len())
+}
+
+/// Makes the trampoline being built trap when the host function it called
+/// raised an error with `trap::raise`. The trap is handled like the ones of
+/// wasm code, which returns to the export call without running any host
+/// code in between.
+pub fn check_pending_error(builder: &mut FunctionBuilder, isa: &dyn isa::TargetIsa) {
+    let mut sig = ir::Signature::new(isa.frontend_config().default_call_conv);
+    sig.returns.push(ir::AbiParam::new(types::I32));
+    let sig = builder.import_signature(sig);
+    let callee = builder
+        .ins()
+        .iconst(isa.pointer_type(), error_pending as usize as i64);
+    let call = builder.ins().call_indirect(sig, callee, &[]);
+    let pending = builder.func.dfg.first_result(call);
+    builder.ins().trapnz(pending, ir::TrapCode::User(0));
+}
+
+/// Trampolines of the host functions of the support and WASI instances,
+/// which are the same for all instances.
+struct HostFunctions {
+    isa: Box<dyn isa::TargetIsa>,
+    code_memory: CodeMemory,
+    fn_builder_ctx: FunctionBuilderContext,
+    /// By host function and signature, as for `Trampolines`; the host
+    /// functions are functions of this library, which are never freed.
+    trampolines: HashMap<(usize, ir::Signature), usize>,
+}
+
+// Only used with the mutex held.
+unsafe impl Send for HostFunctions {}
+
+lazy_static! {
+    static ref HOST_FUNCTIONS: Mutex<HostFunctions> = Mutex::new(HostFunctions {
+        isa: native_isa(),
+        code_memory: CodeMemory::new(),
+        fn_builder_ctx: FunctionBuilderContext::new(),
+        trampolines: HashMap::new(),
+    });
+}
+
+/// Returns the (published) trampoline through which wasm code calls the
+/// `extern "C"` host function `body` of `signature`, named `name()` in the
+/// perf map; see `check_pending_error`.
+pub fn host_function(
+    body: *const VMFunctionBody,
+    signature: &ir::Signature,
+    name: impl FnOnce() -> String,
+) -> *const VMFunctionBody {
+    let mut host_functions = HOST_FUNCTIONS.lock().unwrap();
+    let key = (body as usize, signature.clone());
+    if let Some(trampoline) = host_functions.trampolines.get(&key) {
+        return *trampoline as *const VMFunctionBody;
+    }
+    let HostFunctions {
+        ref isa,
+        ref mut code_memory,
+        ref mut fn_builder_ctx,
+        ref mut trampolines,
+    } = *host_functions;
+    let pointer_type = isa.pointer_type();
+    let mut context = Context::new();
+    context.func =
+        ir::Function::with_name_signature(ir::ExternalName::user(0, 0), signature.clone());
+
+    {
+        let mut builder = FunctionBuilder::new(&mut context.func, fn_builder_ctx);
+        let block0 = builder.create_ebb();
+
+        builder.append_ebb_params_for_function_params(block0);
+        builder.switch_to_block(block0);
+        builder.seal_block(block0);
+
+        let args = builder.func.dfg.ebb_params(block0).to_vec();
+        let new_sig = builder.import_signature(signature.clone());
+        let callee_value = builder.ins().iconst(pointer_type, body as i64);
+        let call = builder.ins().call_indirect(new_sig, callee_value, &args);
+        let results = builder.func.dfg.inst_results(call).to_vec();
+        check_pending_error(&mut builder, isa.as_ref());
+        builder.ins().return_(&results);
+        builder.finalize()
+    }
+
+    let (trampoline, len) = emit(isa.as_ref(), code_memory, &mut context, name);
+    code_memory.publish();
+    backtrace::register_host_trampoline(trampoline as usize, len);
+    trampolines.insert(key, trampoline as usize);
+    trampoline
+}
@@ -0,0 +1,62 @@
+//! Exceptions raised when wasm execution traps.
+
+use pyo3::create_exception;
+use pyo3::exceptions::Exception;
+use pyo3::ffi;
+use pyo3::prelude::*;
+use pyo3::types::PyAny;
+
+use std::cell::RefCell;
+use std::ptr;
+
+create_exception!(lib_wasmtime, Trap, Exception);
+create_exception!(lib_wasmtime, Interrupted, Trap);
+create_exception!(lib_wasmtime, Timeout, Interrupted);
+
+thread_local! {
+    static PENDING_ERROR: RefCell<Option<PyErr>> = RefCell::new(None);
+}
+
+/// Makes the export call in progress raise `err` once the host function
+/// called by wasm code returns: the trampoline it was called through (see
+/// `trampoline::host_function`) then traps, and the value the function
+/// returns is ignored.
+pub fn raise(err: PyErr) {
+    PENDING_ERROR.with(|pending| *pending.borrow_mut() = Some(err));
+}
+
+/// The value of `result` for a host function to return, or a placeholder
+/// after `raise`-ing its error.
+pub fn or_raise<T: Default>(result: PyResult<T>) -> T {
+    result.unwrap_or_else(|err| {
+        raise(err);
+        T::default()
+    })
+}
+
+/// Whether `raise` was called and the error not taken yet; checked by the
+/// trampolines after calling a host function.
+pub extern "C" fn error_pending() -> u32 {
+    PENDING_ERROR.with(|pending| pending.borrow().is_some() as u32)
+}
+
+/// Takes the error passed to `raise`, if the last trap was caused by it.
+pub fn take_pending_error() -> Option<PyErr> {
+    PENDING_ERROR.with(|pending| pending.borrow_mut().take())
+}
+
+/// Calls `f` with the exception object of `err`, e.g. to set attributes on
+/// it, keeping its traceback.
+pub fn update_exception(py: Python, err: PyErr, f: impl FnOnce(&PyAny)) -> PyErr {
+    err.restore(py);
+    unsafe {
+        let mut ptype = ptr::null_mut();
+        let mut pvalue = ptr::null_mut();
+        let mut ptraceback = ptr::null_mut();
+        ffi::PyErr_Fetch(&mut ptype, &mut pvalue, &mut ptraceback);
+        ffi::PyErr_NormalizeException(&mut ptype, &mut pvalue, &mut ptraceback);
+        f(py.from_borrowed_ptr(pvalue));
+        ffi::PyErr_Restore(ptype, pvalue, ptraceback);
+    }
+    PyErr::fetch(py)
+}
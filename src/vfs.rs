@@ -0,0 +1,537 @@
+//! In-memory file trees preopened by WASI guests, see `VirtualDir`.
+
+use pyo3::exceptions::{FileNotFoundError, OSError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use crate::wasi_fs::{
+    errno, filetype, normalize_path, Dirent, Errno, Filestat, Handle, OpenOptions,
+};
+
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::io::SeekFrom;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Inode of the root directory of a tree.
+const ROOT: u64 = 1;
+
+/// Default limit of the total size of the files of a tree.
+const DEFAULT_MAX_SIZE: u64 = 256 * 1024 * 1024;
+
+enum Node {
+    File(Vec<u8>),
+    Dir {
+        entries: BTreeMap<String, u64>,
+        parent: u64,
+    },
+}
+
+/// A tree of files and directories, indexed by inode number.
+pub struct Tree {
+    nodes: HashMap<u64, Node>,
+    next_ino: u64,
+    /// Total size of the files.
+    size: u64,
+    /// Limit of `size`, beyond which writes fail with `NOSPC`.
+    max_size: u64,
+}
+
+impl Tree {
+    fn new(max_size: u64) -> Self {
+        let mut nodes = HashMap::new();
+        nodes.insert(
+            ROOT,
+            Node::Dir {
+                entries: BTreeMap::new(),
+                parent: ROOT,
+            },
+        );
+        Self {
+            nodes,
+            next_ino: ROOT + 1,
+            size: 0,
+            max_size,
+        }
+    }
+
+    /// Returns the node `ino`; nodes of unlinked files are gone, even if
+    /// the guest still has them open.
+    fn node(&self, ino: u64) -> Result<&Node, Errno> {
+        self.nodes.get(&ino).ok_or(errno::BADF)
+    }
+
+    fn node_mut(&mut self, ino: u64) -> Result<&mut Node, Errno> {
+        self.nodes.get_mut(&ino).ok_or(errno::BADF)
+    }
+
+    fn file_mut(&mut self, ino: u64) -> Result<&mut Vec<u8>, Errno> {
+        match self.node_mut(ino)? {
+            Node::File(data) => Ok(data),
+            Node::Dir { .. } => Err(errno::ISDIR),
+        }
+    }
+
+    /// Resizes the file `ino` to `len` bytes, within the size limit of the
+    /// tree.
+    fn resize_file(&mut self, ino: u64, len: u64) -> Result<(), Errno> {
+        let old_len = self.file_mut(ino)?.len() as u64;
+        let size = self.size - old_len;
+        if len > old_len && len > self.max_size - size {
+            return Err(errno::NOSPC);
+        }
+        if len > usize::max_value() as u64 {
+            return Err(errno::FBIG);
+        }
+        self.file_mut(ino)?.resize(len as usize, 0);
+        self.size = size + len;
+        Ok(())
+    }
+
+    /// Removes the node `ino`, which is already unlinked.
+    fn remove_node(&mut self, ino: u64) {
+        if let Some(Node::File(data)) = self.nodes.remove(&ino) {
+            self.size -= data.len() as u64;
+        }
+    }
+
+    fn entries(&self, ino: u64) -> Result<&BTreeMap<String, u64>, Errno> {
+        match self.node(ino)? {
+            Node::Dir { entries, .. } => Ok(entries),
+            Node::File(_) => Err(errno::NOTDIR),
+        }
+    }
+
+    fn entries_mut(&mut self, ino: u64) -> Result<&mut BTreeMap<String, u64>, Errno> {
+        match self.node_mut(ino)? {
+            Node::Dir { entries, .. } => Ok(entries),
+            Node::File(_) => Err(errno::NOTDIR),
+        }
+    }
+
+    /// Resolves the normalized `path` relative to the directory `dir`.
+    fn lookup(&self, dir: u64, path: &str) -> Result<u64, Errno> {
+        let mut ino = dir;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            ino = *self.entries(ino)?.get(name).ok_or(errno::NOENT)?;
+        }
+        Ok(ino)
+    }
+
+    /// Splits the normalized `path` into its parent directory and its last
+    /// component, which has to exist.
+    fn parent<'a>(&self, dir: u64, path: &'a str) -> Result<(u64, &'a str), Errno> {
+        let (parent, name) = match path.rfind('/') {
+            Some(i) => (self.lookup(dir, &path[..i])?, &path[i + 1..]),
+            None => (dir, path),
+        };
+        if name.is_empty() {
+            // The directory itself.
+            return Err(errno::ACCES);
+        }
+        self.entries(parent)?;
+        Ok((parent, name))
+    }
+
+    fn insert(&mut self, parent: u64, name: &str, node: Node) -> Result<u64, Errno> {
+        let ino = self.next_ino;
+        self.entries_mut(parent)?.insert(name.to_string(), ino);
+        self.nodes.insert(ino, node);
+        self.next_ino += 1;
+        Ok(ino)
+    }
+
+    fn filestat(&self, ino: u64) -> Result<Filestat, Errno> {
+        let (filetype, size) = match self.node(ino)? {
+            Node::File(data) => (filetype::REGULAR_FILE, data.len() as u64),
+            Node::Dir { .. } => (filetype::DIRECTORY, 0),
+        };
+        Ok(Filestat {
+            ino,
+            filetype,
+            nlink: 1,
+            size,
+            ..Filestat::default()
+        })
+    }
+
+    /// Writes the file at `path`, creating it and its parent directories.
+    fn write_file(&mut self, path: &str, data: Vec<u8>) -> Result<(), Errno> {
+        let mut dir = ROOT;
+        let mut names: Vec<&str> = path.split('/').collect();
+        let name = names
+            .pop()
+            .filter(|name| !name.is_empty())
+            .ok_or(errno::ISDIR)?;
+        for component in names {
+            dir = match self.entries(dir)?.get(component) {
+                Some(ino) => *ino,
+                None => self.insert(
+                    dir,
+                    component,
+                    Node::Dir {
+                        entries: BTreeMap::new(),
+                        parent: dir,
+                    },
+                )?,
+            };
+        }
+        let ino = match self.entries(dir)?.get(name) {
+            Some(ino) => *ino,
+            None => self.insert(dir, name, Node::File(Vec::new()))?,
+        };
+        self.resize_file(ino, 0)?;
+        if data.len() as u64 > self.max_size - self.size {
+            return Err(errno::NOSPC);
+        }
+        self.size += data.len() as u64;
+        *self.file_mut(ino)? = data;
+        Ok(())
+    }
+
+    /// Lists the files under the directory `dir`, with their paths.
+    fn files(&self, dir: u64, prefix: &str, files: &mut Vec<(String, u64)>) {
+        for (name, ino) in self.entries(dir).expect("directory") {
+            let path = format!("{}{}", prefix, name);
+            match self.nodes[ino] {
+                Node::File(_) => files.push((path, *ino)),
+                Node::Dir { .. } => self.files(*ino, &format!("{}/", path), files),
+            }
+        }
+    }
+}
+
+/// A file or directory of a `Tree` opened by the guest.
+pub struct VfsHandle {
+    tree: Arc<Mutex<Tree>>,
+    ino: u64,
+    position: u64,
+    append: bool,
+}
+
+impl VfsHandle {
+    fn open_ino(&self, ino: u64, append: bool) -> Box<dyn Handle> {
+        Box::new(VfsHandle {
+            tree: self.tree.clone(),
+            ino,
+            position: 0,
+            append,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<Tree> {
+        self.tree.lock().unwrap()
+    }
+}
+
+fn read_at(data: &[u8], buf: &mut [u8], offset: u64) -> usize {
+    let start = offset.min(data.len() as u64) as usize;
+    let n = buf.len().min(data.len() - start);
+    buf[..n].copy_from_slice(&data[start..start + n]);
+    n
+}
+
+/// Writes `buf` at `offset` in the file `ino`, extending it if needed.
+fn write_at(tree: &mut Tree, ino: u64, buf: &[u8], offset: u64) -> Result<usize, Errno> {
+    let end = offset.checked_add(buf.len() as u64).ok_or(errno::FBIG)?;
+    if end > tree.file_mut(ino)?.len() as u64 {
+        tree.resize_file(ino, end)?;
+    }
+    tree.file_mut(ino)?[offset as usize..end as usize].copy_from_slice(buf);
+    Ok(buf.len())
+}
+
+impl Handle for VfsHandle {
+    fn filetype(&self) -> u8 {
+        self.lock()
+            .filestat(self.ino)
+            .map(|stat| stat.filetype)
+            .unwrap_or(filetype::UNKNOWN)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Errno> {
+        let n = self.pread(buf, self.position)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Errno> {
+        let mut tree = self.tree.lock().unwrap();
+        if self.append {
+            self.position = tree.file_mut(self.ino)?.len() as u64;
+        }
+        let n = write_at(&mut tree, self.ino, buf, self.position)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+
+    fn pread(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, Errno> {
+        let mut tree = self.lock();
+        Ok(read_at(tree.file_mut(self.ino)?, buf, offset))
+    }
+
+    fn pwrite(&mut self, buf: &[u8], offset: u64) -> Result<usize, Errno> {
+        let mut tree = self.lock();
+        write_at(&mut tree, self.ino, buf, offset)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Errno> {
+        let len = self.lock().file_mut(self.ino)?.len() as i64;
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset as i64),
+            SeekFrom::Current(offset) => (self.position as i64).checked_add(offset),
+            SeekFrom::End(offset) => len.checked_add(offset),
+        };
+        match position {
+            Some(position) if position >= 0 => self.position = position as u64,
+            _ => return Err(errno::INVAL),
+        }
+        Ok(self.position)
+    }
+
+    fn filestat(&self) -> Result<Filestat, Errno> {
+        self.lock().filestat(self.ino)
+    }
+
+    fn set_size(&mut self, size: u64) -> Result<(), Errno> {
+        self.lock().resize_file(self.ino, size)
+    }
+
+    fn open(&self, path: &str, options: &OpenOptions) -> Result<Box<dyn Handle>, Errno> {
+        let mut tree = self.lock();
+        let ino = match tree.lookup(self.ino, path) {
+            Ok(_) if options.create && options.exclusive => return Err(errno::EXIST),
+            Ok(ino) => ino,
+            Err(errno::NOENT) if options.create => {
+                let (parent, name) = tree.parent(self.ino, path)?;
+                tree.insert(parent, name, Node::File(Vec::new()))?
+            }
+            Err(e) => return Err(e),
+        };
+        if tree.entries(ino).is_ok() {
+            if options.write {
+                return Err(errno::ISDIR);
+            }
+        } else if options.directory {
+            return Err(errno::NOTDIR);
+        } else if options.truncate {
+            tree.resize_file(ino, 0)?;
+        }
+        Ok(self.open_ino(ino, options.append))
+    }
+
+    fn path_filestat(&self, path: &str, _follow: bool) -> Result<Filestat, Errno> {
+        let tree = self.lock();
+        tree.filestat(tree.lookup(self.ino, path)?)
+    }
+
+    fn create_dir(&self, path: &str) -> Result<(), Errno> {
+        let mut tree = self.lock();
+        let (parent, name) = tree.parent(self.ino, path)?;
+        if tree.entries(parent)?.contains_key(name) {
+            return Err(errno::EXIST);
+        }
+        let dir = Node::Dir {
+            entries: BTreeMap::new(),
+            parent,
+        };
+        tree.insert(parent, name, dir).map(|_| ())
+    }
+
+    fn remove_dir(&self, path: &str) -> Result<(), Errno> {
+        let mut tree = self.lock();
+        let (parent, name) = tree.parent(self.ino, path)?;
+        let ino = tree.lookup(parent, name)?;
+        if !tree.entries(ino)?.is_empty() {
+            return Err(errno::NOTEMPTY);
+        }
+        tree.entries_mut(parent)?.remove(name);
+        tree.remove_node(ino);
+        Ok(())
+    }
+
+    fn unlink_file(&self, path: &str) -> Result<(), Errno> {
+        let mut tree = self.lock();
+        let (parent, name) = tree.parent(self.ino, path)?;
+        let ino = tree.lookup(parent, name)?;
+        if let Node::Dir { .. } = tree.node(ino)? {
+            return Err(errno::ISDIR);
+        }
+        tree.entries_mut(parent)?.remove(name);
+        tree.remove_node(ino);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to_dir: &dyn Handle, to: &str) -> Result<(), Errno> {
+        let to_dir = to_dir
+            .as_any()
+            .downcast_ref::<VfsHandle>()
+            .filter(|to_dir| Arc::ptr_eq(&to_dir.tree, &self.tree))
+            .ok_or(errno::XDEV)?;
+        let mut tree = self.lock();
+        let (from_parent, from_name) = tree.parent(self.ino, from)?;
+        let ino = tree.lookup(from_parent, from_name)?;
+        let (to_parent, to_name) = tree.parent(to_dir.ino, to)?;
+        let is_dir = tree.entries(ino).is_ok();
+
+        // A directory can't be moved inside itself.
+        let mut ancestor = to_parent;
+        while is_dir {
+            if ancestor == ino {
+                return Err(errno::INVAL);
+            }
+            match tree.node(ancestor)? {
+                Node::Dir { parent, .. } if *parent != ancestor => ancestor = *parent,
+                _ => break,
+            }
+        }
+
+        if let Some(&existing) = tree.entries(to_parent)?.get(to_name) {
+            if existing == ino {
+                return Ok(());
+            }
+            match (is_dir, tree.entries(existing)) {
+                (false, Ok(_)) => return Err(errno::ISDIR),
+                (true, Err(_)) => return Err(errno::NOTDIR),
+                (true, Ok(entries)) if !entries.is_empty() => return Err(errno::NOTEMPTY),
+                _ => (),
+            }
+            tree.remove_node(existing);
+        }
+        tree.entries_mut(from_parent)?.remove(from_name);
+        tree.entries_mut(to_parent)?
+            .insert(to_name.to_string(), ino);
+        if let Node::Dir { parent, .. } = tree.node_mut(ino)? {
+            *parent = to_parent;
+        }
+        Ok(())
+    }
+
+    fn readdir(&self) -> Result<Vec<Dirent>, Errno> {
+        let tree = self.lock();
+        let parent = match tree.node(self.ino)? {
+            Node::Dir { parent, .. } => *parent,
+            Node::File(_) => return Err(errno::NOTDIR),
+        };
+        let mut entries = vec![
+            Dirent {
+                name: ".".to_string(),
+                filetype: filetype::DIRECTORY,
+                ino: self.ino,
+            },
+            Dirent {
+                name: "..".to_string(),
+                filetype: filetype::DIRECTORY,
+                ino: parent,
+            },
+        ];
+        for (name, ino) in tree.entries(self.ino)? {
+            entries.push(Dirent {
+                name: name.clone(),
+                filetype: tree.filestat(*ino)?.filetype,
+                ino: *ino,
+            });
+        }
+        Ok(entries)
+    }
+}
+
+/// Returns the path relative to the root of a tree of a path given from
+/// Python, where a leading "/" is allowed.
+fn tree_path(path: &str) -> PyResult<String> {
+    normalize_path(path.trim_start_matches('/'))
+        .map_err(|_| OSError::py_err(format!("invalid path {}", path)))
+}
+
+/// A directory held in memory, to be preopened by WASI guests in place of
+/// a host directory: `WasiConfig(preopens={"/data": VirtualDir(files)})`.
+///
+/// `files` maps paths to the contents of files, which are created along
+/// with their parent directories. The guests work on the tree itself, so
+/// `files()` returns what they left. Writes making the files total more
+/// than `max_size` bytes (256 MiB by default) fail with `NOSPC`.
+#[pyclass]
+pub struct VirtualDir {
+    tree: Arc<Mutex<Tree>>,
+}
+
+/// Opens the root of `tree` for a guest.
+pub fn open_root(tree: &Arc<Mutex<Tree>>) -> Box<dyn Handle> {
+    Box::new(VfsHandle {
+        tree: tree.clone(),
+        ino: ROOT,
+        position: 0,
+        append: false,
+    })
+}
+
+impl VirtualDir {
+    pub fn tree(&self) -> Arc<Mutex<Tree>> {
+        self.tree.clone()
+    }
+
+    /// Locks the tree, releasing the GIL while a guest running in another
+    /// thread holds it.
+    fn lock(&self, py: Python) -> MutexGuard<Tree> {
+        py.allow_threads(|| self.tree.lock().unwrap())
+    }
+}
+
+#[pymethods]
+impl VirtualDir {
+    #[new]
+    #[args(files = "None", max_size = "DEFAULT_MAX_SIZE")]
+    fn new(obj: &PyRawObject, files: Option<&PyDict>, max_size: u64) -> PyResult<()> {
+        let mut tree = Tree::new(max_size);
+        if let Some(files) = files {
+            for (path, data) in files.iter() {
+                let path = path.extract::<String>()?;
+                let data = data.downcast_ref::<PyBytes>()?.as_bytes().to_vec();
+                tree.write_file(&tree_path(&path)?, data)
+                    .map_err(|_| OSError::py_err(format!("cannot create {}", path)))?;
+            }
+        }
+        obj.init(VirtualDir {
+            tree: Arc::new(Mutex::new(tree)),
+        });
+        Ok(())
+    }
+
+    /// Returns the files of the tree as a dict of paths to contents.
+    fn files(&self, py: Python) -> PyResult<PyObject> {
+        let tree = self.lock(py);
+        let mut files = Vec::new();
+        tree.files(ROOT, "", &mut files);
+        let dict = PyDict::new(py);
+        for (path, ino) in files {
+            if let Node::File(data) = &tree.nodes[&ino] {
+                dict.set_item(path, PyBytes::new(py, data))?;
+            }
+        }
+        Ok(dict.into_object(py))
+    }
+
+    /// Returns the contents of the file at `path`.
+    fn read(&self, py: Python, path: &str) -> PyResult<PyObject> {
+        let tree = self.lock(py);
+        let ino = tree
+            .lookup(ROOT, &tree_path(path)?)
+            .map_err(|_| FileNotFoundError::py_err(path.to_string()))?;
+        match tree.node(ino) {
+            Ok(Node::File(data)) => Ok(PyBytes::new(py, data).into_object(py)),
+            _ => Err(OSError::py_err(format!("{} is a directory", path))),
+        }
+    }
+
+    /// Creates or replaces the file at `path`.
+    fn write(&self, py: Python, path: &str, data: &PyBytes) -> PyResult<()> {
+        let path_in_tree = tree_path(path)?;
+        self.lock(py)
+            .write_file(&path_in_tree, data.as_bytes().to_vec())
+            .map_err(|_| OSError::py_err(format!("cannot write {}", path)))
+    }
+}
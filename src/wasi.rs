@@ -0,0 +1,1369 @@
+//! WASI (`wasi_snapshot_preview1` and the legacy `wasi_unstable`) imports.
+//!
+//! A `WasiInstance` placed in the import object of `instantiate` is turned
+//! into a host instance exporting the WASI functions. They operate on the
+//! memory exported by the guest as "memory", see `attach_memory`.
+
+use pyo3::create_exception;
+use pyo3::exceptions::{Exception, OSError, TypeError};
+use pyo3::prelude::*;
+use pyo3::types::{PyAny, PyBytes, PyDict};
+
+use crate::support::add_function;
+use crate::trap::raise;
+use crate::vfs::{open_root, Tree, VirtualDir};
+use crate::wasi_fs::{
+    errno, filetype, Errno, Filestat, Handle, HostDir, HostFile, InheritedStdio, NullStream,
+    OpenOptions,
+};
+use crate::wasi_stdio::{take_stream_error, BytesStream, PyStream};
+use cranelift_codegen::ir::{self, types};
+use cranelift_entity::PrimaryMap;
+use wasmtime_environ::Module;
+use wasmtime_runtime::{
+    Export, Imports, InstanceHandle, VMContext, VMFunctionBody, VMMemoryDefinition,
+};
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, SeekFrom};
+use std::path::Path;
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+create_exception!(lib_wasmtime, WasiExit, Exception);
+
+/// Import module names under which a `WasiInstance` is recognized.
+pub const WASI_MODULES: [&str; 2] = ["wasi_snapshot_preview1", "wasi_unstable"];
+
+const RIGHT_FD_READ: u64 = 1 << 1;
+const RIGHT_FD_WRITE: u64 = 1 << 6;
+const RIGHT_FD_ALLOCATE: u64 = 1 << 8;
+const RIGHT_FD_FILESTAT_SET_SIZE: u64 = 1 << 22;
+/// All the rights defined by preview1; they are not enforced.
+const RIGHTS_ALL: u64 = (1 << 29) - 1;
+
+const OFLAGS_CREAT: u32 = 1;
+const OFLAGS_DIRECTORY: u32 = 2;
+const OFLAGS_EXCL: u32 = 4;
+const OFLAGS_TRUNC: u32 = 8;
+const FDFLAGS_APPEND: u32 = 1;
+const LOOKUP_SYMLINK_FOLLOW: u32 = 1;
+
+const CLOCK_REALTIME: u32 = 0;
+const CLOCK_MONOTONIC: u32 = 1;
+const CLOCK_PROCESS_CPUTIME: u32 = 2;
+const CLOCK_THREAD_CPUTIME: u32 = 3;
+
+const EVENTTYPE_CLOCK: u8 = 0;
+const SUBCLOCKFLAGS_ABSTIME: u16 = 1;
+
+/// A file descriptor of the guest.
+struct Descriptor {
+    handle: Box<dyn Handle>,
+    /// Guest path of a preopened directory.
+    preopen: Option<String>,
+    fdflags: u16,
+}
+
+impl Descriptor {
+    fn new(handle: Box<dyn Handle>) -> Self {
+        Self {
+            handle,
+            preopen: None,
+            fdflags: 0,
+        }
+    }
+}
+
+/// State of the WASI "process" of a guest.
+pub struct WasiCtx {
+    args: Vec<String>,
+    env: Vec<String>,
+    fds: BTreeMap<u32, Descriptor>,
+    exit_code: Option<i32>,
+}
+
+/// Bounds-checked access to the linear memory of the guest.
+struct GuestMemory {
+    base: *mut u8,
+    len: usize,
+}
+
+impl GuestMemory {
+    unsafe fn new(definition: *mut VMMemoryDefinition) -> Self {
+        if definition.is_null() {
+            return Self {
+                base: ptr::null_mut(),
+                len: 0,
+            };
+        }
+        Self {
+            base: (*definition).base,
+            len: (*definition).current_length,
+        }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn slice(&self, ptr: u32, len: u32) -> Result<&mut [u8], Errno> {
+        let (ptr, len) = (ptr as usize, len as usize);
+        if ptr.checked_add(len).map_or(true, |end| end > self.len) {
+            return Err(errno::FAULT);
+        }
+        Ok(unsafe { slice::from_raw_parts_mut(self.base.add(ptr), len) })
+    }
+
+    fn read_u32(&self, ptr: u32) -> Result<u32, Errno> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.slice(ptr, 4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn read_u64(&self, ptr: u32) -> Result<u64, Errno> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.slice(ptr, 8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn write(&self, ptr: u32, bytes: &[u8]) -> Result<(), Errno> {
+        self.slice(ptr, bytes.len() as u32)?.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_u8(&self, ptr: u32, value: u8) -> Result<(), Errno> {
+        self.write(ptr, &[value])
+    }
+
+    fn write_u16(&self, ptr: u32, value: u16) -> Result<(), Errno> {
+        self.write(ptr, &value.to_le_bytes())
+    }
+
+    fn write_u32(&self, ptr: u32, value: u32) -> Result<(), Errno> {
+        self.write(ptr, &value.to_le_bytes())
+    }
+
+    fn write_u64(&self, ptr: u32, value: u64) -> Result<(), Errno> {
+        self.write(ptr, &value.to_le_bytes())
+    }
+
+    fn read_str(&self, ptr: u32, len: u32) -> Result<&str, Errno> {
+        std::str::from_utf8(self.slice(ptr, len)?).map_err(|_| errno::ILSEQ)
+    }
+
+    /// Returns the buffers of an array of `iovec`s or `ciovec`s.
+    fn iovecs(&self, iovs: u32, iovs_len: u32) -> Result<Vec<&mut [u8]>, Errno> {
+        let mut buffers = Vec::new();
+        for i in 0..iovs_len {
+            let iov = iovs + i * 8;
+            let buf = self.read_u32(iov)?;
+            let buf_len = self.read_u32(iov + 4)?;
+            buffers.push(self.slice(buf, buf_len)?);
+        }
+        Ok(buffers)
+    }
+}
+
+/// Writes strings and the array of pointers to them, as for `args_get`.
+fn write_strings(
+    memory: &GuestMemory,
+    strings: &[String],
+    pointers: u32,
+    buf: u32,
+) -> Result<(), Errno> {
+    let mut offset = buf;
+    for (i, s) in strings.iter().enumerate() {
+        memory.write_u32(pointers + i as u32 * 4, offset)?;
+        memory.write(offset, s.as_bytes())?;
+        memory.write_u8(offset + s.len() as u32, 0)?;
+        offset += s.len() as u32 + 1;
+    }
+    Ok(())
+}
+
+fn write_sizes(
+    memory: &GuestMemory,
+    strings: &[String],
+    count: u32,
+    buf_size: u32,
+) -> Result<(), Errno> {
+    memory.write_u32(count, strings.len() as u32)?;
+    let size: usize = strings.iter().map(|s| s.len() + 1).sum();
+    memory.write_u32(buf_size, size as u32)
+}
+
+/// Writes a `filestat`; the legacy layout has a 32-bit `nlink`.
+fn write_filestat(
+    memory: &GuestMemory,
+    ptr: u32,
+    stat: &Filestat,
+    legacy: bool,
+) -> Result<(), Errno> {
+    memory.write_u64(ptr, stat.dev)?;
+    memory.write_u64(ptr + 8, stat.ino)?;
+    memory.write_u8(ptr + 16, stat.filetype)?;
+    let ptr = if legacy {
+        memory.write_u32(ptr + 20, stat.nlink as u32)?;
+        ptr + 24
+    } else {
+        memory.write_u64(ptr + 24, stat.nlink)?;
+        ptr + 32
+    };
+    memory.write_u64(ptr, stat.size)?;
+    memory.write_u64(ptr + 8, stat.atim)?;
+    memory.write_u64(ptr + 16, stat.mtim)?;
+    memory.write_u64(ptr + 24, stat.ctim)
+}
+
+fn clock_time(id: u32) -> Result<u64, Errno> {
+    let clock = match id {
+        CLOCK_REALTIME => libc::CLOCK_REALTIME,
+        CLOCK_MONOTONIC => libc::CLOCK_MONOTONIC,
+        CLOCK_PROCESS_CPUTIME => libc::CLOCK_PROCESS_CPUTIME_ID,
+        CLOCK_THREAD_CPUTIME => libc::CLOCK_THREAD_CPUTIME_ID,
+        _ => return Err(errno::INVAL),
+    };
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(clock, &mut ts) } != 0 {
+        return Err(errno::IO);
+    }
+    Ok(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+}
+
+impl WasiCtx {
+    fn descriptor(&mut self, fd: u32) -> Result<&mut Descriptor, Errno> {
+        self.fds.get_mut(&fd).ok_or(errno::BADF)
+    }
+
+    fn handle(&mut self, fd: u32) -> Result<&mut dyn Handle, Errno> {
+        Ok(self.descriptor(fd)?.handle.as_mut())
+    }
+
+    /// Returns the directory `fd` and the normalized `path` relative to it.
+    fn dir_path<'a>(
+        &'a mut self,
+        memory: &GuestMemory,
+        fd: u32,
+        path: u32,
+        path_len: u32,
+    ) -> Result<(&'a mut dyn Handle, String), Errno> {
+        let path = crate::wasi_fs::normalize_path(memory.read_str(path, path_len)?)?;
+        Ok((self.handle(fd)?, path))
+    }
+
+    fn insert(&mut self, descriptor: Descriptor) -> u32 {
+        let mut fd = 0;
+        while self.fds.contains_key(&fd) {
+            fd += 1;
+        }
+        self.fds.insert(fd, descriptor);
+        fd
+    }
+
+    fn fd_rw(
+        &mut self,
+        memory: &GuestMemory,
+        fd: u32,
+        iovs: u32,
+        iovs_len: u32,
+        offset: Option<u64>,
+        write: bool,
+        result: u32,
+    ) -> Result<(), Errno> {
+        let handle = self.handle(fd)?;
+        let mut total = 0;
+        for buf in memory.iovecs(iovs, iovs_len)? {
+            let position = offset.map(|offset| offset + total as u64);
+            let n = match (write, position) {
+                (false, None) => handle.read(buf)?,
+                (false, Some(position)) => handle.pread(buf, position)?,
+                (true, None) => handle.write(buf)?,
+                (true, Some(position)) => handle.pwrite(buf, position)?,
+            };
+            total += n;
+            if n < buf.len() {
+                break;
+            }
+        }
+        memory.write_u32(result, total as u32)
+    }
+
+    fn fd_readdir(
+        &mut self,
+        memory: &GuestMemory,
+        fd: u32,
+        buf: u32,
+        buf_len: u32,
+        cookie: u64,
+        bufused: u32,
+    ) -> Result<(), Errno> {
+        let entries = self.handle(fd)?.readdir()?;
+        let out = memory.slice(buf, buf_len)?;
+        let mut used = 0;
+        for (i, entry) in entries.iter().enumerate().skip(cookie as usize) {
+            let mut dirent = [0u8; 24];
+            dirent[0..8].copy_from_slice(&(i as u64 + 1).to_le_bytes());
+            dirent[8..16].copy_from_slice(&entry.ino.to_le_bytes());
+            dirent[16..20].copy_from_slice(&(entry.name.len() as u32).to_le_bytes());
+            dirent[20] = entry.filetype;
+            // Entries are truncated when the buffer is full, the guest
+            // then calls again with a larger buffer or the next cookie.
+            for chunk in &[&dirent[..], entry.name.as_bytes()] {
+                let n = chunk.len().min(out.len() - used);
+                out[used..used + n].copy_from_slice(&chunk[..n]);
+                used += n;
+            }
+            if used == out.len() {
+                break;
+            }
+        }
+        memory.write_u32(bufused, used as u32)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn path_open(
+        &mut self,
+        memory: &GuestMemory,
+        fd: u32,
+        path: u32,
+        path_len: u32,
+        oflags: u32,
+        rights: u64,
+        fdflags: u32,
+        result: u32,
+    ) -> Result<(), Errno> {
+        let options = OpenOptions {
+            read: rights & RIGHT_FD_READ != 0,
+            write: rights & (RIGHT_FD_WRITE | RIGHT_FD_ALLOCATE | RIGHT_FD_FILESTAT_SET_SIZE) != 0,
+            create: oflags & OFLAGS_CREAT != 0,
+            exclusive: oflags & OFLAGS_EXCL != 0,
+            truncate: oflags & OFLAGS_TRUNC != 0,
+            append: fdflags & FDFLAGS_APPEND != 0,
+            directory: oflags & OFLAGS_DIRECTORY != 0,
+        };
+        let (dir, path) = self.dir_path(memory, fd, path, path_len)?;
+        let handle = dir.open(&path, &options)?;
+        if options.directory && handle.filetype() != filetype::DIRECTORY {
+            return Err(errno::NOTDIR);
+        }
+        let new_fd = self.insert(Descriptor {
+            handle,
+            preopen: None,
+            fdflags: fdflags as u16,
+        });
+        memory.write_u32(result, new_fd)
+    }
+
+    fn path_rename(
+        &mut self,
+        memory: &GuestMemory,
+        fd: u32,
+        old_path: u32,
+        old_path_len: u32,
+        new_fd: u32,
+        new_path: u32,
+        new_path_len: u32,
+    ) -> Result<(), Errno> {
+        let normalize = crate::wasi_fs::normalize_path;
+        let old_path = normalize(memory.read_str(old_path, old_path_len)?)?;
+        let new_path = normalize(memory.read_str(new_path, new_path_len)?)?;
+        let from = self.fds.get(&fd).ok_or(errno::BADF)?;
+        let to = self.fds.get(&new_fd).ok_or(errno::BADF)?;
+        from.handle.rename(&old_path, to.handle.as_ref(), &new_path)
+    }
+
+    /// Supports clock subscriptions by sleeping; descriptors are reported
+    /// as ready right away.
+    fn poll_oneoff(
+        &mut self,
+        memory: &GuestMemory,
+        subscriptions: u32,
+        events: u32,
+        count: u32,
+        result: u32,
+        legacy: bool,
+    ) -> Result<(), Errno> {
+        let (size, clock_offset) = if legacy { (56, 24) } else { (48, 16) };
+        let mut ready = Vec::new();
+        let mut sleep: Option<(u64, u64)> = None;
+        for i in 0..count {
+            let subscription = subscriptions + i * size;
+            let userdata = memory.read_u64(subscription)?;
+            let tag = memory.slice(subscription + 8, 1)?[0];
+            if tag != EVENTTYPE_CLOCK {
+                ready.push((userdata, tag));
+                continue;
+            }
+            let clock = subscription + clock_offset;
+            let id = memory.read_u32(clock)?;
+            let mut timeout = memory.read_u64(clock + 8)?;
+            let flags = u16::from_le_bytes([
+                memory.slice(clock + 24, 1)?[0],
+                memory.slice(clock + 25, 1)?[0],
+            ]);
+            if flags & SUBCLOCKFLAGS_ABSTIME != 0 {
+                timeout = timeout.saturating_sub(clock_time(id)?);
+            }
+            if sleep.map_or(true, |(t, _)| timeout < t) {
+                sleep = Some((timeout, userdata));
+            }
+        }
+        if ready.is_empty() {
+            if let Some((timeout, userdata)) = sleep {
+                thread::sleep(Duration::from_nanos(timeout));
+                ready.push((userdata, EVENTTYPE_CLOCK));
+            }
+        }
+        for (i, (userdata, tag)) in ready.iter().enumerate() {
+            let event = events + i as u32 * 32;
+            memory.slice(event, 32)?.copy_from_slice(&[0; 32]);
+            memory.write_u64(event, *userdata)?;
+            memory.write_u8(event + 10, *tag)?;
+        }
+        memory.write_u32(result, ready.len() as u32)
+    }
+}
+
+/// Host state of a WASI instance.
+struct WasiHost {
+    ctx: Arc<Mutex<WasiCtx>>,
+    memory: *mut VMMemoryDefinition,
+    legacy: bool,
+}
+
+/// Runs a WASI function on the context of the host instance `vmctx` and
+/// returns its errno. An exception raised by a Python stream is raised out
+/// of the export call in progress.
+unsafe fn with_ctx(
+    vmctx: *mut VMContext,
+    f: impl FnOnce(&mut WasiCtx, &GuestMemory, bool) -> Result<(), Errno>,
+) -> u32 {
+    let result = {
+        let host = (*vmctx)
+            .host_state()
+            .downcast_ref::<WasiHost>()
+            .expect("state");
+        let memory = GuestMemory::new(host.memory);
+        let mut ctx = host.ctx.lock().unwrap();
+        f(&mut ctx, &memory, host.legacy)
+    };
+    if let Some(err) = take_stream_error() {
+        raise(err);
+    }
+    match result {
+        Ok(()) => u32::from(errno::SUCCESS),
+        Err(e) => u32::from(e),
+    }
+}
+
+unsafe extern "C" fn args_get(vmctx: *mut VMContext, argv: u32, argv_buf: u32) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        write_strings(m, &ctx.args, argv, argv_buf)
+    })
+}
+
+unsafe extern "C" fn args_sizes_get(vmctx: *mut VMContext, argc: u32, buf_size: u32) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| write_sizes(m, &ctx.args, argc, buf_size))
+}
+
+unsafe extern "C" fn environ_get(vmctx: *mut VMContext, environ: u32, environ_buf: u32) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        write_strings(m, &ctx.env, environ, environ_buf)
+    })
+}
+
+unsafe extern "C" fn environ_sizes_get(vmctx: *mut VMContext, count: u32, buf_size: u32) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| write_sizes(m, &ctx.env, count, buf_size))
+}
+
+unsafe extern "C" fn clock_res_get(vmctx: *mut VMContext, id: u32, result: u32) -> u32 {
+    with_ctx(vmctx, |_, m, _| {
+        clock_time(id)?;
+        m.write_u64(result, 1)
+    })
+}
+
+unsafe extern "C" fn clock_time_get(
+    vmctx: *mut VMContext,
+    id: u32,
+    _precision: u64,
+    result: u32,
+) -> u32 {
+    with_ctx(vmctx, |_, m, _| m.write_u64(result, clock_time(id)?))
+}
+
+unsafe extern "C" fn fd_advise(
+    vmctx: *mut VMContext,
+    fd: u32,
+    _offset: u64,
+    _len: u64,
+    _advice: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, _, _| ctx.handle(fd).map(|_| ()))
+}
+
+unsafe extern "C" fn fd_allocate(vmctx: *mut VMContext, fd: u32, offset: u64, len: u64) -> u32 {
+    with_ctx(vmctx, |ctx, _, _| {
+        let handle = ctx.handle(fd)?;
+        let size = offset.checked_add(len).ok_or(errno::INVAL)?;
+        if size > handle.filestat()?.size {
+            handle.set_size(size)?;
+        }
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn fd_close(vmctx: *mut VMContext, fd: u32) -> u32 {
+    with_ctx(vmctx, |ctx, _, _| {
+        ctx.fds.remove(&fd).map(|_| ()).ok_or(errno::BADF)
+    })
+}
+
+unsafe extern "C" fn fd_sync(vmctx: *mut VMContext, fd: u32) -> u32 {
+    with_ctx(vmctx, |ctx, _, _| ctx.handle(fd)?.sync())
+}
+
+unsafe extern "C" fn fd_fdstat_get(vmctx: *mut VMContext, fd: u32, result: u32) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        let descriptor = ctx.descriptor(fd)?;
+        m.slice(result, 24)?.copy_from_slice(&[0; 24]);
+        m.write_u8(result, descriptor.handle.filetype())?;
+        m.write_u16(result + 2, descriptor.fdflags)?;
+        m.write_u64(result + 8, RIGHTS_ALL)?;
+        m.write_u64(result + 16, RIGHTS_ALL)
+    })
+}
+
+unsafe extern "C" fn fd_fdstat_set_flags(vmctx: *mut VMContext, fd: u32, flags: u32) -> u32 {
+    with_ctx(vmctx, |ctx, _, _| {
+        ctx.descriptor(fd)?.fdflags = flags as u16;
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn fd_fdstat_set_rights(
+    vmctx: *mut VMContext,
+    fd: u32,
+    _base: u64,
+    _inheriting: u64,
+) -> u32 {
+    with_ctx(vmctx, |ctx, _, _| ctx.handle(fd).map(|_| ()))
+}
+
+unsafe extern "C" fn fd_filestat_get(vmctx: *mut VMContext, fd: u32, result: u32) -> u32 {
+    with_ctx(vmctx, |ctx, m, legacy| {
+        let stat = ctx.handle(fd)?.filestat()?;
+        write_filestat(m, result, &stat, legacy)
+    })
+}
+
+unsafe extern "C" fn fd_filestat_set_size(vmctx: *mut VMContext, fd: u32, size: u64) -> u32 {
+    with_ctx(vmctx, |ctx, _, _| ctx.handle(fd)?.set_size(size))
+}
+
+unsafe extern "C" fn fd_filestat_set_times(
+    vmctx: *mut VMContext,
+    fd: u32,
+    _atim: u64,
+    _mtim: u64,
+    _flags: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, _, _| {
+        ctx.handle(fd)?;
+        Err(errno::NOTSUP)
+    })
+}
+
+unsafe extern "C" fn fd_pread(
+    vmctx: *mut VMContext,
+    fd: u32,
+    iovs: u32,
+    iovs_len: u32,
+    offset: u64,
+    result: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        ctx.fd_rw(m, fd, iovs, iovs_len, Some(offset), false, result)
+    })
+}
+
+unsafe extern "C" fn fd_pwrite(
+    vmctx: *mut VMContext,
+    fd: u32,
+    iovs: u32,
+    iovs_len: u32,
+    offset: u64,
+    result: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        ctx.fd_rw(m, fd, iovs, iovs_len, Some(offset), true, result)
+    })
+}
+
+unsafe extern "C" fn fd_read(
+    vmctx: *mut VMContext,
+    fd: u32,
+    iovs: u32,
+    iovs_len: u32,
+    result: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        ctx.fd_rw(m, fd, iovs, iovs_len, None, false, result)
+    })
+}
+
+unsafe extern "C" fn fd_write(
+    vmctx: *mut VMContext,
+    fd: u32,
+    iovs: u32,
+    iovs_len: u32,
+    result: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        ctx.fd_rw(m, fd, iovs, iovs_len, None, true, result)
+    })
+}
+
+unsafe extern "C" fn fd_prestat_get(vmctx: *mut VMContext, fd: u32, result: u32) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        let name = ctx.descriptor(fd)?.preopen.as_ref().ok_or(errno::BADF)?;
+        m.write_u32(result, 0)?;
+        m.write_u32(result + 4, name.len() as u32)
+    })
+}
+
+unsafe extern "C" fn fd_prestat_dir_name(
+    vmctx: *mut VMContext,
+    fd: u32,
+    path: u32,
+    path_len: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        let name = ctx.descriptor(fd)?.preopen.as_ref().ok_or(errno::BADF)?;
+        if (path_len as usize) < name.len() {
+            return Err(errno::NAMETOOLONG);
+        }
+        m.write(path, name.as_bytes())
+    })
+}
+
+unsafe extern "C" fn fd_readdir(
+    vmctx: *mut VMContext,
+    fd: u32,
+    buf: u32,
+    buf_len: u32,
+    cookie: u64,
+    result: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        ctx.fd_readdir(m, fd, buf, buf_len, cookie, result)
+    })
+}
+
+unsafe extern "C" fn fd_renumber(vmctx: *mut VMContext, fd: u32, to: u32) -> u32 {
+    with_ctx(vmctx, |ctx, _, _| {
+        if !ctx.fds.contains_key(&to) {
+            return Err(errno::BADF);
+        }
+        let descriptor = ctx.fds.remove(&fd).ok_or(errno::BADF)?;
+        ctx.fds.insert(to, descriptor);
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn fd_seek(
+    vmctx: *mut VMContext,
+    fd: u32,
+    offset: i64,
+    whence: u32,
+    result: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, legacy| {
+        // The legacy ABI numbers the whences CUR, END, SET.
+        let whence = if legacy { (whence + 1) % 3 } else { whence };
+        let pos = match whence {
+            0 if offset >= 0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(errno::INVAL),
+        };
+        let position = ctx.handle(fd)?.seek(pos)?;
+        m.write_u64(result, position)
+    })
+}
+
+unsafe extern "C" fn fd_tell(vmctx: *mut VMContext, fd: u32, result: u32) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        let position = ctx.handle(fd)?.seek(SeekFrom::Current(0))?;
+        m.write_u64(result, position)
+    })
+}
+
+unsafe extern "C" fn path_create_directory(
+    vmctx: *mut VMContext,
+    fd: u32,
+    path: u32,
+    path_len: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        let (dir, path) = ctx.dir_path(m, fd, path, path_len)?;
+        dir.create_dir(&path)
+    })
+}
+
+unsafe extern "C" fn path_filestat_get(
+    vmctx: *mut VMContext,
+    fd: u32,
+    flags: u32,
+    path: u32,
+    path_len: u32,
+    result: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, legacy| {
+        let (dir, path) = ctx.dir_path(m, fd, path, path_len)?;
+        let stat = dir.path_filestat(&path, flags & LOOKUP_SYMLINK_FOLLOW != 0)?;
+        write_filestat(m, result, &stat, legacy)
+    })
+}
+
+unsafe extern "C" fn path_filestat_set_times(
+    vmctx: *mut VMContext,
+    fd: u32,
+    _flags: u32,
+    _path: u32,
+    _path_len: u32,
+    _atim: u64,
+    _mtim: u64,
+    _fst_flags: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, _, _| {
+        ctx.handle(fd)?;
+        Err(errno::NOTSUP)
+    })
+}
+
+unsafe extern "C" fn path_link(
+    vmctx: *mut VMContext,
+    _fd: u32,
+    _flags: u32,
+    _old_path: u32,
+    _old_path_len: u32,
+    _new_fd: u32,
+    _new_path: u32,
+    _new_path_len: u32,
+) -> u32 {
+    with_ctx(vmctx, |_, _, _| Err(errno::NOTSUP))
+}
+
+unsafe extern "C" fn path_open(
+    vmctx: *mut VMContext,
+    fd: u32,
+    _dirflags: u32,
+    path: u32,
+    path_len: u32,
+    oflags: u32,
+    rights_base: u64,
+    _rights_inheriting: u64,
+    fdflags: u32,
+    result: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        ctx.path_open(m, fd, path, path_len, oflags, rights_base, fdflags, result)
+    })
+}
+
+unsafe extern "C" fn path_readlink(
+    vmctx: *mut VMContext,
+    fd: u32,
+    path: u32,
+    path_len: u32,
+    buf: u32,
+    buf_len: u32,
+    result: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        let (dir, path) = ctx.dir_path(m, fd, path, path_len)?;
+        let target = dir.readlink(&path)?;
+        let n = target.len().min(buf_len as usize);
+        m.write(buf, &target.as_bytes()[..n])?;
+        m.write_u32(result, n as u32)
+    })
+}
+
+unsafe extern "C" fn path_remove_directory(
+    vmctx: *mut VMContext,
+    fd: u32,
+    path: u32,
+    path_len: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        let (dir, path) = ctx.dir_path(m, fd, path, path_len)?;
+        dir.remove_dir(&path)
+    })
+}
+
+unsafe extern "C" fn path_rename(
+    vmctx: *mut VMContext,
+    fd: u32,
+    old_path: u32,
+    old_path_len: u32,
+    new_fd: u32,
+    new_path: u32,
+    new_path_len: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        ctx.path_rename(
+            m,
+            fd,
+            old_path,
+            old_path_len,
+            new_fd,
+            new_path,
+            new_path_len,
+        )
+    })
+}
+
+unsafe extern "C" fn path_symlink(
+    vmctx: *mut VMContext,
+    _old_path: u32,
+    _old_path_len: u32,
+    _fd: u32,
+    _new_path: u32,
+    _new_path_len: u32,
+) -> u32 {
+    with_ctx(vmctx, |_, _, _| Err(errno::NOTSUP))
+}
+
+unsafe extern "C" fn path_unlink_file(
+    vmctx: *mut VMContext,
+    fd: u32,
+    path: u32,
+    path_len: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, _| {
+        let (dir, path) = ctx.dir_path(m, fd, path, path_len)?;
+        dir.unlink_file(&path)
+    })
+}
+
+unsafe extern "C" fn poll_oneoff(
+    vmctx: *mut VMContext,
+    subscriptions: u32,
+    events: u32,
+    count: u32,
+    result: u32,
+) -> u32 {
+    with_ctx(vmctx, |ctx, m, legacy| {
+        ctx.poll_oneoff(m, subscriptions, events, count, result, legacy)
+    })
+}
+
+/// Ends the guest: the export call in progress raises `WasiExit(code)`.
+unsafe extern "C" fn proc_exit(vmctx: *mut VMContext, code: u32) {
+    with_ctx(vmctx, |ctx, _, _| {
+        ctx.exit_code = Some(code as i32);
+        Ok(())
+    });
+    let err = {
+        let _gil = Python::acquire_gil();
+        WasiExit::py_err(code as i32)
+    };
+    raise(err)
+}
+
+unsafe extern "C" fn proc_raise(vmctx: *mut VMContext, _signal: u32) -> u32 {
+    with_ctx(vmctx, |_, _, _| Err(errno::NOSYS))
+}
+
+unsafe extern "C" fn random_get(vmctx: *mut VMContext, buf: u32, buf_len: u32) -> u32 {
+    with_ctx(vmctx, |_, m, _| {
+        let buf = m.slice(buf, buf_len)?;
+        File::open("/dev/urandom")
+            .and_then(|mut f| f.read_exact(buf))
+            .map_err(|_| errno::IO)
+    })
+}
+
+unsafe extern "C" fn sched_yield(vmctx: *mut VMContext) -> u32 {
+    with_ctx(vmctx, |_, _, _| {
+        thread::yield_now();
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn sock_recv(
+    vmctx: *mut VMContext,
+    _fd: u32,
+    _ri_data: u32,
+    _ri_data_len: u32,
+    _ri_flags: u32,
+    _ro_datalen: u32,
+    _ro_flags: u32,
+) -> u32 {
+    with_ctx(vmctx, |_, _, _| Err(errno::NOTSUP))
+}
+
+unsafe extern "C" fn sock_send(
+    vmctx: *mut VMContext,
+    _fd: u32,
+    _si_data: u32,
+    _si_data_len: u32,
+    _si_flags: u32,
+    _so_datalen: u32,
+) -> u32 {
+    with_ctx(vmctx, |_, _, _| Err(errno::NOTSUP))
+}
+
+unsafe extern "C" fn sock_shutdown(vmctx: *mut VMContext, _fd: u32, _how: u32) -> u32 {
+    with_ctx(vmctx, |_, _, _| Err(errno::NOTSUP))
+}
+
+/// Creates the host instance providing the WASI functions on `ctx`.
+fn instantiate_wasi(
+    global_exports: Rc<RefCell<HashMap<String, Option<Export>>>>,
+    ctx: Arc<Mutex<WasiCtx>>,
+    legacy: bool,
+) -> InstanceHandle {
+    let mut module = Module::new();
+    let mut finished_functions = PrimaryMap::new();
+    let (i32, i64) = (types::I32, types::I64);
+    let functions: [(&str, &[ir::Type], &[ir::Type], *const VMFunctionBody); 45] = [
+        ("args_get", &[i32, i32], &[i32], args_get as _),
+        ("args_sizes_get", &[i32, i32], &[i32], args_sizes_get as _),
+        ("environ_get", &[i32, i32], &[i32], environ_get as _),
+        (
+            "environ_sizes_get",
+            &[i32, i32],
+            &[i32],
+            environ_sizes_get as _,
+        ),
+        ("clock_res_get", &[i32, i32], &[i32], clock_res_get as _),
+        (
+            "clock_time_get",
+            &[i32, i64, i32],
+            &[i32],
+            clock_time_get as _,
+        ),
+        ("fd_advise", &[i32, i64, i64, i32], &[i32], fd_advise as _),
+        ("fd_allocate", &[i32, i64, i64], &[i32], fd_allocate as _),
+        ("fd_close", &[i32], &[i32], fd_close as _),
+        ("fd_datasync", &[i32], &[i32], fd_sync as _),
+        ("fd_fdstat_get", &[i32, i32], &[i32], fd_fdstat_get as _),
+        (
+            "fd_fdstat_set_flags",
+            &[i32, i32],
+            &[i32],
+            fd_fdstat_set_flags as _,
+        ),
+        (
+            "fd_fdstat_set_rights",
+            &[i32, i64, i64],
+            &[i32],
+            fd_fdstat_set_rights as _,
+        ),
+        ("fd_filestat_get", &[i32, i32], &[i32], fd_filestat_get as _),
+        (
+            "fd_filestat_set_size",
+            &[i32, i64],
+            &[i32],
+            fd_filestat_set_size as _,
+        ),
+        (
+            "fd_filestat_set_times",
+            &[i32, i64, i64, i32],
+            &[i32],
+            fd_filestat_set_times as _,
+        ),
+        (
+            "fd_pread",
+            &[i32, i32, i32, i64, i32],
+            &[i32],
+            fd_pread as _,
+        ),
+        ("fd_prestat_get", &[i32, i32], &[i32], fd_prestat_get as _),
+        (
+            "fd_prestat_dir_name",
+            &[i32, i32, i32],
+            &[i32],
+            fd_prestat_dir_name as _,
+        ),
+        (
+            "fd_pwrite",
+            &[i32, i32, i32, i64, i32],
+            &[i32],
+            fd_pwrite as _,
+        ),
+        ("fd_read", &[i32, i32, i32, i32], &[i32], fd_read as _),
+        (
+            "fd_readdir",
+            &[i32, i32, i32, i64, i32],
+            &[i32],
+            fd_readdir as _,
+        ),
+        ("fd_renumber", &[i32, i32], &[i32], fd_renumber as _),
+        ("fd_seek", &[i32, i64, i32, i32], &[i32], fd_seek as _),
+        ("fd_sync", &[i32], &[i32], fd_sync as _),
+        ("fd_tell", &[i32, i32], &[i32], fd_tell as _),
+        ("fd_write", &[i32, i32, i32, i32], &[i32], fd_write as _),
+        (
+            "path_create_directory",
+            &[i32, i32, i32],
+            &[i32],
+            path_create_directory as _,
+        ),
+        (
+            "path_filestat_get",
+            &[i32, i32, i32, i32, i32],
+            &[i32],
+            path_filestat_get as _,
+        ),
+        (
+            "path_filestat_set_times",
+            &[i32, i32, i32, i32, i64, i64, i32],
+            &[i32],
+            path_filestat_set_times as _,
+        ),
+        (
+            "path_link",
+            &[i32, i32, i32, i32, i32, i32, i32],
+            &[i32],
+            path_link as _,
+        ),
+        (
+            "path_open",
+            &[i32, i32, i32, i32, i32, i64, i64, i32, i32],
+            &[i32],
+            path_open as _,
+        ),
+        (
+            "path_readlink",
+            &[i32, i32, i32, i32, i32, i32],
+            &[i32],
+            path_readlink as _,
+        ),
+        (
+            "path_remove_directory",
+            &[i32, i32, i32],
+            &[i32],
+            path_remove_directory as _,
+        ),
+        (
+            "path_rename",
+            &[i32, i32, i32, i32, i32, i32],
+            &[i32],
+            path_rename as _,
+        ),
+        (
+            "path_symlink",
+            &[i32, i32, i32, i32, i32],
+            &[i32],
+            path_symlink as _,
+        ),
+        (
+            "path_unlink_file",
+            &[i32, i32, i32],
+            &[i32],
+            path_unlink_file as _,
+        ),
+        (
+            "poll_oneoff",
+            &[i32, i32, i32, i32],
+            &[i32],
+            poll_oneoff as _,
+        ),
+        ("proc_exit", &[i32], &[], proc_exit as _),
+        ("proc_raise", &[i32], &[i32], proc_raise as _),
+        ("random_get", &[i32, i32], &[i32], random_get as _),
+        ("sched_yield", &[], &[i32], sched_yield as _),
+        (
+            "sock_recv",
+            &[i32, i32, i32, i32, i32, i32],
+            &[i32],
+            sock_recv as _,
+        ),
+        (
+            "sock_send",
+            &[i32, i32, i32, i32, i32],
+            &[i32],
+            sock_send as _,
+        ),
+        ("sock_shutdown", &[i32, i32], &[i32], sock_shutdown as _),
+    ];
+    for (name, params, returns, body) in functions.iter() {
+        add_function(
+            &mut module,
+            &mut finished_functions,
+            name,
+            params,
+            returns,
+            *body,
+        );
+    }
+
+    let imports = Imports::new(
+        HashSet::new(),
+        PrimaryMap::new(),
+        PrimaryMap::new(),
+        PrimaryMap::new(),
+        PrimaryMap::new(),
+    );
+    let data_initializers = Vec::new();
+    let signatures = PrimaryMap::new();
+
+    InstanceHandle::new(
+        Rc::new(module),
+        global_exports,
+        finished_functions.into_boxed_slice(),
+        imports,
+        &data_initializers,
+        signatures.into_boxed_slice(),
+        None,
+        Box::new(WasiHost {
+            ctx,
+            memory: ptr::null_mut(),
+            legacy,
+        }),
+    )
+    .expect("wasi instance")
+}
+
+/// Gives the WASI instance `wasi` access to the memory exported by the
+/// guest `instance`, once it is instantiated.
+pub fn attach_memory(wasi: &mut InstanceHandle, instance: &mut InstanceHandle) {
+    let memory = match instance.lookup("memory") {
+        Some(Export::Memory { definition, .. }) => definition,
+        _ => return,
+    };
+    let host = wasi.host_state().downcast_mut::<WasiHost>().expect("state");
+    host.memory = memory;
+}
+
+/// Preopened directory of a `WasiConfig`.
+enum Preopen {
+    Host(String),
+    Virtual(Arc<Mutex<Tree>>),
+}
+
+impl Preopen {
+    fn extract(dir: &PyAny) -> PyResult<Self> {
+        if dir.get_type().is_subclass::<VirtualDir>()? {
+            return Ok(Preopen::Virtual(dir.cast_as::<VirtualDir>()?.tree()));
+        }
+        Ok(Preopen::Host(dir.extract()?))
+    }
+
+    fn open(&self) -> PyResult<Box<dyn Handle>> {
+        Ok(match self {
+            Preopen::Host(path) => Box::new(
+                HostDir::open(Path::new(path))
+                    .map_err(|e| OSError::py_err(format!("cannot preopen {}: {}", path, e)))?,
+            ),
+            Preopen::Virtual(tree) => open_root(tree),
+        })
+    }
+}
+
+/// Standard stream of a `WasiConfig`.
+enum Stdio {
+    /// The stream of the process, or none without `inherit_stdio`.
+    Default,
+    Fd(i32),
+    /// Input of `stdin`.
+    Bytes(Vec<u8>),
+    /// A Python file-like.
+    Object(PyObject),
+}
+
+impl Stdio {
+    /// Parses the `stdin` (`input` set), `stdout` or `stderr` argument.
+    fn extract(py: Python, stream: Option<&PyAny>, input: bool) -> PyResult<Self> {
+        let stream = match stream {
+            Some(stream) if !stream.is_none() => stream,
+            _ => return Ok(Stdio::Default),
+        };
+        if let Ok(fd) = stream.extract::<i32>() {
+            return Ok(Stdio::Fd(fd));
+        }
+        if input {
+            if let Ok(bytes) = stream.downcast_ref::<PyBytes>() {
+                return Ok(Stdio::Bytes(bytes.as_bytes().to_vec()));
+            }
+        }
+        let method = if input { "read" } else { "write" };
+        if !stream.hasattr(method)? {
+            return Err(TypeError::py_err(format!(
+                "expected a file descriptor or an object with a {} method",
+                method
+            )));
+        }
+        Ok(Stdio::Object(stream.into_object(py)))
+    }
+
+    fn handle(&self, py: Python, inherit: bool, fd: i32) -> PyResult<Box<dyn Handle>> {
+        Ok(match self {
+            Stdio::Default if inherit => Box::new(InheritedStdio::new(fd)),
+            Stdio::Default => Box::new(NullStream),
+            Stdio::Fd(stream) => Box::new(HostFile::from_fd(*stream)?),
+            Stdio::Bytes(data) => Box::new(BytesStream::new(data.clone())),
+            Stdio::Object(obj) => Box::new(PyStream::new(py, obj.as_ref(py))?),
+        })
+    }
+}
+
+/// Configuration of the WASI environment of a guest.
+///
+/// `argv` and `env` (a dict) are passed to the guest as is, `preopens`
+/// maps guest paths to the host directories given access to, or to
+/// `VirtualDir`s. The standard
+/// streams are those of the process when `inherit_stdio` is set (the
+/// default). `stdin`, `stdout` and `stderr` redirect them to a file
+/// descriptor or a Python file-like object, `stdin` can also be `bytes`.
+#[pyclass]
+pub struct WasiConfig {
+    argv: Vec<String>,
+    env: Vec<(String, String)>,
+    preopens: Vec<(String, Preopen)>,
+    inherit_stdio: bool,
+    stdio: [Stdio; 3],
+}
+
+#[pymethods]
+impl WasiConfig {
+    #[new]
+    #[args(
+        argv = "None",
+        env = "None",
+        preopens = "None",
+        inherit_stdio = "true",
+        stdin = "None",
+        stdout = "None",
+        stderr = "None"
+    )]
+    fn new(
+        obj: &PyRawObject,
+        argv: Option<Vec<String>>,
+        env: Option<&PyDict>,
+        preopens: Option<&PyDict>,
+        inherit_stdio: bool,
+        stdin: Option<&PyAny>,
+        stdout: Option<&PyAny>,
+        stderr: Option<&PyAny>,
+    ) -> PyResult<()> {
+        let py = obj.py();
+        let pairs = |dict: Option<&PyDict>| -> PyResult<Vec<(String, String)>> {
+            let mut pairs = Vec::new();
+            if let Some(dict) = dict {
+                for (key, value) in dict.iter() {
+                    pairs.push((key.extract()?, value.extract()?));
+                }
+            }
+            Ok(pairs)
+        };
+        let mut dirs = Vec::new();
+        if let Some(preopens) = preopens {
+            for (guest, dir) in preopens.iter() {
+                dirs.push((guest.extract()?, Preopen::extract(dir)?));
+            }
+        }
+        obj.init(WasiConfig {
+            argv: argv.unwrap_or_default(),
+            env: pairs(env)?,
+            preopens: dirs,
+            inherit_stdio,
+            stdio: [
+                Stdio::extract(py, stdin, true)?,
+                Stdio::extract(py, stdout, false)?,
+                Stdio::extract(py, stderr, false)?,
+            ],
+        });
+        Ok(())
+    }
+}
+
+impl WasiConfig {
+    fn build(&self, py: Python) -> PyResult<WasiCtx> {
+        let mut fds = BTreeMap::new();
+        for (fd, stdio) in self.stdio.iter().enumerate() {
+            let handle = stdio.handle(py, self.inherit_stdio, fd as i32)?;
+            fds.insert(fd as u32, Descriptor::new(handle));
+        }
+        for (guest, dir) in &self.preopens {
+            fds.insert(
+                fds.len() as u32,
+                Descriptor {
+                    handle: dir.open()?,
+                    preopen: Some(guest.clone()),
+                    fdflags: 0,
+                },
+            );
+        }
+        Ok(WasiCtx {
+            args: self.argv.clone(),
+            env: self
+                .env
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect(),
+            fds,
+            exit_code: None,
+        })
+    }
+}
+
+/// WASI environment of a guest, to be put in the import object under
+/// "wasi_snapshot_preview1" or "wasi_unstable".
+#[pyclass]
+pub struct WasiInstance {
+    ctx: Arc<Mutex<WasiCtx>>,
+}
+
+impl WasiInstance {
+    /// Creates the host instance to be named `module_name`.
+    pub fn instantiate(
+        &self,
+        global_exports: Rc<RefCell<HashMap<String, Option<Export>>>>,
+        module_name: &str,
+    ) -> InstanceHandle {
+        instantiate_wasi(
+            global_exports,
+            self.ctx.clone(),
+            module_name == "wasi_unstable",
+        )
+    }
+
+    pub fn from_config(py: Python, config: &WasiConfig) -> PyResult<Self> {
+        let ctx = config.build(py)?;
+        Ok(WasiInstance {
+            ctx: Arc::new(Mutex::new(ctx)),
+        })
+    }
+
+    /// Locks the context; the GIL is released while waiting, since the
+    /// guest holding it may need the GIL.
+    fn lock<'a>(&'a self, py: Python) -> std::sync::MutexGuard<'a, WasiCtx> {
+        py.allow_threads(|| self.ctx.lock().unwrap())
+    }
+}
+
+#[pymethods]
+impl WasiInstance {
+    #[new]
+    fn new(obj: &PyRawObject, config: &WasiConfig) -> PyResult<()> {
+        obj.init(WasiInstance::from_config(obj.py(), config)?);
+        Ok(())
+    }
+
+    /// The code passed to `proc_exit`, if the guest called it.
+    #[getter(exit_code)]
+    fn get_exit_code(&self, py: Python) -> Option<i32> {
+        self.lock(py).exit_code
+    }
+}
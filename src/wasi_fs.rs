@@ -0,0 +1,618 @@
+//! Files, directories and streams behind the descriptors of WASI guests.
+
+use std::any::Any;
+use std::ffi::{CStr, CString, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Component, Path, PathBuf};
+
+/// WASI error code.
+pub type Errno = u16;
+
+pub mod errno {
+    use super::Errno;
+
+    pub const SUCCESS: Errno = 0;
+    pub const ACCES: Errno = 2;
+    pub const BADF: Errno = 8;
+    pub const EXIST: Errno = 20;
+    pub const FAULT: Errno = 21;
+    pub const FBIG: Errno = 22;
+    pub const ILSEQ: Errno = 25;
+    pub const INVAL: Errno = 28;
+    pub const IO: Errno = 29;
+    pub const ISDIR: Errno = 31;
+    pub const LOOP: Errno = 32;
+    pub const NAMETOOLONG: Errno = 37;
+    pub const NOENT: Errno = 44;
+    pub const NOSPC: Errno = 51;
+    pub const NOSYS: Errno = 52;
+    pub const NOTDIR: Errno = 54;
+    pub const NOTEMPTY: Errno = 55;
+    pub const NOTSUP: Errno = 58;
+    pub const PERM: Errno = 63;
+    pub const SPIPE: Errno = 70;
+    pub const XDEV: Errno = 75;
+    pub const NOTCAPABLE: Errno = 76;
+}
+
+pub mod filetype {
+    pub const UNKNOWN: u8 = 0;
+    pub const BLOCK_DEVICE: u8 = 1;
+    pub const CHARACTER_DEVICE: u8 = 2;
+    pub const DIRECTORY: u8 = 3;
+    pub const REGULAR_FILE: u8 = 4;
+    pub const SOCKET_STREAM: u8 = 6;
+    pub const SYMBOLIC_LINK: u8 = 7;
+}
+
+/// Converts a host I/O error.
+pub fn from_io_error(e: io::Error) -> Errno {
+    match e.raw_os_error() {
+        Some(libc::EACCES) => errno::ACCES,
+        Some(libc::EBADF) => errno::BADF,
+        Some(libc::EEXIST) => errno::EXIST,
+        Some(libc::EFBIG) => errno::FBIG,
+        Some(libc::EINVAL) => errno::INVAL,
+        Some(libc::EISDIR) => errno::ISDIR,
+        Some(libc::ELOOP) => errno::LOOP,
+        Some(libc::ENAMETOOLONG) => errno::NAMETOOLONG,
+        Some(libc::ENOENT) => errno::NOENT,
+        Some(libc::ENOSPC) => errno::NOSPC,
+        Some(libc::ENOTDIR) => errno::NOTDIR,
+        Some(libc::ENOTEMPTY) => errno::NOTEMPTY,
+        Some(libc::EPERM) => errno::PERM,
+        Some(libc::ESPIPE) => errno::SPIPE,
+        Some(libc::EXDEV) => errno::XDEV,
+        _ => errno::IO,
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Filestat {
+    pub dev: u64,
+    pub ino: u64,
+    pub filetype: u8,
+    pub nlink: u64,
+    pub size: u64,
+    pub atim: u64,
+    pub mtim: u64,
+    pub ctim: u64,
+}
+
+/// An entry returned by `Handle::readdir`.
+pub struct Dirent {
+    pub name: String,
+    pub filetype: u8,
+    pub ino: u64,
+}
+
+/// How `Handle::open` opens a path, decoded from the `path_open` flags.
+#[derive(Default)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    pub create: bool,
+    pub exclusive: bool,
+    pub truncate: bool,
+    pub append: bool,
+    pub directory: bool,
+}
+
+/// An open file description. Stream and file operations fail with `BADF`
+/// or `SPIPE` when not supported, directory ones with `NOTDIR`.
+///
+/// The `path` arguments of directory operations are relative paths checked
+/// with `normalize_path`; an empty path designates the directory itself.
+pub trait Handle: Send {
+    fn filetype(&self) -> u8;
+
+    fn as_any(&self) -> &dyn Any;
+
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(errno::BADF)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(errno::BADF)
+    }
+
+    fn pread(&mut self, _buf: &mut [u8], _offset: u64) -> Result<usize, Errno> {
+        Err(errno::SPIPE)
+    }
+
+    fn pwrite(&mut self, _buf: &[u8], _offset: u64) -> Result<usize, Errno> {
+        Err(errno::SPIPE)
+    }
+
+    fn seek(&mut self, _pos: SeekFrom) -> Result<u64, Errno> {
+        Err(errno::SPIPE)
+    }
+
+    fn filestat(&self) -> Result<Filestat, Errno> {
+        Ok(Filestat {
+            filetype: self.filetype(),
+            nlink: 1,
+            ..Filestat::default()
+        })
+    }
+
+    fn set_size(&mut self, _size: u64) -> Result<(), Errno> {
+        Err(errno::INVAL)
+    }
+
+    fn sync(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn open(&self, _path: &str, _options: &OpenOptions) -> Result<Box<dyn Handle>, Errno> {
+        Err(errno::NOTDIR)
+    }
+
+    fn path_filestat(&self, _path: &str, _follow: bool) -> Result<Filestat, Errno> {
+        Err(errno::NOTDIR)
+    }
+
+    fn create_dir(&self, _path: &str) -> Result<(), Errno> {
+        Err(errno::NOTDIR)
+    }
+
+    fn remove_dir(&self, _path: &str) -> Result<(), Errno> {
+        Err(errno::NOTDIR)
+    }
+
+    fn unlink_file(&self, _path: &str) -> Result<(), Errno> {
+        Err(errno::NOTDIR)
+    }
+
+    /// Renames `from` to `to` in `to_dir`, which has to be of the same kind.
+    fn rename(&self, _from: &str, _to_dir: &dyn Handle, _to: &str) -> Result<(), Errno> {
+        Err(errno::NOTDIR)
+    }
+
+    /// Lists the directory, including "." and "..".
+    fn readdir(&self) -> Result<Vec<Dirent>, Errno> {
+        Err(errno::NOTDIR)
+    }
+
+    fn readlink(&self, _path: &str) -> Result<String, Errno> {
+        Err(errno::NOTDIR)
+    }
+}
+
+/// Checks a guest path relative to a directory descriptor: absolute paths
+/// and paths leaving the directory are refused. Returns the path with the
+/// "." components removed and the ".." ones applied.
+pub fn normalize_path(path: &str) -> Result<String, Errno> {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" if components.is_empty() && path.starts_with('/') => return Err(errno::NOTCAPABLE),
+            "" | "." => (),
+            ".." => {
+                if components.pop().is_none() {
+                    return Err(errno::NOTCAPABLE);
+                }
+            }
+            _ => components.push(component),
+        }
+    }
+    Ok(components.join("/"))
+}
+
+fn host_filetype(file_type: fs::FileType) -> u8 {
+    if file_type.is_dir() {
+        filetype::DIRECTORY
+    } else if file_type.is_file() {
+        filetype::REGULAR_FILE
+    } else if file_type.is_symlink() {
+        filetype::SYMBOLIC_LINK
+    } else if file_type.is_char_device() {
+        filetype::CHARACTER_DEVICE
+    } else if file_type.is_block_device() {
+        filetype::BLOCK_DEVICE
+    } else if file_type.is_socket() {
+        filetype::SOCKET_STREAM
+    } else {
+        filetype::UNKNOWN
+    }
+}
+
+fn host_filestat(metadata: &fs::Metadata) -> Filestat {
+    let nanos = |secs: i64, nsecs: i64| (secs as u64) * 1_000_000_000 + nsecs as u64;
+    Filestat {
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+        filetype: host_filetype(metadata.file_type()),
+        nlink: metadata.nlink(),
+        size: metadata.size(),
+        atim: nanos(metadata.atime(), metadata.atime_nsec()),
+        mtim: nanos(metadata.mtime(), metadata.mtime_nsec()),
+        ctim: nanos(metadata.ctime(), metadata.ctime_nsec()),
+    }
+}
+
+/// A file of the host, or any descriptor passed from Python.
+pub struct HostFile {
+    file: File,
+}
+
+impl HostFile {
+    /// Wraps a duplicate of the host descriptor `fd`.
+    pub fn from_fd(fd: i32) -> io::Result<Self> {
+        let fd = unsafe { libc::dup(fd) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            file: unsafe { File::from_raw_fd(fd) },
+        })
+    }
+}
+
+impl Handle for HostFile {
+    fn filetype(&self) -> u8 {
+        self.file
+            .metadata()
+            .map(|m| host_filetype(m.file_type()))
+            .unwrap_or(filetype::UNKNOWN)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Errno> {
+        self.file.read(buf).map_err(from_io_error)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Errno> {
+        self.file.write(buf).map_err(from_io_error)
+    }
+
+    fn pread(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, Errno> {
+        self.file.read_at(buf, offset).map_err(from_io_error)
+    }
+
+    fn pwrite(&mut self, buf: &[u8], offset: u64) -> Result<usize, Errno> {
+        self.file.write_at(buf, offset).map_err(from_io_error)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Errno> {
+        self.file.seek(pos).map_err(from_io_error)
+    }
+
+    fn filestat(&self) -> Result<Filestat, Errno> {
+        let metadata = self.file.metadata().map_err(from_io_error)?;
+        Ok(host_filestat(&metadata))
+    }
+
+    fn set_size(&mut self, size: u64) -> Result<(), Errno> {
+        self.file.set_len(size).map_err(from_io_error)
+    }
+
+    fn sync(&mut self) -> Result<(), Errno> {
+        self.file.sync_all().map_err(from_io_error)
+    }
+}
+
+/// A directory of the host. Guest paths are resolved from its descriptor
+/// one component at a time, without following symbolic links, so that
+/// neither links nor concurrent changes of the host tree can lead out of
+/// the preopened directory.
+pub struct HostDir {
+    dir: File,
+}
+
+fn c_name(name: &str) -> Result<CString, Errno> {
+    CString::new(name).map_err(|_| errno::ILSEQ)
+}
+
+/// Converts the result of a libc call.
+fn check(result: c_int) -> Result<c_int, Errno> {
+    if result < 0 {
+        Err(from_io_error(io::Error::last_os_error()))
+    } else {
+        Ok(result)
+    }
+}
+
+/// Opens `name` in `dir` with `flags`; a symbolic link fails with `LOOP`
+/// (or `NOTDIR` for a directory).
+fn open_at(dir: &File, name: &CStr, flags: c_int) -> Result<File, Errno> {
+    let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = check(unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags, 0o666) })?;
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// The status of `name` in `dir`, not following a symbolic link.
+fn filestat_at(dir: &File, name: &CStr) -> Result<Filestat, Errno> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    check(unsafe {
+        libc::fstatat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            &mut stat,
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })?;
+    let filetype = match stat.st_mode & libc::S_IFMT {
+        libc::S_IFDIR => filetype::DIRECTORY,
+        libc::S_IFREG => filetype::REGULAR_FILE,
+        libc::S_IFLNK => filetype::SYMBOLIC_LINK,
+        libc::S_IFCHR => filetype::CHARACTER_DEVICE,
+        libc::S_IFBLK => filetype::BLOCK_DEVICE,
+        libc::S_IFSOCK => filetype::SOCKET_STREAM,
+        _ => filetype::UNKNOWN,
+    };
+    let nanos = |secs: i64, nsecs: i64| (secs as u64) * 1_000_000_000 + nsecs as u64;
+    Ok(Filestat {
+        dev: stat.st_dev as u64,
+        ino: stat.st_ino as u64,
+        filetype,
+        nlink: stat.st_nlink as u64,
+        size: stat.st_size as u64,
+        atim: nanos(stat.st_atime as i64, stat.st_atime_nsec as i64),
+        mtim: nanos(stat.st_mtime as i64, stat.st_mtime_nsec as i64),
+        ctim: nanos(stat.st_ctime as i64, stat.st_ctime_nsec as i64),
+    })
+}
+
+fn dirent_filetype(d_type: u8) -> u8 {
+    match d_type {
+        libc::DT_DIR => filetype::DIRECTORY,
+        libc::DT_REG => filetype::REGULAR_FILE,
+        libc::DT_LNK => filetype::SYMBOLIC_LINK,
+        libc::DT_CHR => filetype::CHARACTER_DEVICE,
+        libc::DT_BLK => filetype::BLOCK_DEVICE,
+        libc::DT_SOCK => filetype::SOCKET_STREAM,
+        _ => filetype::UNKNOWN,
+    }
+}
+
+impl HostDir {
+    /// Preopens the host directory `path`.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let dir = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY | libc::O_CLOEXEC)
+            .open(path)?;
+        Ok(Self { dir })
+    }
+
+    /// Opens the directory containing the (normalized) `path` and returns
+    /// it with the last component of the path, "." for the directory
+    /// itself.
+    fn parent(&self, path: &str) -> Result<(File, CString), Errno> {
+        let mut components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let name = components.pop().unwrap_or(".");
+        let mut dir = self.dir.try_clone().map_err(from_io_error)?;
+        for component in components {
+            dir = open_at(
+                &dir,
+                &c_name(component)?,
+                libc::O_RDONLY | libc::O_DIRECTORY,
+            )?;
+        }
+        Ok((dir, c_name(name)?))
+    }
+}
+
+impl Handle for HostDir {
+    fn filetype(&self) -> u8 {
+        filetype::DIRECTORY
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn filestat(&self) -> Result<Filestat, Errno> {
+        let metadata = self.dir.metadata().map_err(from_io_error)?;
+        Ok(host_filestat(&metadata))
+    }
+
+    fn open(&self, path: &str, options: &OpenOptions) -> Result<Box<dyn Handle>, Errno> {
+        let (dir, name) = self.parent(path)?;
+        let write = options.write || options.append;
+        let mut flags = match (options.read || !write, write) {
+            (true, true) => libc::O_RDWR,
+            (false, true) => libc::O_WRONLY,
+            _ => libc::O_RDONLY,
+        };
+        let optional_flags = [
+            (options.append, libc::O_APPEND),
+            (options.create, libc::O_CREAT),
+            (options.create && options.exclusive, libc::O_EXCL),
+            (options.truncate, libc::O_TRUNC),
+            (options.directory, libc::O_DIRECTORY),
+        ];
+        for (set, flag) in optional_flags.iter() {
+            if *set {
+                flags |= flag;
+            }
+        }
+        // Directories can't be opened for writing or created this way.
+        let file = open_at(&dir, &name, flags)?;
+        if file.metadata().map_err(from_io_error)?.is_dir() {
+            return Ok(Box::new(HostDir { dir: file }));
+        }
+        Ok(Box::new(HostFile { file }))
+    }
+
+    /// Symbolic links are not followed: with `follow`, one fails with
+    /// `LOOP`.
+    fn path_filestat(&self, path: &str, follow: bool) -> Result<Filestat, Errno> {
+        let (dir, name) = self.parent(path)?;
+        let stat = filestat_at(&dir, &name)?;
+        if follow && stat.filetype == filetype::SYMBOLIC_LINK {
+            return Err(errno::LOOP);
+        }
+        Ok(stat)
+    }
+
+    fn create_dir(&self, path: &str) -> Result<(), Errno> {
+        let (dir, name) = self.parent(path)?;
+        check(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o777) })?;
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &str) -> Result<(), Errno> {
+        let (dir, name) = self.parent(path)?;
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), libc::AT_REMOVEDIR) })?;
+        Ok(())
+    }
+
+    fn unlink_file(&self, path: &str) -> Result<(), Errno> {
+        let (dir, name) = self.parent(path)?;
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) })?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to_dir: &dyn Handle, to: &str) -> Result<(), Errno> {
+        let to_dir = to_dir
+            .as_any()
+            .downcast_ref::<HostDir>()
+            .ok_or(errno::XDEV)?;
+        let (from_dir, from_name) = self.parent(from)?;
+        let (to_dir, to_name) = to_dir.parent(to)?;
+        check(unsafe {
+            libc::renameat(
+                from_dir.as_raw_fd(),
+                from_name.as_ptr(),
+                to_dir.as_raw_fd(),
+                to_name.as_ptr(),
+            )
+        })?;
+        Ok(())
+    }
+
+    fn readdir(&self) -> Result<Vec<Dirent>, Errno> {
+        let mut entries = Vec::new();
+        for name in &[".", ".."] {
+            entries.push(Dirent {
+                name: name.to_string(),
+                filetype: filetype::DIRECTORY,
+                ino: 0,
+            });
+        }
+        // The stream owns (and closes) a duplicate of the descriptor, which
+        // shares its position.
+        let fd = check(unsafe { libc::dup(self.dir.as_raw_fd()) })?;
+        let stream = unsafe { libc::fdopendir(fd) };
+        if stream.is_null() {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(from_io_error(e));
+        }
+        unsafe {
+            libc::rewinddir(stream);
+            loop {
+                let entry = libc::readdir(stream);
+                if entry.is_null() {
+                    break;
+                }
+                let name = CStr::from_ptr((*entry).d_name.as_ptr()).to_string_lossy();
+                if name == "." || name == ".." {
+                    continue;
+                }
+                entries.push(Dirent {
+                    name: name.into_owned(),
+                    filetype: dirent_filetype((*entry).d_type),
+                    ino: (*entry).d_ino as u64,
+                });
+            }
+            libc::closedir(stream);
+        }
+        Ok(entries)
+    }
+
+    fn readlink(&self, path: &str) -> Result<String, Errno> {
+        let (dir, name) = self.parent(path)?;
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        let len = unsafe {
+            libc::readlinkat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return Err(from_io_error(io::Error::last_os_error()));
+        }
+        buf.truncate(len as usize);
+        let target = PathBuf::from(OsString::from_vec(buf));
+        // Absolute targets would not mean anything to the guest.
+        if target.components().any(|c| match c {
+            Component::RootDir | Component::Prefix(_) => true,
+            _ => false,
+        }) {
+            return Err(errno::NOTCAPABLE);
+        }
+        Ok(target.to_string_lossy().into_owned())
+    }
+}
+
+/// A standard stream of the process, inherited by the guest.
+pub struct InheritedStdio {
+    fd: i32,
+}
+
+impl InheritedStdio {
+    pub fn new(fd: i32) -> Self {
+        Self { fd }
+    }
+}
+
+impl Handle for InheritedStdio {
+    fn filetype(&self) -> u8 {
+        filetype::CHARACTER_DEVICE
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Errno> {
+        let n = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+        if n < 0 {
+            return Err(from_io_error(io::Error::last_os_error()));
+        }
+        Ok(n as usize)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Errno> {
+        let n = unsafe { libc::write(self.fd, buf.as_ptr() as *const _, buf.len()) };
+        if n < 0 {
+            return Err(from_io_error(io::Error::last_os_error()));
+        }
+        Ok(n as usize)
+    }
+}
+
+/// A stream reading as empty and discarding what is written.
+pub struct NullStream;
+
+impl Handle for NullStream {
+    fn filetype(&self) -> u8 {
+        filetype::CHARACTER_DEVICE
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Ok(0)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Errno> {
+        Ok(buf.len())
+    }
+}
@@ -0,0 +1,148 @@
+//! Standard streams of WASI guests backed by Python objects or bytes.
+
+use pyo3::prelude::*;
+use pyo3::types::{PyAny, PyBytes, PyString};
+
+use crate::wasi_fs::{errno, filetype, Errno, Handle};
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::str;
+
+thread_local! {
+    static STREAM_ERROR: RefCell<Option<PyErr>> = RefCell::new(None);
+}
+
+/// Takes the exception raised by the Python object of a stream during the
+/// last WASI call of this thread, if any.
+pub fn take_stream_error() -> Option<PyErr> {
+    STREAM_ERROR.with(|error| error.borrow_mut().take())
+}
+
+/// Records `err` to be raised once the WASI call returns, and reports an
+/// I/O error to the guest meanwhile.
+fn stream_error(err: PyErr) -> Errno {
+    STREAM_ERROR.with(|error| *error.borrow_mut() = Some(err));
+    errno::IO
+}
+
+/// A stream reading the given bytes.
+pub struct BytesStream {
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl BytesStream {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data, position: 0 }
+    }
+}
+
+impl Handle for BytesStream {
+    fn filetype(&self) -> u8 {
+        filetype::CHARACTER_DEVICE
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Errno> {
+        let rest = &self.data[self.position..];
+        let n = rest.len().min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// A stream calling the `read` or `write` method of a Python file-like.
+///
+/// Text streams (anything but `io.RawIOBase` and `io.BufferedIOBase`
+/// instances, so `io.StringIO` or objects with a `write(str)` method) get
+/// the output decoded as UTF-8; a character split across writes is only
+/// passed once complete.
+pub struct PyStream {
+    obj: PyObject,
+    text: bool,
+    /// Incomplete UTF-8 sequence of a text stream, or what was read from a
+    /// text stream beyond the buffer of the guest.
+    pending: Vec<u8>,
+}
+
+impl PyStream {
+    pub fn new(py: Python, obj: &PyAny) -> PyResult<Self> {
+        let io = py.import("io")?;
+        let binary = (io.getattr("RawIOBase")?, io.getattr("BufferedIOBase")?);
+        let text = !py
+            .import("builtins")?
+            .call1("isinstance", (obj, binary))?
+            .extract::<bool>()?;
+        Ok(Self {
+            obj: obj.into_object(py),
+            text,
+            pending: Vec::new(),
+        })
+    }
+
+    fn read_object(&mut self, py: Python, len: usize) -> PyResult<()> {
+        let data = self.obj.call_method1(py, "read", (len,))?;
+        let data = data.as_ref(py);
+        if let Ok(bytes) = data.downcast_ref::<PyBytes>() {
+            self.pending.extend_from_slice(bytes.as_bytes());
+        } else {
+            let s = data.downcast_ref::<PyString>()?;
+            self.pending.extend_from_slice(s.to_string()?.as_bytes());
+        }
+        Ok(())
+    }
+
+    fn write_object(&mut self, py: Python, buf: &[u8]) -> PyResult<()> {
+        if !self.text {
+            self.obj
+                .call_method1(py, "write", (PyBytes::new(py, buf),))?;
+            return Ok(());
+        }
+        self.pending.extend_from_slice(buf);
+        let valid = match str::from_utf8(&self.pending) {
+            Ok(s) => s.len(),
+            // Keep a truncated sequence for the next write.
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => self.pending.len(),
+        };
+        let s = String::from_utf8_lossy(&self.pending[..valid]).into_owned();
+        self.pending.drain(..valid);
+        if !s.is_empty() {
+            self.obj.call_method1(py, "write", (s,))?;
+        }
+        Ok(())
+    }
+}
+
+impl Handle for PyStream {
+    fn filetype(&self) -> u8 {
+        filetype::CHARACTER_DEVICE
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if self.pending.is_empty() {
+            let gil = Python::acquire_gil();
+            self.read_object(gil.python(), buf.len())
+                .map_err(stream_error)?;
+        }
+        let n = self.pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Errno> {
+        let gil = Python::acquire_gil();
+        self.write_object(gil.python(), buf).map_err(stream_error)?;
+        Ok(buf.len())
+    }
+}
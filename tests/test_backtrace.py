@@ -0,0 +1,175 @@
+import platform
+import struct
+import sys
+import traceback
+import unittest
+
+import wasmtime
+from wasm_binary import body, leb128, module, name, section, sleb128, vec
+
+
+def subsection(id, payload):
+    return bytes([id]) + leb128(len(payload)) + payload
+
+
+def demo(inner):
+    """(module $demo
+      (import "env" "log" (func))
+      (func $inner <inner>)
+      (func $outer (export "run") call $inner))"""
+    return module([
+        section(1, vec([b"\x60" + vec([]) + vec([])])),
+        section(2, vec([name("env") + name("log") + b"\x00\x00"])),
+        section(3, vec([b"\x00", b"\x00"])),
+        section(7, vec([name("run") + b"\x00\x02"])),
+        section(10, vec([body(inner), body(b"\x10\x01")])),
+        section(0, name("name")
+                + subsection(0, name("demo"))
+                + subsection(1, vec([leb128(1) + name("inner"),
+                                     leb128(2) + name("outer")]))),
+    ])
+
+
+# $inner traps with `unreachable`, or calls the import.
+WASM = demo(b"\x00")
+CALLING_WASM = demo(b"\x10\x00")
+
+IMPORTS = {"env": {"log": lambda: None}}
+
+
+def line_program(rows, end):
+    """DWARF 4 line program of src/lib.rs, `rows` being (address, line,
+    column) in the code section."""
+    header = (bytes([1, 1, 1, 0xfb, 14, 13])
+              + bytes([0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1])
+              + b"src\0\0" + b"lib.rs\0\x01\x00\x00\0")
+    program = b""
+    address, line = 0, 1
+    for row_address, row_line, column in rows:
+        if not program:
+            program += b"\x00\x05\x02" + struct.pack("<I", row_address)
+        else:
+            program += b"\x02" + leb128(row_address - address)
+        program += b"\x03" + sleb128(row_line - line) + b"\x05" + leb128(column) + b"\x01"
+        address, line = row_address, row_line
+    program += b"\x02" + leb128(end - address) + b"\x00\x01\x01"
+    unit = struct.pack("<HI", 4, len(header)) + header + program
+    return struct.pack("<I", len(unit)) + unit
+
+
+def debug_sections(rows, end):
+    # A compile unit with a DW_AT_stmt_list.
+    abbrev = b"\x01\x11\x00\x10\x17\x00\x00\x00"
+    info = struct.pack("<HIB", 4, 0, 4) + b"\x01" + struct.pack("<I", 0)
+    info = struct.pack("<I", len(info)) + info
+    return [
+        section(0, name(".debug_abbrev") + abbrev),
+        section(0, name(".debug_info") + info),
+        section(0, name(".debug_line") + line_program(rows, end)),
+    ]
+
+
+@unittest.skipUnless(sys.platform.startswith("linux")
+                     and platform.machine() == "x86_64", "x86-64 Linux only")
+class TestBacktrace(unittest.TestCase):
+    def trap(self, **kwargs):
+        run = wasmtime.instantiate(WASM, IMPORTS, **kwargs).instance.exports["run"]
+        with self.assertRaises(wasmtime.Trap) as cm:
+            run()
+        return cm.exception
+
+    def check_frames(self, trap):
+        inner, outer = trap.frames
+        self.assertEqual((inner.module_name, inner.func_index, inner.func_name),
+                         ("demo", 1, "inner"))
+        self.assertEqual((outer.module_name, outer.func_index, outer.func_name),
+                         ("demo", 2, "outer"))
+        # Offsets of `unreachable` and `call` in the module as given.
+        self.assertEqual(WASM[inner.module_offset], 0x00)
+        self.assertEqual(WASM[outer.module_offset], 0x10)
+
+    def test_frames(self):
+        self.check_frames(self.trap())
+
+    def test_frames_instrumented(self):
+        # The interrupt checks shift the code of the compiled module.
+        self.check_frames(self.trap(handle_sigint=True))
+
+    def test_frames_lazy(self):
+        self.check_frames(self.trap(lazy=True))
+
+    def test_str(self):
+        lines = str(self.trap()).splitlines()
+        self.assertEqual(lines[1], "wasm backtrace:")
+        self.assertTrue(lines[2].endswith(" - demo!inner"))
+        self.assertTrue(lines[3].endswith(" - demo!outer"))
+
+    def test_source_lines(self):
+        # `unreachable` and `call` are at 3 and 7 in the code section.
+        wasm = WASM + b"".join(debug_sections([(3, 10, 5), (7, 20, 9)], 10))
+        run = wasmtime.instantiate(wasm, IMPORTS).instance.exports["run"]
+        with self.assertRaises(wasmtime.Trap) as cm:
+            run()
+        inner, outer = cm.exception.frames
+        self.assertEqual((inner.file, inner.line, inner.column), ("src/lib.rs", 10, 5))
+        self.assertEqual((outer.file, outer.line, outer.column), ("src/lib.rs", 20, 9))
+        self.assertIn("demo!inner at src/lib.rs:10:5", str(cm.exception))
+
+    def test_no_source_lines(self):
+        inner, outer = self.trap().frames
+        self.assertEqual((inner.file, inner.line, inner.column), (None, None, None))
+
+    def test_no_names(self):
+        plain = module([
+            section(1, vec([b"\x60" + vec([]) + vec([])])),
+            section(3, vec([b"\x00"])),
+            section(7, vec([name("run") + b"\x00\x00"])),
+            section(10, vec([body(b"\x00")])),
+        ])
+        run = wasmtime.instantiate(plain, {}).instance.exports["run"]
+        with self.assertRaises(wasmtime.Trap) as cm:
+            run()
+        frame, = cm.exception.frames
+        self.assertEqual((frame.module_name, frame.func_index, frame.func_name),
+                         (None, 0, None))
+        self.assertIn("<module>!<wasm function 0>", str(frame))
+
+
+
+@unittest.skipUnless(sys.platform.startswith("linux")
+                     and platform.machine() == "x86_64", "x86-64 Linux only")
+class TestTraceback(unittest.TestCase):
+    def host_error(self, wasm):
+        def log():
+            raise KeyError("from the host")
+
+        run = wasmtime.instantiate(wasm, {"env": {"log": log}}).instance.exports["run"]
+        with self.assertRaises(KeyError) as cm:
+            run()
+        return traceback.extract_tb(cm.exception.__traceback__)
+
+    def test_wasm_frames(self):
+        entries = self.host_error(CALLING_WASM)
+        self.assertEqual([e.name for e in entries[-3:]], ["outer", "inner", "log"])
+        outer, inner = entries[-3:-1]
+        self.assertEqual(outer.filename, "<wasm demo @ 0x33>")
+        self.assertEqual(inner.filename, "<wasm demo @ 0x2e>")
+
+    def test_source_lines(self):
+        # The calls are at 3 and 8 in the code section.
+        wasm = CALLING_WASM + b"".join(debug_sections([(3, 10, 5), (8, 20, 9)], 11))
+        outer, inner = self.host_error(wasm)[-3:-1]
+        self.assertEqual((outer.filename, outer.lineno), ("src/lib.rs", 20))
+        self.assertEqual((inner.filename, inner.lineno), ("src/lib.rs", 10))
+
+    def test_trap(self):
+        run = wasmtime.instantiate(WASM, IMPORTS).instance.exports["run"]
+        with self.assertRaises(wasmtime.Trap) as cm:
+            run()
+        entries = traceback.extract_tb(cm.exception.__traceback__)
+        self.assertEqual([e.name for e in entries[-2:]], ["outer", "inner"])
+        self.assertEqual(len(cm.exception.frames), 2)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,119 @@
+import os
+import tempfile
+import unittest
+
+import wasmtime
+from wasm_binary import I32, body, module, name, section, vec
+
+
+def add_module(constant):
+    # (module (func (export "add") (param i32) (result i32)
+    #   local.get 0 i32.const <constant> i32.add))
+    return module([
+        section(1, vec([b"\x60" + vec([I32]) + vec([I32])])),
+        section(3, vec([b"\x00"])),
+        section(7, vec([name("add") + b"\x00\x00"])),
+        section(10, vec([body(b"\x20\x00\x41" + bytes([constant]) + b"\x6a")])),
+    ])
+
+
+class TestCache(unittest.TestCase):
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.directory = self.tmp.name
+        wasmtime.enable_cache(self.directory)
+
+    def tearDown(self):
+        wasmtime.disable_cache()
+        self.tmp.cleanup()
+
+    def entries(self):
+        return sorted(f for f in os.listdir(self.directory) if f.endswith(".bin"))
+
+    def add(self, wasm, x):
+        return wasmtime.instantiate(wasm, {}).instance.exports["add"](x)
+
+    def test_reuses_compiled_module(self):
+        wasm = add_module(1)
+        self.assertEqual(self.add(wasm, 1), 2)
+        entries = self.entries()
+        self.assertEqual(len(entries), 1)
+        self.assertEqual(self.add(wasm, 2), 3)
+        self.assertEqual(self.entries(), entries)
+
+    def test_keyed_by_content(self):
+        self.assertEqual(self.add(add_module(1), 1), 2)
+        self.assertEqual(self.add(add_module(2), 1), 3)
+        self.assertEqual(len(self.entries()), 2)
+
+    def test_keyed_by_settings(self):
+        wasm = add_module(1)
+        self.add(wasm, 0)
+        # The instrumentation for Ctrl-C changes the compiled code.
+        wasmtime.instantiate(wasm, {}, handle_sigint=True)
+        self.assertEqual(len(self.entries()), 2)
+
+    def test_corrupt_entry_is_replaced(self):
+        wasm = add_module(1)
+        self.add(wasm, 0)
+        (entry,) = self.entries()
+        path = os.path.join(self.directory, entry)
+        with open(path, "wb") as f:
+            f.write(b"garbage")
+        self.assertEqual(self.add(wasm, 1), 2)
+        self.assertEqual(self.entries(), [entry])
+        self.assertGreater(os.path.getsize(path), len(b"garbage"))
+
+    def test_size_cap_evicts_least_recently_used(self):
+        self.add(add_module(1), 0)
+        (first,) = self.entries()
+        size = os.path.getsize(os.path.join(self.directory, first))
+        old = os.path.getmtime(os.path.join(self.directory, first)) - 60
+        os.utime(os.path.join(self.directory, first), (old, old))
+
+        wasmtime.enable_cache(self.directory, max_size=size * 3 // 2)
+        self.add(add_module(2), 0)
+        entries = self.entries()
+        self.assertEqual(len(entries), 1)
+        self.assertNotEqual(entries, [first])
+
+    def test_hit_refreshes_entry(self):
+        wasm = add_module(1)
+        self.add(wasm, 0)
+        (entry,) = self.entries()
+        path = os.path.join(self.directory, entry)
+        os.utime(path, (0, 0))
+        self.add(wasm, 0)
+        self.assertGreater(os.path.getmtime(path), 0)
+
+    def test_clear_and_disable(self):
+        self.add(add_module(1), 0)
+        wasmtime.clear_cache()
+        self.assertEqual(self.entries(), [])
+        wasmtime.disable_cache()
+        self.assertEqual(self.add(add_module(1), 1), 2)
+        self.assertEqual(self.entries(), [])
+
+    def test_directory_created_private(self):
+        directory = os.path.join(self.directory, "sub", "cache")
+        wasmtime.enable_cache(directory)
+        self.assertEqual(os.stat(directory).st_mode & 0o777, 0o700)
+
+    def test_shared_directory_refused(self):
+        directory = os.path.join(self.directory, "shared")
+        os.mkdir(directory)
+        os.chmod(directory, 0o777)
+        with self.assertRaises(PermissionError):
+            wasmtime.enable_cache(directory)
+
+    def test_no_default_directory(self):
+        environ = dict(os.environ)
+        self.addCleanup(os.environ.update, environ)
+        os.environ.pop("XDG_CACHE_HOME", None)
+        os.environ.pop("HOME", None)
+        with self.assertRaises(OSError):
+            wasmtime.enable_cache()
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,66 @@
+import threading
+import unittest
+
+import wasmtime
+from wasm_binary import I32, body, leb128, module, name, section, sleb128, vec
+
+FUNCTIONS = 200
+
+
+def many_functions_module():
+    # (module (func (export "f<i>") (param i32) (result i32)
+    #   local.get 0 i32.const <i> i32.add) ...)
+    return module([
+        section(1, vec([b"\x60" + vec([I32]) + vec([I32])])),
+        section(3, vec([b"\x00"] * FUNCTIONS)),
+        section(7, vec([name("f%d" % i) + b"\x00" + leb128(i)
+                        for i in range(FUNCTIONS)])),
+        section(10, vec([body(b"\x20\x00\x41" + sleb128(i) + b"\x6a")
+                         for i in range(FUNCTIONS)])),
+    ])
+
+
+class TestCompiler(unittest.TestCase):
+    def tearDown(self):
+        wasmtime.configure_compiler()
+
+    def check(self, wasm):
+        exports = wasmtime.instantiate(wasm, {}).instance.exports
+        for i in range(FUNCTIONS):
+            self.assertEqual(exports["f%d" % i](1), i + 1)
+
+    def test_threads(self):
+        wasmtime.configure_compiler(threads=3)
+        self.assertEqual(wasmtime.compiler_threads(), 3)
+        self.check(many_functions_module())
+
+    def test_single_thread(self):
+        wasmtime.configure_compiler(threads=1)
+        self.assertEqual(wasmtime.compiler_threads(), 1)
+        self.check(many_functions_module())
+
+    def test_default_threads(self):
+        wasmtime.configure_compiler()
+        self.assertGreaterEqual(wasmtime.compiler_threads(), 1)
+
+    def test_concurrent_compilations(self):
+        wasmtime.configure_compiler(threads=2)
+        wasm = many_functions_module()
+        errors = []
+
+        def run():
+            try:
+                self.check(wasm)
+            except BaseException as e:
+                errors.append(e)
+
+        threads = [threading.Thread(target=run) for _ in range(4)]
+        for t in threads:
+            t.start()
+        for t in threads:
+            t.join()
+        self.assertEqual(errors, [])
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,202 @@
+import os
+import platform
+import sys
+import tempfile
+import unittest
+
+import wasmtime
+from test_interrupt import WASM as SPIN
+from wasm_binary import I32, body, leb128, module, name, section, sleb128, vec
+
+
+# $inner stores its param in $g and traps with `unreachable`.
+INNER = vec([leb128(1) + b"\x7c"]) + b"\x20\x00\x24\x00\x00\x0b"
+
+# (module $dump
+#   (memory 1)
+#   (global $g (mut i32) (i32.const 0))
+#   (global i64 (i64.const -5))
+#   (func $inner (param i32) (local f64) local.get 0 global.set $g unreachable)
+#   (func $outer (export "run") (param i32) local.get 0 call $inner)
+#   (data (i32.const 16) "hello"))
+WASM = module([
+    section(1, vec([b"\x60" + vec([I32]) + vec([])])),
+    section(3, vec([b"\x00", b"\x00"])),
+    section(5, vec([b"\x00\x01"])),
+    section(6, vec([b"\x7f\x01\x41\x00\x0b", b"\x7e\x00\x42" + sleb128(-5) + b"\x0b"])),
+    section(7, vec([name("run") + b"\x00\x01"])),
+    section(10, vec([leb128(len(INNER)) + INNER, body(b"\x20\x00\x10\x00")])),
+    section(11, vec([b"\x00\x41\x10\x0b" + vec([b"hello"])])),
+    section(0, name("name") + b"\x00" + leb128(len(name("dump"))) + name("dump")),
+])
+
+# $run keeps its local across the call of $trap, so it is spilled.
+RUN = vec([leb128(1) + I32]) + b"\x20\x00\x41\x05\x6a\x21\x01\x10\x00\x20\x01\x0b"
+
+# (module
+#   (func $trap unreachable)
+#   (func (export "run") (param i32) (result i32) (local i32)
+#     local.get 0 i32.const 5 i32.add local.set 1
+#     call $trap
+#     local.get 1))
+SPILLED = module([
+    section(1, vec([b"\x60" + vec([]) + vec([]), b"\x60" + vec([I32]) + vec([I32])])),
+    section(3, vec([b"\x00", b"\x01"])),
+    section(7, vec([name("run") + b"\x00\x01"])),
+    section(10, vec([body(b"\x00"), leb128(len(RUN)) + RUN])),
+])
+
+
+def read_leb128(data, pos):
+    result = shift = 0
+    while True:
+        byte = data[pos]
+        pos += 1
+        result |= (byte & 0x7f) << shift
+        shift += 7
+        if not byte & 0x80:
+            return result, pos
+
+
+def read_name(data, pos):
+    size, pos = read_leb128(data, pos)
+    return data[pos:pos + size].decode(), pos + size
+
+
+def sections(data):
+    """(id, payload) of the sections of a wasm binary; custom sections are
+    (name, contents)."""
+    assert data[:8] == b"\0asm\x01\0\0\0"
+    pos, result = 8, []
+    while pos < len(data):
+        id = data[pos]
+        size, pos = read_leb128(data, pos + 1)
+        payload = data[pos:pos + size]
+        pos += size
+        if id == 0:
+            custom, start = read_name(payload, 0)
+            result.append((custom, payload[start:]))
+        else:
+            result.append((id, payload))
+    return result
+
+
+def read_value(data, pos):
+    """A value of a "corestack" section, as encoded."""
+    size = {0x01: 0, 0x7d: 4, 0x7c: 8}.get(data[pos])
+    if size is None:
+        _, end = read_leb128(data, pos + 1)
+    else:
+        end = pos + 1 + size
+    return data[pos:end], end
+
+
+def stack_frames(corestack):
+    """(instance, function, code offset, locals) of the frames of a
+    "corestack" section, the locals being their encoded values."""
+    thread, pos = read_name(corestack, 1)
+    count, pos = read_leb128(corestack, pos)
+    frames = []
+    for _ in range(count):
+        instance, pos = read_leb128(corestack, pos + 1)
+        function, pos = read_leb128(corestack, pos)
+        offset, pos = read_leb128(corestack, pos)
+        count_locals, pos = read_leb128(corestack, pos)
+        locals = b""
+        for _ in range(count_locals):
+            value, pos = read_value(corestack, pos)
+            locals += value
+        frames.append((instance, function, offset, locals))
+        stack, pos = read_leb128(corestack, pos)
+        assert stack == 0
+    return thread, frames
+
+
+class TestCoredump(unittest.TestCase):
+    def setUp(self):
+        self.dir = tempfile.TemporaryDirectory()
+        self.addCleanup(self.dir.cleanup)
+
+    def dump(self, wasm=WASM):
+        store = wasmtime.Store(coredump_dir=self.dir.name)
+        run = wasmtime.instantiate(wasm, {}, store=store).instance.exports["run"]
+        with self.assertRaises(wasmtime.Trap) as cm:
+            run(7)
+        path = cm.exception.coredump
+        self.assertEqual(os.path.dirname(path), self.dir.name)
+        with open(path, "rb") as f:
+            return dict(sections(f.read()))
+
+    def test_memory(self):
+        dump = self.dump()
+        self.assertEqual(dump[5], vec([b"\x00\x01"]))
+        # The first block of the memory, the others being zeros.
+        data = dump[11]
+        self.assertEqual(data[:5], b"\x01\x00\x41\x00\x0b")
+        size, pos = read_leb128(data, 5)
+        self.assertEqual(size, 4096)
+        self.assertEqual(data[pos + 16:pos + 21], b"hello")
+
+    def test_globals(self):
+        dump = self.dump()
+        self.assertEqual(dump[6], vec([b"\x7f\x01\x41\x07\x0b",
+                                       b"\x7e\x00\x42" + sleb128(-5) + b"\x0b"]))
+
+    def test_process(self):
+        dump = self.dump()
+        self.assertEqual(dump["core"], b"\x00" + name("dump"))
+        self.assertEqual(dump["coremodules"], vec([b"\x00" + name("dump")]))
+        self.assertEqual(dump["coreinstances"],
+                         vec([b"\x00\x00" + vec([b"\x00"]) + vec([b"\x00", b"\x01"])]))
+
+    @unittest.skipUnless(sys.platform.startswith("linux")
+                         and platform.machine() == "x86_64", "x86-64 Linux only")
+    def test_stack(self):
+        thread, frames = stack_frames(self.dump()["corestack"])
+        self.assertEqual(thread, "MainThread")
+        # `unreachable` after the locals of $inner, `call` in $outer; the
+        # param and local are not in stack slots there, so their values are
+        # missing.
+        self.assertEqual(frames, [(0, 0, len(INNER) - 2, b"\x01\x01"),
+                                  (0, 1, 3, b"\x01")])
+
+    @unittest.skipUnless(sys.platform.startswith("linux")
+                         and platform.machine() == "x86_64", "x86-64 Linux only")
+    def test_spilled_local(self):
+        _, frames = stack_frames(self.dump(SPILLED)["corestack"])
+        # The param of $run is dead by the call, its local holds 7 + 5.
+        self.assertEqual(frames[1][3], b"\x01\x7f" + sleb128(12))
+
+    def test_instrumented(self):
+        store = wasmtime.Store(coredump_dir=self.dir.name)
+        res = wasmtime.instantiate(WASM, {}, store=store, handle_sigint=True)
+        with self.assertRaises(wasmtime.Trap) as cm:
+            res.instance.exports["run"](7)
+        with open(cm.exception.coredump, "rb") as f:
+            dump = dict(sections(f.read()))
+        # The globals of the instrumentation are left out.
+        self.assertEqual(dump[6][:6], b"\x02\x7f\x01\x41\x07\x0b")
+
+    def test_disabled(self):
+        run = wasmtime.instantiate(WASM, {}).instance.exports["run"]
+        with self.assertRaises(wasmtime.Trap) as cm:
+            run(7)
+        self.assertFalse(hasattr(cm.exception, "coredump"))
+
+    def test_timeout(self):
+        store = wasmtime.Store(coredump_dir=self.dir.name)
+        spin = wasmtime.instantiate(SPIN, {}, store=store).instance.exports["spin"]
+        with self.assertRaises(wasmtime.Timeout) as cm:
+            spin(timeout=0.1)
+        self.assertFalse(hasattr(cm.exception, "coredump"))
+        self.assertEqual(os.listdir(self.dir.name), [])
+
+    def test_store_property(self):
+        store = wasmtime.Store()
+        self.assertIsNone(store.coredump_dir)
+        store.coredump_dir = self.dir.name
+        self.assertEqual(store.coredump_dir, self.dir.name)
+
+
+if __name__ == "__main__":
+    unittest.main()
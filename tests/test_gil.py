@@ -0,0 +1,58 @@
+import threading
+import time
+import unittest
+
+import wasmtime
+from test_interrupt import WASM
+
+
+class TestGil(unittest.TestCase):
+    def test_python_runs_during_wasm_call(self):
+        spin = wasmtime.instantiate(WASM, {}).instance.exports["spin"]
+        errors = []
+        started = threading.Event()
+
+        def run():
+            started.set()
+            try:
+                spin(timeout=2)
+            except wasmtime.Timeout:
+                pass
+            except BaseException as e:
+                errors.append(e)
+
+        thread = threading.Thread(target=run)
+        thread.start()
+        started.wait()
+        time.sleep(0.2)
+        # With the GIL held by the wasm call, this thread would only run
+        # again once the call timed out.
+        count = 0
+        start = time.monotonic()
+        while time.monotonic() - start < 0.5:
+            count += 1
+        self.assertTrue(thread.is_alive())
+        self.assertGreater(count, 0)
+        thread.join()
+        self.assertEqual(errors, [])
+
+    def test_calls_in_separate_stores_overlap(self):
+        spins = [wasmtime.instantiate(WASM, {}).instance.exports["spin"] for _ in range(2)]
+        start = time.monotonic()
+
+        def run(spin):
+            try:
+                spin(timeout=0.5)
+            except wasmtime.Timeout:
+                pass
+
+        threads = [threading.Thread(target=run, args=(spin,)) for spin in spins]
+        for thread in threads:
+            thread.start()
+        for thread in threads:
+            thread.join()
+        self.assertLess(time.monotonic() - start, 0.9)
+
+
+if __name__ == "__main__":
+    unittest.main()
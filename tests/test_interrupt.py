@@ -0,0 +1,59 @@
+import os
+import signal
+import sys
+import threading
+import unittest
+
+import wasmtime
+from wasm_binary import body, module, name, section, vec
+
+
+# (module
+#   (func (export "spin") loop br 0 end)
+#   (func (export "nop")))
+WASM = module([
+    section(1, vec([b"\x60" + vec([]) + vec([])])),
+    section(3, vec([b"\x00", b"\x00"])),
+    section(7, vec([name("spin") + b"\x00\x00", name("nop") + b"\x00\x01"])),
+    section(10, vec([body(b"\x03\x40\x0c\x00\x0b"), body(b"")])),
+])
+
+
+def later(f):
+    timer = threading.Timer(0.1, f)
+    timer.start()
+    return timer
+
+
+class TestInterrupt(unittest.TestCase):
+    def test_interrupt_from_thread(self):
+        instance = wasmtime.instantiate(WASM, {}).instance
+        handle = instance.interrupt_handle()
+        timer = later(handle.interrupt)
+        with self.assertRaises(wasmtime.Interrupted):
+            instance.exports["spin"]()
+        timer.join()
+        # The request is consumed by the call it stopped.
+        instance.exports["nop"]()
+
+    def test_interrupt_before_call(self):
+        instance = wasmtime.instantiate(WASM, {}).instance
+        instance.interrupt_handle().interrupt()
+        with self.assertRaises(wasmtime.Interrupted):
+            instance.exports["spin"]()
+
+    def test_interrupted_is_trap(self):
+        self.assertTrue(issubclass(wasmtime.Interrupted, wasmtime.Trap))
+
+    @unittest.skipIf(sys.platform == "win32", "POSIX signals only")
+    def test_sigint(self):
+        instance = wasmtime.instantiate(WASM, {}, handle_sigint=True).instance
+        timer = later(lambda: os.kill(os.getpid(), signal.SIGINT))
+        with self.assertRaises(KeyboardInterrupt):
+            instance.exports["spin"]()
+        timer.join()
+        instance.exports["nop"]()
+
+
+if __name__ == "__main__":
+    unittest.main()
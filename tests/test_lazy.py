@@ -0,0 +1,125 @@
+import platform
+import unittest
+
+import wasmtime
+from wasm_binary import I32, body, module, name, section, vec
+
+
+# (module
+#   (func $fac (export "fac") (param i32) (result i32)
+#     local.get 0 i32.eqz
+#     if (result i32) i32.const 1
+#     else local.get 0 local.get 0 i32.const 1 i32.sub call $fac i32.mul end)
+#   (func $double (export "double") (param i32) (result i32)
+#     local.get 0 i32.const 2 i32.mul)
+#   (func (export "double_fac") (param i32) (result i32)
+#     local.get 0 call $fac call $double)
+#   (func (export "unused") (result i32) i32.const 0))
+WASM = module([
+    section(1, vec([
+        b"\x60" + vec([I32]) + vec([I32]),
+        b"\x60" + vec([]) + vec([I32]),
+    ])),
+    section(3, vec([b"\x00", b"\x00", b"\x00", b"\x01"])),
+    section(7, vec([
+        name("fac") + b"\x00\x00",
+        name("double") + b"\x00\x01",
+        name("double_fac") + b"\x00\x02",
+        name("unused") + b"\x00\x03",
+    ])),
+    section(10, vec([
+        body(b"\x20\x00\x45\x04\x7f\x41\x01\x05"
+             b"\x20\x00\x20\x00\x41\x01\x6b\x10\x00\x6c\x0b"),
+        body(b"\x20\x00\x41\x02\x6c"),
+        body(b"\x20\x00\x10\x00\x10\x01"),
+        body(b"\x41\x00"),
+    ])),
+])
+
+
+@unittest.skipUnless(platform.machine() in ("x86_64", "AMD64"), "x86-64 only")
+class TestLazy(unittest.TestCase):
+    def test_call(self):
+        exports = wasmtime.instantiate(WASM, {}, lazy=True).instance.exports
+        self.assertEqual(exports["double"](21), 42)
+        self.assertEqual(exports["double"](5), 10)
+
+    def test_recursion(self):
+        exports = wasmtime.instantiate(WASM, {}, lazy=True).instance.exports
+        self.assertEqual(exports["fac"](5), 120)
+
+    def test_calls_between_functions(self):
+        exports = wasmtime.instantiate(WASM, {}, lazy=True).instance.exports
+        self.assertEqual(exports["double_fac"](4), 48)
+        self.assertEqual(exports["fac"](3), 6)
+
+    def test_instances_are_independent(self):
+        first = wasmtime.instantiate(WASM, {}, lazy=True).instance.exports
+        second = wasmtime.instantiate(WASM, {}, lazy=True).instance.exports
+        self.assertEqual(first["fac"](4), 24)
+        self.assertEqual(second["double_fac"](3), 12)
+
+    def test_module(self):
+        res = wasmtime.instantiate(WASM, {}, lazy=True)
+        compiled = res.module
+        self.assertIs(res.module, compiled)
+        exports = wasmtime.instantiate(compiled, {}).instance.exports
+        self.assertEqual(exports["unused"](), 0)
+
+    def test_many_arguments(self):
+        # (module (func (export "sum") (param i32 i32 i32 i32 i32 i32 i32 i32)
+        #   (result i32) local.get 0 local.get 1 i32.add ... local.get 7 i32.add))
+        code = b"\x20\x00" + b"".join(b"\x20" + bytes([i]) + b"\x6a" for i in range(1, 8))
+        wasm = module([
+            section(1, vec([b"\x60" + vec([I32] * 8) + vec([I32])])),
+            section(3, vec([b"\x00"])),
+            section(7, vec([name("sum") + b"\x00\x00"])),
+            section(10, vec([body(code)])),
+        ])
+        exports = wasmtime.instantiate(wasm, {}, lazy=True).instance.exports
+        self.assertEqual(exports["sum"](1, 2, 3, 4, 5, 6, 7, 8), 36)
+
+    def test_vmctx_accesses(self):
+        # (module
+        #   (type $t (func (param i32 i32) (result i32)))
+        #   (type $r (func (result i32)))
+        #   (import "env" "add" (func $add (type $t)))
+        #   (table 1 anyfunc) (elem (i32.const 0) $two)
+        #   (memory 1)
+        #   (global $g (mut i32) (i32.const 40))
+        #   (func $two (type $r) i32.const 2)
+        #   (func (export "run") (type $r)
+        #     i32.const 1 memory.grow drop
+        #     i32.const 0 memory.size i32.store
+        #     global.get $g i32.const 0 call_indirect (type $r) call $add
+        #     i32.const 0 i32.load i32.add))
+        wasm = module([
+            section(1, vec([b"\x60" + vec([I32, I32]) + vec([I32]),
+                            b"\x60" + vec([]) + vec([I32])])),
+            section(2, vec([name("env") + name("add") + b"\x00\x00"])),
+            section(3, vec([b"\x01", b"\x01"])),
+            section(4, vec([b"\x70\x00\x01"])),
+            section(5, vec([b"\x00\x01"])),
+            section(6, vec([b"\x7f\x01\x41\x28\x0b"])),
+            section(7, vec([name("run") + b"\x00\x02"])),
+            section(9, vec([b"\x00\x41\x00\x0b" + vec([b"\x01"])])),
+            section(10, vec([
+                body(b"\x41\x02"),
+                body(b"\x41\x01\x40\x00\x1a"
+                     b"\x41\x00\x3f\x00\x36\x02\x00"
+                     b"\x23\x00\x41\x00\x11\x01\x00\x10\x00"
+                     b"\x41\x00\x28\x02\x00\x6a"),
+            ])),
+        ])
+
+        def add(a: "i32", b: "i32") -> "i32":
+            return a + b
+
+        for lazy in (False, True):
+            with self.subTest(lazy=lazy):
+                res = wasmtime.instantiate(wasm, {"env": {"add": add}}, lazy=lazy)
+                self.assertEqual(res.instance.exports["run"](), 44)
+
+
+if __name__ == "__main__":
+    unittest.main()
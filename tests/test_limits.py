@@ -0,0 +1,161 @@
+import gc
+import threading
+import unittest
+
+import wasmtime
+from wasm_binary import I32, body, module, name, section, vec
+
+PAGE = 65536
+
+
+def grow_module(pages=1, table=None):
+    """(module
+      (memory (export "memory") <pages>)
+      (table <table> funcref)
+      (func (export "grow") (param i32) (result i32) local.get 0 memory.grow))"""
+    sections = [
+        section(1, vec([b"\x60" + vec([I32]) + vec([I32])])),
+        section(3, vec([b"\x00"])),
+    ]
+    if table is not None:
+        sections.append(section(4, vec([b"\x70\x00" + bytes([table])])))
+    sections += [
+        section(5, vec([b"\x00" + bytes([pages])])),
+        section(7, vec([name("grow") + b"\x00\x00", name("memory") + b"\x02\x00"])),
+        section(10, vec([body(b"\x20\x00\x40\x00")])),
+    ]
+    return module(sections)
+
+
+WASM = grow_module()
+
+
+def import_grow_module(pages=1):
+    """(module
+      (import "env" "memory" (memory <pages>))
+      (func (export "grow") (param i32) (result i32) local.get 0 memory.grow))"""
+    return module([
+        section(1, vec([b"\x60" + vec([I32]) + vec([I32])])),
+        section(2, vec([name("env") + name("memory") + b"\x02\x00" + bytes([pages])])),
+        section(3, vec([b"\x00"])),
+        section(7, vec([name("grow") + b"\x00\x00"])),
+        section(10, vec([body(b"\x20\x00\x40\x00")])),
+    ])
+
+# (module (import "env" "f" (func)))
+IMPORTING = module([
+    section(1, vec([b"\x60" + vec([]) + vec([])])),
+    section(2, vec([name("env") + name("f") + b"\x00\x00"])),
+])
+
+
+class TestLimits(unittest.TestCase):
+    def instantiate(self, wasm=WASM, **limits):
+        store = wasmtime.Store(limiter=wasmtime.ResourceLimiter(**limits))
+        return wasmtime.instantiate(wasm, {}, store=store).instance.exports
+
+    def test_memory_size(self):
+        exports = self.instantiate(memory_size=2 * PAGE)
+        self.assertEqual(exports["grow"](1), 1)
+        self.assertEqual(exports["grow"](1), -1)
+        self.assertEqual(exports["memory"].current, 2)
+        exports["memory"].grow(1)
+        self.assertEqual(exports["memory"].current, 2)
+
+    def test_memory_minimum(self):
+        with self.assertRaises(wasmtime.ResourceLimitExceeded):
+            self.instantiate(grow_module(pages=3), memory_size=2 * PAGE)
+
+    def test_imported_memory(self):
+        memory = wasmtime.Memory(1)
+        store = wasmtime.Store(limiter=wasmtime.ResourceLimiter(memory_size=2 * PAGE))
+        res = wasmtime.instantiate(import_grow_module(), {"env": {"memory": memory}}, store=store)
+        exports = res.instance.exports
+        self.assertEqual(exports["grow"](1), 1)
+        self.assertEqual(exports["grow"](1), -1)
+        self.assertEqual(memory.current, 2)
+
+    def test_imported_memory_minimum(self):
+        store = wasmtime.Store(limiter=wasmtime.ResourceLimiter(memory_size=2 * PAGE))
+        with self.assertRaises(wasmtime.ResourceLimitExceeded):
+            wasmtime.instantiate(
+                import_grow_module(pages=3), {"env": {"memory": wasmtime.Memory(3)}}, store=store
+            )
+
+    def test_table_elements(self):
+        self.instantiate(grow_module(table=2), table_elements=2)
+        with self.assertRaises(wasmtime.ResourceLimitExceeded):
+            self.instantiate(grow_module(table=2), table_elements=1)
+
+    def test_instances(self):
+        store = wasmtime.Store(limiter=wasmtime.ResourceLimiter(instances=1))
+        wasmtime.instantiate(WASM, {}, store=store)
+        with self.assertRaises(wasmtime.ResourceLimitExceeded):
+            wasmtime.instantiate(WASM, {}, store=store)
+
+    def test_dropped_instances(self):
+        store = wasmtime.Store(limiter=wasmtime.ResourceLimiter(instances=1))
+        res = wasmtime.instantiate(WASM, {}, store=store)
+        del res
+        gc.collect()
+        wasmtime.instantiate(WASM, {}, store=store)
+
+    def test_failed_instantiation(self):
+        store = wasmtime.Store(limiter=wasmtime.ResourceLimiter(instances=1))
+        with self.assertRaises(ValueError):
+            wasmtime.instantiate(IMPORTING, {}, store=store)
+        wasmtime.instantiate(WASM, {}, store=store)
+
+    def test_concurrent_instantiations(self):
+        store = wasmtime.Store(limiter=wasmtime.ResourceLimiter(instances=1))
+        results, errors = [], []
+
+        def run():
+            try:
+                results.append(wasmtime.instantiate(WASM, {}, store=store))
+            except wasmtime.ResourceLimitExceeded as e:
+                errors.append(e)
+
+        threads = [threading.Thread(target=run) for _ in range(8)]
+        for thread in threads:
+            thread.start()
+        for thread in threads:
+            thread.join()
+        self.assertEqual((len(results), len(errors)), (1, 7))
+
+    def test_link_error(self):
+        with self.assertRaisesRegex(ValueError, "unknown import"):
+            wasmtime.instantiate(IMPORTING, {})
+        with self.assertRaises(TypeError):
+            wasmtime.instantiate(IMPORTING, {"env": 1})
+
+    def test_invalid_imported_modules(self):
+        with self.assertRaises(ValueError):
+            wasmtime.imported_modules(b"\x00asm\x01\x00\x00\x00\x02\x05")
+
+    def test_on_grow(self):
+        calls = []
+
+        def on_grow(current, desired):
+            calls.append((current, desired))
+            return desired <= 2 * PAGE
+
+        exports = self.instantiate(on_grow=on_grow)
+        self.assertEqual(exports["grow"](1), 1)
+        self.assertEqual(exports["grow"](1), -1)
+        exports["memory"].grow(1)
+        self.assertEqual(exports["memory"].current, 2)
+        self.assertEqual(calls, [(PAGE, 2 * PAGE), (2 * PAGE, 3 * PAGE), (2 * PAGE, 3 * PAGE)])
+
+    def test_on_grow_error(self):
+        def on_grow(current, desired):
+            raise KeyError("no growth")
+
+        exports = self.instantiate(on_grow=on_grow)
+        with self.assertRaises(KeyError):
+            exports["grow"](1)
+        self.assertEqual(exports["memory"].current, 1)
+
+
+if __name__ == "__main__":
+    unittest.main()
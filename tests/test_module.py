@@ -0,0 +1,159 @@
+import sys
+import unittest
+
+import wasmtime
+from wasm_binary import I32, body, leb128, module, name, section, vec
+
+
+# (module (func (export "add") (param i32 i32) (result i32)
+#   local.get 0 local.get 1 i32.add))
+WASM = module([
+    section(1, vec([b"\x60" + vec([I32, I32]) + vec([I32])])),
+    section(3, vec([b"\x00"])),
+    section(7, vec([name("add") + b"\x00\x00"])),
+    section(10, vec([body(b"\x20\x00\x20\x01\x6a")])),
+])
+
+
+def add(source, **kwargs):
+    res = wasmtime.instantiate(source, {}, **kwargs)
+    return res.instance.exports["add"]
+
+
+class TestModule(unittest.TestCase):
+    def test_instantiate_module(self):
+        compiled = wasmtime.Module(WASM)
+        self.assertEqual(add(compiled)(1, 2), 3)
+        self.assertEqual(add(compiled)(3, 4), 7)
+
+    def test_instantiate_with_other_settings(self):
+        compiled = wasmtime.Module(WASM)
+        self.assertEqual(add(compiled, handle_sigint=True)(1, 2), 3)
+        store = wasmtime.Store(limiter=wasmtime.ResourceLimiter(memory_size=65536))
+        self.assertEqual(add(compiled, store=store)(1, 2), 3)
+
+    def test_result_module(self):
+        res = wasmtime.instantiate(WASM, {})
+        data = res.module.serialize()
+        self.assertEqual(add(wasmtime.Module.deserialize(data))(2, 2), 4)
+
+    def test_round_trip(self):
+        data = wasmtime.Module(WASM).serialize()
+        self.assertIsInstance(data, bytes)
+        compiled = wasmtime.Module.deserialize(data)
+        self.assertEqual(add(compiled)(5, 6), 11)
+        self.assertEqual(compiled.serialize(), data)
+
+    def test_not_serialized_module(self):
+        with self.assertRaisesRegex(ValueError, "not a serialized module"):
+            wasmtime.Module.deserialize(WASM)
+        data = wasmtime.Module(WASM).serialize()
+        with self.assertRaisesRegex(ValueError, "not a serialized module"):
+            wasmtime.Module.deserialize(data[:len(data) // 2])
+
+    def test_incompatible_module(self):
+        data = wasmtime.Module(WASM).serialize()
+        data = data.replace(b"cranelift 0.", b"cranelift 9.", 1)
+        with self.assertRaisesRegex(ValueError, "incompatible serialized module: "
+                                    "produced by cranelift 9"):
+            wasmtime.Module.deserialize(data)
+
+    def test_disassemble(self):
+        text = wasmtime.Module(WASM).disassemble("add")
+        self.assertTrue(text)
+        self.assertIn("ret", text.split())
+
+    def test_cranelift_ir(self):
+        before, after = wasmtime.Module(WASM).cranelift_ir("add")
+        self.assertIn("iadd", before)
+        self.assertIn("return", before)
+        self.assertIn("iadd", after)
+        self.assertNotEqual(before, after)
+
+    def test_inspect_unknown_function(self):
+        compiled = wasmtime.Module(WASM)
+        with self.assertRaisesRegex(ValueError, "no sub function export"):
+            compiled.disassemble("sub")
+        with self.assertRaisesRegex(ValueError, "no sub function export"):
+            compiled.cranelift_ir("sub")
+
+    def test_run_command_module(self):
+        config = wasmtime.WasiConfig()
+        compiled = wasmtime.Module(WASM)
+        self.assertEqual(wasmtime.run_command(compiled, config, invoke="add", args=[1, 2]), 3)
+
+
+class TestSections(unittest.TestCase):
+    def module(self, *custom):
+        sections = [
+            section(1, vec([b"\x60" + vec([I32, I32]) + vec([I32])])),
+            section(3, vec([b"\x00", b"\x00"])),
+            section(7, vec([name("add") + b"\x00\x00"])),
+            section(10, vec([body(b"\x20\x00\x20\x01\x6a")] * 2)),
+        ]
+        return module(sections + [section(0, c) for c in custom])
+
+    def test_custom_sections(self):
+        compiled = wasmtime.Module(self.module(
+            name("build-info") + b"commit=1234",
+            name("other") + b"x",
+            name("build-info") + b"",
+        ))
+        self.assertEqual(compiled.custom_sections("build-info"), [b"commit=1234", b""])
+        self.assertEqual(compiled.custom_sections("other"), [b"x"])
+        self.assertEqual(compiled.custom_sections("missing"), [])
+
+    def test_function_names(self):
+        names = vec([leb128(0) + name("add"), leb128(1) + name("$helper")])
+        compiled = wasmtime.Module(self.module(
+            name("name") + b"\x01" + leb128(len(names)) + names,
+        ))
+        self.assertEqual(compiled.function_names, {0: "add", 1: "$helper"})
+        self.assertEqual(len(compiled.custom_sections("name")), 1)
+
+    def test_no_name_section(self):
+        self.assertEqual(wasmtime.Module(self.module()).function_names, {})
+
+
+# Another x86-64 target than the host, the only architecture supported.
+OTHER_TARGET = "x86_64-unknown-%s" % (
+    "linux-gnu" if sys.platform.startswith("freebsd") else "freebsd")
+
+
+class TestCrossTarget(unittest.TestCase):
+    def test_target(self):
+        compiled = wasmtime.Module(WASM, target=OTHER_TARGET)
+        self.assertEqual(compiled.target, OTHER_TARGET)
+        self.assertNotEqual(wasmtime.Module(WASM).target, OTHER_TARGET)
+
+    def test_cannot_instantiate(self):
+        compiled = wasmtime.Module(WASM, target=OTHER_TARGET)
+        with self.assertRaisesRegex(ValueError, "compiled for " + OTHER_TARGET):
+            wasmtime.instantiate(compiled, {})
+
+    def test_deserialize_on_mismatched_host(self):
+        data = wasmtime.Module(WASM, target=OTHER_TARGET).serialize()
+        with self.assertRaisesRegex(ValueError, "compiled for " + OTHER_TARGET):
+            wasmtime.Module.deserialize(data)
+        compiled = wasmtime.Module.deserialize(data, target=OTHER_TARGET)
+        self.assertEqual(compiled.serialize(), data)
+
+    def test_object(self):
+        data = wasmtime.compile_to_object(WASM, target=OTHER_TARGET)
+        self.assertEqual(data[:4], b"\x7fELF")
+        with self.assertRaisesRegex(ValueError, "compiled for " + OTHER_TARGET):
+            wasmtime.Module.from_object(data)
+
+    def test_unknown_target(self):
+        with self.assertRaises(ValueError):
+            wasmtime.Module(WASM, target="not-a-triple")
+
+    def test_unsupported_architecture(self):
+        with self.assertRaisesRegex(ValueError, "unsupported architecture"):
+            wasmtime.Module(WASM, target="aarch64-unknown-linux-gnu")
+        with self.assertRaisesRegex(ValueError, "unsupported architecture"):
+            wasmtime.compile_to_object(WASM, target="aarch64-unknown-linux-gnu")
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,86 @@
+import os
+import platform
+import shutil
+import struct
+import subprocess
+import sys
+import tempfile
+import unittest
+
+import wasmtime
+from test_module import WASM, add
+
+
+def patch_module_symbol(data, **fields):
+    """Overwrites fields of the wasmtime_py_module symbol of an ELF64
+    little-endian object."""
+    data = bytearray(data)
+    shoff, = struct.unpack_from("<Q", data, 0x28)
+    shentsize, shnum = struct.unpack_from("<HH", data, 0x3a)
+    sections = [struct.unpack_from("<IIQQQQIIQQ", data, shoff + i * shentsize)
+                for i in range(shnum)]
+    for _, sh_type, _, _, offset, size, link, _, _, entsize in sections:
+        if sh_type != 2:  # SHT_SYMTAB
+            continue
+        strtab = sections[link][4]
+        for sym in range(offset, offset + size, entsize):
+            st_name, = struct.unpack_from("<I", data, sym)
+            if data[strtab + st_name:].startswith(b"wasmtime_py_module\0"):
+                for field, value in fields.items():
+                    fmt, at = {"st_shndx": ("<H", 6), "st_value": ("<Q", 8),
+                               "st_size": ("<Q", 16)}[field]
+                    struct.pack_into(fmt, data, sym + at, value)
+                return bytes(data)
+    raise AssertionError("no wasmtime_py_module symbol")
+
+
+@unittest.skipUnless(sys.platform.startswith("linux"), "ELF host required")
+class TestObject(unittest.TestCase):
+    def test_elf_object(self):
+        data = wasmtime.compile_to_object(WASM)
+        self.assertEqual(data[:4], b"\x7fELF")
+        self.assertIn(b"_wasm_function_0", data)
+        self.assertIn(b"wasmtime_py_module", data)
+
+    def test_load_object(self):
+        data = wasmtime.compile_to_object(WASM)
+        self.assertEqual(add(wasmtime.Module.from_object(data))(1, 2), 3)
+
+    def test_load_object_of_module(self):
+        compiled = wasmtime.Module(WASM, handle_sigint=True)
+        data = wasmtime.compile_to_object(compiled)
+        loaded = wasmtime.Module.from_object(data)
+        self.assertEqual(loaded.serialize(), compiled.serialize())
+
+    def test_host_target(self):
+        triple = "%s-unknown-linux-gnu" % platform.machine()
+        data = wasmtime.compile_to_object(WASM, target=triple)
+        self.assertEqual(add(wasmtime.Module.from_object(data))(1, 2), 3)
+
+    def test_invalid_target(self):
+        with self.assertRaises(ValueError):
+            wasmtime.compile_to_object(WASM, target="not-a-triple")
+
+    def test_not_an_object(self):
+        with self.assertRaises(ValueError):
+            wasmtime.Module.from_object(WASM)
+
+    def test_invalid_module_symbol(self):
+        data = wasmtime.compile_to_object(WASM)
+        for fields in [{"st_value": 2 ** 64 - 1}, {"st_size": 2 ** 63},
+                       {"st_shndx": 0}, {"st_shndx": 0xfff1}]:
+            with self.subTest(**fields), self.assertRaises(ValueError):
+                wasmtime.Module.from_object(patch_module_symbol(data, **fields))
+
+    @unittest.skipUnless(shutil.which("objdump"), "objdump is not installed")
+    def test_objdump(self):
+        with tempfile.TemporaryDirectory() as directory:
+            path = os.path.join(directory, "module.o")
+            with open(path, "wb") as f:
+                f.write(wasmtime.compile_to_object(WASM))
+            out = subprocess.check_output(["objdump", "-d", path]).decode()
+        self.assertIn("<_wasm_function_0>:", out)
+
+
+if __name__ == "__main__":
+    unittest.main()
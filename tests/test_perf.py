@@ -0,0 +1,61 @@
+import os
+import platform
+import unittest
+
+import wasmtime
+from test_backtrace import CALLING_WASM
+
+
+def log():
+    pass
+
+
+class TestPerfMap(unittest.TestCase):
+    def setUp(self):
+        self.path = wasmtime.enable_perf_map()
+        self.addCleanup(wasmtime.disable_perf_map)
+
+    def names(self):
+        with open(self.path) as f:
+            lines = f.read().splitlines()
+        names = []
+        for line in lines:
+            start, size, name = line.split(" ", 2)
+            self.assertGreater(int(size, 16), 0)
+            names.append(name)
+        return names
+
+    def test_path(self):
+        self.assertEqual(self.path, "/tmp/perf-%d.map" % os.getpid())
+
+    def test_functions(self):
+        imports = {"env": {"log": log}}
+        run = wasmtime.instantiate(CALLING_WASM, imports).instance.exports["run"]
+        run()
+        names = self.names()
+        self.assertIn("demo!inner", names)
+        self.assertIn("demo!outer", names)
+        self.assertIn("trampoline to demo!outer", names)
+        self.assertIn("trampoline to host function log", names)
+
+    @unittest.skipUnless(platform.machine() in ("x86_64", "AMD64"), "x86-64 only")
+    def test_lazy(self):
+        imports = {"env": {"log": log}}
+        run = wasmtime.instantiate(CALLING_WASM, imports, lazy=True).instance.exports["run"]
+        names = self.names()
+        self.assertIn("demo!inner (lazy stub)", names)
+        self.assertIn("lazy compilation trampoline", names)
+        # Functions are listed when compiled, on their first call.
+        compiled = names.count("demo!inner")
+        run()
+        self.assertEqual(self.names().count("demo!inner"), compiled + 1)
+
+    def test_disabled(self):
+        wasmtime.disable_perf_map()
+        before = self.names()
+        wasmtime.instantiate(CALLING_WASM, {"env": {"log": log}})
+        self.assertEqual(self.names(), before)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,74 @@
+import platform
+import sys
+import unittest
+
+import wasmtime
+from test_backtrace import subsection
+from wasm_binary import I32, body, leb128, module, name, section, vec
+
+
+# (module $prof
+#   (func $spin (param i32) loop local.get 0 i32.const 1 i32.sub local.tee 0 br_if 0 end)
+#   (func $outer (export "run") (param i32) local.get 0 call $spin))
+WASM = module([
+    section(1, vec([b"\x60" + vec([I32]) + vec([])])),
+    section(3, vec([b"\x00", b"\x00"])),
+    section(7, vec([name("run") + b"\x00\x01"])),
+    section(10, vec([body(b"\x03\x40\x20\x00\x41\x01\x6b\x22\x00\x0d\x00\x0b"),
+                     body(b"\x20\x00\x10\x00")])),
+    section(0, name("name")
+            + subsection(0, name("prof"))
+            + subsection(1, vec([leb128(0) + name("spin"), leb128(1) + name("outer")]))),
+])
+
+ITERATIONS = 300000000
+
+
+@unittest.skipUnless(sys.platform.startswith("linux")
+                     and platform.machine() == "x86_64", "x86-64 Linux only")
+class TestProfile(unittest.TestCase):
+    def run_profiled(self, instance, profiled=None):
+        with wasmtime.profile(profiled or instance) as p:
+            instance.exports["run"](ITERATIONS)
+        return p
+
+    def test_functions(self):
+        p = self.run_profiled(wasmtime.instantiate(WASM, {}).instance)
+        self.assertGreater(p.samples, 0)
+        functions = {name: (own, total) for name, own, total in p.functions()}
+        self.assertEqual(p.functions()[0][0], "prof!spin")
+        own, total = functions["prof!spin"]
+        self.assertGreater(own, 0)
+        self.assertEqual(own, total)
+        self.assertIn("prof!outer", functions)
+
+    def test_collapsed(self):
+        p = self.run_profiled(wasmtime.instantiate(WASM, {}).instance)
+        stacks = dict(line.rsplit(" ", 1) for line in p.collapsed().splitlines())
+        self.assertIn("prof!outer;prof!spin", stacks)
+        self.assertEqual(sum(map(int, stacks.values())), p.samples)
+
+    def test_other_instance(self):
+        instance = wasmtime.instantiate(WASM, {}).instance
+        other = wasmtime.instantiate(WASM, {}).instance
+        p = self.run_profiled(instance, profiled=other)
+        self.assertEqual(p.samples, 0)
+        self.assertEqual(p.collapsed(), "")
+
+    def test_one_at_a_time(self):
+        instance = wasmtime.instantiate(WASM, {}).instance
+        with wasmtime.profile(instance):
+            with self.assertRaises(RuntimeError):
+                with wasmtime.profile(instance):
+                    pass
+
+    def test_invalid_interval(self):
+        instance = wasmtime.instantiate(WASM, {}).instance
+        with self.assertRaises(ValueError):
+            wasmtime.Profile(instance, 0)
+        with self.assertRaises(ValueError):
+            wasmtime.Profile(instance, 1e-7)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,98 @@
+import threading
+import unittest
+
+import wasmtime
+from wasm_binary import I32, body, module, name, section, sleb128, vec
+
+
+# (module
+#   (import "env" "memory" (memory 1 1 shared))
+#   (func (export "wait") (result i32)
+#     i32.const 0 i32.const 0 i64.const -1 i32.atomic.wait)
+#   (func (export "notify") (result i32)
+#     i32.const 0 i32.const 1 memory.atomic.notify)
+#   (func (export "add") (param i32) (result i32)
+#     i32.const 4 local.get 0 i32.atomic.rmw.add)
+#   (func (export "store") (param i32)
+#     i32.const 0 local.get 0 i32.atomic.store))
+WASM = module([
+    section(1, vec([
+        b"\x60" + vec([]) + vec([I32]),
+        b"\x60" + vec([I32]) + vec([I32]),
+        b"\x60" + vec([I32]) + vec([]),
+    ])),
+    section(2, vec([name("env") + name("memory") + b"\x02\x03\x01\x01"])),
+    section(3, vec([b"\x00", b"\x00", b"\x01", b"\x02"])),
+    section(7, vec([
+        name("wait") + b"\x00\x00",
+        name("notify") + b"\x00\x01",
+        name("add") + b"\x00\x02",
+        name("store") + b"\x00\x03",
+    ])),
+    section(10, vec([
+        body(b"\x41\x00\x41\x00\x42" + sleb128(-1) + b"\xfe\x01\x02\x00"),
+        body(b"\x41\x00\x41\x01\xfe\x00\x02\x00"),
+        body(b"\x41\x04\x20\x00\xfe\x1e\x02\x00"),
+        body(b"\x41\x00\x20\x00\xfe\x17\x02\x00"),
+    ])),
+])
+
+THREADS = 4
+ITERATIONS = 1000
+
+
+class TestSharedMemory(unittest.TestCase):
+    def instantiate(self, memory):
+        # Each instance gets its own store, so they can run in parallel.
+        res = wasmtime.instantiate(WASM, {"env": {"memory": memory}})
+        return res.instance.exports
+
+    def test_shared_memory_needs_maximum(self):
+        with self.assertRaises(ValueError):
+            wasmtime.Memory(1, shared=True)
+        self.assertTrue(wasmtime.Memory(1, 1, shared=True).shared)
+        self.assertFalse(wasmtime.Memory(1, 1).shared)
+
+    def test_atomic_add_from_many_threads(self):
+        memory = wasmtime.Memory(1, 1, shared=True)
+        adds = [self.instantiate(memory)["add"] for _ in range(THREADS)]
+
+        def run(add):
+            for _ in range(ITERATIONS):
+                add(1)
+
+        threads = [threading.Thread(target=run, args=(add,)) for add in adds]
+        for t in threads:
+            t.start()
+        for t in threads:
+            t.join()
+        self.assertEqual(int.from_bytes(bytes(memory)[4:8], "little"),
+                         THREADS * ITERATIONS)
+
+    def test_wait_and_notify(self):
+        memory = wasmtime.Memory(1, 1, shared=True)
+        waiter = self.instantiate(memory)
+        notifier = self.instantiate(memory)
+        results = []
+        thread = threading.Thread(target=lambda: results.append(waiter["wait"]()))
+        thread.start()
+        # The notification is lost if it comes before the wait.
+        while notifier["notify"]() == 0:
+            thread.join(0.01)
+        thread.join()
+        self.assertEqual(results, [0])
+
+    def test_wait_not_equal(self):
+        memory = wasmtime.Memory(1, 1, shared=True)
+        exports = self.instantiate(memory)
+        exports["store"](1)
+        self.assertEqual(exports["wait"](), 1)
+
+    def test_wait_on_unshared_memory_traps(self):
+        exports = self.instantiate(wasmtime.Memory(1, 1))
+        with self.assertRaises(wasmtime.Trap):
+            exports["wait"]()
+
+
+if __name__ == "__main__":
+    unittest.main()
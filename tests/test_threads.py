@@ -0,0 +1,126 @@
+import threading
+import unittest
+
+import wasmtime
+from wasm_binary import I32, body, module, name, section, vec
+
+
+# (module
+#   (import "env" "callback" (func $cb (param i32) (result i32)))
+#   (memory (export "memory") 1)
+#   (func (export "add") (param i32 i32) (result i32)
+#     local.get 0 local.get 1 i32.add)
+#   (func (export "call_back") (param i32) (result i32)
+#     local.get 0 call $cb)
+#   (func (export "bump") (result i32)
+#     ;; non-atomic increment of the i32 at address 0
+#     i32.const 0 i32.const 0 i32.load i32.const 1 i32.add i32.store
+#     i32.const 0 i32.load))
+WASM = module([
+    section(1, vec([
+        b"\x60" + vec([I32]) + vec([I32]),
+        b"\x60" + vec([I32, I32]) + vec([I32]),
+        b"\x60" + vec([]) + vec([I32]),
+    ])),
+    section(2, vec([name("env") + name("callback") + b"\x00\x00"])),
+    section(3, vec([b"\x01", b"\x00", b"\x02"])),
+    section(5, vec([b"\x00\x01"])),
+    section(7, vec([
+        name("memory") + b"\x02\x00",
+        name("add") + b"\x00\x01",
+        name("call_back") + b"\x00\x02",
+        name("bump") + b"\x00\x03",
+    ])),
+    section(10, vec([
+        body(b"\x20\x00\x20\x01\x6a"),
+        body(b"\x20\x00\x10\x00"),
+        body(b"\x41\x00\x41\x00\x28\x02\x00\x41\x01\x6a\x36\x02\x00"
+             b"\x41\x00\x28\x02\x00"),
+    ])),
+])
+
+THREADS = 8
+ITERATIONS = 200
+
+
+def run_threads(target):
+    errors = []
+
+    def run(index):
+        try:
+            target(index)
+        except BaseException as e:
+            errors.append(e)
+
+    threads = [threading.Thread(target=run, args=(i,)) for i in range(THREADS)]
+    for t in threads:
+        t.start()
+    for t in threads:
+        t.join()
+    if errors:
+        raise errors[0]
+
+
+class TestThreads(unittest.TestCase):
+    def instantiate(self, callback=lambda x: x):
+        res = wasmtime.instantiate(WASM, {"env": {"callback": callback}})
+        return res.instance.exports
+
+    def test_call_from_many_threads(self):
+        exports = self.instantiate()
+        add = exports["add"]
+
+        def target(index):
+            for i in range(ITERATIONS):
+                self.assertEqual(add(index, i), index + i)
+
+        run_threads(target)
+
+    def test_calls_are_serialized(self):
+        exports = self.instantiate()
+        bump = exports["bump"]
+        run_threads(lambda index: [bump() for _ in range(ITERATIONS)])
+        self.assertEqual(bump(), THREADS * ITERATIONS + 1)
+        self.assertEqual(bytes(exports["memory"])[0:4],
+                         (THREADS * ITERATIONS + 1).to_bytes(4, "little"))
+
+    def test_python_import_from_many_threads(self):
+        callers = set()
+
+        def callback(x):
+            callers.add(threading.get_ident())
+            return x * 2
+
+        call_back = self.instantiate(callback)["call_back"]
+
+        def target(index):
+            for i in range(ITERATIONS):
+                self.assertEqual(call_back(i), i * 2)
+
+        run_threads(target)
+        self.assertEqual(len(callers), THREADS)
+
+    def test_reentrant_call_from_many_threads(self):
+        exports = {}
+
+        def callback(x):
+            return exports["add"](x, 1)
+
+        exports.update(self.instantiate(callback))
+        call_back = exports["call_back"]
+
+        def target(index):
+            for i in range(ITERATIONS):
+                self.assertEqual(call_back(i), i + 1)
+
+        run_threads(target)
+
+    def test_memory_grow_from_many_threads(self):
+        exports = self.instantiate()
+        memory = exports["memory"]
+        run_threads(lambda index: memory.grow(1))
+        self.assertEqual(memory.current, 1 + THREADS)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,97 @@
+import time
+import unittest
+
+import wasmtime
+from test_interrupt import WASM
+from wasm_binary import body, module, name, section, vec
+
+
+# (module
+#   (import "env" "sleep" (func))
+#   (func (export "trap") call 0 unreachable)
+#   (func (export "return") call 0)
+#   (func (export "spin") loop br 0 end))
+SLEEPING = module([
+    section(1, vec([b"\x60" + vec([]) + vec([])])),
+    section(2, vec([name("env") + name("sleep") + b"\x00\x00"])),
+    section(3, vec([b"\x00", b"\x00", b"\x00"])),
+    section(7, vec([
+        name("trap") + b"\x00\x01",
+        name("return") + b"\x00\x02",
+        name("spin") + b"\x00\x03",
+    ])),
+    section(10, vec([
+        body(b"\x10\x00\x00"),
+        body(b"\x10\x00"),
+        body(b"\x03\x40\x0c\x00\x0b"),
+    ])),
+])
+
+
+class TestTimeout(unittest.TestCase):
+    def test_call_timeout(self):
+        spin = wasmtime.instantiate(WASM, {}).instance.exports["spin"]
+        start = time.monotonic()
+        with self.assertRaises(wasmtime.Timeout):
+            spin(timeout=0.1)
+        self.assertGreaterEqual(time.monotonic() - start, 0.1)
+
+    def test_store_timeout(self):
+        store = wasmtime.Store(timeout=0.1)
+        self.assertEqual(store.timeout, 0.1)
+        spin = wasmtime.instantiate(WASM, {}, store=store).instance.exports["spin"]
+        with self.assertRaises(wasmtime.Timeout):
+            spin()
+
+    def test_call_overrides_store(self):
+        store = wasmtime.Store(timeout=60)
+        spin = wasmtime.instantiate(WASM, {}, store=store).instance.exports["spin"]
+        start = time.monotonic()
+        with self.assertRaises(wasmtime.Timeout):
+            spin(timeout=0.1)
+        self.assertLess(time.monotonic() - start, 60)
+
+    def test_finished_in_time(self):
+        exports = wasmtime.instantiate(WASM, {}).instance.exports
+        for _ in range(100):
+            exports["nop"](timeout=10)
+        with self.assertRaises(wasmtime.Timeout):
+            exports["spin"](timeout=0.1)
+        exports["nop"](timeout=10)
+
+    def test_trap_after_deadline(self):
+        # The deadline passes during the import, but `unreachable` traps
+        # before any interrupt check.
+        res = wasmtime.instantiate(SLEEPING, {"env": {"sleep": lambda: time.sleep(0.2)}})
+        with self.assertRaises(wasmtime.Trap) as cm:
+            res.instance.exports["trap"](timeout=0.1)
+        self.assertNotIsInstance(cm.exception, wasmtime.Interrupted)
+
+    def test_interrupt_after_deadline(self):
+        handle = None
+
+        def sleep():
+            time.sleep(0.2)
+            handle.interrupt()
+
+        instance = wasmtime.instantiate(SLEEPING, {"env": {"sleep": sleep}}).instance
+        handle = instance.interrupt_handle()
+        instance.exports["return"](timeout=0.1)
+        # The request outlives the deadline of the call it was made in.
+        with self.assertRaises(wasmtime.Interrupted) as cm:
+            instance.exports["spin"](timeout=10)
+        self.assertNotIsInstance(cm.exception, wasmtime.Timeout)
+
+    def test_timeout_is_interrupted(self):
+        self.assertTrue(issubclass(wasmtime.Timeout, wasmtime.Interrupted))
+
+    def test_invalid_timeout(self):
+        nop = wasmtime.instantiate(WASM, {}).instance.exports["nop"]
+        with self.assertRaises(ValueError):
+            nop(timeout=0)
+        with self.assertRaises(ValueError):
+            wasmtime.Store(timeout=-1)
+
+
+if __name__ == "__main__":
+    unittest.main()
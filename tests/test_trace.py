@@ -0,0 +1,109 @@
+import unittest
+
+import wasmtime
+from wasm_binary import I32, body, module, name, section, vec
+
+
+# (module
+#   (import "env" "add" (func $add (param i32 i32) (result i32)))
+#   (func (export "run") (param i32) (result i32) local.get 0 i32.const 1 call $add))
+WASM = module([
+    section(1, vec([b"\x60" + vec([I32, I32]) + vec([I32]),
+                    b"\x60" + vec([I32]) + vec([I32])])),
+    section(2, vec([name("env") + name("add") + b"\x00\x00"])),
+    section(3, vec([b"\x01"])),
+    section(7, vec([name("run") + b"\x00\x01"])),
+    section(10, vec([body(b"\x20\x00\x41\x01\x10\x00")])),
+])
+
+
+def add(a: "i32", b: "i32") -> "i32":
+    return a + b
+
+
+def fail(a: "i32", b: "i32") -> "i32":
+    raise KeyError("from the host")
+
+
+EVENTS = [
+    ("call_wasm", "run", (41,)),
+    ("call_host", "env.add", (41, 1)),
+    ("return_host", "env.add", (42,)),
+    ("return_wasm", "run", (42,)),
+]
+
+
+class TestTrace(unittest.TestCase):
+    def setUp(self):
+        self.events = []
+
+    def trace(self, event, name, values):
+        self.events.append((event, name, values))
+
+    def instantiate(self, host=add, **kwargs):
+        return wasmtime.instantiate(WASM, {"env": {"add": host}}, **kwargs).instance
+
+    def test_instance(self):
+        instance = self.instantiate()
+        instance.set_trace(self.trace)
+        self.assertEqual(instance.exports["run"](41), 42)
+        self.assertEqual(self.events, EVENTS)
+
+    def test_store(self):
+        store = wasmtime.Store()
+        store.set_trace(self.trace)
+        instance = self.instantiate(store=store)
+        self.assertEqual(instance.exports["run"](41), 42)
+        self.assertEqual(self.events, EVENTS)
+
+    def test_store_and_instance(self):
+        store = wasmtime.Store()
+        store.set_trace(lambda *event: self.trace("store", *event))
+        instance = self.instantiate(store=store)
+        instance.set_trace(lambda *event: self.trace("instance", *event))
+        instance.exports["run"](41)
+        self.assertEqual([event[0] for event in self.events], ["store", "instance"] * 4)
+
+    def test_other_instance(self):
+        instance = self.instantiate()
+        instance.set_trace(self.trace)
+        self.instantiate().exports["run"](41)
+        self.assertEqual(self.events, [])
+
+    def test_disabled(self):
+        instance = self.instantiate()
+        instance.set_trace(self.trace)
+        instance.set_trace(None)
+        instance.exports["run"](41)
+        self.assertEqual(self.events, [])
+
+    def test_host_exception(self):
+        instance = self.instantiate(fail)
+        instance.set_trace(self.trace)
+        with self.assertRaises(KeyError) as cm:
+            instance.exports["run"](41)
+        self.assertEqual([event[:2] for event in self.events],
+                         [event[:2] for event in EVENTS])
+        self.assertIs(self.events[2][2], cm.exception)
+        self.assertIs(self.events[3][2], cm.exception)
+
+    def test_callback_exception(self):
+        called = []
+
+        def host(a: "i32", b: "i32") -> "i32":
+            called.append((a, b))
+            return 0
+
+        def trace(event, name, values):
+            raise ValueError(event)
+
+        instance = self.instantiate(host)
+        instance.set_trace(trace)
+        with self.assertRaises(ValueError) as cm:
+            instance.exports["run"](41)
+        self.assertEqual(cm.exception.args, ("call_wasm",))
+        self.assertEqual(called, [])
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,295 @@
+import contextlib
+import io
+import os
+import tempfile
+import unittest
+
+import wasmtime
+import wasmtime.__main__
+from wasm_binary import I32, I64, body, leb128, module, name, section, vec
+
+
+# (module
+#   (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32)))
+#   (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+#   (import "wasi_snapshot_preview1" "args_sizes_get" (func (param i32 i32) (result i32)))
+#   (import "wasi_snapshot_preview1" "fd_read" (func (param i32 i32 i32 i32) (result i32)))
+#   (memory (export "memory") 1)
+#   (data (i32.const 0) "\10\00\00\00\06\00\00\00")
+#   (data (i32.const 16) "hello\n")
+#   (func (export "hello") (result i32)
+#     i32.const 1 i32.const 0 i32.const 1 i32.const 8 call 0)
+#   (func (export "exit") (param i32)
+#     local.get 0 call 1)
+#   (func (export "argc") (result i32)
+#     i32.const 32 i32.const 36 call 2 drop i32.const 32 i32.load)
+#   (func (export "echo") (result i32)
+#     i32.const 0 i32.const 0 i32.const 1 i32.const 8 call 3 drop
+#     i32.const 1 i32.const 0 i32.const 1 i32.const 8 call 0))
+def wasi_module(module_name):
+    return module([
+        section(1, vec([
+            b"\x60" + vec([I32, I32, I32, I32]) + vec([I32]),
+            b"\x60" + vec([I32]) + vec([]),
+            b"\x60" + vec([]) + vec([I32]),
+            b"\x60" + vec([I32, I32]) + vec([I32]),
+        ])),
+        section(2, vec([
+            name(module_name) + name("fd_write") + b"\x00\x00",
+            name(module_name) + name("proc_exit") + b"\x00\x01",
+            name(module_name) + name("args_sizes_get") + b"\x00\x03",
+            name(module_name) + name("fd_read") + b"\x00\x00",
+        ])),
+        section(3, vec([b"\x02", b"\x01", b"\x02", b"\x02"])),
+        section(5, vec([b"\x00\x01"])),
+        section(7, vec([
+            name("memory") + b"\x02\x00",
+            name("hello") + b"\x00\x04",
+            name("exit") + b"\x00\x05",
+            name("argc") + b"\x00\x06",
+            name("echo") + b"\x00\x07",
+        ])),
+        section(10, vec([
+            body(b"\x41\x01\x41\x00\x41\x01\x41\x08\x10\x00"),
+            body(b"\x20\x00\x10\x01"),
+            body(b"\x41\x20\x41\x24\x10\x02\x1a\x41\x20\x28\x02\x00"),
+            body(b"\x41\x00\x41\x00\x41\x01\x41\x08\x10\x03\x1a"
+                 b"\x41\x01\x41\x00\x41\x01\x41\x08\x10\x00"),
+        ])),
+        section(11, vec([
+            b"\x00\x41\x00\x0b" + leb128(8) + b"\x10\x00\x00\x00\x06\x00\x00\x00",
+            b"\x00\x41\x10\x0b" + leb128(6) + b"hello\n",
+        ])),
+    ])
+
+
+class TestWasi(unittest.TestCase):
+    def instantiate(self, config, module_name="wasi_snapshot_preview1"):
+        wasi = wasmtime.WasiInstance(config)
+        res = wasmtime.instantiate(wasi_module(module_name), {module_name: wasi})
+        return wasi, res.instance.exports
+
+    def test_captured_stdout(self):
+        r, w = os.pipe()
+        try:
+            _, exports = self.instantiate(wasmtime.WasiConfig(stdout=w))
+            self.assertEqual(exports["hello"](), 0)
+            self.assertEqual(os.read(r, 6), b"hello\n")
+        finally:
+            os.close(r)
+            os.close(w)
+
+    def test_legacy_module_name(self):
+        r, w = os.pipe()
+        try:
+            config = wasmtime.WasiConfig(stdout=w)
+            _, exports = self.instantiate(config, "wasi_unstable")
+            self.assertEqual(exports["hello"](), 0)
+            self.assertEqual(os.read(r, 6), b"hello\n")
+        finally:
+            os.close(r)
+            os.close(w)
+
+    def test_stdout_to_string_io(self):
+        stdout = io.StringIO()
+        _, exports = self.instantiate(wasmtime.WasiConfig(stdout=stdout))
+        exports["hello"]()
+        exports["hello"]()
+        self.assertEqual(stdout.getvalue(), "hello\nhello\n")
+
+    def test_stdout_to_bytes_io(self):
+        stdout = io.BytesIO()
+        _, exports = self.instantiate(wasmtime.WasiConfig(stdout=stdout))
+        exports["hello"]()
+        self.assertEqual(stdout.getvalue(), b"hello\n")
+
+    def test_stdin_from_bytes(self):
+        stdout = io.StringIO()
+        config = wasmtime.WasiConfig(stdin=b"abcdef", stdout=stdout)
+        _, exports = self.instantiate(config)
+        self.assertEqual(exports["echo"](), 0)
+        self.assertEqual(stdout.getvalue(), "abcdef")
+
+    def test_stdin_from_file_like(self):
+        stdout = io.BytesIO()
+        config = wasmtime.WasiConfig(stdin=io.StringIO("uvwxyz"), stdout=stdout)
+        _, exports = self.instantiate(config)
+        exports["echo"]()
+        self.assertEqual(stdout.getvalue(), b"uvwxyz")
+
+    def test_stream_exception(self):
+        class Failing:
+            def write(self, data):
+                raise ValueError("full")
+
+        _, exports = self.instantiate(wasmtime.WasiConfig(stdout=Failing()))
+        with self.assertRaisesRegex(ValueError, "full"):
+            exports["hello"]()
+
+    def test_argv(self):
+        config = wasmtime.WasiConfig(argv=["prog", "a", "b"])
+        _, exports = self.instantiate(config)
+        self.assertEqual(exports["argc"](), 3)
+
+    def test_proc_exit(self):
+        wasi, exports = self.instantiate(wasmtime.WasiConfig())
+        self.assertIsNone(wasi.exit_code)
+        with self.assertRaises(wasmtime.WasiExit) as cm:
+            exports["exit"](3)
+        self.assertEqual(cm.exception.args[0], 3)
+        self.assertEqual(wasi.exit_code, 3)
+
+    def test_missing_preopen(self):
+        config = wasmtime.WasiConfig(preopens={"/": "/nonexistent/directory"})
+        with self.assertRaises(OSError):
+            wasmtime.WasiInstance(config)
+
+
+# (module
+#   (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32)))
+#   (import "wasi_snapshot_preview1" "fd_read" (func (param i32 i32 i32 i32) (result i32)))
+#   (import "wasi_snapshot_preview1" "path_open"
+#     (func (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+#   (memory (export "memory") 1)
+#   (data (i32.const 0) "\10\00\00\00\06\00\00\00")
+#   (data (i32.const 16) "hello\n")
+#   (data (i32.const 48) "out.txt")
+#   (data (i32.const 56) "in.txt")
+#   (func (export "create") (result i32)
+#     ;; Opens "out.txt" of the preopen 3 with O_CREAT|O_TRUNC, writes "hello\n".
+#     i32.const 3 i32.const 0 i32.const 48 i32.const 7 i32.const 9
+#     i64.const 64 i64.const 0 i32.const 0 i32.const 40 call 2 drop
+#     i32.const 40 i32.load i32.const 0 i32.const 1 i32.const 8 call 0)
+#   (func (export "copy") (result i32)
+#     ;; Reads 6 bytes of "in.txt" of the preopen 3 and writes them to stdout.
+#     i32.const 3 i32.const 0 i32.const 56 i32.const 6 i32.const 0
+#     i64.const 2 i64.const 0 i32.const 0 i32.const 40 call 2 drop
+#     i32.const 40 i32.load i32.const 0 i32.const 1 i32.const 8 call 1 drop
+#     i32.const 1 i32.const 0 i32.const 1 i32.const 8 call 0))
+VFS_WASM = module([
+    section(1, vec([
+        b"\x60" + vec([I32, I32, I32, I32]) + vec([I32]),
+        b"\x60" + vec([I32, I32, I32, I32, I32, I64, I64, I32, I32]) + vec([I32]),
+        b"\x60" + vec([]) + vec([I32]),
+    ])),
+    section(2, vec([
+        name("wasi_snapshot_preview1") + name("fd_write") + b"\x00\x00",
+        name("wasi_snapshot_preview1") + name("fd_read") + b"\x00\x00",
+        name("wasi_snapshot_preview1") + name("path_open") + b"\x00\x01",
+    ])),
+    section(3, vec([b"\x02", b"\x02"])),
+    section(5, vec([b"\x00\x01"])),
+    section(7, vec([
+        name("memory") + b"\x02\x00",
+        name("create") + b"\x00\x03",
+        name("copy") + b"\x00\x04",
+    ])),
+    section(10, vec([
+        body(b"\x41\x03\x41\x00\x41\x30\x41\x07\x41\x09"
+             b"\x42\xc0\x00\x42\x00\x41\x00\x41\x28\x10\x02\x1a"
+             b"\x41\x28\x28\x02\x00\x41\x00\x41\x01\x41\x08\x10\x00"),
+        body(b"\x41\x03\x41\x00\x41\x38\x41\x06\x41\x00"
+             b"\x42\x02\x42\x00\x41\x00\x41\x28\x10\x02\x1a"
+             b"\x41\x28\x28\x02\x00\x41\x00\x41\x01\x41\x08\x10\x01\x1a"
+             b"\x41\x01\x41\x00\x41\x01\x41\x08\x10\x00"),
+    ])),
+    section(11, vec([
+        b"\x00\x41\x00\x0b" + leb128(8) + b"\x10\x00\x00\x00\x06\x00\x00\x00",
+        b"\x00\x41\x10\x0b" + leb128(6) + b"hello\n",
+        b"\x00\x41\x30\x0b" + leb128(7) + b"out.txt",
+        b"\x00\x41\x38\x0b" + leb128(6) + b"in.txt",
+    ])),
+])
+
+
+class TestVirtualDir(unittest.TestCase):
+    def instantiate(self, vdir, stdout=None):
+        config = wasmtime.WasiConfig(preopens={"/sandbox": vdir}, stdout=stdout)
+        wasi = wasmtime.WasiInstance(config)
+        res = wasmtime.instantiate(VFS_WASM, {"wasi_snapshot_preview1": wasi})
+        return res.instance.exports
+
+    def test_files(self):
+        vdir = wasmtime.VirtualDir({"a.txt": b"a", "/sub/dir/b.txt": b"b"})
+        self.assertEqual(vdir.files(), {"a.txt": b"a", "sub/dir/b.txt": b"b"})
+        vdir.write("sub/c.txt", b"c")
+        self.assertEqual(vdir.read("/sub/c.txt"), b"c")
+        with self.assertRaises(FileNotFoundError):
+            vdir.read("missing.txt")
+
+    def test_guest_writes_file(self):
+        vdir = wasmtime.VirtualDir({"in.txt": b"abcdef"})
+        exports = self.instantiate(vdir)
+        self.assertEqual(exports["create"](), 0)
+        self.assertEqual(vdir.files(), {"in.txt": b"abcdef", "out.txt": b"hello\n"})
+
+    def test_max_size(self):
+        vdir = wasmtime.VirtualDir({"in.txt": b"abcdef"}, max_size=10)
+        exports = self.instantiate(vdir)
+        self.assertEqual(exports["create"](), 51)  # NOSPC
+        self.assertEqual(vdir.files(), {"in.txt": b"abcdef", "out.txt": b""})
+        with self.assertRaises(OSError):
+            wasmtime.VirtualDir({"in.txt": b"abcdef"}, max_size=4)
+
+    def test_guest_reads_file(self):
+        stdout = io.BytesIO()
+        exports = self.instantiate(wasmtime.VirtualDir({"in.txt": b"abcdef"}), stdout)
+        self.assertEqual(exports["copy"](), 0)
+        self.assertEqual(stdout.getvalue(), b"abcdef")
+
+
+class TestHostDir(unittest.TestCase):
+    def setUp(self):
+        tmp = tempfile.TemporaryDirectory()
+        self.addCleanup(tmp.cleanup)
+        self.sandbox = os.path.join(tmp.name, "sandbox")
+        self.outside = os.path.join(tmp.name, "outside.txt")
+        os.mkdir(self.sandbox)
+
+    def instantiate(self):
+        config = wasmtime.WasiConfig(preopens={"/sandbox": self.sandbox})
+        wasi = wasmtime.WasiInstance(config)
+        res = wasmtime.instantiate(VFS_WASM, {"wasi_snapshot_preview1": wasi})
+        return res.instance.exports
+
+    def test_guest_writes_file(self):
+        self.assertEqual(self.instantiate()["create"](), 0)
+        with open(os.path.join(self.sandbox, "out.txt"), "rb") as f:
+            self.assertEqual(f.read(), b"hello\n")
+
+    def test_dangling_symlink_not_followed(self):
+        os.symlink(self.outside, os.path.join(self.sandbox, "out.txt"))
+        self.assertNotEqual(self.instantiate()["create"](), 0)
+        self.assertFalse(os.path.exists(self.outside))
+
+    def test_symlink_not_followed(self):
+        with open(self.outside, "wb") as f:
+            f.write(b"outside")
+        os.symlink(self.outside, os.path.join(self.sandbox, "out.txt"))
+        self.assertNotEqual(self.instantiate()["create"](), 0)
+        with open(self.outside, "rb") as f:
+            self.assertEqual(f.read(), b"outside")
+
+
+class TestRunCommand(unittest.TestCase):
+    def test_run_command(self):
+        data = wasi_module("wasi_snapshot_preview1")
+        config = wasmtime.WasiConfig(argv=["prog", "x"])
+        self.assertEqual(wasmtime.run_command(data, config, invoke="argc"), 2)
+        with self.assertRaises(wasmtime.WasiExit) as cm:
+            wasmtime.run_command(data, config, invoke="exit", args=[4])
+        self.assertEqual(cm.exception.args[0], 4)
+        self.assertNotIsInstance(cm.exception, wasmtime.Trap)
+        with self.assertRaises(ValueError):
+            wasmtime.run_command(data, config)  # no _start
+
+    def test_main(self):
+        with tempfile.NamedTemporaryFile(suffix=".wasm") as f:
+            f.write(wasi_module("wasi_snapshot_preview1"))
+            f.flush()
+            main = wasmtime.__main__.main
+            self.assertEqual(main(["run", "--invoke", "exit", f.name, "--", "5"]), 5)
+            stdout = io.StringIO()
+            with contextlib.redirect_stdout(stdout):
+                self.assertEqual(main(["run", "--invoke", "argc", f.name]), 0)
+            self.assertEqual(stdout.getvalue(), "1\n")
@@ -0,0 +1,49 @@
+"""Helpers assembling wasm binaries for the tests."""
+
+
+def leb128(n):
+    out = bytearray()
+    while True:
+        byte = n & 0x7f
+        n >>= 7
+        if n:
+            out.append(byte | 0x80)
+        else:
+            out.append(byte)
+            return bytes(out)
+
+
+def sleb128(n):
+    out = bytearray()
+    while True:
+        byte = n & 0x7f
+        n >>= 7
+        if (n == 0 and not byte & 0x40) or (n == -1 and byte & 0x40):
+            out.append(byte)
+            return bytes(out)
+        out.append(byte | 0x80)
+
+
+def vec(items):
+    return leb128(len(items)) + b"".join(items)
+
+
+def name(s):
+    return vec([bytes([c]) for c in s.encode()])
+
+
+def section(id, payload):
+    return bytes([id]) + leb128(len(payload)) + payload
+
+
+def body(code):
+    code = b"\x00" + code + b"\x0b"  # no locals, end
+    return leb128(len(code)) + code
+
+
+I32 = b"\x7f"
+I64 = b"\x7e"
+
+
+def module(sections):
+    return b"\x00asm\x01\x00\x00\x00" + b"".join(sections)